@@ -1,6 +1,6 @@
 use base64::prelude::*;
 
-use bevy::prelude::{App, Plugin, Res, ResMut, Commands, Entity, Name, Resource, Update, Query, IntoScheduleConfigs};
+use bevy::prelude::{App, Plugin, Res, ResMut, Commands, Entity, Name, Resource, Update, Query, IntoScheduleConfigs, Local};
 use bevy_state::prelude::{States, State, NextState, OnEnter};
 use bevy_state::app::AppExtStates;
 use log::{info, warn, error};
@@ -14,10 +14,32 @@ use std::net::{SocketAddr, Ipv4Addr};
 
 // Resource to store connection details from matchmaker
 #[derive(Resource, Clone)]
+#[non_exhaustive]
 pub struct ConnectionDetails {
     pub connect_token: ConnectToken,
     pub server_addr: SocketAddr,
     pub cert_digest: String,
+    /// The session we were given a token for, if the matchmaker said which
+    pub session_id: Option<String>,
+    /// Our client id in the token, if the matchmaker said, for reconnecting as the same player
+    pub client_id: Option<u64>,
+    /// Share this with friends, along with `session_id`, so they can join our session
+    pub join_token: Option<String>,
+}
+
+impl ConnectionDetails {
+    /// Details for a gameserver, without any of the optional session details. Set those fields
+    /// afterwards if you have them.
+    pub fn new(connect_token: ConnectToken, server_addr: SocketAddr, cert_digest: String) -> Self {
+        Self {
+            connect_token,
+            server_addr,
+            cert_digest,
+            session_id: None,
+            client_id: None,
+            join_token: None,
+        }
+    }
 }
 
 pub mod prelude {
@@ -70,6 +92,20 @@ pub struct BevygapClientConfig {
     pub game_name: String,
     /// The version of the game, used in the matchmaker request.
     pub game_version: String,
+    /// Optional region, continent, etc. for the gameserver, used in the matchmaker request.
+    /// The matchmaker rejects anything not in its allow-list.
+    pub placement: SessionPlacement,
+    /// Join this existing session, eg a friend's, instead of starting a new one. Its players
+    /// share a gameserver. The session id is in the friend's `SessionRequestAccepted` feedback.
+    pub session_id: Option<String>,
+    /// The join token for `session_id`, from the friend's `ConnectionDetails::join_token`, or our
+    /// own when reconnecting. The matchmaker refuses to add us to the session without it.
+    pub join_token: Option<String>,
+    /// Rejoin `session_id` as this client id, after our connection to its gameserver dropped.
+    /// The gameserver keeps our place for a while, and sees us as the same player.
+    /// Needs `join_token` too. `bevygap_reconnect_client` sets all three from the last
+    /// `ConnectionDetails`.
+    pub reconnect_client_id: Option<u64>,
 }
 
 impl Default for BevygapClientConfig {
@@ -79,6 +115,10 @@ impl Default for BevygapClientConfig {
             fake_client_ip: None,
             game_name: "bevygap-spaceships".to_string(),
             game_version: "1".to_string(),
+            placement: SessionPlacement::default(),
+            session_id: None,
+            join_token: None,
+            reconnect_client_id: None,
         }
     }
 }
@@ -126,6 +166,7 @@ fn handle_matchmaker_response(
     // Store the connection details in a resource instead of directly modifying ClientConfig
     mut next_state: ResMut<NextState<BevygapClientState>>,
     config: Res<BevygapClientConfig>,
+    mut accepted_session: Local<Option<String>>,
 ) {
     for (entity, mut nfws) in q.iter_mut() {
         match nfws.next_event() {
@@ -148,6 +189,10 @@ fn handle_matchmaker_response(
                             game: config.game_name.clone(),
                             version: config.game_version.clone(),
                             player_limit: std::env::var("VOIDLOOP_PLAYER_LIMIT").ok().and_then(|s| s.parse::<u8>().ok()),
+                            placement: config.placement.clone(),
+                            session_id: config.session_id.clone(),
+                            join_token: config.join_token.clone(),
+                            reconnect_client_id: config.reconnect_client_id,
                         };
                         let payload = serde_json::to_string(&req).unwrap();
                         info!("Sending payload: {payload}");
@@ -193,10 +238,12 @@ fn handle_matchmaker_response(
                                     "Request acknowledged".to_string(),
                                 ))
                             }
-                            SessionRequestFeedback::SessionRequestAccepted(sess_id) => next_state
-                                .set(BevygapClientState::AwaitingResponse(format!(
+                            SessionRequestFeedback::SessionRequestAccepted(sess_id) => {
+                                next_state.set(BevygapClientState::AwaitingResponse(format!(
                                     "Session created: {sess_id}"
-                                ))),
+                                )));
+                                *accepted_session = Some(sess_id);
+                            }
                             SessionRequestFeedback::ProgressReport(prog_msg) => {
                                 next_state.set(BevygapClientState::AwaitingResponse(format!(
                                     "Progress: {prog_msg}"
@@ -210,6 +257,8 @@ fn handle_matchmaker_response(
                                 ip,
                                 port,
                                 cert_digest,
+                                client_id,
+                                join_token,
                             } => {
                                 let cert_digest = cert_digest.replace(':', "");
                                 info!("Using cert digest {cert_digest}");
@@ -231,11 +280,15 @@ fn handle_matchmaker_response(
                                 info!("Got matchmaker response, game server: {server_addr:?}");
 
                                 // Store connection details in a resource
-                                commands.insert_resource(ConnectionDetails {
+                                let mut details = ConnectionDetails::new(
                                     connect_token,
                                     server_addr,
                                     cert_digest,
-                                });
+                                );
+                                details.session_id = accepted_session.take();
+                                details.client_id = client_id;
+                                details.join_token = join_token;
+                                commands.insert_resource(details);
                                 
                                 next_state.set(BevygapClientState::ReadyToConnect);
                             }
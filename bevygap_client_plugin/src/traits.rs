@@ -1,7 +1,9 @@
 use crate::prelude::*;
+use crate::ConnectionDetails;
 use bevy::prelude::{Commands, World};
 use bevy_state::prelude::NextState;
 use bevy::prelude::Command;
+use log::warn;
 
 struct BevygapConnectCommand;
 
@@ -12,12 +14,46 @@ impl Command for BevygapConnectCommand {
     }
 }
 
+/// Asks the matchmaker for a new token to the session in the last `ConnectionDetails`, as the
+/// same client id, proving we were in it with its join token.
+struct BevygapReconnectCommand;
+
+impl Command for BevygapReconnectCommand {
+    fn apply(self, world: &mut World) {
+        let Some(details) = world.get_resource::<ConnectionDetails>() else {
+            warn!("Not reconnecting, we haven't connected yet");
+            return;
+        };
+        let (Some(session_id), Some(client_id), Some(join_token)) = (
+            details.session_id.clone(),
+            details.client_id,
+            details.join_token.clone(),
+        ) else {
+            warn!("Not reconnecting, the matchmaker didn't tell us our session, client id and join token");
+            return;
+        };
+        let mut config = world.resource_mut::<BevygapClientConfig>();
+        config.session_id = Some(session_id);
+        config.join_token = Some(join_token);
+        config.reconnect_client_id = Some(client_id);
+        let mut s = world.resource_mut::<NextState<BevygapClientState>>();
+        s.set(BevygapClientState::Request);
+    }
+}
+
 pub trait BevygapConnectExt {
     fn bevygap_connect_client(&mut self);
+    /// After our connection to the gameserver dropped, get back into the same session as the
+    /// same player. Only works for a short while after dropping.
+    fn bevygap_reconnect_client(&mut self);
 }
 
 impl<'w, 's> BevygapConnectExt for Commands<'w, 's> {
     fn bevygap_connect_client(&mut self) {
         self.queue(BevygapConnectCommand);
     }
+
+    fn bevygap_reconnect_client(&mut self) {
+        self.queue(BevygapReconnectCommand);
+    }
 }
@@ -0,0 +1,178 @@
+/// Players whose gameserver connection dropped. They stay in their Edgegap session for
+/// `reconnect_grace_secs`, during which they can ask for a fresh connect token to it, before
+/// the session cleanup watcher removes them.
+///
+/// The `dropped_players` KV bucket is the source of truth, so every matchmaker replica agrees on
+/// who may reconnect, and a restart doesn't forget anyone. This is each replica's copy of it,
+/// kept up to date by the cleanup watcher.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+pub(crate) struct DroppedPlayers {
+    /// By active_connections key
+    players: Arc<Mutex<HashMap<String, Tracked>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DroppedPlayer {
+    pub(crate) session_id: String,
+    pub(crate) client_id: String,
+    /// The IP they connected from, if we saw them connect
+    pub(crate) client_ip: Option<String>,
+    /// When they're removed from the session, unless they reconnect, in unix seconds
+    pub(crate) expires_at: u64,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    player: DroppedPlayer,
+    /// Revision of the player's entry in `dropped_players`
+    revision: u64,
+}
+
+impl DroppedPlayers {
+    /// Records a player's entry in `dropped_players`.
+    pub(crate) fn dropped(&self, key: String, revision: u64, player: DroppedPlayer) {
+        self.players
+            .lock()
+            .unwrap()
+            .insert(key, Tracked { player, revision });
+    }
+
+    /// Forgets a player who connected again, or whose entry was deleted, returning true if they
+    /// had dropped.
+    pub(crate) fn reconnected(&self, key: &str) -> bool {
+        self.players.lock().unwrap().remove(key).is_some()
+    }
+
+    /// Whether the player may have a new token for their session: they dropped recently, and
+    /// are asking from the same IP. If so, returns their entry with `expires_at` extended, and
+    /// the revision it replaces.
+    pub(crate) fn reconnecting(
+        &self,
+        key: &str,
+        client_ip: &str,
+        expires_at: u64,
+        now: u64,
+    ) -> Option<(DroppedPlayer, u64)> {
+        let players = self.players.lock().unwrap();
+        let tracked = players.get(key)?;
+        if tracked.player.expires_at <= now
+            || tracked.player.client_ip.as_deref() != Some(client_ip)
+        {
+            return None;
+        }
+        let player = DroppedPlayer {
+            expires_at: tracked.player.expires_at.max(expires_at),
+            ..tracked.player.clone()
+        };
+        Some((player, tracked.revision))
+    }
+
+    /// Whether anyone dropped from this session may still reconnect to it.
+    pub(crate) fn in_session(&self, session_id: &str) -> bool {
+        let players = self.players.lock().unwrap();
+        players
+            .values()
+            .any(|tracked| tracked.player.session_id == session_id)
+    }
+
+    /// The IPs of the players who may still reconnect to this session, unless we don't know
+    /// them all.
+    pub(crate) fn ips_in_session(&self, session_id: &str) -> Option<Vec<String>> {
+        let players = self.players.lock().unwrap();
+        players
+            .values()
+            .filter(|tracked| tracked.player.session_id == session_id)
+            .map(|tracked| tracked.player.client_ip.clone())
+            .collect()
+    }
+
+    /// Forgets players whose time to reconnect is up, returning them with their keys and
+    /// revisions, so their entries can be deleted.
+    pub(crate) fn expire(&self, now: u64) -> Vec<(String, u64, DroppedPlayer)> {
+        let mut players = self.players.lock().unwrap();
+        let expired_keys: Vec<String> = players
+            .iter()
+            .filter(|(_, tracked)| tracked.player.expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired_keys
+            .into_iter()
+            .filter_map(|key| {
+                let tracked = players.remove(&key)?;
+                Some((key, tracked.revision, tracked.player))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn dropped(session_id: &str, client_id: &str, expires_at: u64) -> DroppedPlayer {
+        DroppedPlayer {
+            session_id: session_id.to_string(),
+            client_id: client_id.to_string(),
+            client_ip: Some("1.2.3.4".to_string()),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_reconnecting_within_grace() {
+        let players = DroppedPlayers::default();
+        let soon = NOW + 30;
+        players.dropped("s1.1".to_string(), 7, dropped("s1", "1", soon));
+        assert_eq!(players.reconnecting("s1.1", "5.6.7.8", soon, NOW), None);
+        assert_eq!(players.reconnecting("s1.2", "1.2.3.4", soon, NOW), None);
+        let later = soon + 30;
+        assert_eq!(
+            players.reconnecting("s1.1", "1.2.3.4", later, NOW),
+            Some((dropped("s1", "1", later), 7))
+        );
+        // Only an earlier deadline than theirs: keep it
+        assert_eq!(
+            players.reconnecting("s1.1", "1.2.3.4", NOW + 1, NOW),
+            Some((dropped("s1", "1", soon), 7))
+        );
+        assert!(players.in_session("s1"));
+        assert!(players.reconnected("s1.1"));
+        assert!(!players.in_session("s1"));
+        assert!(!players.reconnected("s1.1"));
+    }
+
+    #[test]
+    fn test_expired_players_cant_reconnect() {
+        let players = DroppedPlayers::default();
+        players.dropped("s1.1".to_string(), 1, dropped("s1", "1", NOW - 60));
+        players.dropped("s1.2".to_string(), 2, dropped("s1", "2", NOW + 30));
+        assert_eq!(players.reconnecting("s1.1", "1.2.3.4", NOW + 30, NOW), None);
+        let expired = players.expire(NOW);
+        assert_eq!(
+            expired,
+            vec![("s1.1".to_string(), 1, dropped("s1", "1", NOW - 60))]
+        );
+        assert!(players.in_session("s1"));
+        assert_eq!(
+            players.ips_in_session("s1"),
+            Some(vec!["1.2.3.4".to_string()])
+        );
+        assert!(players.expire(NOW).is_empty());
+    }
+
+    #[test]
+    fn test_entry_round_trips() {
+        let player = dropped("s1", "1", NOW);
+        let json = serde_json::to_vec(&player).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<DroppedPlayer>(&json).unwrap(),
+            player
+        );
+    }
+}
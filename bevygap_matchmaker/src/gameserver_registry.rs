@@ -0,0 +1,178 @@
+/// Keeps track of running gameservers, from the heartbeats they write to the `gameservers` KV
+/// bucket. Servers that stop sending heartbeats are presumed dead, and forgotten.
+use crate::MatchmakerState;
+use ::time::OffsetDateTime;
+use async_nats::jetstream::kv::Operation;
+use bevygap_shared::nats::GAMESERVER_HEARTBEAT_MAX_AGE;
+use bevygap_shared::protocol::GameserverHeartbeat;
+use futures::StreamExt;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Default)]
+pub(crate) struct GameserverRegistry {
+    servers: Arc<RwLock<HashMap<String, RegisteredGameserver>>>,
+}
+
+struct RegisteredGameserver {
+    heartbeat: GameserverHeartbeat,
+    /// When the heartbeat was written to the bucket
+    seen_at: Instant,
+}
+
+impl GameserverRegistry {
+    /// Records a heartbeat, returning true if we didn't know about this gameserver yet.
+    fn update(&self, heartbeat: GameserverHeartbeat, seen_at: Instant) -> bool {
+        let request_id = heartbeat.request_id.clone();
+        let registered = RegisteredGameserver { heartbeat, seen_at };
+        self.servers
+            .write()
+            .unwrap()
+            .insert(request_id, registered)
+            .is_none()
+    }
+
+    fn remove(&self, request_id: &str) -> Option<GameserverHeartbeat> {
+        let removed = self.servers.write().unwrap().remove(request_id)?;
+        Some(removed.heartbeat)
+    }
+
+    /// Forgets gameservers we haven't had a heartbeat from for `max_age`, returning them.
+    fn expire(&self, max_age: Duration) -> Vec<GameserverHeartbeat> {
+        let mut expired = Vec::new();
+        self.servers.write().unwrap().retain(|_, server| {
+            if server.seen_at.elapsed() < max_age {
+                return true;
+            }
+            expired.push(server.heartbeat.clone());
+            false
+        });
+        expired
+    }
+
+    /// The latest heartbeat from a gameserver, if it's alive.
+    pub(crate) fn get(&self, request_id: &str) -> Option<GameserverHeartbeat> {
+        let servers = self.servers.read().unwrap();
+        Some(servers.get(request_id)?.heartbeat.clone())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.servers.read().unwrap().len()
+    }
+}
+
+/// Keeps the registry up to date from the `gameservers` bucket, and forgets any gameserver that
+/// stops sending heartbeats.
+pub(crate) async fn gameserver_registry_watcher(
+    state: &MatchmakerState,
+) -> Result<(), async_nats::Error> {
+    info!("Watching for gameserver heartbeats");
+    let registry = &state.gameservers;
+    let kv = state.nats.kv_gameservers();
+    let mut watcher = kv.watch_with_history(">").await?;
+    // the bucket drops expired entries without telling watchers, so check for them ourselves
+    let mut expiry = tokio::time::interval(GAMESERVER_HEARTBEAT_MAX_AGE / 3);
+    loop {
+        tokio::select! {
+            event = watcher.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("KV event error watching gameservers: {e:?}");
+                        continue;
+                    }
+                };
+                if event.operation != Operation::Put {
+                    if registry.remove(&event.key).is_some() {
+                        info!("Gameserver {} deregistered, {} running", event.key, registry.len());
+                    }
+                    continue;
+                }
+                let heartbeat: GameserverHeartbeat = match serde_json::from_slice(&event.value) {
+                    Ok(heartbeat) => heartbeat,
+                    Err(e) => {
+                        warn!("Invalid heartbeat for gameserver {}: {e}", event.key);
+                        continue;
+                    }
+                };
+                // history is replayed when we start watching, so go by when it was written
+                let age = (OffsetDateTime::now_utc() - event.created)
+                    .try_into()
+                    .unwrap_or_default();
+                let seen_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                let (request_id, public_ip) =
+                    (heartbeat.request_id.clone(), heartbeat.public_ip.clone());
+                if registry.update(heartbeat, seen_at) {
+                    info!(
+                        "Gameserver {request_id} registered at {public_ip}, {} running",
+                        registry.len()
+                    );
+                }
+            }
+            _ = expiry.tick() => {
+                for heartbeat in registry.expire(GAMESERVER_HEARTBEAT_MAX_AGE) {
+                    warn!(
+                        "Gameserver {} at {} stopped sending heartbeats, presumed dead",
+                        heartbeat.request_id, heartbeat.public_ip
+                    );
+                }
+            }
+        }
+    }
+    info!("Gameserver registry watcher exiting");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevygap_shared::protocol::GameserverStatus;
+
+    fn heartbeat(request_id: &str, players: u32) -> GameserverHeartbeat {
+        GameserverHeartbeat {
+            request_id: request_id.to_string(),
+            public_ip: "1.2.3.4".to_string(),
+            fqdn: None,
+            location: None,
+            ports: Default::default(),
+            players,
+            max_players: Some(2),
+            status: GameserverStatus::Ready,
+            app_version: None,
+            ticks_per_second: Some(60.0),
+            sent_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_registry_tracks_heartbeats() {
+        let registry = GameserverRegistry::default();
+        assert!(registry.update(heartbeat("a", 0), Instant::now()));
+        assert!(!registry.update(heartbeat("a", 1), Instant::now()));
+        assert!(registry.update(heartbeat("b", 2), Instant::now()));
+        assert_eq!(registry.get("a").unwrap().players, 1);
+        assert!(registry.get("a").unwrap().accepting_players());
+        assert!(!registry.get("b").unwrap().accepting_players());
+
+        assert_eq!(registry.remove("a").unwrap().request_id, "a");
+        assert!(registry.get("a").is_none());
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn test_registry_expires_silent_gameservers() {
+        let registry = GameserverRegistry::default();
+        let long_ago = Instant::now() - Duration::from_secs(60);
+        registry.update(heartbeat("dead", 0), long_ago);
+        registry.update(heartbeat("alive", 0), Instant::now());
+        let expired = registry.expire(Duration::from_secs(30));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].request_id, "dead");
+        assert!(registry.get("alive").is_some());
+    }
+}
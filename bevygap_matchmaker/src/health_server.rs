@@ -0,0 +1,40 @@
+//! Tiny http server exposing `/healthz` and `/readyz`, since the matchmaker is otherwise
+//! only reachable over NATS.
+use crate::MatchmakerState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use bevygap_shared::health::HealthReport;
+use bevygap_shared::supervisor::Shutdown;
+use log::*;
+
+pub(crate) async fn serve_health(
+    state: MatchmakerState,
+    mut shutdown: Shutdown,
+) -> Result<(), std::io::Error> {
+    let bind = state.settings.health_bind.clone();
+    let app = Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(bind.as_str()).await?;
+    info!("Health endpoints listening on {}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+}
+
+async fn healthz_handler(State(state): State<MatchmakerState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.liveness(state.nats.health_checks().await);
+    (status_code(&report), Json(report))
+}
+
+async fn readyz_handler(State(state): State<MatchmakerState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.readiness(state.nats.health_checks().await);
+    (status_code(&report), Json(report))
+}
+
+fn status_code(report: &HealthReport) -> StatusCode {
+    StatusCode::from_u16(report.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
@@ -0,0 +1,78 @@
+//! Join tokens let a player bring friends into their session.
+//!
+//! Session ids are sent to clients, so knowing one mustn't be enough to join it. With each
+//! `SessionReady`, a player gets a join token for their session: an HMAC of the session id, keyed
+//! with the lightyear private key. They pass it on with the session id, and the matchmaker only
+//! adds players to a session if they present a valid token for it.
+//!
+//! Without `lightyear_private_key` the key is all zeros, which anyone could sign tokens with, so
+//! no tokens are issued or accepted then.
+use base64::prelude::*;
+use ring::hmac;
+
+/// Added to the session id before signing, so join tokens can't be confused with anything else
+/// signed with the same key.
+const JOIN_TOKEN_CONTEXT: &str = "bevygap-join:";
+
+fn key(private_key: &[u8]) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, private_key)
+}
+
+fn message(session_id: &str) -> String {
+    format!("{JOIN_TOKEN_CONTEXT}{session_id}")
+}
+
+/// Whether join tokens can be signed with this key, ie. it isn't the all-zero default.
+pub(crate) fn can_sign(private_key: &[u8]) -> bool {
+    private_key.iter().any(|b| *b != 0)
+}
+
+/// The join token for `session_id`, unless the key can't sign them.
+pub(crate) fn join_token(private_key: &[u8], session_id: &str) -> Option<String> {
+    if !can_sign(private_key) {
+        return None;
+    }
+    let tag = hmac::sign(&key(private_key), message(session_id).as_bytes());
+    Some(BASE64_URL_SAFE_NO_PAD.encode(tag.as_ref()))
+}
+
+/// Whether `token` is the join token for `session_id`. Compared in constant time.
+pub(crate) fn verify_join_token(private_key: &[u8], session_id: &str, token: &str) -> bool {
+    if !can_sign(private_key) {
+        return false;
+    }
+    let Ok(tag) = BASE64_URL_SAFE_NO_PAD.decode(token) else {
+        return false;
+    };
+    hmac::verify(&key(private_key), message(session_id).as_bytes(), &tag).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_token_only_valid_for_its_session() {
+        let private_key = [7u8; 32];
+        let token = join_token(&private_key, "950dd2eaff09-S").unwrap();
+        assert!(verify_join_token(&private_key, "950dd2eaff09-S", &token));
+        assert!(!verify_join_token(&private_key, "1234abcd5678-S", &token));
+        assert!(!verify_join_token(&[8u8; 32], "950dd2eaff09-S", &token));
+        assert!(!verify_join_token(
+            &private_key,
+            "950dd2eaff09-S",
+            "not a token"
+        ));
+        assert!(!verify_join_token(&private_key, "950dd2eaff09-S", ""));
+    }
+
+    #[test]
+    fn test_no_join_tokens_with_the_zero_key() {
+        let zero_key = [0u8; 32];
+        assert_eq!(join_token(&zero_key, "950dd2eaff09-S"), None);
+        // a token anyone could have made with the zero key
+        let tag = hmac::sign(&key(&zero_key), message("950dd2eaff09-S").as_bytes());
+        let forged = BASE64_URL_SAFE_NO_PAD.encode(tag.as_ref());
+        assert!(!verify_join_token(&zero_key, "950dd2eaff09-S", &forged));
+    }
+}
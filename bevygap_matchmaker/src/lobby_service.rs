@@ -0,0 +1,132 @@
+//! Starts dedicated deployments for the httpd's lobby rooms.
+//!
+//! The httpd asks for a deployment on `lobby.deploy` when a room starts, and we reply once
+//! it's running. Each player then gets their own connect token with a normal session request
+//! that includes the deployment's request id, see `session_request_streamer`.
+use crate::session_request_streamer::edgegap_error_feedback;
+use crate::{MatchmakerState, EDGEGAP_HEALTH_CHECK};
+use async_nats::service::ServiceExt;
+use bevygap_shared::protocol::{LobbyDeployRequest, LobbyDeployment};
+use edgegap_async::models::DeployModel;
+use edgegap_async::EdgegapError;
+use futures::StreamExt;
+use log::*;
+
+pub(crate) async fn lobby_deploy_handler(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+    let client = state.nats_client();
+    info!("Listening for lobby deploy requests on 'lobby.deploy'");
+
+    let service = client
+        .service_builder()
+        .description("Start dedicated deployments for lobby rooms")
+        .start("lobby", "0.0.1")
+        .await?;
+
+    let g = service.group_with_queue_group("lobby", "lobby_queue");
+    let mut deploy = g.endpoint("deploy").await?;
+
+    while let Some(request) = deploy.next().await {
+        let deploy_request: LobbyDeployRequest =
+            match serde_json::from_slice(&request.message.payload) {
+                Ok(deploy_request) => deploy_request,
+                Err(e) => {
+                    warn!("Error decoding lobby deploy request: {e}");
+                    let _ = request
+                        .respond(Err(async_nats::service::error::Error {
+                            status: "error decoding lobby deploy request!".to_string(),
+                            code: 400,
+                        }))
+                        .await;
+                    continue;
+                }
+            };
+        // deployments take a while, so don't hold up other rooms
+        let state = state.clone();
+        tokio::spawn(async move {
+            let room_id = deploy_request.room_id.clone();
+            let response = match deploy_lobby(&state, deploy_request).await {
+                Ok(deployment) => Ok(serde_json::to_vec(&deployment).unwrap().into()),
+                Err(e) => {
+                    let (code, status) = edgegap_error_feedback(&e);
+                    error!("Failed to deploy lobby room {room_id}: {code}={status}");
+                    Err(async_nats::service::error::Error {
+                        status,
+                        code: code.into(),
+                    })
+                }
+            };
+            if let Err(e) = request.respond(response).await {
+                warn!("Failed to reply to lobby deploy request for {room_id}: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Deploys the configured app and version, and waits for it to be running.
+/// The deployment is stopped again if it doesn't become usable.
+async fn deploy_lobby(
+    state: &MatchmakerState,
+    deploy_request: LobbyDeployRequest,
+) -> Result<LobbyDeployment, EdgegapError> {
+    let settings = &state.settings;
+    let mut deploy = DeployModel::new(settings.app_name.clone());
+    deploy.version_name = Some(settings.app_version.clone());
+    deploy.ip_list = Some(deploy_request.ip_list);
+    deploy.tags = Some(vec![format!("lobby-{}", deploy_request.room_id)]);
+
+    info!(
+        "Deploying {} {} for lobby room {}",
+        settings.app_name, settings.app_version, deploy_request.room_id
+    );
+    let request = state
+        .edgegap()
+        .deploy(deploy)
+        .await
+        .inspect_err(|e| state.health.record_failure(EDGEGAP_HEALTH_CHECK, e))?;
+    state.health.record_success(EDGEGAP_HEALTH_CHECK);
+    let request_id = request.request_id;
+
+    let running = state
+        .edgegap()
+        .wait_deployment_ready(
+            &request_id,
+            settings.lobby_deploy_poll_interval(),
+            settings.lobby_deploy_timeout(),
+        )
+        .await
+        .and_then(|status| {
+            // use the first port, as for sessions
+            let port = status
+                .ports
+                .iter()
+                .flat_map(|ports| ports.values())
+                .find_map(|port| port.external)
+                .ok_or_else(|| EdgegapError::DeploymentFailed {
+                    request_id: request_id.clone(),
+                    reason: "No ports found in deployment".to_string(),
+                })?;
+            Ok((status.public_ip, port as u16))
+        });
+    let (ip, port) = match running {
+        Ok(running) => running,
+        Err(e) => {
+            warn!("Stopping unusable lobby deployment {request_id}");
+            if let Err(stop_err) = state.edgegap().stop_deployment(&request_id).await {
+                error!("Failed to stop lobby deployment {request_id}: {stop_err}");
+            }
+            return Err(e);
+        }
+    };
+    info!(
+        "Lobby room {} deployed as {request_id} on {ip}:{port}",
+        deploy_request.room_id
+    );
+    Ok(LobbyDeployment {
+        request_id,
+        app_name: settings.app_name.clone(),
+        app_version: settings.app_version.clone(),
+        ip,
+        port,
+    })
+}
@@ -1,214 +1,231 @@
-// use std::time::Duration;
-
 // use async_nats::jetstream;
 // use async_nats::jetstream::stream::Stream;
 // use async_nats::jetstream::stream::StorageType;
 use async_nats::Client;
-use clap::Parser;
-use edgegap_async::apis::applications_api::*;
-use edgegap_async::apis::configuration::*;
-use futures::stream::StreamExt;
+use edgegap_async::apis::retry::RetryPolicy;
+use edgegap_async::EdgegapClient;
 use lightyear::netcode::PRIVATE_KEY_BYTES;
 use log::*;
+use std::time::Duration;
 use tracing_subscriber::{layer::*, util::*};
 
+use bevygap_shared::health::{Health, HealthState};
 use bevygap_shared::nats::*;
-
+use bevygap_shared::supervisor::*;
+
+mod dropped_players;
+mod gameserver_registry;
+mod health_server;
+mod join_token;
+mod lobby_service;
+mod placement;
 mod session_delete_worker;
 mod session_reaper;
 mod session_service;
 
+use dropped_players::DroppedPlayers;
+use gameserver_registry::GameserverRegistry;
 use session_delete_worker::*;
 use session_reaper::*;
 use session_service::*;
 
 mod session_request_streamer;
+mod settings;
 
-pub const MAX_SESSION_CREATION_SECONDS: u64 = 60;
-
-fn edgegap_configuration(_settings: &Settings) -> Configuration {
-    let key =
-        std::env::var("EDGEGAP_API_KEY").expect("EDGEGAP_API_KEY environment variable is not set");
-    Configuration {
-        base_path: "https://api.edgegap.com/".to_string(),
-        api_key: Some(ApiKey { prefix: None, key }),
-        ..Default::default()
-    }
-}
+use settings::Settings;
 
-#[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
-pub struct Settings {
-    #[arg(long, default_value = "spacepit_server")]
-    app_name: String,
-    #[arg(long, default_value = "v0.0.1")]
-    app_version: String,
-    /// private key, in format 1,2,3,4..  which should be 32 u8s long (for signing lightyear tokens)
-    #[arg(long, default_value = "")]
-    lightyear_private_key: String,
-    /// The lightyear protocol id (u64)
-    #[arg(long, default_value = "1982")]
-    lightyear_protocol_id: u64,
-    /// The webhook url for edgegap session creation events
-    /// (should write to nats for you, see bevygap_webhook_sink)
-    #[arg(long, default_value = None)]
-    session_webhook_url: Option<String>,
-    /// Optional maximum player limit for the lobby/session (1-4)
-    #[arg(long)]
-    player_limit: Option<u8>,
-}
+/// Default for `max_session_creation_seconds` in the config.
+pub const MAX_SESSION_CREATION_SECONDS: u64 = 60;
 
-impl Settings {
-    fn parse_private_key(&self) -> [u8; PRIVATE_KEY_BYTES] {
-        if self.lightyear_private_key.is_empty() {
-            return [0u8; PRIVATE_KEY_BYTES];
-        }
-        let private_key: Vec<u8> = self
-            .lightyear_private_key
-            .chars()
-            .filter(|c| c.is_ascii_digit() || *c == ',')
-            .collect::<String>()
-            .split(',')
-            .map(|s| {
-                s.parse::<u8>()
-                    .expect("Failed to parse number in private key")
-            })
-            .collect();
-
-        if private_key.len() != PRIVATE_KEY_BYTES {
-            panic!(
-                "Private key must contain exactly {} numbers",
-                PRIVATE_KEY_BYTES
-            );
-        }
-
-        let mut bytes = [0u8; PRIVATE_KEY_BYTES];
-        bytes.copy_from_slice(&private_key);
-        bytes
+/// Health check name for calls to the Edgegap API.
+pub(crate) const EDGEGAP_HEALTH_CHECK: &str = "edgegap";
+/// Health check name for the configured app and version being usable.
+const EDGEGAP_APP_HEALTH_CHECK: &str = "edgegap.application";
+
+fn edgegap_client(settings: &Settings) -> EdgegapClient {
+    let client = if !settings.edgegap_api_key.is_empty() {
+        EdgegapClient::new(settings.edgegap_api_key.clone())
+    } else if let Some(path) = &settings.edgegap_api_key_file {
+        EdgegapClient::from_key_file(path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()))
+    } else {
+        panic!(
+            "Edgegap API key not set, use EDGEGAP_API_KEY, EDGEGAP_API_KEY_FILE or edgegap_api_key in the config file"
+        );
     }
-
-    pub fn protocol_id(&self) -> u64 {
-        self.lightyear_protocol_id
+    .with_base_path(settings.edgegap_base_path.clone());
+    if settings.edgegap_max_retries == 0 {
+        return client;
     }
-}
-
-async fn watch_for_gameserver_announcements(
-    state: &MatchmakerState,
-) -> Result<(), async_nats::Error> {
-    info!("Watching for gameserver announcements");
-    let client = state.nats_client();
-    let mut subscriber = client.subscribe("gameserver.contexts").await?;
-
-    while let Some(message) = subscriber.next().await {
-        info!("NEW GAMESERVER: {:?}", message);
+    let policy = RetryPolicy {
+        max_retries: settings.edgegap_max_retries,
+        default_timeout: Some(Duration::from_secs(settings.edgegap_timeout_secs)),
+        ..Default::default()
     }
-    info!("Gameserver announcement watcher exiting");
-    Ok(())
+    .endpoint_timeout(
+        None,
+        "/v1/session",
+        Duration::from_secs(settings.edgegap_session_timeout_secs),
+    );
+    client.with_retry_policy(policy)
 }
 
 #[derive(Clone)]
 pub(crate) struct MatchmakerState {
     nats: BevygapNats,
-    api_config: Configuration,
+    edgegap: EdgegapClient,
     settings: Settings,
     lypkey: [u8; PRIVATE_KEY_BYTES],
+    health: Health,
+    gameservers: GameserverRegistry,
+    dropped_players: DroppedPlayers,
 }
 
 impl MatchmakerState {
     pub(crate) fn nats_client(&self) -> Client {
         self.nats.client()
     }
-    pub(crate) fn configuration(&self) -> &Configuration {
-        &self.api_config
+    pub(crate) fn edgegap(&self) -> &EdgegapClient {
+        &self.edgegap
     }
     pub(crate) fn lightyear_private_key(&self) -> [u8; PRIVATE_KEY_BYTES] {
         self.lypkey
     }
-    pub(crate) fn player_limit(&self) -> Option<u8> { self.settings.player_limit }
-    pub(crate) fn settings(&self) -> &Settings { &self.settings }
+    pub(crate) fn player_limit(&self) -> Option<u8> {
+        self.settings.player_limit
+    }
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), async_nats::Error> {
     setup_logging();
+    let settings = Settings::load();
     info!("Starting Edgegap Matchmaker");
-    let bgnats = BevygapNats::new_and_connect("matchmaker").await.unwrap();
-    let settings = Settings::parse();
+    let bgnats = BevygapNats::new_and_connect_with_settings("matchmaker", &settings.nats)
+        .await
+        .unwrap();
     let lypkey = settings.parse_private_key();
-    let api_config = edgegap_configuration(&settings);
+    let edgegap = edgegap_client(&settings);
     let mm_state = MatchmakerState {
         nats: bgnats,
-        api_config,
+        edgegap,
         settings,
         lypkey,
+        health: Health::new(),
+        gameservers: GameserverRegistry::default(),
+        dropped_players: DroppedPlayers::default(),
     };
 
+    let supervisor = Supervisor::new("matchmaker", mm_state.settings.supervisor.clone())
+        .with_health(mm_state.health.clone());
+
+    let state = mm_state.clone();
+    supervisor.spawn(ChildSpec::new("health_server"), move |shutdown| {
+        health_server::serve_health(state.clone(), shutdown)
+    });
+
     // ensure the specified app and version are valid and ready for players.
-    verify_application(&mm_state).await?;
+    // readiness reports failed until this succeeds.
+    let retry_interval = Duration::from_secs(mm_state.settings.verify_application_retry_secs);
+    while let Err(e) = verify_application(&mm_state).await {
+        error!("🔴 {e}, retrying in {retry_interval:?}");
+        mm_state
+            .health
+            .set(EDGEGAP_APP_HEALTH_CHECK, HealthState::Failed, Some(e));
+        tokio::time::sleep(retry_interval).await;
+    }
+
+    let state = mm_state.clone();
+    supervisor.spawn(ChildSpec::new("session_request_streamer"), move |_| {
+        let state = state.clone();
+        async move { session_request_streamer::streaming_session_request_handler(&state).await }
+    });
 
     let state = mm_state.clone();
-    let _a = tokio::spawn(async move {
-        session_request_streamer::streaming_session_request_handler(&state).await
+    supervisor.spawn(ChildSpec::new("session_cleanup_watcher"), move |_| {
+        let state = state.clone();
+        async move { session_cleanup_watcher(&state).await }
     });
 
     let state = mm_state.clone();
-    let _a = tokio::spawn(async move { session_cleanup_supervisor(&state).await });
+    supervisor.spawn(ChildSpec::new("unclaimed_session_reaper"), move |_| {
+        let state = state.clone();
+        async move { unclaimed_session_reaper(&state).await }
+    });
+
     let state = mm_state.clone();
-    let _b = tokio::spawn(async move { delete_session_worker_supervisor(&state).await });
+    supervisor.spawn(ChildSpec::new("delete_session_worker"), move |_| {
+        let state = state.clone();
+        async move { delete_session_worker(&state).await }
+    });
 
     let state = mm_state.clone();
-    let _watcher = tokio::spawn(async move {
-        match watch_for_gameserver_announcements(&state).await {
-            Ok(_) => info!("Gameserver announcement watcher completed"),
-            Err(e) => error!("Error in gameserver announcement watcher: {}", e),
-        }
+    supervisor.spawn(ChildSpec::new("gameserver_registry_watcher"), move |_| {
+        let state = state.clone();
+        async move { gameserver_registry::gameserver_registry_watcher(&state).await }
     });
 
     let state = mm_state.clone();
-    let session_service = tokio::spawn(async move {
-        match session_request_supervisor(&state).await {
-            Ok(_) => info!("Session service completed"),
-            Err(e) => error!("Error in session service: {}", e),
-        }
+    supervisor.spawn(ChildSpec::new("lobby_deploy_handler"), move |_| {
+        let state = state.clone();
+        async move { lobby_service::lobby_deploy_handler(&state).await }
     });
 
-    // just to block from exiting:
-    session_service.await.unwrap();
+    for i in 0..SESSION_REQUEST_HANDLERS {
+        let state = mm_state.clone();
+        supervisor.spawn(ChildSpec::new(format!("session_service.{i}")), move |_| {
+            let state = state.clone();
+            async move { session_request_handler(&state).await }
+        });
+    }
+
+    tokio::select! {
+        _ = supervisor.wait() => warn!("All matchmaker tasks have exited"),
+        _ = termination_signal() => supervisor.shutdown().await,
+    }
 
-    // shouldn't get here
     info!("Edgegap Matchmaker exiting");
     Ok(())
-    // dbg!(deployments);
 }
 
-async fn verify_application(state: &MatchmakerState) -> Result<(), async_nats::Error> {
-    let config = state.configuration();
+async fn verify_application(state: &MatchmakerState) -> Result<(), String> {
+    let edgegap = state.edgegap();
     let settings = &state.settings;
 
-    let app = application_get(config, settings.app_name.as_str())
+    let app = edgegap
+        .application(settings.app_name.as_str())
         .await
-        .unwrap_or_else(|e| panic!("Edgegap API doesn't know this application name: {e}"));
+        .map_err(|e| format!("Edgegap API doesn't know this application name: {e}"))?;
 
     info!(
         "🟢 Application '{}' , active: {}, last_updated: {}",
         app.name, app.is_active, app.last_updated
     );
 
-    let app_version = app_version_get(
-        config,
-        settings.app_name.as_str(),
-        settings.app_version.as_str(),
-    )
-    .await
-    .unwrap_or_else(|e| panic!("Edgegap API doesn't know this application version: {e}"));
+    let app_version = edgegap
+        .app_version(settings.app_name.as_str(), settings.app_version.as_str())
+        .await
+        .map_err(|e| format!("Edgegap API doesn't know this application version: {e}"))?;
 
     if app_version.is_active.unwrap_or(false) {
         info!("🟢 Application version '{}' is active.", app_version.name);
+        state.health.record_success(EDGEGAP_APP_HEALTH_CHECK);
     } else {
         error!(
             "🔴 Application version '{}' is not active, won't be able to create sessions.",
             app_version.name
         );
+        state.health.set(
+            EDGEGAP_APP_HEALTH_CHECK,
+            HealthState::Degraded,
+            Some(format!(
+                "application version '{}' is not active",
+                app_version.name
+            )),
+        );
         // std::process::exit(1);
     }
 
@@ -0,0 +1,140 @@
+//! Placement filters for sessions, ie. which region, continent, country, city or tagged
+//! deployments Edgegap may put a session on.
+use bevygap_shared::protocol::SessionPlacement;
+use edgegap_async::NewSession;
+use serde::{Deserialize, Serialize};
+
+/// Which placement filters clients may ask for. Values are matched case-insensitively, and
+/// anything not listed is rejected, so by default clients can't choose placement at all.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct PlacementSettings {
+    /// Used for any field the request doesn't set, eg. to send every session to a tournament
+    /// fleet's tag. These aren't checked against the allow-lists.
+    pub defaults: SessionPlacement,
+    pub allowed_regions: Vec<String>,
+    pub allowed_continents: Vec<String>,
+    pub allowed_countries: Vec<String>,
+    pub allowed_cities: Vec<String>,
+    pub allowed_selectors: Vec<String>,
+}
+
+impl PlacementSettings {
+    /// Checks a request's placement against the allow-lists, then fills in the defaults.
+    /// Values are replaced with the allow-list's spelling.
+    pub(crate) fn resolve(&self, requested: SessionPlacement) -> Result<SessionPlacement, String> {
+        let selectors = requested
+            .selectors
+            .iter()
+            .map(|tag| allowed("selector", tag, &self.allowed_selectors))
+            .collect::<Result<Vec<_>, _>>()?;
+        let resolved = SessionPlacement {
+            region: allowed_opt("region", requested.region, &self.allowed_regions)?,
+            continent: allowed_opt("continent", requested.continent, &self.allowed_continents)?,
+            country: allowed_opt("country", requested.country, &self.allowed_countries)?,
+            city: allowed_opt("city", requested.city, &self.allowed_cities)?,
+            selectors,
+        };
+        Ok(resolved.or(&self.defaults))
+    }
+}
+
+fn allowed(kind: &str, value: &str, allow_list: &[String]) -> Result<String, String> {
+    allow_list
+        .iter()
+        .find(|a| a.eq_ignore_ascii_case(value.trim()))
+        .cloned()
+        .ok_or_else(|| format!("Placement {kind} '{value}' is not allowed"))
+}
+
+fn allowed_opt(
+    kind: &str,
+    value: Option<String>,
+    allow_list: &[String],
+) -> Result<Option<String>, String> {
+    value.map(|v| allowed(kind, &v, allow_list)).transpose()
+}
+
+/// Adds the placement filters to a session request for Edgegap.
+pub(crate) fn apply_placement(mut session: NewSession, placement: &SessionPlacement) -> NewSession {
+    if let Some(region) = &placement.region {
+        session = session.region(region);
+    }
+    if let Some(continent) = &placement.continent {
+        session = session.continent(continent);
+    }
+    if let Some(country) = &placement.country {
+        session = session.country(country);
+    }
+    if let Some(city) = &placement.city {
+        session = session.city(city);
+    }
+    for tag in &placement.selectors {
+        session = session.selector(tag);
+    }
+    session
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edgegap_async::models::SessionModel;
+
+    fn settings() -> PlacementSettings {
+        PlacementSettings {
+            defaults: SessionPlacement {
+                continent: Some("Europe".to_string()),
+                selectors: vec!["casual".to_string()],
+                ..Default::default()
+            },
+            allowed_regions: vec!["North America".to_string()],
+            allowed_selectors: vec!["tournament".to_string(), "casual".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_placement_allow_list_and_defaults() {
+        let settings = settings();
+
+        let resolved = settings.resolve(SessionPlacement::default()).unwrap();
+        assert_eq!(resolved, settings.defaults);
+
+        let requested = SessionPlacement {
+            region: Some("north america".to_string()),
+            selectors: vec!["Tournament".to_string()],
+            ..Default::default()
+        };
+        let resolved = settings.resolve(requested).unwrap();
+        assert_eq!(resolved.region.as_deref(), Some("North America"));
+        assert_eq!(resolved.continent.as_deref(), Some("Europe"));
+        assert_eq!(resolved.selectors, ["tournament"]);
+
+        let model: SessionModel = apply_placement(NewSession::new("mygame"), &resolved).into();
+        assert_eq!(model.region.as_deref(), Some("North America"));
+        assert_eq!(model.continent.as_deref(), Some("Europe"));
+        assert_eq!(model.selectors.unwrap()[0].tag, "tournament");
+        assert_eq!(model.city, None);
+    }
+
+    #[test]
+    fn test_placement_not_in_allow_list_is_rejected() {
+        let settings = settings();
+        for requested in [
+            SessionPlacement {
+                region: Some("Europe".to_string()),
+                ..Default::default()
+            },
+            SessionPlacement {
+                city: Some("Montreal".to_string()),
+                ..Default::default()
+            },
+            SessionPlacement {
+                selectors: vec!["casual".to_string(), "staff".to_string()],
+                ..Default::default()
+            },
+        ] {
+            assert!(settings.resolve(requested).is_err());
+        }
+    }
+}
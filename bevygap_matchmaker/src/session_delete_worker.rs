@@ -1,29 +1,12 @@
-use crate::MatchmakerState;
+use crate::{MatchmakerState, EDGEGAP_HEALTH_CHECK};
 use async_nats::jetstream::{self};
-use edgegap_async::apis::sessions_api::*;
+use edgegap_async::EdgegapError;
 use futures::StreamExt;
 use log::*;
 
-// need an erlang/OTP like supervision tree!
-pub async fn delete_session_worker_supervisor(
+pub(crate) async fn delete_session_worker(
     state: &MatchmakerState,
 ) -> Result<(), async_nats::Error> {
-    loop {
-        let state = state.clone();
-        let handle = tokio::spawn(async move {
-            let res = delete_session_worker(&state).await;
-            if let Err(e) = res {
-                error!("delete_session_worker error: {e:?}");
-            }
-        });
-        futures::future::join_all([handle]).await;
-        warn!("delete_session_worker exited, restarting after timeout");
-        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-    }
-    // Ok(())
-}
-
-async fn delete_session_worker(state: &MatchmakerState) -> Result<(), async_nats::Error> {
     let stream = state.nats.delete_session_stream();
     let consumer = stream
         .create_consumer(jetstream::consumer::pull::Config {
@@ -38,37 +21,33 @@ async fn delete_session_worker(state: &MatchmakerState) -> Result<(), async_nats
         let mut messages = consumer.fetch().max_messages(100).messages().await?;
         while let Some(Ok(message)) = messages.next().await {
             let session_id = String::from_utf8(message.payload.to_vec())?;
-            match session_delete(state.configuration(), session_id.as_str()).await {
+            match state.edgegap().delete_session(session_id.as_str()).await {
                 Ok(session_delete_response) => {
+                    state.health.record_success(EDGEGAP_HEALTH_CHECK);
                     info!("session_delete ok: {:?}", session_delete_response);
                     message.ack().await?;
                 }
-                Err(edgegap_async::apis::Error::ResponseError(resp_content)) => {
-                    match resp_content.status.as_u16() {
-                        404 => {
-                            // session already deleted or never existed.
-                            warn!(
-                                "session_delete 404: {session_id} - already deleted or not found?"
-                            );
-                            message.ack().await?;
-                        }
-                        410 => {
-                            // "instance already terminated"
-                            warn!("session_delete 410 'instance already terminated': {session_id}");
-                            message.ack().await?;
-                        }
-                        code => {
-                            error!("session_delete error status = {code} for {session_id} {resp_content:?}");
-                        }
-                    }
+                Err(e) if e.is_gone() => {
+                    // 404: session already deleted or never existed.
+                    // 410: "instance already terminated"
+                    warn!("session_delete {session_id} already gone: {e}");
+                    message.ack().await?;
+                }
+                Err(e @ EdgegapError::Api { .. }) => {
+                    error!("session_delete error for {session_id}: {e}");
+                    state.health.record_failure(EDGEGAP_HEALTH_CHECK, &e);
                 }
                 Err(e) => {
                     // TODO What to do about junk data on queue that can never be deleted?
                     error!("unhandled session_delete error {session_id}: {e:?}");
+                    state.health.record_failure(EDGEGAP_HEALTH_CHECK, &e);
                 }
             }
         }
-        tokio::time::sleep(std::time::Duration::from_millis(5000)).await;
+        tokio::time::sleep(std::time::Duration::from_millis(
+            state.settings.delete_worker_interval_ms,
+        ))
+        .await;
     }
 
     // Ok(())
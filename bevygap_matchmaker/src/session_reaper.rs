@@ -1,39 +1,27 @@
 /// Detects orphaned edgegap sessions and schedules them for deletion by the API
 /// Actual API-delete call happens in the session_delete_worker.
-use crate::MatchmakerState;
+use crate::dropped_players::DroppedPlayer;
+use crate::{MatchmakerState, EDGEGAP_HEALTH_CHECK};
 use ::time::OffsetDateTime;
-use async_nats::jetstream::kv::Operation;
+use async_nats::jetstream::kv::{CreateErrorKind, Operation};
+use bevygap_shared::nats::parse_session_player_key;
+use bevygap_shared::protocol::SessionPlayer;
+use bevygap_shared::util::unix_now;
 use futures::{StreamExt, TryStreamExt};
 use log::*;
+use std::collections::HashMap;
 use tokio::time::{self, Duration};
 
-pub(crate) async fn session_cleanup_supervisor(
-    orig_state: &MatchmakerState,
-) -> Result<(), async_nats::Error> {
-    let state = orig_state.clone();
-    let handle1 = tokio::spawn(async move {
-        loop {
-            let _ = session_cleanup_watcher(&state).await;
-            error!("session_cleanup_watcher exited, restarting");
-        }
-    });
-    let state = orig_state.clone();
-    let handle2 = tokio::spawn(async move {
-        loop {
-            let _ = unclaimed_session_reaper(&state).await;
-            error!("unclaimed_session_reaper exited, restarting");
-        }
-    });
-    futures::future::join_all([handle1, handle2]).await;
-    Ok(())
-}
-
 /// Get all the session keys in unclaimed sessions - if any are older than 30 seconds,
 /// enqueue them for deletion.
 /// Session ids must be removed from unclaimed_sessions once a gameserver connection happens.
-async fn unclaimed_session_reaper(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+pub(crate) async fn unclaimed_session_reaper(
+    state: &MatchmakerState,
+) -> Result<(), async_nats::Error> {
     // how often to check for orphaned sessions:
-    let mut interval = time::interval(Duration::from_millis(5000));
+    let mut interval = time::interval(Duration::from_millis(
+        state.settings.unclaimed_reaper_interval_ms,
+    ));
     let kv = state.nats.kv_unclaimed_sessions();
     loop {
         interval.tick().await;
@@ -46,8 +34,8 @@ async fn unclaimed_session_reaper(state: &MatchmakerState) -> Result<(), async_n
                 .expect("Failed to convert session_id to string");
             let age = OffsetDateTime::now_utc() - entry.created;
             info!("* Session {session_id} is {age} old");
-            if age > Duration::from_secs(crate::MAX_SESSION_CREATION_SECONDS + 2) {
-                warn!("Unclaimed session {session_id} is older than 30 seconds = {age}");
+            if age > state.settings.max_session_creation_time() + Duration::from_secs(2) {
+                warn!("Unclaimed session {session_id} is too old = {age}");
                 // write to delete_sessions work queue and remove from unclaimed_sessions KV
                 state
                     .nats
@@ -60,37 +48,312 @@ async fn unclaimed_session_reaper(state: &MatchmakerState) -> Result<(), async_n
     // Ok(())
 }
 
-/// Deletes sessions once a gameserver removes the active_sessions KV entry.
+/// Deletes sessions once a gameserver removes the last of their players from active_connections.
 ///  this is the happy path, where there were no orphans..
-async fn session_cleanup_watcher(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+///
+/// Players leaving a session that others are still playing in are removed from it instead.
+/// Either way, we wait `reconnect_grace_secs` first, in case the player's connection only blipped.
+/// Players waiting to reconnect are kept in `dropped_players`, which is replayed on startup.
+pub(crate) async fn session_cleanup_watcher(
+    state: &MatchmakerState,
+) -> Result<(), async_nats::Error> {
     let kv = state.nats.kv_active_connections();
+    let kv_dropped = state.nats.kv_dropped_players();
+    let dropped = &state.dropped_players;
+    let grace = state.settings.reconnect_grace();
     let mut watcher = kv.watch(">").await?;
-    while let Some(event) = watcher.next().await {
-        info!("{event:?}");
-        match event {
-            Ok(event) => {
-                let session_id = event.key;
-                if event.operation == Operation::Delete {
-                    info!("active_connection deleted, deleting session {session_id}",);
-                    state
-                        .nats
-                        .enqueue_session_delete(session_id.clone())
-                        .await?;
-                }
+    let mut dropped_watcher = kv_dropped.watch_with_history(">").await?;
+    let mut connected = connected_players(kv).await?;
+    let mut expiry = time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            event = watcher.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                info!("{event:?}");
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("KV event error watching for session cleanup: {:?}", e);
+                        continue;
+                    }
+                };
+                let Some((session_id, client_id)) = parse_session_player_key(&event.key) else {
+                    warn!("Ignoring unexpected active_connections key {}", event.key);
+                    continue;
+                };
                 if event.operation == Operation::Put {
-                    info!("New Session put {session_id}, deleting from unclaimed_sessions ");
+                    if dropped.reconnected(&event.key) {
+                        info!("Client {client_id} reconnected to session {session_id}");
+                        kv_dropped.delete(&event.key).await?;
+                    } else {
+                        info!(
+                            "Client {client_id} joined session {session_id}, deleting from unclaimed_sessions"
+                        );
+                    }
+                    let client_ip = match serde_json::from_slice::<SessionPlayer>(&event.value) {
+                        Ok(player) => Some(player.client_ip),
+                        Err(e) => {
+                            warn!("Invalid active connection for {}: {e}", event.key);
+                            None
+                        }
+                    };
+                    connected.connected(session_id, client_id, client_ip);
                     // delete this session_id from unclaimed_sessions.
-                    let _ = state
-                        .nats
-                        .kv_unclaimed_sessions()
-                        .delete(session_id.as_str())
-                        .await;
+                    let _ = state.nats.kv_unclaimed_sessions().delete(session_id).await;
+                    continue;
+                }
+                info!(
+                    "Client {client_id} dropped from session {session_id}, \
+                     waiting {grace:?} for them to reconnect"
+                );
+                let player = DroppedPlayer {
+                    session_id: session_id.to_string(),
+                    client_id: client_id.to_string(),
+                    client_ip: connected.disconnected(session_id, client_id),
+                    expires_at: unix_now() + grace.as_secs(),
+                };
+                // Every replica sees the drop, the first to record it wins.
+                match kv_dropped
+                    .create(&event.key, serde_json::to_vec(&player)?.into())
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == CreateErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            event = dropped_watcher.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("KV event error watching dropped players: {:?}", e);
+                        continue;
+                    }
+                };
+                if event.operation != Operation::Put {
+                    dropped.reconnected(&event.key);
+                    continue;
+                }
+                match serde_json::from_slice::<DroppedPlayer>(&event.value) {
+                    Ok(player) => dropped.dropped(event.key, event.revision, player),
+                    Err(e) => warn!("Invalid dropped player for {}: {e}", event.key),
                 }
             }
-            Err(e) => {
-                warn!("KV event error watching for session cleanup: {:?}", e);
+            _ = expiry.tick() => {
+                for (key, revision, player) in dropped.expire(unix_now()) {
+                    // Fails if another replica got there first, or they just asked to reconnect.
+                    if let Err(e) = kv_dropped.delete_expect_revision(&key, Some(revision)).await {
+                        info!("Not removing {key}, its entry changed: {e}");
+                        continue;
+                    }
+                    player_left(state, &connected, player).await?;
+                }
             }
         }
     }
     Ok(())
 }
+
+/// Removes a player who didn't reconnect in time from their session, or deletes the session if
+/// nobody else is in it.
+async fn player_left(
+    state: &MatchmakerState,
+    connected: &ConnectedPlayers,
+    player: DroppedPlayer,
+) -> Result<(), async_nats::Error> {
+    let DroppedPlayer {
+        session_id,
+        client_id,
+        client_ip,
+        ..
+    } = player;
+    if connected.has_players(&session_id) || state.dropped_players.in_session(&session_id) {
+        info!("Client {client_id} left session {session_id}, removing them from it");
+        let ip_list = match client_ip {
+            Some(client_ip) => vec![client_ip],
+            None => unknown_session_users(state, connected, &session_id).await,
+        };
+        if !ip_list.is_empty() {
+            remove_session_user(state, &session_id, ip_list).await;
+        }
+    } else {
+        info!("Last client left session {session_id}, deleting it");
+        state.nats.enqueue_session_delete(session_id).await?;
+    }
+    Ok(())
+}
+
+/// Players in `active_connections`, by session, kept up to date by the cleanup watcher. Deleted
+/// entries have no value, so this also remembers each player's IP from when they connected.
+#[derive(Debug, Default)]
+struct ConnectedPlayers {
+    /// Session id → client id → IP, if their entry had one
+    sessions: HashMap<String, HashMap<String, Option<String>>>,
+}
+
+impl ConnectedPlayers {
+    fn connected(&mut self, session_id: &str, client_id: &str, client_ip: Option<String>) {
+        self.sessions
+            .entry(session_id.to_string())
+            .or_default()
+            .insert(client_id.to_string(), client_ip);
+    }
+
+    /// Forgets a player, returning their IP if we knew it.
+    fn disconnected(&mut self, session_id: &str, client_id: &str) -> Option<String> {
+        let players = self.sessions.get_mut(session_id)?;
+        let client_ip = players.remove(client_id).flatten();
+        if players.is_empty() {
+            self.sessions.remove(session_id);
+        }
+        client_ip
+    }
+
+    /// Whether any players are still connected to the session.
+    fn has_players(&self, session_id: &str) -> bool {
+        self.sessions.contains_key(session_id)
+    }
+
+    /// The IPs of the players connected to the session, unless we don't know them all.
+    fn ips(&self, session_id: &str) -> Option<Vec<String>> {
+        let Some(players) = self.sessions.get(session_id) else {
+            return Some(Vec::new());
+        };
+        players.values().cloned().collect()
+    }
+}
+
+/// The IPs of a player who left without us learning their IP, going by who's still in the
+/// session: any of the session's users that no connected or reconnecting player is using.
+/// Empty if we don't know everyone else's IP either, rather than risk removing them.
+async fn unknown_session_users(
+    state: &MatchmakerState,
+    connected: &ConnectedPlayers,
+    session_id: &str,
+) -> Vec<String> {
+    let still_playing = connected
+        .ips(session_id)
+        .zip(state.dropped_players.ips_in_session(session_id));
+    let Some((mut still_playing, reconnecting)) = still_playing else {
+        warn!("Can't tell which of session {session_id}'s users left, keeping them all");
+        return Vec::new();
+    };
+    still_playing.extend(reconnecting);
+    match state.edgegap().get_session(session_id).await {
+        Ok(session_get) => {
+            let users: Vec<String> = session_get
+                .session_users
+                .unwrap_or_default()
+                .into_iter()
+                .map(|user| user.ip)
+                .collect();
+            departed_users(&users, &still_playing)
+        }
+        Err(e) if e.is_gone() => Vec::new(),
+        Err(e) => {
+            warn!("Failed to get session {session_id} to remove a user: {e}");
+            state.health.record_failure(EDGEGAP_HEALTH_CHECK, e);
+            Vec::new()
+        }
+    }
+}
+
+/// The session's users that aren't any of the players still playing.
+fn departed_users(users: &[String], still_playing: &[String]) -> Vec<String> {
+    users
+        .iter()
+        .filter(|ip| !still_playing.contains(ip))
+        .cloned()
+        .collect()
+}
+
+/// The players already connected when we start watching.
+async fn connected_players(
+    kv: &async_nats::jetstream::kv::Store,
+) -> Result<ConnectedPlayers, async_nats::Error> {
+    let mut connected = ConnectedPlayers::default();
+    let mut keys = kv.keys().await?.boxed();
+    while let Some(key) = keys.try_next().await? {
+        let Some((session_id, client_id)) = parse_session_player_key(&key) else {
+            continue;
+        };
+        let Some(value) = kv.get(&key).await? else {
+            continue;
+        };
+        let client_ip = serde_json::from_slice::<SessionPlayer>(&value)
+            .ok()
+            .map(|player| player.client_ip);
+        connected.connected(session_id, client_id, client_ip);
+    }
+    Ok(connected)
+}
+
+/// Tells Edgegap a player has left a session. The session carries on without them, so a failure
+/// is only logged.
+async fn remove_session_user(state: &MatchmakerState, session_id: &str, ip_list: Vec<String>) {
+    match state
+        .edgegap()
+        .remove_session_users(session_id, ip_list)
+        .await
+    {
+        Ok(users) => info!(
+            "Session {session_id} has {} users left",
+            users.session_users.len()
+        ),
+        Err(e) if e.is_gone() => info!("Session {session_id} already gone"),
+        Err(e) => {
+            warn!("Failed to remove user from session {session_id}: {e}");
+            state.health.record_failure(EDGEGAP_HEALTH_CHECK, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connected_players_by_session() {
+        let mut connected = ConnectedPlayers::default();
+        connected.connected("s1", "1", Some("1.2.3.4".to_string()));
+        connected.connected("s1", "2", None);
+        connected.connected("s2", "3", Some("5.6.7.8".to_string()));
+
+        assert_eq!(
+            connected.disconnected("s1", "1"),
+            Some("1.2.3.4".to_string())
+        );
+        assert!(connected.has_players("s1"));
+        assert_eq!(connected.disconnected("s1", "2"), None);
+        assert!(!connected.has_players("s1"));
+        assert!(connected.has_players("s2"));
+        assert_eq!(connected.disconnected("s1", "2"), None);
+    }
+
+    #[test]
+    fn test_connected_ips_only_if_all_known() {
+        let mut connected = ConnectedPlayers::default();
+        assert_eq!(connected.ips("s1"), Some(vec![]));
+        connected.connected("s1", "1", Some("1.2.3.4".to_string()));
+        assert_eq!(connected.ips("s1"), Some(vec!["1.2.3.4".to_string()]));
+        connected.connected("s1", "2", None);
+        assert_eq!(connected.ips("s1"), None);
+    }
+
+    #[test]
+    fn test_departed_users() {
+        let ips = |ips: &[&str]| ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>();
+        let users = ips(&["1.1.1.1", "2.2.2.2", "3.3.3.3"]);
+        assert_eq!(
+            departed_users(&users, &ips(&["1.1.1.1", "3.3.3.3"])),
+            ips(&["2.2.2.2"])
+        );
+        // a player sharing an IP with someone still playing stays in the session
+        assert!(departed_users(&ips(&["1.1.1.1"]), &ips(&["1.1.1.1"])).is_empty());
+    }
+}
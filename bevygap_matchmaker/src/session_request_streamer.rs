@@ -1,17 +1,24 @@
-use crate::MatchmakerState;
+use crate::join_token::{can_sign, join_token, verify_join_token};
+use crate::placement::apply_placement;
+use crate::{MatchmakerState, EDGEGAP_HEALTH_CHECK};
 use async_nats::error::Error as NatsError;
 use async_nats::{Client, Subject};
 use base64::prelude::*;
+use bevygap_shared::nats::{client_session_key, session_player_key};
 use bevygap_shared::protocol::*;
-use edgegap_async::{apis::sessions_api::*, apis::Error as EdgegapError, models::SessionModel};
-use futures::StreamExt;
+use bevygap_shared::util::unix_now;
+use edgegap_async::models::SessionGet;
+use edgegap_async::{EdgegapClient, EdgegapError, NewSession};
+use futures::{pin_mut, StreamExt, TryStreamExt};
 use lightyear::netcode::ConnectToken;
 use log::*;
 use serde::{de, Deserialize};
 use std::net::{IpAddr, SocketAddr};
-use std::time::Duration;
 use tokio::time::Instant;
 
+/// How many players a session can have, if `player_limit` isn't set.
+const MAX_SESSION_PLAYERS: u8 = 4;
+
 #[derive(Deserialize, Debug)]
 pub struct SessionRequest {
     /// the ip of the client that wants a session
@@ -44,6 +51,34 @@ impl SessionRequest {
             obj: parsed,
         })
     }
+
+    /// The deployment to link the session to, for players joining a lobby room's gameserver.
+    pub fn deployment_request_id(&self) -> Option<&str> {
+        self.obj.get("deployment_request_id")?.as_str()
+    }
+
+    /// Another player's session to join, rather than starting a new one.
+    pub fn session_id(&self) -> Option<&str> {
+        self.obj.get("session_id")?.as_str()
+    }
+
+    /// The join token for `session_id`, from a player already in it.
+    pub fn join_token(&self) -> Option<&str> {
+        self.obj.get("join_token")?.as_str()
+    }
+
+    /// The client id the player had in `session_id`, for reconnecting after they dropped.
+    pub fn reconnect_client_id(&self) -> Option<u64> {
+        self.obj.get("reconnect_client_id")?.as_u64()
+    }
+
+    /// The placement filters the client asked for, if any.
+    pub fn placement(&self) -> Result<SessionPlacement, serde_json::Error> {
+        match self.obj.get("placement") {
+            Some(placement) => serde_json::from_value(placement.clone()),
+            None => Ok(SessionPlacement::default()),
+        }
+    }
 }
 
 /// For sending progress updates back to the caller
@@ -73,26 +108,70 @@ async fn stream_request_processor(
     state: &MatchmakerState,
     session_request: SessionRequest,
     responder: &ChunkResponder,
-) -> Result<(), MyError<SessionPostError>> {
+) -> Result<(), MyError> {
     // Sender for feedback responses, client will recieve multiple before the Finished one.
     info!("Generating streaming session for {session_request:?}");
     responder.send(SessionRequestFeedback::Acknowledged).await?;
 
+    if let Some(session_id) = session_request.session_id() {
+        let client_ip = &session_request.client_ip;
+        let join_token = session_request.join_token().unwrap_or_default();
+        let (session_get, client_id) = match session_request.reconnect_client_id() {
+            Some(client_id) => {
+                let session_get = rejoin_session(
+                    state, session_id, join_token, client_id, client_ip, responder,
+                )
+                .await?;
+                (session_get, client_id)
+            }
+            None => {
+                let session_get =
+                    join_session(state, session_id, join_token, client_ip, responder).await?;
+                (session_get, rand::random())
+            }
+        };
+        return send_connect_token(state, session_get, client_id, client_ip, responder).await;
+    }
+
     // Extract game name from the client request, fall back to configured app_name if not provided
-    let app_name = session_request.obj.get("game")
+    let app_name = session_request
+        .obj
+        .get("game")
         .and_then(|v| v.as_str())
         .unwrap_or(&state.settings.app_name)
         .to_string();
 
-    info!("Creating session for app: {}", app_name);
-    let mut session_model = SessionModel::new(app_name);
-    session_model.ip_list = Some(vec![session_request.client_ip.to_string()]);
-    session_model
-        .webhook_url
-        .clone_from(&state.settings.session_webhook_url);
+    let new_session = NewSession::new(app_name.clone())
+        .client_ip(session_request.client_ip.to_string())
+        .webhook_url(state.settings.session_webhook_url.clone());
+    let new_session = match session_request.deployment_request_id() {
+        // the deployment's location was already chosen when it was deployed
+        Some(request_id) => {
+            check_gameserver_accepting(state, request_id)?;
+            info!("Creating session for app: {app_name} on deployment {request_id}");
+            new_session.deployment(request_id)
+        }
+        None => {
+            let placement = session_request
+                .placement()
+                .map_err(|e| MyError::Bevygap(400, format!("Invalid placement: {e}")))?;
+            let placement = state
+                .settings
+                .placement
+                .resolve(placement)
+                .map_err(|e| MyError::Bevygap(400, e))?;
+            info!("Creating session for app: {app_name} with placement {placement:?}");
+            apply_placement(new_session, &placement)
+        }
+    };
     // create session via edgegap api.
     // this gives us our session_id, but could be in a non-Ready state for a while.
-    let post_session = session_post(state.configuration(), session_model).await?;
+    let post_session = state
+        .edgegap()
+        .create_session(new_session)
+        .await
+        .inspect_err(|e| state.health.record_failure(EDGEGAP_HEALTH_CHECK, e))?;
+    state.health.record_success(EDGEGAP_HEALTH_CHECK);
 
     // info!("{post_session:?}");
 
@@ -106,19 +185,24 @@ async fn stream_request_processor(
     let mut tries = 0;
     // let mut first_seen_session_id = false;
     let start_time = Instant::now();
-    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+    tokio::time::sleep(state.settings.session_poll_interval()).await;
+    // gets the session immediately, then after each poll interval until it's ready
+    let polls = state.edgegap().poll_session(
+        post_session.session_id.as_str(),
+        state.settings.session_poll_interval(),
+    );
+    pin_mut!(polls);
     loop {
         tries += 1;
         info!("GET SESSION... ({tries})");
-        session_get = get_session(state.configuration(), post_session.session_id.as_str())
+        session_get = polls
+            .try_next()
             .await
-            .map_err(|e| {
-                error!("get session error: {:?}", e);
-                EdgegapError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("get session error: {}", e),
-                ))
-            })?;
+            .inspect_err(|e| {
+                error!("get session error: {e}");
+                state.health.record_failure(EDGEGAP_HEALTH_CHECK, e);
+            })?
+            .expect("poll_session yields until the session is ready");
         let feedback = SessionRequestFeedback::ProgressReport(format!(
             "{} ({})",
             session_get.status, session_get.elapsed
@@ -151,19 +235,34 @@ async fn stream_request_processor(
         }
 
         let elapsed = Instant::now().duration_since(start_time);
-        if elapsed > Duration::from_secs(crate::MAX_SESSION_CREATION_SECONDS) {
+        if elapsed > state.settings.max_session_creation_time() {
             //TODO schedule delete of session id!
             return Err(MyError::Bevygap(
                 408,
                 "session still not ready, timed out.".into(),
             ));
         }
-
-        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
     }
 
-    // info!("{session_get:?}");
+    let client_id = rand::random();
+    send_connect_token(
+        state,
+        session_get,
+        client_id,
+        &session_request.client_ip,
+        responder,
+    )
+    .await
+}
 
+/// Once the session is ready, gives the player a connect token for its gameserver.
+async fn send_connect_token(
+    state: &MatchmakerState,
+    session_get: SessionGet,
+    client_id: u64,
+    client_ip: &str,
+    responder: &ChunkResponder,
+) -> Result<(), MyError> {
     // We must wait until the session is ready / linked before telling the client to connect.
     // You can ask for a webhook, but for now we just poll until it's ready.
 
@@ -188,10 +287,6 @@ async fn stream_request_processor(
         .and_then(|(_, port_info)| port_info.external)
         .expect("Couldn't get port");
 
-    //  assign a new client_id
-    let client_id = rand::random();
-    // info!("client_id = {client_id}");
-
     let public_ip_str = deployment.public_ip.as_str();
 
     let ip = public_ip_str
@@ -221,7 +316,12 @@ async fn stream_request_processor(
     let token_bytes = token.try_into_bytes().expect("Failed to serialize token");
     let token_base64 = BASE64_STANDARD.encode(token_bytes);
 
-    register_ids_in_nats(state, client_id.to_string(), session_get.session_id).await?;
+    let join_token = join_token(&state.lightyear_private_key(), &session_get.session_id);
+    let player = SessionPlayer {
+        session_id: session_get.session_id,
+        client_ip: client_ip.to_string(),
+    };
+    register_ids_in_nats(state, &deployment.request_id, client_id.to_string(), player).await?;
 
     responder
         .send(SessionRequestFeedback::SessionReady {
@@ -229,6 +329,8 @@ async fn stream_request_processor(
             ip: deployment.public_ip,
             port: port as u16,
             cert_digest,
+            client_id: Some(client_id),
+            join_token,
         })
         .await?;
     // send an empty chunk to finish:
@@ -236,41 +338,207 @@ async fn stream_request_processor(
     Ok(())
 }
 
+/// Refuses players without the session's join token, which every player in it was given.
+fn check_join_token(
+    state: &MatchmakerState,
+    session_id: &str,
+    join_token: &str,
+    client_ip: &str,
+) -> Result<(), MyError> {
+    if !can_sign(&state.lightyear_private_key()) {
+        return Err(MyError::Bevygap(
+            403,
+            "Joining sessions needs lightyear_private_key to be set".into(),
+        ));
+    }
+    if !verify_join_token(&state.lightyear_private_key(), session_id, join_token) {
+        warn!("Refusing {client_ip} entry to session {session_id}, bad join token");
+        return Err(MyError::Bevygap(403, "Invalid join token".into()));
+    }
+    Ok(())
+}
+
+/// Adds the player to another player's session, so they share its gameserver. They need the
+/// session's join token, from a player already in it, and the session must have room.
+async fn join_session(
+    state: &MatchmakerState,
+    session_id: &str,
+    join_token: &str,
+    client_ip: &str,
+    responder: &ChunkResponder,
+) -> Result<SessionGet, MyError> {
+    check_join_token(state, session_id, join_token, client_ip)?;
+    info!("Adding {client_ip} to session {session_id}");
+    let session_get = state
+        .edgegap()
+        .get_session(session_id)
+        .await
+        .inspect_err(|e| state.health.record_failure(EDGEGAP_HEALTH_CHECK, e))?;
+    let deployment = match &session_get.deployment {
+        Some(deployment) if session_get.ready => deployment,
+        _ => return Err(MyError::Bevygap(409, "Session isn't ready yet".into())),
+    };
+    check_session_capacity(&session_get, state.player_limit())?;
+    check_gameserver_accepting(state, &deployment.request_id)?;
+    let added = add_session_user(state.edgegap(), session_id, client_ip, state.player_limit())
+        .await
+        .inspect_err(|e| state.health.record_failure(EDGEGAP_HEALTH_CHECK, e))?;
+    state.health.record_success(EDGEGAP_HEALTH_CHECK);
+    if !added {
+        return Err(session_full(state.player_limit()));
+    }
+    responder
+        .send(SessionRequestFeedback::SessionRequestAccepted(
+            session_id.to_string(),
+        ))
+        .await?;
+    Ok(session_get)
+}
+
+/// Gives a player whose connection dropped a new token for their session, with the same client
+/// id, so the gameserver knows it's them. Like joining, this needs the session's join token, which
+/// they were given with their first token. They're still in the Edgegap session, and the
+/// gameserver is keeping their place, so there's no need to check it has room.
+async fn rejoin_session(
+    state: &MatchmakerState,
+    session_id: &str,
+    join_token: &str,
+    client_id: u64,
+    client_ip: &str,
+    responder: &ChunkResponder,
+) -> Result<SessionGet, MyError> {
+    check_join_token(state, session_id, join_token, client_ip)?;
+    info!("Client {client_id} at {client_ip} reconnecting to session {session_id}");
+    let key = session_player_key(session_id, &client_id.to_string());
+    let now = unix_now();
+    let expires_at = now + state.settings.reconnect_grace().as_secs();
+    let too_late = || MyError::Bevygap(403, "Too late to reconnect to this session".into());
+    let (player, revision) = state
+        .dropped_players
+        .reconnecting(&key, client_ip, expires_at, now)
+        .ok_or_else(too_late)?;
+    // Extend their time in dropped_players, unless it just expired, so every replica sees it.
+    let value = serde_json::to_vec(&player).map_err(|e| MyError::Bevygap(500, e.to_string()))?;
+    state
+        .nats
+        .kv_dropped_players()
+        .update(&key, value.into(), revision)
+        .await
+        .map_err(|_| too_late())?;
+    let session_get = state
+        .edgegap()
+        .get_session(session_id)
+        .await
+        .inspect_err(|e| state.health.record_failure(EDGEGAP_HEALTH_CHECK, e))?;
+    state.health.record_success(EDGEGAP_HEALTH_CHECK);
+    if session_get.deployment.is_none() || !session_get.ready {
+        return Err(MyError::Bevygap(409, "Session isn't ready".into()));
+    }
+    responder
+        .send(SessionRequestFeedback::SessionRequestAccepted(
+            session_id.to_string(),
+        ))
+        .await?;
+    Ok(session_get)
+}
+
+/// Refuses to add a player to a session that already has `player_limit` players, or
+/// [`MAX_SESSION_PLAYERS`] if no limit is configured.
+fn check_session_capacity(
+    session_get: &SessionGet,
+    player_limit: Option<u8>,
+) -> Result<(), MyError> {
+    let limit = player_limit.unwrap_or(MAX_SESSION_PLAYERS);
+    if session_get.user_count >= i32::from(limit) {
+        return Err(session_full(player_limit));
+    }
+    Ok(())
+}
+
+fn session_full(player_limit: Option<u8>) -> MyError {
+    let limit = player_limit.unwrap_or(MAX_SESSION_PLAYERS);
+    MyError::Bevygap(409, format!("Session is full ({limit} players)"))
+}
+
+/// Adds the player to the session, returning false if that took it over the player limit.
+/// [`check_session_capacity`] isn't enough on its own, since other players can be joining at the
+/// same time, so this checks where the player landed in the session's users, and removes them
+/// again if the players added before them already filled it.
+async fn add_session_user(
+    edgegap: &EdgegapClient,
+    session_id: &str,
+    client_ip: &str,
+    player_limit: Option<u8>,
+) -> Result<bool, EdgegapError> {
+    let users = edgegap
+        .add_session_users(session_id, vec![client_ip.to_string()])
+        .await?
+        .session_users;
+    let limit = usize::from(player_limit.unwrap_or(MAX_SESSION_PLAYERS));
+    let position = users
+        .iter()
+        .rposition(|user| user.ip == client_ip)
+        .unwrap_or(users.len().saturating_sub(1));
+    if position < limit {
+        return Ok(true);
+    }
+    warn!("Session {session_id} filled up while adding {client_ip}, removing them again");
+    edgegap
+        .remove_session_users(session_id, vec![client_ip.to_string()])
+        .await?;
+    Ok(false)
+}
+
+/// Refuses gameservers whose heartbeat says they're full or shutting down. No heartbeat yet is
+/// fine, they may still be starting up.
+fn check_gameserver_accepting(state: &MatchmakerState, request_id: &str) -> Result<(), MyError> {
+    let Some(gameserver) = state.gameservers.get(request_id) else {
+        return Ok(());
+    };
+    if gameserver.accepting_players() {
+        return Ok(());
+    }
+    let reason = if gameserver.is_full() {
+        "Gameserver is full"
+    } else {
+        "Gameserver isn't accepting players"
+    };
+    Err(MyError::Bevygap(503, reason.to_string()))
+}
+
 async fn register_ids_in_nats(
     state: &MatchmakerState,
+    request_id: &str,
     client_id: String,
-    session_id: String,
-) -> Result<(), MyError<SessionPostError>> {
-    let session_id_val = session_id.clone().into();
+    player: SessionPlayer,
+) -> Result<(), MyError> {
+    let player_json =
+        serde_json::to_vec(&player).map_err(|e| MyError::Bevygap(500, e.to_string()))?;
     state
         .nats
         .kv_c2s()
-        .put(client_id.as_str(), session_id_val)
+        .put(
+            client_session_key(request_id, &client_id),
+            player_json.into(),
+        )
         .await
-        .map_err(|e| {
-            EdgegapError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to put token KV entry: {}", e),
-            ))
-        })?;
+        .map_err(|e| MyError::Bevygap(500, format!("Failed to put token KV entry: {e}")))?;
     state
         .nats
         .kv_s2c()
-        .put(session_id.as_str(), client_id.into())
+        .put(
+            session_player_key(&player.session_id, &client_id),
+            client_id.into(),
+        )
         .await
-        .map_err(|e| {
-            EdgegapError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to put token KV entry: {}", e),
-            ))
-        })?;
+        .map_err(|e| MyError::Bevygap(500, format!("Failed to put token KV entry: {e}")))?;
     Ok(())
 }
 
 async fn lookup_cert_digest(
     state: &MatchmakerState,
     public_ip: &IpAddr,
-) -> Result<String, MyError<SessionPostError>> {
+) -> Result<String, MyError> {
     let ip_str = public_ip.to_string();
     match state.nats.kv_cert_digests().get(ip_str).await {
         Ok(Some(cert_digest)) => Ok(String::from_utf8(cert_digest.into()).unwrap()),
@@ -342,14 +610,9 @@ pub(crate) async fn streaming_session_request_handler(
                         .send(SessionRequestFeedback::Error(err_code, err_msg))
                         .await;
                 }
-                Err(MyError::Edgegap(edgegap_async::apis::Error::ResponseError(e))) => {
-                    let (err_code, err_msg) = match e.entity {
-                        Some(SessionPostError::Status400(ee)) => (400, ee.message),
-                        Some(SessionPostError::Status401(ee)) => (401, ee.message),
-                        Some(SessionPostError::Status409(ee)) => (409, ee.message),
-                        _ => (503, "unknown error".to_string()),
-                    };
-                    error!("error in session_responder: {err_code}={err_msg}");
+                Err(MyError::Edgegap(e)) => {
+                    let (err_code, err_msg) = edgegap_error_feedback(&e);
+                    error!("error in session_responder: {err_code}={err_msg} ({e})");
                     let _ = responder
                         .send(SessionRequestFeedback::Error(err_code, err_msg))
                         .await;
@@ -361,13 +624,6 @@ pub(crate) async fn streaming_session_request_handler(
                         .send(SessionRequestFeedback::Error(500, err_response))
                         .await;
                 }
-                Err(e) => {
-                    error!("Error in stream_request_processor: {:?}", e);
-                    let err_response = format!("SessionPostError: {e:?}");
-                    let _ = responder
-                        .send(SessionRequestFeedback::Error(500, err_response))
-                        .await;
-                }
             }
             // close the response by sending an empty message
             let _ = responder.finish().await;
@@ -378,26 +634,165 @@ pub(crate) async fn streaming_session_request_handler(
     Ok(())
 }
 
-// don't want to modify the auto-generated edgegap client error type,
-// so wrap it up here to also add our nats error type.
+/// Errors from handling a session request, reported back to the client as a status and message.
 #[derive(Debug)]
-pub(crate) enum MyError<T> {
-    Edgegap(EdgegapError<T>), // Wrap the third-party error
-    Nats(async_nats::Error),  // Add a variant for async_nats errors
+pub(crate) enum MyError {
+    Edgegap(EdgegapError),
+    Nats(async_nats::Error),
     Bevygap(u16, String),
 }
-impl<T> From<EdgegapError<T>> for MyError<T> {
-    fn from(err: EdgegapError<T>) -> Self {
+impl From<EdgegapError> for MyError {
+    fn from(err: EdgegapError) -> Self {
         MyError::Edgegap(err)
     }
 }
-impl<T> From<NatsError<async_nats::client::PublishErrorKind>> for MyError<T> {
+impl From<NatsError<async_nats::client::PublishErrorKind>> for MyError {
     fn from(err: NatsError<async_nats::client::PublishErrorKind>) -> Self {
         MyError::Nats(Box::new(err))
     }
 }
-impl<T> From<async_nats::Error> for MyError<T> {
+impl From<async_nats::Error> for MyError {
     fn from(err: async_nats::Error) -> Self {
         MyError::Nats(err)
     }
 }
+
+/// The status code and message to report to the client for an Edgegap API failure.
+pub(crate) fn edgegap_error_feedback(e: &EdgegapError) -> (u16, String) {
+    match e {
+        EdgegapError::Api { status, message } => (status.as_u16(), message.clone()),
+        EdgegapError::CircuitOpen(_) => {
+            (503, "Edgegap API unavailable, try again later".to_string())
+        }
+        EdgegapError::Timeout { .. } => (408, "session still not ready, timed out.".to_string()),
+        EdgegapError::DeploymentTimeout { .. } => {
+            (408, "deployment still not ready, timed out.".to_string())
+        }
+        other => (500, other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edgegap_async::fixtures::{
+        Cassette, FixtureServer, Interaction, RecordedRequest, RecordedResponse,
+    };
+
+    fn session_with_users(user_count: i32) -> SessionGet {
+        SessionGet::new(
+            "950dd2eaff09-S".to_string(),
+            "Ready".to_string(),
+            true,
+            true,
+            "Seat".to_string(),
+            user_count,
+            1,
+            "2024-11-05 09:12:44.120000".to_string(),
+            12,
+        )
+    }
+
+    #[test]
+    fn test_session_capacity() {
+        assert!(check_session_capacity(&session_with_users(1), Some(2)).is_ok());
+        assert!(matches!(
+            check_session_capacity(&session_with_users(2), Some(2)),
+            Err(MyError::Bevygap(409, _))
+        ));
+        // without a player limit, sessions still can't grow forever
+        assert!(check_session_capacity(&session_with_users(3), None).is_ok());
+        assert!(check_session_capacity(&session_with_users(4), None).is_err());
+    }
+
+    fn session_users(ips: &[&str]) -> serde_json::Value {
+        let users: Vec<_> = ips
+            .iter()
+            .map(|ip| serde_json::json!({ "ip": ip }))
+            .collect();
+        serde_json::json!({ "session_users": users })
+    }
+
+    fn users_interaction(method: &str, ip: &str, ips: &[&str]) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: method.to_string(),
+                path: "/v1/session/950dd2eaff09-S/users".to_string(),
+                query: None,
+                body: Some(serde_json::json!({ "ip_list": [ip] })),
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: Default::default(),
+                body: session_users(ips),
+                text: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_racing_joins_dont_overfill_session() {
+        let cassette = Cassette {
+            interactions: vec![
+                users_interaction("PUT", "5.5.5.5", &["1.1.1.1", "5.5.5.5"]),
+                users_interaction("PUT", "6.6.6.6", &["1.1.1.1", "5.5.5.5", "6.6.6.6"]),
+                users_interaction("DELETE", "6.6.6.6", &["1.1.1.1", "5.5.5.5"]),
+            ],
+        };
+        let server = FixtureServer::replay(cassette).await.unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+        // two joins at once: both checked the session while it had one player, one short of
+        // the limit, before either was added
+        let session_get = session_with_users(1);
+        assert!(check_session_capacity(&session_get, Some(2)).is_ok());
+        assert!(check_session_capacity(&session_get, Some(2)).is_ok());
+        let first = add_session_user(&client, "950dd2eaff09-S", "5.5.5.5", Some(2)).await;
+        let second = add_session_user(&client, "950dd2eaff09-S", "6.6.6.6", Some(2)).await;
+        assert!(server.misses().is_empty(), "{:?}", server.misses());
+        let bodies: Vec<_> = server.received().into_iter().map(|r| r.body).collect();
+        server.finish().await.unwrap();
+        // only the first one in keeps their place
+        assert!(first.unwrap());
+        assert!(!second.unwrap());
+        assert_eq!(
+            bodies.last().cloned().flatten(),
+            Some(serde_json::json!({ "ip_list": ["6.6.6.6"] }))
+        );
+    }
+
+    #[test]
+    fn test_join_token_read_from_request() {
+        let raw =
+            br#"{"client_ip": "1.2.3.4", "session_id": "950dd2eaff09-S", "join_token": "abc"}"#;
+        let request = SessionRequest::from_raw(raw).unwrap();
+        assert_eq!(request.session_id(), Some("950dd2eaff09-S"));
+        assert_eq!(request.join_token(), Some("abc"));
+        let request = SessionRequest::from_raw(br#"{"client_ip": "1.2.3.4"}"#).unwrap();
+        assert_eq!(request.join_token(), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_post_errors_keep_their_status() {
+        let server = FixtureServer::from_env("session_post_errors")
+            .await
+            .unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+        let mut feedback = Vec::new();
+        for _ in 0..4 {
+            match client.create_session(NewSession::new("mygame")).await {
+                Err(e) => feedback.push(edgegap_error_feedback(&e)),
+                other => panic!("expected an error response, got {other:?}"),
+            }
+        }
+        server.finish().await.unwrap();
+        assert_eq!(
+            feedback,
+            [
+                (400, "No available location for this session".to_string()),
+                (401, "Invalid token".to_string()),
+                (409, "App version is not active".to_string()),
+                (429, "Too Many Requests".to_string()),
+            ]
+        );
+    }
+}
@@ -1,8 +1,12 @@
+use crate::placement::apply_placement;
+use crate::session_request_streamer::edgegap_error_feedback;
 use crate::MatchmakerState;
 use async_nats::service::ServiceExt;
 use base64::prelude::*;
-use edgegap_async::{apis::sessions_api::*, apis::Error as EdgegapError, models::SessionModel};
-use futures::StreamExt;
+use bevygap_shared::nats::{client_session_key, session_player_key};
+use bevygap_shared::protocol::SessionPlayer;
+use edgegap_async::{EdgegapError, NewSession};
+use futures::{pin_mut, StreamExt, TryStreamExt};
 use lightyear::netcode::ConnectToken;
 use log::*;
 use serde::{de, Deserialize, Serialize};
@@ -52,15 +56,12 @@ fn decode_request(raw: &[u8]) -> Result<SessionRequest, serde_json::Error> {
     })
 }
 
-pub async fn session_request_supervisor(state: &MatchmakerState) -> Result<(), async_nats::Error> {
-    let handles = (0..5).map(|_| session_request_handler(state));
+/// How many session_request_handler tasks to run, each one is a supervised child.
+pub(crate) const SESSION_REQUEST_HANDLERS: usize = 5;
 
-    futures::future::join_all(handles).await;
-
-    Ok(())
-}
-
-async fn session_request_handler(state: &MatchmakerState) -> Result<(), async_nats::Error> {
+pub(crate) async fn session_request_handler(
+    state: &MatchmakerState,
+) -> Result<(), async_nats::Error> {
     let client = state.nats_client();
     info!("Listening for session requests on 'session_requests'");
 
@@ -94,29 +95,14 @@ async fn session_request_handler(state: &MatchmakerState) -> Result<(), async_na
                             .unwrap();
                     }
 
-                    Err(edgegap_async::apis::Error::ResponseError(e)) => {
-                        error!("edgegap api error: {:?}", e);
-                        let (err_code, err_msg) = match e.entity {
-                            Some(SessionPostError::Status400(ee)) => (400, ee.message),
-                            Some(SessionPostError::Status401(ee)) => (401, ee.message),
-                            Some(SessionPostError::Status409(ee)) => (409, ee.message),
-                            _ => (999, "unknown error".to_string()),
-                        };
+                    Err(e) => {
+                        error!("edgegap api error: {e:?}");
+                        let (err_code, err_msg) = edgegap_error_feedback(&e);
                         error!("error in session_responder: {err_code}={err_msg}");
                         request
                             .respond(Err(async_nats::service::error::Error {
                                 status: err_msg,
-                                code: err_code,
-                            }))
-                            .await
-                            .unwrap();
-                    }
-                    Err(e) => {
-                        error!("Unhandled error in session_responder: {:?}", e);
-                        request
-                            .respond(Err(async_nats::service::error::Error {
-                                status: format!("error generating session: {}", e),
-                                code: 500, // internal server error
+                                code: err_code.into(),
                             }))
                             .await
                             .unwrap();
@@ -155,7 +141,7 @@ async fn session_request_handler(state: &MatchmakerState) -> Result<(), async_na
 async fn session_responder(
     state: &MatchmakerState,
     session_request: &SessionRequest,
-) -> Result<SessionResponse, EdgegapError<SessionPostError>> {
+) -> Result<SessionResponse, EdgegapError> {
     // let client = state.nats_client();
 
     info!("Generating session for {session_request:?}");
@@ -167,19 +153,21 @@ async fn session_responder(
     // * deployment_request_id
 
     // Extract game name from the client request, fall back to configured app_name if not provided
-    let app_name = session_request.obj.get("game")
+    let app_name = session_request
+        .obj
+        .get("game")
         .and_then(|v| v.as_str())
         .unwrap_or(&state.settings.app_name)
         .to_string();
 
     info!("Creating session for app: {}", app_name);
-    let mut session_model = SessionModel::new(app_name);
-    session_model.ip_list = Some(vec![session_request.client_ip.to_string()]);
-    session_model
-        .webhook_url
-        .clone_from(&state.settings.session_webhook_url);
+    let new_session = NewSession::new(app_name)
+        .client_ip(session_request.client_ip.to_string())
+        .webhook_url(state.settings.session_webhook_url.clone());
+    // requests to this service can't choose placement, so only the configured defaults apply
+    let new_session = apply_placement(new_session, &state.settings.placement.defaults);
     // create session via edgegap api:
-    let post_session = session_post(state.configuration(), session_model).await?;
+    let post_session = state.edgegap().create_session(new_session).await?;
 
     info!("{post_session:?}");
 
@@ -202,18 +190,19 @@ async fn session_responder(
     let mut session_get;
     let mut tries = 0;
     let mut first_seen_session_id = false;
-    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+    tokio::time::sleep(state.settings.session_poll_interval()).await;
+    let polls = state.edgegap().poll_session(
+        post_session.session_id.as_str(),
+        state.settings.session_poll_interval(),
+    );
+    pin_mut!(polls);
     loop {
         tries += 1;
         info!("GET SESSION... ({tries})");
-        session_get = get_session(state.configuration(), post_session.session_id.as_str())
-            .await
-            .map_err(|e| {
-                EdgegapError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("get session error: {}", e),
-                ))
-            })?;
+        session_get = polls
+            .try_next()
+            .await?
+            .expect("poll_session yields until the session is ready");
 
         info!("{session_get:?}");
 
@@ -243,8 +232,6 @@ async fn session_responder(
                 "session not ready timeout on tries",
             )));
         }
-
-        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
     }
 
     // info!("{session_get:?}");
@@ -255,7 +242,7 @@ async fn session_responder(
     let deployment = session_get.deployment.expect("deployment not found");
 
     let Some(ports) = deployment.ports else {
-        return Err(edgegap_async::apis::Error::Io(std::io::Error::new(
+        return Err(EdgegapError::Io(std::io::Error::new(
             std::io::ErrorKind::Other,
             "No ports found in deployment!",
         )));
@@ -319,12 +306,17 @@ async fn session_responder(
     // user-level code using lightyear doesn't even see the connect token, so we do the
     // lookup based on clientid.
     let client_id_str = client_id.to_string();
+    let player = SessionPlayer {
+        session_id: session_get.session_id.clone(),
+        client_ip: session_request.client_ip.clone(),
+    };
+    let player_json = serde_json::to_vec(&player).expect("Failed to serialize session player");
     state
         .nats
         .kv_c2s()
         .put(
-            client_id_str.as_str(),
-            session_get.session_id.clone().into(),
+            client_session_key(&deployment.request_id, &client_id_str),
+            player_json.into(),
         )
         .await
         .map_err(|e| {
@@ -336,7 +328,10 @@ async fn session_responder(
     state
         .nats
         .kv_s2c()
-        .put(session_get.session_id.as_str(), client_id_str.into())
+        .put(
+            session_player_key(&session_get.session_id, &client_id_str),
+            client_id_str.into(),
+        )
         .await
         .map_err(|e| {
             EdgegapError::Io(std::io::Error::new(
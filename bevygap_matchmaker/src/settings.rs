@@ -0,0 +1,194 @@
+use crate::placement::PlacementSettings;
+use bevygap_shared::config::*;
+use bevygap_shared::nats::NatsSettings;
+use bevygap_shared::supervisor::SupervisorSettings;
+use clap::Parser;
+use lightyear::netcode::PRIVATE_KEY_BYTES;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Prefix for env var overrides, eg `BEVYGAP_MATCHMAKER_APP_NAME=mygame`.
+const ENV_PREFIX: &str = "BEVYGAP_MATCHMAKER";
+
+/// Command line args. Anything given here overrides the config file and env vars.
+#[derive(Parser, Serialize, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    #[serde(skip)]
+    config: ConfigArgs,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_name: Option<String>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_version: Option<String>,
+    /// private key, in format 1,2,3,4..  which should be 32 u8s long (for signing lightyear tokens)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lightyear_private_key: Option<String>,
+    /// The lightyear protocol id (u64)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lightyear_protocol_id: Option<u64>,
+    /// The webhook url for edgegap session creation events
+    /// (should write to nats for you, see bevygap_webhook_sink)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_webhook_url: Option<String>,
+    /// Optional maximum player limit for the lobby/session (1-4)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    player_limit: Option<u8>,
+}
+
+/// Effective matchmaker config, see `ConfigLoader` for how the layers are combined.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    pub(crate) app_name: String,
+    pub(crate) app_version: String,
+    /// private key, in format 1,2,3,4..  which should be 32 u8s long (for signing lightyear tokens)
+    pub(crate) lightyear_private_key: String,
+    /// The lightyear protocol id (u64)
+    pub(crate) lightyear_protocol_id: u64,
+    /// The webhook url for edgegap session creation events
+    pub(crate) session_webhook_url: Option<String>,
+    /// Optional maximum player limit for the lobby/session (1-4)
+    pub(crate) player_limit: Option<u8>,
+    /// Edgegap API key, including the "token " prefix. Also read from EDGEGAP_API_KEY.
+    pub(crate) edgegap_api_key: String,
+    /// Read the Edgegap API key from this file instead, eg a docker secret.
+    /// Also read from EDGEGAP_API_KEY_FILE.
+    pub(crate) edgegap_api_key_file: Option<PathBuf>,
+    pub(crate) edgegap_base_path: String,
+    /// Retries for transient Edgegap API failures (429, and 5xx on idempotent calls). 0 disables
+    /// retries, the per-request timeouts and the circuit breaker.
+    pub(crate) edgegap_max_retries: u32,
+    /// Timeout for Edgegap API calls
+    pub(crate) edgegap_timeout_secs: u64,
+    /// Timeout for the session create and poll calls, which are on the player's critical path
+    pub(crate) edgegap_session_timeout_secs: u64,
+    /// Give up waiting for a session to become ready after this long
+    pub(crate) max_session_creation_seconds: u64,
+    /// How often to poll the Edgegap API while waiting for a session to become ready
+    pub(crate) session_poll_interval_ms: u64,
+    /// Give up waiting for a lobby room's deployment to be running after this long
+    pub(crate) lobby_deploy_timeout_secs: u64,
+    /// How often to poll the Edgegap API while waiting for a lobby room's deployment
+    pub(crate) lobby_deploy_poll_interval_ms: u64,
+    /// How often to check for unclaimed sessions that need deleting
+    pub(crate) unclaimed_reaper_interval_ms: u64,
+    /// How often the delete worker fetches from the session delete queue
+    pub(crate) delete_worker_interval_ms: u64,
+    /// How long a player whose connection dropped can reconnect to their session for, before
+    /// they're removed from it, or it's deleted if they were the last player
+    pub(crate) reconnect_grace_secs: u64,
+    /// The ip:port to serve /healthz and /readyz on
+    pub(crate) health_bind: String,
+    /// How long to wait before re-checking the Edgegap application if it isn't usable
+    pub(crate) verify_application_retry_secs: u64,
+    /// Which placement filters clients may request, and the defaults for every session
+    pub(crate) placement: PlacementSettings,
+    /// Restart backoff and limits for the background tasks
+    pub(crate) supervisor: SupervisorSettings,
+    pub(crate) nats: NatsSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            app_name: "spacepit_server".to_string(),
+            app_version: "v0.0.1".to_string(),
+            lightyear_private_key: String::new(),
+            lightyear_protocol_id: 1982,
+            session_webhook_url: None,
+            player_limit: None,
+            edgegap_api_key: String::new(),
+            edgegap_api_key_file: None,
+            edgegap_base_path: "https://api.edgegap.com/".to_string(),
+            edgegap_max_retries: 3,
+            edgegap_timeout_secs: 30,
+            edgegap_session_timeout_secs: 10,
+            max_session_creation_seconds: crate::MAX_SESSION_CREATION_SECONDS,
+            session_poll_interval_ms: 200,
+            lobby_deploy_timeout_secs: 120,
+            lobby_deploy_poll_interval_ms: 1000,
+            unclaimed_reaper_interval_ms: 5000,
+            delete_worker_interval_ms: 5000,
+            reconnect_grace_secs: 30,
+            health_bind: "0.0.0.0:3002".to_string(),
+            verify_application_retry_secs: 30,
+            placement: PlacementSettings::default(),
+            supervisor: SupervisorSettings::default(),
+            nats: NatsSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Parses the command line, then loads the layered config.
+    pub fn load() -> Self {
+        let cli = Cli::parse();
+        let loader = ConfigLoader::new(ENV_PREFIX)
+            .env_alias("EDGEGAP_API_KEY", "edgegap_api_key")
+            .env_alias("EDGEGAP_API_KEY_FILE", "edgegap_api_key_file")
+            .with_nats_env_aliases();
+        load_or_exit(loader, &cli.config, &cli, |settings: &Self| {
+            settings.nats.validate()
+        })
+    }
+
+    pub(crate) fn parse_private_key(&self) -> [u8; PRIVATE_KEY_BYTES] {
+        if self.lightyear_private_key.is_empty() {
+            return [0u8; PRIVATE_KEY_BYTES];
+        }
+        let private_key: Vec<u8> = self
+            .lightyear_private_key
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == ',')
+            .collect::<String>()
+            .split(',')
+            .map(|s| {
+                s.parse::<u8>()
+                    .expect("Failed to parse number in private key")
+            })
+            .collect();
+
+        if private_key.len() != PRIVATE_KEY_BYTES {
+            panic!(
+                "Private key must contain exactly {} numbers",
+                PRIVATE_KEY_BYTES
+            );
+        }
+
+        let mut bytes = [0u8; PRIVATE_KEY_BYTES];
+        bytes.copy_from_slice(&private_key);
+        bytes
+    }
+
+    pub fn protocol_id(&self) -> u64 {
+        self.lightyear_protocol_id
+    }
+
+    pub(crate) fn session_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.session_poll_interval_ms)
+    }
+
+    pub(crate) fn max_session_creation_time(&self) -> Duration {
+        Duration::from_secs(self.max_session_creation_seconds)
+    }
+
+    pub(crate) fn reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.reconnect_grace_secs)
+    }
+
+    pub(crate) fn lobby_deploy_timeout(&self) -> Duration {
+        Duration::from_secs(self.lobby_deploy_timeout_secs)
+    }
+
+    pub(crate) fn lobby_deploy_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.lobby_deploy_poll_interval_ms)
+    }
+}
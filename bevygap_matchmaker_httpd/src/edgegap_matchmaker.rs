@@ -0,0 +1,403 @@
+//! Matchmaking with an Edgegap-managed matchmaker, instead of bevygap_matchmaker creating a
+//! session per request.
+//!
+//! Each session request becomes a ticket for the configured matchmaker release. Once Edgegap
+//! assigns the ticket a deployment, we ask bevygap_matchmaker for a session on it, as lobby rooms
+//! do. So players still get a lightyear connect token and the gameserver's cert digest, and the
+//! client plugin sees the same `SessionRequestFeedback` stream either way.
+use bevygap_shared::protocol::SessionRequestFeedback;
+use log::*;
+use reqwest::Method;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt as _;
+
+use crate::settings::Settings;
+use crate::AppState;
+
+/// An Edgegap matchmaker release, see the module docs.
+pub(crate) struct EdgegapMatchmaker {
+    http: reqwest::Client,
+    /// The release's own API
+    url: String,
+    token: String,
+    profile: String,
+    /// The app and version the release deploys, which session requests are sent for
+    app_name: String,
+    app_version: String,
+    poll_interval: Duration,
+}
+
+/// A matchmaking ticket. Only the fields we use.
+#[derive(Debug, Deserialize)]
+struct Ticket {
+    id: String,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    assignment: Option<TicketAssignment>,
+}
+
+/// The deployment a ticket was matched to.
+#[derive(Debug, Deserialize)]
+struct TicketAssignment {
+    #[serde(default)]
+    request_id: Option<String>,
+    /// The deployment's FQDN, which starts with its request id
+    #[serde(default)]
+    fqdn: Option<String>,
+}
+
+impl TicketAssignment {
+    fn request_id(&self) -> Option<String> {
+        self.request_id.clone().or_else(|| {
+            let fqdn = self.fqdn.as_deref()?;
+            fqdn.split('.').next().map(str::to_string)
+        })
+    }
+}
+
+/// Why a request failed, sent to the client as `SessionRequestFeedback::Error`.
+type Failure = (u16, String);
+
+impl EdgegapMatchmaker {
+    /// Checks the configured release exists, and finds out what it deploys.
+    pub(crate) async fn connect(settings: &Settings) -> anyhow::Result<Self> {
+        if settings.edgegap_matchmaker_url.is_empty() {
+            anyhow::bail!("edgegap_matchmaker_url is needed to use an Edgegap matchmaker");
+        }
+        let Some(token) = settings
+            .edgegap_matchmaker_token
+            .clone()
+            .filter(|t| !t.is_empty())
+        else {
+            anyhow::bail!("edgegap_matchmaker_token is needed to use an Edgegap matchmaker");
+        };
+        let edgegap = settings.edgegap_client()?;
+        let name = &settings.edgegap_matchmaker_name;
+        let release = edgegap
+            .matchmaker_release(name, &settings.edgegap_matchmaker_release)
+            .await?;
+        info!(
+            "Using Edgegap matchmaker {name} release {}, which deploys {} {}",
+            release.version, release.app_name, release.version_name
+        );
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url: settings
+                .edgegap_matchmaker_url
+                .trim_end_matches('/')
+                .to_string(),
+            token,
+            profile: settings.edgegap_matchmaker_profile.clone(),
+            app_name: release.app_name,
+            app_version: release.version_name,
+            poll_interval: settings.edgegap_matchmaker_poll_interval(),
+        })
+    }
+
+    /// Calls the matchmaker's API, failing unless the response is a success.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<reqwest::Response, Failure> {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.url))
+            .header(reqwest::header::AUTHORIZATION, &self.token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| (502, format!("Edgegap matchmaker error: {e}")))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+        let text = resp.text().await.unwrap_or_default();
+        Err((502, format!("Edgegap matchmaker replied {status}: {text}")))
+    }
+
+    async fn ticket(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Ticket, Failure> {
+        let resp = self.send(method, path, body).await?;
+        resp.json()
+            .await
+            .map_err(|e| (502, format!("Invalid Edgegap matchmaker ticket: {e}")))
+    }
+
+    /// Polls the ticket until it's assigned a deployment, reporting its status as it changes.
+    async fn wait_for_assignment(
+        &self,
+        mut ticket: Ticket,
+        feedback: &mpsc::Sender<SessionRequestFeedback>,
+    ) -> Result<String, Failure> {
+        let mut reported = String::new();
+        loop {
+            if let Some(request_id) = ticket.assignment.as_ref().and_then(|a| a.request_id()) {
+                return Ok(request_id);
+            }
+            if ticket.status.eq_ignore_ascii_case("cancelled") {
+                return Err((410, "Matchmaking ticket was cancelled".to_string()));
+            }
+            if ticket.status != reported {
+                reported = ticket.status.clone();
+                let report =
+                    SessionRequestFeedback::ProgressReport(format!("Matchmaking: {reported}"));
+                if feedback.send(report).await.is_err() {
+                    return Err((499, "Client went away".to_string()));
+                }
+            }
+            tokio::time::sleep(self.poll_interval).await;
+            ticket = self
+                .ticket(Method::GET, &format!("/tickets/{}", ticket.id), None)
+                .await?;
+        }
+    }
+
+    /// Finds the player a gameserver, then gets them a connect token for it.
+    async fn match_session(
+        &self,
+        state: &AppState,
+        client_ip: &str,
+        feedback: &mpsc::Sender<SessionRequestFeedback>,
+    ) -> Result<(), Failure> {
+        let timeout = state.settings.session_request_timeout();
+        let request_id = self.find_deployment(client_ip, timeout, feedback).await?;
+        self.request_token(state, client_ip, &request_id, feedback)
+            .await
+    }
+
+    /// Creates a ticket for the player, and waits for it to be assigned a deployment, returning
+    /// its request id. The ticket is cancelled if that doesn't happen within `timeout`.
+    async fn find_deployment(
+        &self,
+        client_ip: &str,
+        timeout: Duration,
+        feedback: &mpsc::Sender<SessionRequestFeedback>,
+    ) -> Result<String, Failure> {
+        let body = serde_json::json!({
+            "profile": self.profile,
+            "player_ip": client_ip,
+            "attributes": {},
+        });
+        let ticket = self.ticket(Method::POST, "/tickets", Some(body)).await?;
+        let ticket_id = ticket.id.clone();
+        info!("Created Edgegap matchmaker ticket {ticket_id} for {client_ip}");
+
+        let assigned = tokio::time::timeout(timeout, self.wait_for_assignment(ticket, feedback))
+            .await
+            .unwrap_or_else(|_| Err((504, "No match found in time".to_string())));
+        let request_id = match assigned {
+            Ok(request_id) => request_id,
+            Err(failure) => {
+                info!(
+                    "Cancelling Edgegap matchmaker ticket {ticket_id}: {}",
+                    failure.1
+                );
+                if let Err((_, msg)) = self
+                    .send(Method::DELETE, &format!("/tickets/{ticket_id}"), None)
+                    .await
+                {
+                    warn!("Couldn't cancel Edgegap matchmaker ticket {ticket_id}: {msg}");
+                }
+                return Err(failure);
+            }
+        };
+        info!("Edgegap matchmaker ticket {ticket_id} assigned to deployment {request_id}");
+        Ok(request_id)
+    }
+
+    /// The NATS subject and payload asking bevygap_matchmaker for a session on `request_id`.
+    fn token_request(&self, client_ip: &str, request_id: &str) -> (String, serde_json::Value) {
+        let subject = format!("matchmaker.request.{}.{}", self.app_name, self.app_version);
+        let payload = serde_json::json!({
+            "client_ip": client_ip,
+            "game": self.app_name,
+            "version": self.app_version,
+            "deployment_request_id": request_id,
+        });
+        (subject, payload)
+    }
+
+    /// Asks bevygap_matchmaker for a session on the assigned deployment, passing its feedback on.
+    async fn request_token(
+        &self,
+        state: &AppState,
+        client_ip: &str,
+        request_id: &str,
+        feedback: &mpsc::Sender<SessionRequestFeedback>,
+    ) -> Result<(), Failure> {
+        let (subject, payload) = self.token_request(client_ip, request_id);
+        let client = state.bgnats.client();
+        let reply_inbox = client.new_inbox();
+        let mut response_subscriber = client
+            .subscribe(reply_inbox.clone())
+            .await
+            .map_err(|e| (500, format!("NATS error: {e}")))?;
+        client
+            .publish_with_reply(subject, reply_inbox, payload.to_string().into())
+            .await
+            .map_err(|e| (500, format!("Failed to send token request: {e}")))?;
+
+        let timeout = state.settings.session_request_timeout();
+        while let Ok(Some(msg)) = tokio::time::timeout(timeout, response_subscriber.next()).await {
+            if msg.payload.is_empty() {
+                return Ok(());
+            }
+            match serde_json::from_slice::<SessionRequestFeedback>(&msg.payload) {
+                // we already acknowledged the request, before matchmaking
+                Ok(SessionRequestFeedback::Acknowledged) => {}
+                Ok(msg) => {
+                    if feedback.send(msg).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => warn!("Unexpected token request response: {e}"),
+            }
+        }
+        Err((504, "No connect token from matchmaker".to_string()))
+    }
+}
+
+/// Streams feedback for a session request, as bevygap_matchmaker would over NATS. Must only be
+/// called when `AppState::edgegap_matchmaker` is set.
+pub(crate) fn request_session(
+    state: Arc<AppState>,
+    client_ip: String,
+) -> mpsc::Receiver<SessionRequestFeedback> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let matchmaker = state
+            .edgegap_matchmaker
+            .as_ref()
+            .expect("Edgegap matchmaker not configured");
+        if tx.send(SessionRequestFeedback::Acknowledged).await.is_err() {
+            return;
+        }
+        if let Err((code, msg)) = matchmaker.match_session(&state, &client_ip, &tx).await {
+            warn!("Edgegap matchmaking failed for {client_ip}: {code} {msg}");
+            let _ = tx.send(SessionRequestFeedback::Error(code, msg)).await;
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edgegap_async::fixtures::{Cassette, FixtureServer};
+
+    fn settings(server: &FixtureServer) -> Settings {
+        Settings {
+            edgegap_api_key: "token fixture".to_string(),
+            edgegap_base_path: server.base_path(),
+            edgegap_matchmaker_name: "mygame-mm".to_string(),
+            edgegap_matchmaker_release: "r1".to_string(),
+            edgegap_matchmaker_url: format!("{}/mm/", server.base_path()),
+            edgegap_matchmaker_token: Some("mm-token".to_string()),
+            edgegap_matchmaker_poll_interval_ms: 1,
+            ..Default::default()
+        }
+    }
+
+    fn interaction(method: &str, path: &str, body: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "request": { "method": method, "path": path },
+            "response": { "status": 200, "body": body },
+        })
+    }
+
+    async fn fixture_server() -> FixtureServer {
+        let cassette: Cassette = serde_json::from_value(serde_json::json!({
+            "interactions": [
+                interaction("GET", "/v1/aom/matchmaker/mygame-mm/release/managed/r1", serde_json::json!({
+                    "created_at": "2024-11-05 09:12:44.120000",
+                    "updated_at": "2024-11-05 09:12:44.120000",
+                    "app_name": "mygame",
+                    "version_name": "v1",
+                    "version": "r1",
+                    "release_config_name": "casual",
+                })),
+                interaction("POST", "/mm/tickets", serde_json::json!({
+                    "id": "t1",
+                    "status": "SEARCHING",
+                })),
+                interaction("GET", "/mm/tickets/t1", serde_json::json!({
+                    "id": "t1",
+                    "status": "TEAM_FOUND",
+                })),
+                interaction("GET", "/mm/tickets/t1", serde_json::json!({
+                    "id": "t1",
+                    "status": "HOST_ASSIGNED",
+                    "assignment": { "fqdn": "93924761ccde.pr.edgegap.net" },
+                })),
+            ]
+        }))
+        .unwrap();
+        FixtureServer::replay(cassette).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ticket_assignment_and_token_request() {
+        let server = fixture_server().await;
+        let matchmaker = EdgegapMatchmaker::connect(&settings(&server))
+            .await
+            .unwrap();
+        assert_eq!(matchmaker.token, "mm-token");
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let request_id = matchmaker
+            .find_deployment("1.2.3.4", Duration::from_secs(5), &tx)
+            .await
+            .unwrap();
+        assert_eq!(request_id, "93924761ccde");
+        drop(tx);
+        let mut reports = Vec::new();
+        while let Some(SessionRequestFeedback::ProgressReport(report)) = rx.recv().await {
+            reports.push(report);
+        }
+        assert_eq!(
+            reports,
+            ["Matchmaking: SEARCHING", "Matchmaking: TEAM_FOUND"]
+        );
+
+        let (subject, payload) = matchmaker.token_request("1.2.3.4", &request_id);
+        assert_eq!(subject, "matchmaker.request.mygame.v1");
+        assert_eq!(payload["client_ip"], "1.2.3.4");
+        assert_eq!(payload["deployment_request_id"], "93924761ccde");
+
+        let ticket = server
+            .received()
+            .into_iter()
+            .find(|r| r.path == "/mm/tickets")
+            .unwrap();
+        assert_eq!(ticket.body.unwrap()["player_ip"], "1.2.3.4");
+        assert!(server.misses().is_empty(), "{:?}", server.misses());
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_matchmaker_token_required() {
+        let server = fixture_server().await;
+        let settings = Settings {
+            edgegap_matchmaker_token: None,
+            ..settings(&server)
+        };
+        let err = EdgegapMatchmaker::connect(&settings).await.err().unwrap();
+        assert!(err.to_string().contains("edgegap_matchmaker_token"));
+        // failed before anything, including the api key, was sent anywhere
+        assert!(server.received().is_empty());
+        server.finish().await.unwrap();
+    }
+}
@@ -1,264 +1,413 @@
-use axum::{extract::{State, Path}, Json};
-use axum::http::StatusCode;
+use axum::{extract::{ConnectInfo, Path, Query, State}, Json};
+use axum::http::{header, HeaderMap, StatusCode};
+use bevygap_shared::lobby::{LobbyError, LobbyRecord, LobbyRoom, RoomStatus, SessionInfo};
+use bevygap_shared::protocol::SessionRequestFeedback;
+use tokio_stream::StreamExt as _;
 use serde::{Serialize, Deserialize};
-use std::{collections::HashMap, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH, Duration}};
+use std::{net::SocketAddr, sync::{atomic::Ordering, Arc}};
 use log::*;
-use async_nats::client::RequestErrorKind;
 
+use crate::lobby_ws::{publish_room_event, RoomEvent};
+use crate::session_request_handler::{get_client_ip, QsParams};
 use crate::AppState;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct LobbyRoom {
-    pub id: String,
-    pub host_name: String,
-    pub game_mode: String,
-    pub created_at: u64,
-    pub started: bool,
-    pub current_players: u32,
-    pub max_players: u32,
-    /// Session information when game server is deployed
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub session_info: Option<SessionInfo>,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct SessionInfo {
-    pub session_id: Option<String>,
-    pub game_server_ip: Option<String>,
-    pub game_server_port: Option<u16>,
-    pub connect_token: Option<String>,
-    pub deployment_status: String,
-}
-
 #[derive(Clone, Debug, Deserialize)]
 pub struct CreateRoomRequest {
     pub host_name: String,
     pub game_mode: String,
     #[serde(default)]
     pub max_players: Option<u32>,
+    /// Players must give this, or the invite code, to join
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Hide the room from the rooms list, so players need the invite code to join
+    #[serde(default)]
+    pub invite_only: bool,
 }
 
-#[derive(Default)]
-pub struct LobbyStore {
-    pub rooms: Mutex<HashMap<String, LobbyRoom>>, // id -> room
-    pub max_rooms: usize,
+/// The reply to creating or joining a room: the room's fields, as these replied with before
+/// rooms had members, plus the new member's details. Members send the session token as
+/// `Authorization: Bearer <token>` to leave, ready up, and get their connect token, and the
+/// host also to start the room and kick players. Any member can share the invite code.
+#[derive(Clone, Debug, Serialize)]
+pub struct JoinedRoom {
+    #[serde(flatten)]
+    pub room: LobbyRoom,
+    pub player_name: String,
+    pub session_token: String,
+    pub invite_code: String,
 }
 
-impl LobbyStore {
-    pub fn new(max_rooms: usize) -> Self { Self { rooms: Mutex::new(HashMap::new()), max_rooms } }
+impl JoinedRoom {
+    fn new(record: LobbyRecord, player_name: String) -> Self {
+        let session_token = record.session_token(&player_name).unwrap_or_default().to_string();
+        Self { room: record.room, player_name, session_token, invite_code: record.invite_code }
+    }
 }
 
+/// Bumped when lobby clients need to change. Version 2 needs the session token from creating or
+/// joining a room to leave, start, kick, ready up and get a connect token.
+pub const LOBBY_API_VERSION: u32 = 2;
+
 #[derive(Clone, Debug, Serialize)]
 pub struct LobbyStatus {
+    pub api_version: u32,
     pub max_rooms: usize,
     pub active_rooms: usize,
     pub total_rooms: usize,
+    /// Waiting rooms removed for being idle, by this instance since it started
+    pub evicted_idle_rooms: u64,
+    /// Started rooms removed once their game was over, by this instance since it started
+    pub evicted_finished_rooms: u64,
 }
 
-fn now_secs() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() }
+fn lobby_error(e: LobbyError) -> (StatusCode, String) {
+    if let LobbyError::Store(msg) = &e { error!("Lobby store error: {}", msg); }
+    (StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR), e.to_string())
+}
 
-pub async fn list_rooms(State(state): State<Arc<AppState>>) -> Json<Vec<LobbyRoom>> {
-    let rooms = state.lobby.rooms.lock().unwrap();
-    let mut v: Vec<LobbyRoom> = rooms.values().filter(|r| !r.started).cloned().collect();
-    v.sort_by_key(|r| r.created_at);
-    Json(v)
+/// The session token from the `Authorization: Bearer` header.
+fn session_token(headers: &HeaderMap) -> Result<String, (StatusCode, String)> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| lobby_error(LobbyError::Unauthorized))
 }
 
-pub async fn create_room(State(state): State<Arc<AppState>>, Json(req): Json<CreateRoomRequest>) -> Result<Json<LobbyRoom>, (axum::http::StatusCode, String)> {
-    let mut rooms = state.lobby.rooms.lock().unwrap();
-    let max = state.lobby.max_rooms;
-    let active_count = rooms.values().filter(|r| !r.started).count();
-    if active_count >= max { 
-        return Err((axum::http::StatusCode::TOO_MANY_REQUESTS, format!("maximum active rooms reached ({})", max)));
-    }
+pub async fn list_rooms(State(state): State<Arc<AppState>>) -> Result<Json<Vec<LobbyRoom>>, (StatusCode, String)> {
+    let rooms = state.lobby.rooms().await.map_err(lobby_error)?;
+    Ok(Json(rooms.into_iter().map(|r| r.room).filter(|r| !r.started && !r.invite_only).collect()))
+}
 
-    let id = format!("ROOM{:03}", (rooms.len() as u32 + 1));
-    let room = LobbyRoom { 
-        id: id.clone(),
-        host_name: req.host_name,
-        game_mode: req.game_mode,
-        created_at: now_secs(),
-        started: false,
-        current_players: 1,
-        max_players: req.max_players.unwrap_or(4).min(16),
-        session_info: None,
+pub async fn create_room(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<QsParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<CreateRoomRequest>,
+) -> Result<Json<JoinedRoom>, (axum::http::StatusCode, String)> {
+    let client_ip = get_client_ip(&params, &addr, &headers, &state);
+    if req.host_name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "host_name required".to_string()));
+    }
+    let max_players = req.max_players.unwrap_or(4).clamp(1, 16);
+    let record = state.lobby.create(|id| {
+        LobbyRecord::new(id, &req.host_name, &req.game_mode, max_players, &client_ip)
+            .with_password(req.password.clone())
+            .with_invite_only(req.invite_only)
+    }).await.map_err(lobby_error)?;
+    info!("Created lobby room {}", record.room.id);
+    let record = match state.lobby_backend.room_created(&record).await {
+        Ok(Some(external_id)) => {
+            let id = record.room.id.clone();
+            state.lobby.update(&id, |record| {
+                record.external_id = Some(external_id.clone());
+                Ok(())
+            }).await.map_err(lobby_error)?
+        }
+        Ok(None) => record,
+        Err(e) => {
+            warn!("Lobby backend couldn't create room {}: {}", record.room.id, e);
+            record
+        }
     };
-    rooms.insert(id.clone(), room.clone());
-    info!("Created lobby room {}", id);
-    Ok(Json(room))
+    Ok(Json(JoinedRoom::new(record, req.host_name)))
 }
 
-pub async fn lobby_status(State(state): State<Arc<AppState>>) -> Json<LobbyStatus> {
-    let rooms = state.lobby.rooms.lock().unwrap();
+pub async fn lobby_status(State(state): State<Arc<AppState>>) -> Result<Json<LobbyStatus>, (StatusCode, String)> {
+    let rooms = state.lobby.rooms().await.map_err(lobby_error)?;
     let total = rooms.len();
-    let active = rooms.values().filter(|r| !r.started).count();
-    Json(LobbyStatus { max_rooms: state.lobby.max_rooms, active_rooms: active, total_rooms: total })
+    let active = rooms.iter().filter(|r| !r.room.started).count();
+    Ok(Json(LobbyStatus {
+        api_version: LOBBY_API_VERSION,
+        max_rooms: state.lobby.max_rooms(),
+        active_rooms: active,
+        total_rooms: total,
+        evicted_idle_rooms: state.lobby_evictions.idle.load(Ordering::Relaxed),
+        evicted_finished_rooms: state.lobby_evictions.finished.load(Ordering::Relaxed),
+    }))
+}
+
+/// Starts deploying the room's gameserver, replying straight away with the room in the
+/// Deploying state. Players then poll `/lobby/api/rooms/:id/token` for their connect token.
+/// Only the host may start the room, once everyone else is ready.
+pub async fn start_room(State(state): State<Arc<AppState>>, Path(id): Path<String>, headers: HeaderMap) -> Result<Json<LobbyRoom>, (StatusCode, String)> {
+    let token = session_token(&headers)?;
+    let record = state.lobby.update(&id, |record| {
+        let player_name = record.authorize(&token)?;
+        record.check_start(player_name)?;
+        let room = &mut record.room;
+        if !matches!(room.status, RoomStatus::Waiting | RoomStatus::Failed) {
+            return Err(LobbyError::Conflict("room already started".to_string()));
+        }
+        room.started = true;
+        room.status = RoomStatus::Deploying;
+        room.session_info = Some(SessionInfo::status("Deploying"));
+        Ok(())
+    }).await.map_err(lobby_error)?;
+
+    info!("Starting room {} - deploying game server with the {} backend", id, state.lobby_backend.name());
+    tokio::spawn(deploy_room(state.clone(), record.clone()));
+    Ok(Json(record.room))
 }
 
-pub async fn start_room(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Result<Json<LobbyRoom>, (axum::http::StatusCode, String)> {
-    // First, check if room exists and is not already started
-    {
-        let rooms = state.lobby.rooms.lock().unwrap();
-        if let Some(room) = rooms.get(&id) {
-            if room.started {
-                return Err((StatusCode::CONFLICT, "room already started".to_string()));
+/// Asks the lobby backend for the room's deployment, and records the outcome on the room.
+async fn deploy_room(state: Arc<AppState>, record: LobbyRecord) {
+    let id = record.room.id.clone();
+    let result = state.lobby_backend.deploy_room(&record).await;
+
+    match &result {
+        Ok(deployment) => info!("Game server deployment successful for room {}: {:?}", id, deployment),
+        Err(e) => error!("Game server deployment failed for room {}: {}", id, e),
+    }
+    let updated = state.lobby.update(&id, |record| {
+        match &result {
+            Ok(deployment) => {
+                record.room.status = RoomStatus::Ready;
+                record.room.session_info = Some(SessionInfo {
+                    request_id: Some(deployment.request_id.clone()),
+                    game_server_ip: Some(deployment.ip.clone()),
+                    game_server_port: Some(deployment.port),
+                    deployment_status: "Ready".to_string(),
+                });
+                record.deployment = Some(deployment.clone());
+            }
+            Err(e) => {
+                record.room.started = false;
+                record.room.status = RoomStatus::Failed;
+                record.room.session_info = Some(SessionInfo::status(format!("Failed: {}", e)));
             }
-        } else {
-            return Err((StatusCode::NOT_FOUND, "room not found".to_string()));
         }
+        Ok(())
+    }).await;
+    if let Err(e) = updated {
+        warn!("Couldn't record deployment outcome for room {}: {}", id, e);
     }
+}
+
+/// Gets the calling player their own connect token for the room's gameserver.
+/// Until the room is Ready this is a 409, so clients can poll it after the room starts.
+pub async fn room_token(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<QsParams>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<SessionRequestFeedback>, (StatusCode, String)> {
+    let token = session_token(&headers)?;
+    let client_ip = get_client_ip(&params, &addr, &headers, &state);
+    request_room_token(&state, &id, &token, &client_ip).await.map(Json)
+}
 
-    info!("Starting room {} - deploying game server", id);
+/// Asks the matchmaker for a connect token to the room's gameserver, for the member with this
+/// session token, at `client_ip`. Also used by the lobby websocket, to push tokens to members
+/// once the room is ready.
+pub(crate) async fn request_room_token(state: &AppState, id: &str, session_token: &str, client_ip: &str) -> Result<SessionRequestFeedback, (StatusCode, String)> {
+    let record = state.lobby.get(id).await.map_err(lobby_error)?;
+    let player_name = record.authorize(session_token).map_err(lobby_error)?.to_string();
+    let deployment = match (record.deployment, record.room.status) {
+        (Some(deployment), RoomStatus::Ready | RoomStatus::InGame) => deployment,
+        (_, status) => return Err((StatusCode::CONFLICT, format!("room is {:?}", status))),
+    };
+    info!("Player {} ({}) wants a token for room {}", player_name, client_ip, id);
 
-    // Use a fake client IP for room-based deployments
-    // In a real implementation, this could be the host's IP or a configured deployment region
-    let client_ip = state.settings.fake_ip.to_string();
-    
-    // Create payload for session creation - include room info
-    let payload = format!("{{\"client_ip\":\"{}\", \"room_id\":\"{}\", \"game\":\"lobby-room\"}}", client_ip, id);
-    
-    // Send session creation request via NATS
-    let request = async_nats::client::Request::new()
-        .timeout(Some(Duration::from_secs(60)))
-        .payload(payload.into());
+    // a normal session request, linked to the room's deployment
+    let subject = format!("matchmaker.request.{}.{}", deployment.app_name, deployment.app_version);
+    let payload = serde_json::json!({
+        "client_ip": client_ip,
+        "game": deployment.app_name,
+        "version": deployment.app_version,
+        "deployment_request_id": deployment.request_id,
+    });
 
-    let session_result = state
-        .bgnats
-        .client()
-        .send_request("session.gensession", request)
-        .await;
+    let client = state.bgnats.client();
+    let reply_inbox = client.new_inbox();
+    let mut response_subscriber = client
+        .subscribe(reply_inbox.clone())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("NATS error: {}", e)))?;
+    client
+        .publish_with_reply(subject, reply_inbox, payload.to_string().into())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to send token request: {}", e)))?;
 
-    // Update room with deployment status
-    let mut rooms = state.lobby.rooms.lock().unwrap();
-    if let Some(room) = rooms.get_mut(&id) {
-        match session_result {
-            Ok(resp) => {
-                // Check if there was an error in the response
-                if let Some((code, msg)) = maybe_message_error(&resp) {
-                    error!("Game server deployment failed for room {}: {} - {}", id, code, msg);
-                    room.session_info = Some(SessionInfo {
-                        session_id: None,
-                        game_server_ip: None,
-                        game_server_port: None,
-                        connect_token: None,
-                        deployment_status: format!("Failed: {}", msg),
-                    });
-                    return Err((
-                        StatusCode::from_u16(code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
-                        format!("Failed to deploy game server: {}", msg),
-                    ));
-                } else {
-                    info!("Game server deployment successful for room {}", id);
-                    
-                    // Parse the session response to extract connection details
-                    let session_response = String::from_utf8_lossy(&resp.payload);
-                    let session_info = if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&session_response) {
-                        SessionInfo {
-                            session_id: parsed.get("session_id").and_then(|v| v.as_str()).map(|s| s.to_string()),
-                            game_server_ip: parsed.get("gameserver_ip").and_then(|v| v.as_str()).map(|s| s.to_string()),
-                            game_server_port: parsed.get("gameserver_port").and_then(|v| v.as_u64()).map(|n| n as u16),
-                            connect_token: parsed.get("connect_token").and_then(|v| v.as_str()).map(|s| s.to_string()),
-                            deployment_status: "Ready".to_string(),
-                        }
-                    } else {
-                        SessionInfo {
-                            session_id: None,
-                            game_server_ip: None,
-                            game_server_port: None,
-                            connect_token: None,
-                            deployment_status: "Ready (details pending)".to_string(),
-                        }
-                    };
-                    
-                    room.started = true;
-                    room.session_info = Some(session_info);
-                    info!("Room {} marked as started with deployed game server", id);
+    // the matchmaker streams progress reports, then the token or an error
+    let timeout = state.settings.session_request_timeout();
+    let mut session_id = None;
+    while let Ok(Some(msg)) = tokio::time::timeout(timeout, response_subscriber.next()).await {
+        if msg.payload.is_empty() {
+            break;
+        }
+        match serde_json::from_slice::<SessionRequestFeedback>(&msg.payload) {
+            Ok(SessionRequestFeedback::SessionRequestAccepted(accepted)) => session_id = Some(accepted),
+            Ok(ready @ SessionRequestFeedback::SessionReady { .. }) => {
+                // the janitor removes the room once all its sessions are deleted
+                if let Err(e) = state.lobby.update(id, |record| {
+                    record.room.status = RoomStatus::InGame;
+                    record.game_session_ids.extend(session_id.clone());
+                    Ok(())
+                }).await {
+                    warn!("Couldn't mark room {} as in game: {}", id, e);
                 }
+                return Ok(ready);
             }
-            Err(e) => {
-                error!("NATS error deploying game server for room {}: {:?}", id, e);
-                let error_msg = match e.kind() {
-                    RequestErrorKind::TimedOut => "Deployment request timeout",
-                    RequestErrorKind::NoResponders => "No deployment service available",
-                    RequestErrorKind::Other => "Deployment service error",
-                };
-                
-                room.session_info = Some(SessionInfo {
-                    session_id: None,
-                    game_server_ip: None,
-                    game_server_port: None,
-                    connect_token: None,
-                    deployment_status: format!("Failed: {}", error_msg),
-                });
-                
-                return Err((
-                    match e.kind() {
-                        RequestErrorKind::TimedOut => StatusCode::REQUEST_TIMEOUT,
-                        RequestErrorKind::NoResponders => StatusCode::SERVICE_UNAVAILABLE,
-                        RequestErrorKind::Other => StatusCode::INTERNAL_SERVER_ERROR,
-                    },
-                    error_msg.to_string(),
-                ));
+            Ok(SessionRequestFeedback::Error(code, msg)) => {
+                return Err((StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR), msg));
             }
+            Ok(feedback) => debug!("Room {} token request: {}", id, feedback),
+            Err(e) => warn!("Unexpected token request response: {}", e),
         }
-        
-        Ok(Json(room.clone()))
-    } else {
-        Err((StatusCode::NOT_FOUND, "room not found".to_string()))
     }
+    Err((StatusCode::GATEWAY_TIMEOUT, "No connect token from matchmaker".to_string()))
 }
 
-// Helper function to check for NATS service errors (copied from main.rs)
-fn maybe_message_error(message: &async_nats::Message) -> Option<(usize, String)> {
-    let h = message.headers.clone()?;
-    if let Some(code) = h.get(async_nats::service::NATS_SERVICE_ERROR_CODE) {
-        let msg_str = h
-            .get(async_nats::service::NATS_SERVICE_ERROR)
-            .unwrap()
-            .to_string();
-        Some((code.as_str().parse::<usize>().unwrap(), msg_str))
-    } else {
-        None
+#[derive(Clone, Debug, Deserialize)]
+pub struct JoinRoomRequest {
+    /// Must be unique within the room. Defaults to "Player N".
+    pub player_name: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub invite_code: Option<String>,
+}
+
+pub async fn join_room(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<QsParams>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<JoinRoomRequest>,
+) -> Result<Json<JoinedRoom>, (StatusCode, String)> {
+    let client_ip = get_client_ip(&params, &addr, &headers, &state);
+    let mut player_name = String::new();
+    let record = state.lobby.update(&id, |record| {
+        player_name = req.player_name.clone().unwrap_or_else(|| format!("Player {}", record.room.members.len() + 1));
+        record.add_member(&player_name, &client_ip, req.password.as_deref(), req.invite_code.as_deref())
+    }).await.map_err(lobby_error)?;
+    info!("{} joined room {}, current players {}", player_name, id, record.room.current_players);
+    if let Err(e) = state.lobby_backend.player_joined(&record, &player_name).await {
+        warn!("Lobby backend couldn't add {} to room {}: {}", player_name, id, e);
     }
+    publish_room_event(&state, &id, &RoomEvent::PlayerJoined { player_name: player_name.clone(), current_players: record.room.current_players }).await;
+    Ok(Json(JoinedRoom::new(record, player_name)))
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct JoinRoomRequest {
+/// The body older clients send when leaving. The session token says who's leaving now, so
+/// this is optional, but if `player_name` is given it has to be the token's player.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LeaveRoomRequest {
     pub player_name: Option<String>,
 }
 
-pub async fn join_room(State(state): State<Arc<AppState>>, Path(id): Path<String>, Json(_req): Json<JoinRoomRequest>) -> Result<Json<LobbyRoom>, (StatusCode, String)> {
-    let mut rooms = state.lobby.rooms.lock().unwrap();
-    if let Some(room) = rooms.get_mut(&id) {
-        if room.started {
-            return Err((StatusCode::CONFLICT, "room already started".to_string()));
+/// Leaves the room. If the host leaves, the longest-standing member becomes host, and the room
+/// is removed once it's empty, unless it has started.
+pub async fn leave_room(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    req: Option<Json<LeaveRoomRequest>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let token = session_token(&headers)?;
+    let Json(req) = req.unwrap_or_default();
+    let (mut player_name, mut was_host) = (String::new(), false);
+    let record = state.lobby.update(&id, |record| {
+        player_name = record.authorize(&token)?.to_string();
+        if req.player_name.as_ref().is_some_and(|name| *name != player_name) {
+            return Err(LobbyError::Forbidden("can't leave on behalf of another player".to_string()));
         }
-        if room.current_players >= room.max_players {
-            return Err((StatusCode::CONFLICT, "room full".to_string()));
+        was_host = record.room.host_name == player_name;
+        record.remove_member(&player_name)
+    }).await.map_err(lobby_error)?;
+    info!("{} left room {}, current players {}", player_name, id, record.room.current_players);
+    if let Err(e) = state.lobby_backend.player_left(&record, &player_name).await {
+        warn!("Lobby backend couldn't remove {} from room {}: {}", player_name, id, e);
+    }
+    publish_room_event(&state, &id, &RoomEvent::PlayerLeft { player_name: player_name.clone(), current_players: record.room.current_players }).await;
+    if was_host && record.room.current_players > 0 {
+        info!("{} is now the host of room {}", record.room.host_name, id);
+        publish_room_event(&state, &id, &RoomEvent::HostChanged { host_name: record.room.host_name.clone() }).await;
+    }
+    // only if nobody joined since
+    let empty = |record: &LobbyRecord| record.room.current_players == 0 && !record.room.started;
+    if empty(&record) && state.lobby.remove_if(&id, empty).await.map_err(lobby_error)? {
+        info!("Removed empty not-started room {}", id);
+        if let Err(e) = state.lobby_backend.room_removed(&record).await {
+            warn!("Lobby backend couldn't remove room {}: {}", id, e);
         }
-        room.current_players += 1;
-        info!("Player joined room {}, current players {}", id, room.current_players);
-        Ok(Json(room.clone()))
-    } else {
-        Err((StatusCode::NOT_FOUND, "room not found".to_string()))
     }
+    Ok(StatusCode::NO_CONTENT)
 }
+
 #[derive(Clone, Debug, Deserialize)]
-pub struct LeaveRoomRequest {
-    pub player_name: Option<String>,
+pub struct KickRequest {
+    pub player_name: String,
 }
 
-pub async fn leave_room(State(state): State<Arc<AppState>>, Path(id): Path<String>, Json(_req): Json<LeaveRoomRequest>) -> Result<StatusCode, (StatusCode, String)> {
-    let mut rooms = state.lobby.rooms.lock().unwrap();
-    if let Some(room) = rooms.get_mut(&id) {
-        if room.current_players > 0 { room.current_players -= 1; }
-        info!("Player left room {}, current players {}", id, room.current_players);
-        if room.current_players == 0 && !room.started {
-            rooms.remove(&id);
-            info!("Removed empty not-started room {}", id);
+/// Removes a player from the room. Only the host may kick.
+pub async fn kick_player(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<KickRequest>,
+) -> Result<Json<LobbyRoom>, (StatusCode, String)> {
+    let token = session_token(&headers)?;
+    let record = state.lobby.update(&id, |record| {
+        let host_name = record.authorize(&token)?.to_string();
+        record.kick(&host_name, &req.player_name)
+    }).await.map_err(lobby_error)?;
+    info!("{} was kicked from room {}", req.player_name, id);
+    if let Err(e) = state.lobby_backend.player_left(&record, &req.player_name).await {
+        warn!("Lobby backend couldn't remove {} from room {}: {}", req.player_name, id, e);
+    }
+    publish_room_event(&state, &id, &RoomEvent::PlayerKicked { player_name: req.player_name }).await;
+    Ok(Json(record.room))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReadyRequest {
+    pub ready: bool,
+}
+
+pub async fn set_ready(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<ReadyRequest>,
+) -> Result<Json<LobbyRoom>, (StatusCode, String)> {
+    let token = session_token(&headers)?;
+    update_ready(&state, &id, &token, req.ready).await.map(|record| Json(record.room))
+}
+
+/// Sets the member's ready state, and tells the rest of the room.
+/// Also used by the lobby websocket's `Ready` command.
+pub(crate) async fn update_ready(state: &AppState, id: &str, session_token: &str, ready: bool) -> Result<LobbyRecord, (StatusCode, String)> {
+    let mut player_name = String::new();
+    let record = state.lobby.update(id, |record| {
+        player_name = record.authorize(session_token)?.to_string();
+        record.set_ready(&player_name, ready)
+    }).await.map_err(lobby_error)?;
+    publish_room_event(state, id, &RoomEvent::Ready { player_name, ready }).await;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevygap_shared::lobby::new_room_id;
+
+    #[test]
+    fn test_joined_room_keeps_the_room_fields() {
+        let record = LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4");
+        let room = serde_json::to_value(&record.room).unwrap();
+        let joined = serde_json::to_value(JoinedRoom::new(record, "alice".to_string())).unwrap();
+        for (field, value) in room.as_object().unwrap() {
+            assert_eq!(joined.get(field), Some(value), "{field}");
         }
-        Ok(StatusCode::NO_CONTENT)
-    } else {
-        Err((StatusCode::NOT_FOUND, "room not found".to_string()))
+        assert_eq!(joined["player_name"], "alice");
+        assert!(!joined["session_token"].as_str().unwrap().is_empty());
     }
 }
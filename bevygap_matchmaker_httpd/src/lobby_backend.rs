@@ -0,0 +1,425 @@
+//! Lobby backends deploy the gameserver for started rooms, and can mirror rooms into an external
+//! lobby service. Rooms, members and session tokens always live in the `lobby_rooms` KV bucket,
+//! so the lobby API and websocket behave the same whichever backend is configured:
+//!
+//! * [`BuiltinLobby`] asks the matchmaker for a dedicated deployment over NATS.
+//! * [`EdgegapLobby`] mirrors rooms and their players into an Edgegap Lobbies service, and has
+//!   it start the room's deployment.
+use async_nats::client::RequestErrorKind;
+use bevygap_shared::lobby::{LobbyError, LobbyRecord};
+use bevygap_shared::protocol::{LobbyDeployRequest, LobbyDeployment};
+use edgegap_async::{EdgegapClient, EdgegapError};
+use futures::future::{BoxFuture, FutureExt as _};
+use log::*;
+use reqwest::Method;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::settings::{LobbyBackendKind, Settings};
+
+pub(crate) type BackendFuture<'a, T> = BoxFuture<'a, Result<T, LobbyError>>;
+
+/// Called by the lobby API once a change to a room has been stored. Only a failed
+/// [`Self::deploy_room`] affects the room, other failures are logged and otherwise ignored.
+pub(crate) trait LobbyBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The room was created. Returns the room's id in the backend, if it keeps its own, which
+    /// is stored as the record's `external_id`.
+    fn room_created<'a>(&'a self, _record: &'a LobbyRecord) -> BackendFuture<'a, Option<String>> {
+        async { Ok(None) }.boxed()
+    }
+
+    fn player_joined<'a>(
+        &'a self,
+        _record: &'a LobbyRecord,
+        _player_name: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// The player left or was kicked.
+    fn player_left<'a>(
+        &'a self,
+        _record: &'a LobbyRecord,
+        _player_name: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// Starts the room's gameserver, resolving once it's running.
+    fn deploy_room<'a>(&'a self, record: &'a LobbyRecord) -> BackendFuture<'a, LobbyDeployment>;
+
+    /// The room was removed, because everyone left or the janitor evicted it.
+    fn room_removed<'a>(&'a self, _record: &'a LobbyRecord) -> BackendFuture<'a, ()> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// The backend chosen by `lobby_backend` in the settings.
+pub(crate) async fn connect(
+    settings: &Settings,
+    nats: async_nats::Client,
+) -> anyhow::Result<Box<dyn LobbyBackend>> {
+    Ok(match settings.lobby_backend {
+        LobbyBackendKind::Builtin => Box::new(BuiltinLobby {
+            nats,
+            deploy_timeout: settings.lobby_deploy_timeout(),
+        }),
+        LobbyBackendKind::Edgegap => Box::new(EdgegapLobby::connect(settings).await?),
+    })
+}
+
+/// Deploys rooms with the matchmaker's `lobby.deploy` NATS service.
+pub(crate) struct BuiltinLobby {
+    nats: async_nats::Client,
+    deploy_timeout: Duration,
+}
+
+impl LobbyBackend for BuiltinLobby {
+    fn name(&self) -> &'static str {
+        "builtin"
+    }
+
+    fn deploy_room<'a>(&'a self, record: &'a LobbyRecord) -> BackendFuture<'a, LobbyDeployment> {
+        let deploy_request = LobbyDeployRequest {
+            room_id: record.room.id.clone(),
+            ip_list: record.player_ips(),
+        };
+        async move {
+            let request = async_nats::client::Request::new()
+                .timeout(Some(self.deploy_timeout))
+                .payload(serde_json::to_vec(&deploy_request).unwrap().into());
+            match self.nats.send_request("lobby.deploy", request).await {
+                Ok(resp) => {
+                    if let Some((code, msg)) = maybe_message_error(&resp) {
+                        debug!(
+                            "Matchmaker refused to deploy room {}: {code}",
+                            deploy_request.room_id
+                        );
+                        return Err(LobbyError::Backend(msg));
+                    }
+                    serde_json::from_slice(&resp.payload).map_err(|e| {
+                        LobbyError::Backend(format!("Invalid deployment response: {e}"))
+                    })
+                }
+                Err(e) => {
+                    error!(
+                        "NATS error deploying game server for room {}: {:?}",
+                        deploy_request.room_id, e
+                    );
+                    Err(LobbyError::Backend(
+                        match e.kind() {
+                            RequestErrorKind::TimedOut => "Deployment request timeout",
+                            RequestErrorKind::NoResponders => "No deployment service available",
+                            RequestErrorKind::Other => "Deployment service error",
+                        }
+                        .to_string(),
+                    ))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+fn maybe_message_error(message: &async_nats::Message) -> Option<(usize, String)> {
+    let h = message.headers.clone()?;
+    if let Some(code) = h.get(async_nats::service::NATS_SERVICE_ERROR_CODE) {
+        let msg_str = h
+            .get(async_nats::service::NATS_SERVICE_ERROR)
+            .unwrap()
+            .to_string();
+        Some((code.as_str().parse::<usize>().unwrap(), msg_str))
+    } else {
+        None
+    }
+}
+
+/// Mirrors rooms into a hosted Edgegap Lobbies service, which deploys the gameserver when the
+/// room starts. Players are identified there by their player name, which is unique in the room.
+pub(crate) struct EdgegapLobby {
+    edgegap: EdgegapClient,
+    http: reqwest::Client,
+    /// The lobby service's own API
+    url: String,
+    token: String,
+    poll_interval: Duration,
+    deploy_timeout: Duration,
+}
+
+/// A room in the Edgegap Lobbies service. Only the fields we use.
+#[derive(Debug, Deserialize)]
+struct EdgegapRoom {
+    lobby_id: String,
+    #[serde(default)]
+    assignment: Option<EdgegapAssignment>,
+}
+
+/// Where a started Edgegap lobby room is being deployed.
+#[derive(Debug, Deserialize)]
+struct EdgegapAssignment {
+    #[serde(default)]
+    request_id: Option<String>,
+    /// The deployment's FQDN, which starts with its request id
+    #[serde(default)]
+    host: Option<String>,
+}
+
+impl EdgegapAssignment {
+    fn request_id(&self) -> Option<String> {
+        self.request_id.clone().or_else(|| {
+            let host = self.host.as_deref()?;
+            host.split('.').next().map(str::to_string)
+        })
+    }
+}
+
+impl EdgegapLobby {
+    /// Finds the configured lobby service, creating and deploying it if needed, and waits until
+    /// it has a URL.
+    pub(crate) async fn connect(settings: &Settings) -> anyhow::Result<Self> {
+        let Some(token) = settings
+            .edgegap_lobby_token
+            .clone()
+            .filter(|t| !t.is_empty())
+        else {
+            anyhow::bail!("edgegap_lobby_token is needed to use the edgegap lobby backend");
+        };
+        let edgegap = settings.edgegap_client()?;
+        let name = settings.edgegap_lobby_name.as_str();
+        let poll_interval = settings.edgegap_lobby_poll_interval();
+        let mut service = match edgegap.lobby_service(name).await {
+            Err(e) if e.is_gone() => {
+                info!("Creating Edgegap lobby service {name}");
+                edgegap.create_lobby_service(name).await?
+            }
+            service => service?,
+        };
+        if service.url.is_empty() {
+            info!("Deploying Edgegap lobby service {name}");
+            service = edgegap.deploy_lobby_service(name).await?;
+        }
+        let wait = async {
+            while service.url.is_empty() {
+                debug!("Edgegap lobby service {name} is {}", service.status);
+                tokio::time::sleep(poll_interval).await;
+                service = edgegap.lobby_service(name).await?;
+            }
+            Ok::<_, EdgegapError>(service)
+        };
+        let service = tokio::time::timeout(settings.lobby_deploy_timeout(), wait)
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out waiting for Edgegap lobby service {name}"))??;
+        info!(
+            "Using Edgegap lobby service {name} at {}, status {}",
+            service.url, service.status
+        );
+        Ok(Self {
+            edgegap,
+            http: reqwest::Client::new(),
+            url: service.url.trim_end_matches('/').to_string(),
+            token,
+            poll_interval,
+            deploy_timeout: settings.lobby_deploy_timeout(),
+        })
+    }
+
+    /// Calls the lobby service's API, failing unless the response is a success.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<reqwest::Response, LobbyError> {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.url))
+            .header(reqwest::header::AUTHORIZATION, &self.token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let resp = request.send().await.map_err(edgegap_lobby_error)?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+        let text = resp.text().await.unwrap_or_default();
+        Err(match status {
+            reqwest::StatusCode::NOT_FOUND => LobbyError::NotFound,
+            _ => LobbyError::Backend(format!("Edgegap lobby service replied {status}: {text}")),
+        })
+    }
+
+    async fn room(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<EdgegapRoom, LobbyError> {
+        let resp = self.send(method, path, body).await?;
+        resp.json().await.map_err(edgegap_lobby_error)
+    }
+
+    async fn create(&self, record: &LobbyRecord) -> Result<String, LobbyError> {
+        let body = serde_json::json!({
+            "name": record.room.id,
+            "capacity": record.room.max_players,
+            "is_joinable": true,
+            "tags": [record.room.game_mode],
+            "player": { "id": record.room.host_name },
+        });
+        let lobby = self.room(Method::POST, "/lobbies", Some(body)).await?;
+        // the host is added on create, the rest join as they would have
+        for member in &record.room.members {
+            if member.player_name != record.room.host_name {
+                self.membership(&lobby.lobby_id, "join", &member.player_name)
+                    .await?;
+            }
+        }
+        Ok(lobby.lobby_id)
+    }
+
+    async fn membership(
+        &self,
+        lobby_id: &str,
+        action: &str,
+        player_name: &str,
+    ) -> Result<(), LobbyError> {
+        let body = serde_json::json!({ "lobby_id": lobby_id, "player": { "id": player_name } });
+        self.send(Method::PATCH, &format!("/lobbies:{action}"), Some(body))
+            .await
+            .map(drop)
+    }
+
+    /// Starts the Edgegap lobby room, and waits for its deployment to be running.
+    async fn start(&self, lobby_id: &str) -> Result<LobbyDeployment, LobbyError> {
+        let body = serde_json::json!({ "lobby_id": lobby_id });
+        self.send(Method::POST, "/lobbies:start", Some(body))
+            .await?;
+        let request_id = loop {
+            let lobby = self
+                .room(Method::GET, &format!("/lobbies/{lobby_id}"), None)
+                .await?;
+            if let Some(request_id) = lobby.assignment.as_ref().and_then(|a| a.request_id()) {
+                break request_id;
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        };
+        info!("Edgegap lobby {lobby_id} is deploying as {request_id}");
+        let status = self
+            .edgegap
+            .wait_deployment_ready(&request_id, self.poll_interval, self.deploy_timeout)
+            .await
+            .map_err(edgegap_lobby_error)?;
+        // use the first port, as for sessions
+        let port = status
+            .ports
+            .iter()
+            .flat_map(|ports| ports.values())
+            .find_map(|port| port.external)
+            .ok_or_else(|| LobbyError::Backend("No ports found in deployment".to_string()))?;
+        Ok(LobbyDeployment {
+            request_id,
+            app_name: status.app_name,
+            app_version: status.app_version,
+            ip: status.public_ip,
+            port: port as u16,
+        })
+    }
+}
+
+impl LobbyBackend for EdgegapLobby {
+    fn name(&self) -> &'static str {
+        "edgegap"
+    }
+
+    fn room_created<'a>(&'a self, record: &'a LobbyRecord) -> BackendFuture<'a, Option<String>> {
+        async move { self.create(record).await.map(Some) }.boxed()
+    }
+
+    fn player_joined<'a>(
+        &'a self,
+        record: &'a LobbyRecord,
+        player_name: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        async move {
+            match &record.external_id {
+                Some(lobby_id) => self.membership(lobby_id, "join", player_name).await,
+                // mirrored with all members if the room starts
+                None => Ok(()),
+            }
+        }
+        .boxed()
+    }
+
+    fn player_left<'a>(
+        &'a self,
+        record: &'a LobbyRecord,
+        player_name: &'a str,
+    ) -> BackendFuture<'a, ()> {
+        async move {
+            match &record.external_id {
+                Some(lobby_id) => self.membership(lobby_id, "leave", player_name).await,
+                None => Ok(()),
+            }
+        }
+        .boxed()
+    }
+
+    fn deploy_room<'a>(&'a self, record: &'a LobbyRecord) -> BackendFuture<'a, LobbyDeployment> {
+        async move {
+            let lobby_id = match &record.external_id {
+                Some(lobby_id) => lobby_id.clone(),
+                None => self.create(record).await?,
+            };
+            tokio::time::timeout(self.deploy_timeout, self.start(&lobby_id))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(LobbyError::Backend(format!(
+                        "Timed out waiting for Edgegap lobby {lobby_id} to deploy"
+                    )))
+                })
+        }
+        .boxed()
+    }
+
+    fn room_removed<'a>(&'a self, record: &'a LobbyRecord) -> BackendFuture<'a, ()> {
+        async move {
+            let Some(lobby_id) = &record.external_id else {
+                return Ok(());
+            };
+            match self
+                .send(Method::DELETE, &format!("/lobbies/{lobby_id}"), None)
+                .await
+            {
+                Ok(_) | Err(LobbyError::NotFound) => Ok(()),
+                Err(e) => Err(e),
+            }
+        }
+        .boxed()
+    }
+}
+
+fn edgegap_lobby_error(e: impl std::fmt::Display) -> LobbyError {
+    LobbyError::Backend(format!("Edgegap lobby service error: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lobby_token_required() {
+        let settings = Settings {
+            lobby_backend: LobbyBackendKind::Edgegap,
+            edgegap_api_key: "token abc".to_string(),
+            // nothing listens here, so this fails if the lobby service is looked up
+            edgegap_base_path: "http://127.0.0.1:9/".to_string(),
+            edgegap_lobby_token: Some(String::new()),
+            ..Settings::default()
+        };
+        let err = EdgegapLobby::connect(&settings).await.err().unwrap();
+        assert!(err.to_string().contains("edgegap_lobby_token"), "{err}");
+    }
+}
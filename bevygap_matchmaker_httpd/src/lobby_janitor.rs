@@ -0,0 +1,119 @@
+//! Removes lobby rooms nobody needs any more:
+//!
+//! * waiting rooms that haven't changed for `lobby_idle_ttl_secs`, eg. a host who wandered off
+//! * started rooms, once Edgegap has deleted every session handed out for their deployment
+//!
+//! Sessions are deleted through the matchmaker's delete queue, whether the player disconnected
+//! or never connected, so we listen to that. Every httpd instance runs a janitor, which is fine
+//! since removals are compare-and-swap deletes.
+use bevygap_shared::lobby::{now_secs, LobbyError, LobbyRecord};
+use bevygap_shared::nats::BevygapNats;
+use bevygap_shared::supervisor::Shutdown;
+use log::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_stream::StreamExt as _;
+
+use crate::AppState;
+
+/// Rooms removed by this instance's janitor, reported by `lobby_status`.
+#[derive(Debug, Default)]
+pub(crate) struct LobbyEvictions {
+    pub(crate) idle: AtomicU64,
+    pub(crate) finished: AtomicU64,
+}
+
+pub(crate) async fn lobby_janitor(
+    state: Arc<AppState>,
+    mut shutdown: Shutdown,
+) -> Result<(), async_nats::Error> {
+    let mut deleted_sessions = state
+        .bgnats
+        .client()
+        .subscribe(BevygapNats::delete_session_subject("*"))
+        .await?;
+    let mut sweep = tokio::time::interval(state.settings.lobby_janitor_interval());
+    info!(
+        "Lobby janitor running, idle TTL {:?}",
+        state.settings.lobby_idle_ttl()
+    );
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = sweep.tick() => {
+                if let Err(e) = remove_idle_rooms(&state).await {
+                    warn!("Lobby janitor couldn't sweep idle rooms: {e}");
+                }
+            }
+            msg = deleted_sessions.next() => {
+                let Some(msg) = msg else {
+                    return Err("session delete subscription ended".into());
+                };
+                let session_id = String::from_utf8_lossy(&msg.payload).to_string();
+                if let Err(e) = session_deleted(&state, &session_id).await {
+                    warn!("Lobby janitor couldn't handle deleted session {session_id}: {e}");
+                }
+            }
+        }
+    }
+}
+
+async fn remove_idle_rooms(state: &AppState) -> Result<(), LobbyError> {
+    let idle_ttl = state.settings.lobby_idle_ttl();
+    for record in state.lobby.rooms().await? {
+        let id = record.room.id.clone();
+        if !record.is_idle(now_secs(), idle_ttl) {
+            continue;
+        }
+        // unless someone joined, left or started it since we looked
+        if state
+            .lobby
+            .remove_if(&id, |r| r.is_idle(now_secs(), idle_ttl))
+            .await?
+        {
+            info!("Removed idle lobby room {id}");
+            state.lobby_evictions.idle.fetch_add(1, Ordering::Relaxed);
+            room_removed(state, &record).await;
+        }
+    }
+    Ok(())
+}
+
+async fn session_deleted(state: &AppState, session_id: &str) -> Result<(), LobbyError> {
+    let Some(record) = state
+        .lobby
+        .rooms()
+        .await?
+        .into_iter()
+        .find(|r| r.game_session_ids.iter().any(|id| id == session_id))
+    else {
+        return Ok(());
+    };
+    let id = record.room.id;
+    let record = state
+        .lobby
+        .update(&id, |record| {
+            record.end_game_session(session_id);
+            Ok(())
+        })
+        .await?;
+    debug!(
+        "Session {session_id} of lobby room {id} deleted, {} left",
+        record.game_session_ids.len()
+    );
+    if record.is_finished() && state.lobby.remove_if(&id, |r| r.is_finished()).await? {
+        info!("Removed finished lobby room {id}");
+        state
+            .lobby_evictions
+            .finished
+            .fetch_add(1, Ordering::Relaxed);
+        room_removed(state, &record).await;
+    }
+    Ok(())
+}
+
+async fn room_removed(state: &AppState, record: &LobbyRecord) {
+    if let Err(e) = state.lobby_backend.room_removed(record).await {
+        warn!("Lobby backend couldn't remove room {}: {e}", record.room.id);
+    }
+}
@@ -0,0 +1,609 @@
+//! Live lobby updates over `/lobby/ws`, so clients don't have to poll the rooms list.
+//!
+//! Room changes come from a watch on the lobby KV bucket, shared by every connection through
+//! `AppState::lobby_changes`. Events within a room (joins, leaves, chat, ready state) are
+//! published on `lobby.events.{room_id}`, so members connected to other httpd instances see
+//! them too.
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use bevygap_shared::lobby::{LobbyError, LobbyRecord, LobbyRoom, RoomChange, RoomStatus};
+use bevygap_shared::protocol::SessionRequestFeedback;
+use bevygap_shared::supervisor::Shutdown;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::StreamExt as _;
+
+use crate::lobby::{request_room_token, update_ready};
+use crate::session_request_handler::{get_client_ip, QsParams};
+use crate::AppState;
+
+/// Longest chat message we pass on, in characters
+const MAX_CHAT_LEN: usize = 500;
+
+/// Sent by lobby clients over the websocket, as JSON.
+#[derive(Debug, Deserialize)]
+pub(crate) enum LobbyCommand {
+    /// Follow a room's events as a member, with the session token from creating or joining it
+    /// with the HTTP API. Connect details are pushed once the room's gameserver is ready.
+    Enter {
+        room_id: String,
+        session_token: String,
+    },
+    /// Stop following the current room
+    Exit,
+    Chat(String),
+    Ready(bool),
+}
+
+/// Sent to lobby clients over the websocket, as JSON.
+#[derive(Debug, Serialize)]
+pub(crate) enum LobbyFeed {
+    /// Rooms waiting for players. Sent on connect, and again if this client fell behind.
+    Rooms(Vec<LobbyRoom>),
+    /// Any room was created or changed, including started ones. Invite-only rooms are only
+    /// sent to their members.
+    RoomUpdated(LobbyRoom),
+    /// A room this client was shown was removed, or can't be seen by it anymore
+    RoomRemoved(String),
+    /// Something happened in the room this client entered
+    Room {
+        room_id: String,
+        event: RoomEvent,
+    },
+    /// The entered room was started, and its gameserver is deploying
+    Started(String),
+    /// The entered room's gameserver is ready: this player's `SessionReady`, or an `Error`
+    Connect(SessionRequestFeedback),
+    Error(u16, String),
+}
+
+/// Events within a room, published on `lobby.events.{room_id}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum RoomEvent {
+    PlayerJoined {
+        player_name: String,
+        current_players: u32,
+    },
+    PlayerLeft {
+        player_name: String,
+        current_players: u32,
+    },
+    PlayerKicked {
+        player_name: String,
+    },
+    /// The host left, and this member took over
+    HostChanged {
+        host_name: String,
+    },
+    Chat {
+        player_name: String,
+        text: String,
+    },
+    Ready {
+        player_name: String,
+        ready: bool,
+    },
+}
+
+fn room_events_subject(room_id: &str) -> String {
+    format!("lobby.events.{room_id}")
+}
+
+/// Tells everyone following the room about `event`. Failures are only logged, since the
+/// change that caused the event has already happened.
+pub(crate) async fn publish_room_event(state: &AppState, room_id: &str, event: &RoomEvent) {
+    let payload = serde_json::to_vec(event).unwrap();
+    if let Err(e) = state
+        .bgnats
+        .client()
+        .publish(room_events_subject(room_id), payload.into())
+        .await
+    {
+        warn!("Failed to publish event for room {room_id}: {e}");
+    }
+}
+
+/// Forwards changes from the lobby KV bucket to every websocket on this instance.
+pub(crate) async fn watch_rooms(
+    state: Arc<AppState>,
+    mut shutdown: Shutdown,
+) -> Result<(), LobbyError> {
+    let mut changes = std::pin::pin!(state.lobby.watch().await?);
+    info!("Watching lobby rooms");
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            change = changes.next() => match change {
+                // an error just means no websockets are connected right now
+                Some(change) => { let _ = state.lobby_changes.send(change?); }
+                None => return Err(LobbyError::Store("lobby rooms watch ended".to_string())),
+            }
+        }
+    }
+}
+
+pub(crate) async fn handler_lobby_websocket(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<QsParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let client_ip = get_client_ip(&params, &addr, &headers, &state);
+    info!("lobby ws for ip {client_ip}");
+    ws.on_upgrade(move |socket| handle_socket(socket, client_ip, state))
+}
+
+async fn handle_socket(mut socket: WebSocket, client_ip: String, state: Arc<AppState>) {
+    if let Err(e) = handle_socket_inner(&mut socket, client_ip, state).await {
+        warn!("lobby ws: {e}");
+        let _ = send(&mut socket, &LobbyFeed::Error(500, e)).await;
+    }
+    info!("lobby websocket connection closed");
+}
+
+/// The room a connection has entered.
+struct EnteredRoom {
+    room_id: String,
+    player_name: String,
+    session_token: String,
+    progress: RoomProgress,
+    events: async_nats::Subscriber,
+}
+
+/// How far the entered room has got towards a running game, from this player's point of view.
+#[derive(Debug, Default)]
+struct RoomProgress {
+    status: RoomStatus,
+    /// Whether we've asked for this player's connect token yet
+    connect_requested: bool,
+}
+
+/// What to do after the entered room's status changed.
+#[derive(Debug, PartialEq)]
+enum ProgressAction {
+    /// Tell the player the room started deploying
+    Started,
+    /// Fetch this player's connect token
+    RequestConnect,
+}
+
+impl RoomProgress {
+    /// Records the room's latest status. `Started` is only returned when the room starts
+    /// deploying, and `RequestConnect` only once per successful deployment.
+    fn update(&mut self, status: RoomStatus) -> Option<ProgressAction> {
+        let previous = std::mem::replace(&mut self.status, status);
+        match status {
+            RoomStatus::Deploying if previous != RoomStatus::Deploying => {
+                Some(ProgressAction::Started)
+            }
+            RoomStatus::Ready | RoomStatus::InGame if !self.connect_requested => {
+                self.connect_requested = true;
+                Some(ProgressAction::RequestConnect)
+            }
+            // a failed deploy can be retried, which should get new connect details
+            RoomStatus::Failed => {
+                self.connect_requested = false;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where a connection stands with a room that just changed.
+#[derive(Debug, PartialEq)]
+enum Membership {
+    /// It's the entered room, and this player is still in it
+    Member,
+    /// It's the entered room, but this player was kicked or left from elsewhere
+    Removed,
+    /// Any other room
+    Outsider,
+}
+
+impl Membership {
+    /// `entered` is the room id and player name of the connection's entered room, if any.
+    fn of(entered: Option<(&str, &str)>, record: &LobbyRecord) -> Self {
+        match entered {
+            Some((room_id, player_name)) if room_id == record.room.id => {
+                if record.member(player_name).is_some() {
+                    Membership::Member
+                } else {
+                    Membership::Removed
+                }
+            }
+            _ => Membership::Outsider,
+        }
+    }
+
+    /// Invite-only rooms are only shown to their members.
+    fn can_see(&self, room: &LobbyRoom) -> bool {
+        *self == Membership::Member || !room.invite_only
+    }
+}
+
+/// The rooms a socket has been shown, so it's only told about removals of rooms it knows of.
+/// Otherwise the ids of invite-only rooms would leak when they're removed.
+#[derive(Debug, Default)]
+struct ShownRooms(HashSet<String>);
+
+impl ShownRooms {
+    /// Sends a room list, replacing whatever was shown before.
+    fn listed(&mut self, feed: LobbyFeed) -> LobbyFeed {
+        if let LobbyFeed::Rooms(rooms) = &feed {
+            self.0 = rooms.iter().map(|room| room.id.clone()).collect();
+        }
+        feed
+    }
+
+    /// Shows the room if the socket may see it. If it may no longer see a room it was shown,
+    /// eg. after being kicked from an invite-only room, it's removed instead.
+    fn updated(&mut self, room: LobbyRoom, visible: bool) -> Option<LobbyFeed> {
+        if visible {
+            self.0.insert(room.id.clone());
+            Some(LobbyFeed::RoomUpdated(room))
+        } else if self.0.remove(&room.id) {
+            Some(LobbyFeed::RoomRemoved(room.id))
+        } else {
+            None
+        }
+    }
+
+    fn removed(&mut self, room_id: String) -> Option<LobbyFeed> {
+        self.0
+            .remove(&room_id)
+            .then_some(LobbyFeed::RoomRemoved(room_id))
+    }
+}
+
+async fn handle_socket_inner(
+    socket: &mut WebSocket,
+    client_ip: String,
+    state: Arc<AppState>,
+) -> Result<(), String> {
+    // subscribe before listing, so no change falls between the two
+    let mut changes = state.lobby_changes.subscribe();
+    let mut shown = ShownRooms::default();
+    send(socket, &shown.listed(waiting_rooms(&state).await)).await?;
+
+    // connect tokens are fetched in the background, then sent from here
+    let (feed_tx, mut feed_rx) = mpsc::channel::<LobbyFeed>(4);
+    let mut entered: Option<EnteredRoom> = None;
+
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                };
+                let feed = match serde_json::from_str::<LobbyCommand>(&text) {
+                    Ok(command) => {
+                        handle_command(command, &mut entered, &state, &client_ip, &feed_tx).await
+                    }
+                    Err(e) => Some(LobbyFeed::Error(400, format!("Invalid lobby command: {e}"))),
+                };
+                if let Some(feed) = feed {
+                    send(socket, &feed).await?;
+                }
+            }
+            change = changes.recv() => match change {
+                Ok(RoomChange::Updated(record)) => {
+                    let membership = Membership::of(
+                        entered.as_ref().map(|e| (e.room_id.as_str(), e.player_name.as_str())),
+                        &record,
+                    );
+                    match membership {
+                        Membership::Removed => {
+                            let msg = format!("You're no longer in room {}", record.room.id);
+                            entered = None;
+                            send(socket, &LobbyFeed::Error(403, msg)).await?;
+                        }
+                        Membership::Member => {
+                            if let Some(feed) = entered.as_mut().and_then(|room| {
+                                room_status_changed(room, record.room.status, &state, &client_ip, &feed_tx)
+                            }) {
+                                send(socket, &feed).await?;
+                            }
+                        }
+                        Membership::Outsider => {}
+                    }
+                    let visible = membership.can_see(&record.room);
+                    if let Some(feed) = shown.updated(record.room, visible) {
+                        send(socket, &feed).await?;
+                    }
+                }
+                Ok(RoomChange::Removed(room_id)) => {
+                    if entered.as_ref().is_some_and(|e| e.room_id == room_id) {
+                        entered = None;
+                    }
+                    if let Some(feed) = shown.removed(room_id) {
+                        send(socket, &feed).await?;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    debug!("lobby ws fell {n} room changes behind, resending rooms");
+                    send(socket, &shown.listed(waiting_rooms(&state).await)).await?;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Err("Lobby feed closed".to_string()),
+            },
+            Some((room_id, event)) = next_room_event(&mut entered) => {
+                send(socket, &LobbyFeed::Room { room_id, event }).await?;
+            }
+            Some(feed) = feed_rx.recv() => {
+                send(socket, &feed).await?;
+            }
+        }
+    }
+}
+
+async fn handle_command(
+    command: LobbyCommand,
+    entered: &mut Option<EnteredRoom>,
+    state: &Arc<AppState>,
+    client_ip: &str,
+    feed_tx: &mpsc::Sender<LobbyFeed>,
+) -> Option<LobbyFeed> {
+    match command {
+        LobbyCommand::Enter {
+            room_id,
+            session_token,
+        } => {
+            let record = match state.lobby.get(&room_id).await {
+                Ok(record) => record,
+                Err(e) => return Some(LobbyFeed::Error(e.status_code(), e.to_string())),
+            };
+            let player_name = match record.authorize(&session_token) {
+                Ok(player_name) => player_name.to_string(),
+                Err(e) => return Some(LobbyFeed::Error(e.status_code(), e.to_string())),
+            };
+            let events = match state
+                .bgnats
+                .client()
+                .subscribe(room_events_subject(&room_id))
+                .await
+            {
+                Ok(events) => events,
+                Err(e) => return Some(LobbyFeed::Error(500, format!("NATS error: {e}"))),
+            };
+            info!("{player_name} entered room {room_id} over ws");
+            let room = entered.insert(EnteredRoom {
+                room_id,
+                player_name,
+                session_token,
+                progress: RoomProgress::default(),
+                events,
+            });
+            // catches up if the room already started
+            room_status_changed(room, record.room.status, state, client_ip, feed_tx)
+        }
+        LobbyCommand::Exit => {
+            *entered = None;
+            None
+        }
+        LobbyCommand::Chat(text) => {
+            let Some(room) = entered else {
+                return Some(not_entered());
+            };
+            let event = RoomEvent::Chat {
+                player_name: room.player_name.clone(),
+                text: text.chars().take(MAX_CHAT_LEN).collect(),
+            };
+            publish_room_event(state, &room.room_id, &event).await;
+            None
+        }
+        LobbyCommand::Ready(ready) => {
+            let Some(room) = entered else {
+                return Some(not_entered());
+            };
+            update_ready(state, &room.room_id, &room.session_token, ready)
+                .await
+                .err()
+                .map(|(code, msg)| LobbyFeed::Error(code.as_u16(), msg))
+        }
+    }
+}
+
+fn not_entered() -> LobbyFeed {
+    LobbyFeed::Error(409, "Enter a room first".to_string())
+}
+
+/// Tracks the entered room's status. Returns `Started` when it starts deploying, and once it's
+/// ready, fetches this player's connect token in the background.
+fn room_status_changed(
+    room: &mut EnteredRoom,
+    status: RoomStatus,
+    state: &Arc<AppState>,
+    client_ip: &str,
+    feed_tx: &mpsc::Sender<LobbyFeed>,
+) -> Option<LobbyFeed> {
+    match room.progress.update(status)? {
+        ProgressAction::Started => Some(LobbyFeed::Started(room.room_id.clone())),
+        ProgressAction::RequestConnect => {
+            let (state, room_id, session_token, client_ip) = (
+                state.clone(),
+                room.room_id.clone(),
+                room.session_token.clone(),
+                client_ip.to_string(),
+            );
+            let feed_tx = feed_tx.clone();
+            tokio::spawn(async move {
+                let feedback = request_room_token(&state, &room_id, &session_token, &client_ip)
+                    .await
+                    .unwrap_or_else(|(code, msg)| {
+                        SessionRequestFeedback::Error(code.as_u16(), msg)
+                    });
+                let _ = feed_tx.send(LobbyFeed::Connect(feedback)).await;
+            });
+            None
+        }
+    }
+}
+
+/// The next event in the entered room, or never if there isn't one.
+async fn next_room_event(entered: &mut Option<EnteredRoom>) -> Option<(String, RoomEvent)> {
+    let Some(room) = entered else {
+        return std::future::pending().await;
+    };
+    loop {
+        let msg = room.events.next().await?;
+        match serde_json::from_slice(&msg.payload) {
+            Ok(event) => return Some((room.room_id.clone(), event)),
+            Err(e) => warn!("Invalid event for room {}: {e}", room.room_id),
+        }
+    }
+}
+
+async fn waiting_rooms(state: &AppState) -> LobbyFeed {
+    match state.lobby.rooms().await {
+        Ok(rooms) => LobbyFeed::Rooms(
+            rooms
+                .into_iter()
+                .map(|r| r.room)
+                .filter(|r| !r.started && !r.invite_only)
+                .collect(),
+        ),
+        Err(e) => LobbyFeed::Error(e.status_code(), e.to_string()),
+    }
+}
+
+async fn send(socket: &mut WebSocket, feed: &LobbyFeed) -> Result<(), String> {
+    let text = serde_json::to_string(feed).unwrap();
+    socket
+        .send(Message::Text(text))
+        .await
+        .map_err(|e| format!("Can't send to lobby ws client: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevygap_shared::lobby::new_room_id;
+
+    #[test]
+    fn test_started_emitted_once() {
+        let mut progress = RoomProgress::default();
+        assert_eq!(progress.update(RoomStatus::Waiting), None);
+        assert_eq!(
+            progress.update(RoomStatus::Deploying),
+            Some(ProgressAction::Started)
+        );
+        assert_eq!(progress.update(RoomStatus::Deploying), None);
+    }
+
+    #[test]
+    fn test_connect_requested_once_until_failed() {
+        let mut progress = RoomProgress::default();
+        progress.update(RoomStatus::Deploying);
+        assert_eq!(
+            progress.update(RoomStatus::Ready),
+            Some(ProgressAction::RequestConnect)
+        );
+        assert_eq!(progress.update(RoomStatus::Ready), None);
+        assert_eq!(progress.update(RoomStatus::InGame), None);
+
+        // entering an already running room requests straight away
+        let mut late = RoomProgress::default();
+        assert_eq!(
+            late.update(RoomStatus::InGame),
+            Some(ProgressAction::RequestConnect)
+        );
+
+        // a failed deploy that's retried gets new connect details
+        assert_eq!(progress.update(RoomStatus::Failed), None);
+        assert!(!progress.connect_requested);
+        assert_eq!(
+            progress.update(RoomStatus::Deploying),
+            Some(ProgressAction::Started)
+        );
+        assert_eq!(
+            progress.update(RoomStatus::Ready),
+            Some(ProgressAction::RequestConnect)
+        );
+    }
+
+    #[test]
+    fn test_invite_only_rooms_hidden_from_non_members() {
+        let mut hidden =
+            LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4").with_invite_only(true);
+        let code = hidden.invite_code.clone();
+        hidden
+            .add_member("bob", "5.6.7.8", None, Some(&code))
+            .unwrap();
+        let room_id = hidden.room.id.clone();
+
+        let outsider = Membership::of(None, &hidden);
+        assert_eq!(outsider, Membership::Outsider);
+        assert!(!outsider.can_see(&hidden.room));
+        let elsewhere = Membership::of(Some(("ROOMOTHER", "bob")), &hidden);
+        assert!(!elsewhere.can_see(&hidden.room));
+
+        let member = Membership::of(Some((room_id.as_str(), "bob")), &hidden);
+        assert_eq!(member, Membership::Member);
+        assert!(member.can_see(&hidden.room));
+
+        hidden.kick("alice", "bob").unwrap();
+        let kicked = Membership::of(Some((room_id.as_str(), "bob")), &hidden);
+        assert_eq!(kicked, Membership::Removed);
+        assert!(!kicked.can_see(&hidden.room));
+    }
+
+    #[test]
+    fn test_public_rooms_visible_to_everyone() {
+        let mut public = LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4");
+        public.add_member("bob", "5.6.7.8", None, None).unwrap();
+        let room_id = public.room.id.clone();
+        assert!(Membership::of(None, &public).can_see(&public.room));
+        public.remove_member("bob").unwrap();
+        let left = Membership::of(Some((room_id.as_str(), "bob")), &public);
+        assert_eq!(left, Membership::Removed);
+        assert!(left.can_see(&public.room));
+    }
+
+    #[test]
+    fn test_removals_only_sent_for_shown_rooms() {
+        let public = LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4");
+        let mut hidden =
+            LobbyRecord::new(new_room_id(), "carol", "ffa", 4, "1.2.3.4").with_invite_only(true);
+        let mut shown = ShownRooms::default();
+        shown.listed(LobbyFeed::Rooms(vec![public.room.clone()]));
+
+        let outsider = Membership::of(None, &hidden);
+        assert!(shown
+            .updated(hidden.room.clone(), outsider.can_see(&hidden.room))
+            .is_none());
+        assert!(shown.removed(hidden.room.id.clone()).is_none());
+        assert!(matches!(
+            shown.removed(public.room.id.clone()),
+            Some(LobbyFeed::RoomRemoved(id)) if id == public.room.id
+        ));
+        assert!(shown.removed(public.room.id.clone()).is_none());
+
+        // a member sees the invite-only room, until they're kicked
+        let code = hidden.invite_code.clone();
+        hidden
+            .add_member("bob", "5.6.7.8", None, Some(&code))
+            .unwrap();
+        let room_id = hidden.room.id.clone();
+        let member = Membership::of(Some((room_id.as_str(), "bob")), &hidden);
+        assert!(matches!(
+            shown.updated(hidden.room.clone(), member.can_see(&hidden.room)),
+            Some(LobbyFeed::RoomUpdated(_))
+        ));
+        hidden.kick("carol", "bob").unwrap();
+        let kicked = Membership::of(Some((room_id.as_str(), "bob")), &hidden);
+        assert!(matches!(
+            shown.updated(hidden.room.clone(), kicked.can_see(&hidden.room)),
+            Some(LobbyFeed::RoomRemoved(id)) if id == room_id
+        ));
+        assert!(shown.removed(room_id).is_none());
+    }
+}
@@ -7,80 +7,85 @@ use axum::{
     extract::Query,
     response::Html,
     response::{IntoResponse, Response},
-    Router,
+    Json, Router,
 };
+use bevygap_shared::health::{Health, HealthReport};
+use bevygap_shared::lobby::{LobbyStore, RoomChange};
 use bevygap_shared::nats::*;
-use clap::Parser;
+use bevygap_shared::supervisor::*;
 use log::*;
 use serde::{de, Deserialize, Deserializer};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use tokio::sync::broadcast;
 use std::{fmt, str::FromStr};
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::*, util::*};
 
+mod edgegap_matchmaker;
 mod session_request_handler;
 mod session_request_handler_ws;
 mod lobby;
+mod lobby_backend;
+mod lobby_janitor;
+mod lobby_ws;
+mod settings;
 
-#[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
-pub struct Settings {
-    /// Domain to allow CORS access
-    /// (ie, host serving your index.html)
-    #[arg(long, default_value = "http://localhost:8000")]
-    cors: String,
-
-    /// The ip:port to bind the http listener to
-    #[arg(long, default_value = "0.0.0.0:3000")]
-    bind: String,
-    /// Optional player limit for wannaplay() (1-4)
-    #[arg(long)]
-    player_limit: Option<u8>,
-
-    /// Maximum number of active lobby rooms
-    #[arg(long, default_value_t = default_max_rooms())]
-    max_rooms: usize,
-
-    /// A fake IP to use instead of the client IP, if the request comes from localhost.
-    ///
-    /// This is useful for local development – use your normal IP so that deployments you
-    /// trigger are geographically near by.
-    ///
-    /// The default fake IP is near London, United Kindom.
-    #[arg(long, default_value = "81.128.157.100")]
-    fake_ip: String,
-}
-
-fn default_max_rooms() -> usize {
-    std::env::var("LOBBY_MAX_ROOMS").ok().and_then(|s| s.parse().ok()).unwrap_or(10)
-}
-
-impl Settings {
-    pub fn allowed_origin(&self) -> String {
-        self.cors.trim().to_string()
-    }
-}
+use settings::{MatchmakerMode, Settings};
 
 pub(crate) struct AppState {
     pub(crate) bgnats: BevygapNats,
     pub(crate) settings: Settings,
-    pub(crate) lobby: lobby::LobbyStore,
+    pub(crate) lobby: LobbyStore,
+    /// Deploys started rooms, and mirrors rooms into an external lobby service if configured
+    pub(crate) lobby_backend: Box<dyn lobby_backend::LobbyBackend>,
+    /// Changes to lobby rooms, for the lobby websockets. Fed by `lobby_ws::watch_rooms`.
+    pub(crate) lobby_changes: broadcast::Sender<RoomChange>,
+    pub(crate) lobby_evictions: lobby_janitor::LobbyEvictions,
+    /// Set in `edgegap` matchmaker mode, when session requests become Edgegap tickets
+    pub(crate) edgegap_matchmaker: Option<edgegap_matchmaker::EdgegapMatchmaker>,
+    pub(crate) health: Health,
 }
 
+/// Health check name for requests forwarded to the matchmaker over NATS.
+const MATCHMAKER_HEALTH_CHECK: &str = "matchmaker";
+
 #[tokio::main]
 async fn main() {
     setup_logging();
-    let settings = Settings::parse();
+    let settings = Settings::load();
 
-    let bgnats = BevygapNats::new_and_connect("bevygap_matchmaker_httpd")
+    let bgnats = BevygapNats::new_and_connect_with_settings("bevygap_matchmaker_httpd", &settings.nats)
         .await
         .unwrap();
+    let lobby = LobbyStore::open(bgnats.client(), settings.max_rooms, settings.lobby_room_ttl())
+        .await
+        .expect("Failed to open the lobby rooms KV bucket");
+    let lobby_backend = lobby_backend::connect(&settings, bgnats.client())
+        .await
+        .expect("Failed to set up the lobby backend");
+    info!("Using the {} lobby backend", lobby_backend.name());
+    let edgegap_matchmaker = match settings.matchmaker_mode {
+        MatchmakerMode::Bevygap => None,
+        MatchmakerMode::Edgegap => Some(
+            edgegap_matchmaker::EdgegapMatchmaker::connect(&settings)
+                .await
+                .expect("Failed to set up the Edgegap matchmaker"),
+        ),
+    };
+    let health = Health::new();
+    let supervisor = Supervisor::new("bevygap_matchmaker_httpd", settings.supervisor.clone())
+        .with_health(health.clone());
+
     let app_state = Arc::new(AppState {
         bgnats,
-        lobby: lobby::LobbyStore::new(settings.max_rooms),
+        lobby,
+        lobby_backend,
+        lobby_changes: broadcast::channel(256).0,
+        lobby_evictions: Default::default(),
+        edgegap_matchmaker,
         settings: settings.clone(),
+        health,
     });
 
     info!(
@@ -111,6 +116,8 @@ async fn main() {
     let app = Router::new()
         .route("/", get(index_handler))
         .route("/matchmaker", get(index_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
         // this probably warrants a formtoken like system or something too..
         .route("/matchmaker/wannaplay", post(wannaplay_handler))
         .route(
@@ -127,30 +134,65 @@ async fn main() {
         .route("/lobby/api/rooms/:id/start", post(lobby::start_room))
         .route("/lobby/api/rooms/:id/join", post(lobby::join_room))
         .route("/lobby/api/rooms/:id/leave", post(lobby::leave_room))
+        .route("/lobby/api/rooms/:id/kick", post(lobby::kick_player))
+        .route("/lobby/api/rooms/:id/ready", post(lobby::set_ready))
+        .route("/lobby/api/rooms/:id/token", post(lobby::room_token))
+        .route("/lobby/ws", any(lobby_ws::handler_lobby_websocket))
         .layer(cors_layer)
-        .with_state(app_state);
+        .with_state(app_state.clone());
 
-    // run it
-    let listener = tokio::net::TcpListener::bind(settings.bind.as_str())
-        .await
-        .unwrap();
-    info!(
-        "bevygap_matchmaker_httpd listening on {}",
-        listener.local_addr().unwrap()
-    );
+    let watch_state = app_state.clone();
+    supervisor.spawn(ChildSpec::new("lobby_watch"), move |shutdown| {
+        lobby_ws::watch_rooms(watch_state.clone(), shutdown)
+    });
+    supervisor.spawn(ChildSpec::new("lobby_janitor"), move |shutdown| {
+        lobby_janitor::lobby_janitor(app_state.clone(), shutdown)
+    });
+
+
+    let bind = settings.bind.clone();
+    supervisor.spawn(ChildSpec::new("http"), move |mut shutdown| {
+        let app = app.clone();
+        let bind = bind.clone();
+        async move {
+            let listener = tokio::net::TcpListener::bind(bind.as_str()).await?;
+            info!(
+                "bevygap_matchmaker_httpd listening on {}",
+                listener.local_addr()?
+            );
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move { shutdown.cancelled().await })
+            .await
+        }
+    });
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+    tokio::select! {
+        _ = supervisor.wait() => warn!("All httpd tasks have exited"),
+        _ = termination_signal() => supervisor.shutdown().await,
+    }
 }
 
 async fn index_handler() -> Html<&'static str> {
     Html("<h1>Bevygap Matchmaker Webservice.</h1><p>Nothing to see here, move along.</p>")
 }
 
+async fn healthz_handler(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.liveness(state.bgnats.health_checks().await);
+    (health_status_code(&report), Json(report))
+}
+
+async fn readyz_handler(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.readiness(state.bgnats.health_checks().await);
+    (health_status_code(&report), Json(report))
+}
+
+fn health_status_code(report: &HealthReport) -> StatusCode {
+    StatusCode::from_u16(report.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 struct WannaplayParams {
@@ -193,7 +235,7 @@ async fn wannaplay_handler(
     // this timeout should far exceed the cutoff time in the matchmaker.
     // it is merely a last line of defense.
     let request = async_nats::client::Request::new()
-        .timeout(Some(Duration::from_secs(60)))
+        .timeout(Some(state.settings.session_request_timeout()))
         .payload(payload.into());
 
     match state
@@ -207,6 +249,7 @@ async fn wannaplay_handler(
         // to figure out if it was actually an error?
         // see: https://github.com/nats-io/nats.rs/blob/main/async-nats/tests/service_tests.rs#L245
         Ok(resp) => {
+            state.health.record_success(MATCHMAKER_HEALTH_CHECK);
             if let Some((code, msg)) = maybe_message_error(&resp) {
                 error!("Got error matchmaker response: {:?}", msg);
                 (
@@ -221,6 +264,7 @@ async fn wannaplay_handler(
         }
         Err(e) => {
             warn!("Got Err matchmaker response: {:?}", e);
+            state.health.record_failure(MATCHMAKER_HEALTH_CHECK, &e);
             match e.kind() {
                 RequestErrorKind::TimedOut => {
                     (StatusCode::REQUEST_TIMEOUT, "Request timeout").into_response()
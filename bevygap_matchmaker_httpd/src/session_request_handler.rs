@@ -10,7 +10,7 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt as _;
 
-use crate::AppState;
+use crate::{edgegap_matchmaker, AppState};
 
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
@@ -34,6 +34,22 @@ pub(crate) async fn session_chunked_responder(
     let client_ip = get_client_ip(&params, &addr, req.headers(), &state);
 
     info!("session_chunked_responder for ip {client_ip}");
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(
+        header::TRANSFER_ENCODING,
+        HeaderValue::from_static("chunked"),
+    );
+
+    if state.edgegap_matchmaker.is_some() {
+        let feedback = edgegap_matchmaker::request_session(state, client_ip);
+        let stream = tokio_stream::wrappers::ReceiverStream::new(feedback)
+            .map(|msg| Ok::<String, Infallible>(serde_json::to_string(&msg).unwrap()));
+        return (headers, Body::from_stream(stream));
+    }
     // should include app name/ver?
     let payload = format!("{{\"client_ip\":\"{client_ip}\"}}");
 
@@ -77,16 +93,6 @@ pub(crate) async fn session_chunked_responder(
 
     let stream = tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok::<String, Infallible>);
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_static("application/json"),
-    );
-    headers.insert(
-        header::TRANSFER_ENCODING,
-        HeaderValue::from_static("chunked"),
-    );
-
     (
         headers,
         Body::from_stream(stream), // Wrap the stream in an HTTP body for chunked transfer
@@ -102,7 +108,7 @@ pub(crate) async fn session_chunked_responder(
 ///
 /// Additionally if they above yields a localhost address, we replace it with
 /// settings.fake_ip, which is also useful for dev.
-fn get_client_ip(
+pub(crate) fn get_client_ip(
     params: &QsParams,
     addr: &SocketAddr,
     headers: &HeaderMap,
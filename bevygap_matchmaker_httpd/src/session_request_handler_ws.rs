@@ -16,7 +16,7 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio_stream::StreamExt as _;
 
-use crate::AppState;
+use crate::{edgegap_matchmaker, AppState};
 
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
@@ -114,18 +114,43 @@ async fn handle_socket_inner(
 
     let (game_name, game_ver) = request_session.game_name_and_version()?;
 
+    if state.edgegap_matchmaker.is_some() {
+        // the matchmaker release decides the game and where it's placed
+        let mut feedback = edgegap_matchmaker::request_session(state, client_ip);
+        while let Some(msg) = feedback.recv().await {
+            let chunk = serde_json::to_string(&msg).unwrap();
+            info!("> {chunk}");
+            if socket.send(Message::Text(chunk)).await.is_err() {
+                return Err("Can't send chunk to ws client".to_string());
+            }
+        }
+        return Ok(());
+    }
+
     let subject = format!("matchmaker.request.{game_name}.{game_ver}");
 
-    // this should be safe because of our regex check on name and version..
-    let payload = if let Some(limit) = request_session.player_limit {
-        format!(
-            r#"{{"client_ip":"{client_ip}", "game":"{game_name}", "version":"{game_ver}", "player_limit": {limit}}}"#
-        )
-    } else {
-        format!(
-            r#"{{"client_ip":"{client_ip}", "game":"{game_name}", "version":"{game_ver}"}}"#
-        )
-    };
+    let mut payload = serde_json::json!({
+        "client_ip": client_ip,
+        "game": game_name,
+        "version": game_ver,
+    });
+    if let Some(limit) = request_session.player_limit {
+        payload["player_limit"] = limit.into();
+    }
+    // the matchmaker checks these against its allow-list
+    if !request_session.placement.is_empty() {
+        payload["placement"] = serde_json::to_value(&request_session.placement).unwrap();
+    }
+    if let Some(session_id) = &request_session.session_id {
+        payload["session_id"] = session_id.clone().into();
+    }
+    if let Some(join_token) = &request_session.join_token {
+        payload["join_token"] = join_token.clone().into();
+    }
+    if let Some(client_id) = request_session.reconnect_client_id {
+        payload["reconnect_client_id"] = client_id.into();
+    }
+    let payload = payload.to_string();
 
     info!("Sending request to {subject} with payload {payload}");
 
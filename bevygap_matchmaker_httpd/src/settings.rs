@@ -0,0 +1,222 @@
+use bevygap_shared::config::*;
+use bevygap_shared::nats::NatsSettings;
+use bevygap_shared::supervisor::SupervisorSettings;
+use clap::Parser;
+use edgegap_async::{EdgegapClient, EdgegapError};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Prefix for env var overrides, eg `BEVYGAP_HTTPD_BIND=0.0.0.0:8080`.
+const ENV_PREFIX: &str = "BEVYGAP_HTTPD";
+
+/// Command line args. Anything given here overrides the config file and env vars.
+#[derive(Parser, Serialize, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    #[serde(skip)]
+    config: ConfigArgs,
+    /// Domain to allow CORS access
+    /// (ie, host serving your index.html)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cors: Option<String>,
+    /// The ip:port to bind the http listener to
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind: Option<String>,
+    /// Optional player limit for wannaplay() (1-4)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    player_limit: Option<u8>,
+    /// Maximum number of active lobby rooms
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_rooms: Option<usize>,
+    /// A fake IP to use instead of the client IP, if the request comes from localhost.
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fake_ip: Option<String>,
+}
+
+/// Where lobby rooms are deployed from, see the `lobby_backend` module.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LobbyBackendKind {
+    /// Ask the matchmaker for a dedicated deployment over NATS
+    Builtin,
+    /// Mirror rooms into an Edgegap Lobbies service, which starts the deployment
+    Edgegap,
+}
+
+/// Who matches session requests to a gameserver.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchmakerMode {
+    /// bevygap_matchmaker creates an Edgegap session for each request
+    Bevygap,
+    /// Requests become tickets for an Edgegap-managed matchmaker
+    Edgegap,
+}
+
+/// Effective httpd config, see `ConfigLoader` for how the layers are combined.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// Domain to allow CORS access
+    /// (ie, host serving your index.html)
+    pub(crate) cors: String,
+    /// The ip:port to bind the http listener to
+    pub(crate) bind: String,
+    /// Optional player limit for wannaplay() (1-4)
+    pub(crate) player_limit: Option<u8>,
+    /// Maximum number of active lobby rooms. Also read from LOBBY_MAX_ROOMS.
+    pub(crate) max_rooms: usize,
+    /// A fake IP to use instead of the client IP, if the request comes from localhost.
+    ///
+    /// This is useful for local development – use your normal IP so that deployments you
+    /// trigger are geographically near by.
+    ///
+    /// The default fake IP is near London, United Kindom.
+    pub(crate) fake_ip: String,
+    /// Timeout for session requests made to the matchmaker over NATS.
+    /// This should far exceed the matchmaker's own session creation timeout.
+    pub(crate) session_request_timeout_secs: u64,
+    /// Timeout for lobby room deployments, requested from the matchmaker over NATS or started
+    /// by the Edgegap Lobbies service. This should exceed the matchmaker's
+    /// `lobby_deploy_timeout_secs`.
+    pub(crate) lobby_deploy_timeout_secs: u64,
+    /// Lobby rooms are kept in NATS KV, and dropped if they aren't changed for this long.
+    /// Only applies when the `lobby_rooms` bucket is first created.
+    pub(crate) lobby_room_ttl_secs: u64,
+    /// Waiting lobby rooms that haven't changed for this long are removed by the janitor.
+    pub(crate) lobby_idle_ttl_secs: u64,
+    /// How often the lobby janitor looks for idle rooms.
+    pub(crate) lobby_janitor_interval_secs: u64,
+    /// Which lobby backend deploys started rooms, see `lobby_backend`.
+    pub(crate) lobby_backend: LobbyBackendKind,
+    /// Edgegap API key, including the "token " prefix, for the `edgegap` lobby backend.
+    /// Also read from EDGEGAP_API_KEY.
+    pub(crate) edgegap_api_key: String,
+    /// Read the Edgegap API key from this file instead, eg a docker secret.
+    /// Also read from EDGEGAP_API_KEY_FILE.
+    pub(crate) edgegap_api_key_file: Option<PathBuf>,
+    pub(crate) edgegap_base_path: String,
+    /// Name of the Edgegap Lobbies service to use, which is created and deployed if needed.
+    pub(crate) edgegap_lobby_name: String,
+    /// Authorization header for the Edgegap Lobbies service, required by the `edgegap` lobby
+    /// backend. The Edgegap API key is never used for this, since it'd be sent to the service.
+    pub(crate) edgegap_lobby_token: Option<String>,
+    /// How often to poll Edgegap while waiting for the lobby service or a room's deployment.
+    pub(crate) edgegap_lobby_poll_interval_ms: u64,
+    /// Who finds players a gameserver, see `edgegap_matchmaker`.
+    pub(crate) matchmaker_mode: MatchmakerMode,
+    /// The Edgegap-managed matchmaker, and release, to send tickets to in `edgegap` mode
+    pub(crate) edgegap_matchmaker_name: String,
+    pub(crate) edgegap_matchmaker_release: String,
+    /// The matchmaker release's API URL, from the Edgegap dashboard
+    pub(crate) edgegap_matchmaker_url: String,
+    /// Authorization header for the matchmaker's API, required in `edgegap` mode. The Edgegap
+    /// API key is never used for this, since it'd be sent to `edgegap_matchmaker_url`.
+    pub(crate) edgegap_matchmaker_token: Option<String>,
+    /// The matchmaking profile tickets are created with
+    pub(crate) edgegap_matchmaker_profile: String,
+    /// How often to poll a ticket while waiting for it to be assigned a gameserver
+    pub(crate) edgegap_matchmaker_poll_interval_ms: u64,
+    /// Restart backoff and limits for the background tasks
+    pub(crate) supervisor: SupervisorSettings,
+    pub(crate) nats: NatsSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            cors: "http://localhost:8000".to_string(),
+            bind: "0.0.0.0:3000".to_string(),
+            player_limit: None,
+            max_rooms: 10,
+            fake_ip: "81.128.157.100".to_string(),
+            session_request_timeout_secs: 60,
+            lobby_deploy_timeout_secs: 180,
+            lobby_room_ttl_secs: 3600,
+            lobby_idle_ttl_secs: 600,
+            lobby_janitor_interval_secs: 30,
+            lobby_backend: LobbyBackendKind::Builtin,
+            edgegap_api_key: String::new(),
+            edgegap_api_key_file: None,
+            edgegap_base_path: "https://api.edgegap.com/".to_string(),
+            edgegap_lobby_name: "bevygap-lobby".to_string(),
+            edgegap_lobby_token: None,
+            edgegap_lobby_poll_interval_ms: 1000,
+            matchmaker_mode: MatchmakerMode::Bevygap,
+            edgegap_matchmaker_name: String::new(),
+            edgegap_matchmaker_release: String::new(),
+            edgegap_matchmaker_url: String::new(),
+            edgegap_matchmaker_token: None,
+            edgegap_matchmaker_profile: "default".to_string(),
+            edgegap_matchmaker_poll_interval_ms: 1000,
+            supervisor: SupervisorSettings::default(),
+            nats: NatsSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Parses the command line, then loads the layered config.
+    pub fn load() -> Self {
+        let cli = Cli::parse();
+        let loader = ConfigLoader::new(ENV_PREFIX)
+            .env_alias("LOBBY_MAX_ROOMS", "max_rooms")
+            .env_alias("EDGEGAP_API_KEY", "edgegap_api_key")
+            .env_alias("EDGEGAP_API_KEY_FILE", "edgegap_api_key_file")
+            .with_nats_env_aliases();
+        load_or_exit(loader, &cli.config, &cli, |settings: &Self| {
+            settings.nats.validate()
+        })
+    }
+
+    pub fn allowed_origin(&self) -> String {
+        self.cors.trim().to_string()
+    }
+
+    pub(crate) fn session_request_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_request_timeout_secs)
+    }
+
+    pub(crate) fn lobby_deploy_timeout(&self) -> Duration {
+        Duration::from_secs(self.lobby_deploy_timeout_secs)
+    }
+
+    pub(crate) fn lobby_room_ttl(&self) -> Duration {
+        Duration::from_secs(self.lobby_room_ttl_secs)
+    }
+
+    pub(crate) fn lobby_idle_ttl(&self) -> Duration {
+        Duration::from_secs(self.lobby_idle_ttl_secs)
+    }
+
+    pub(crate) fn lobby_janitor_interval(&self) -> Duration {
+        Duration::from_secs(self.lobby_janitor_interval_secs.max(1))
+    }
+
+    pub(crate) fn edgegap_lobby_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.edgegap_lobby_poll_interval_ms)
+    }
+
+    pub(crate) fn edgegap_matchmaker_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.edgegap_matchmaker_poll_interval_ms)
+    }
+
+    /// A client for the Edgegap API, for the `edgegap` lobby backend and matchmaker.
+    pub(crate) fn edgegap_client(&self) -> Result<EdgegapClient, EdgegapError> {
+        let client = if !self.edgegap_api_key.is_empty() {
+            EdgegapClient::new(self.edgegap_api_key.clone())
+        } else if let Some(path) = &self.edgegap_api_key_file {
+            EdgegapClient::from_key_file(path)?
+        } else {
+            return Err(EdgegapError::MissingApiKey);
+        };
+        Ok(client.with_base_path(self.edgegap_base_path.clone()))
+    }
+}
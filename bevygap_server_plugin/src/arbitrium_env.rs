@@ -1,4 +1,8 @@
 use bevy::prelude::*;
+use edgegap_async::models::{DeploymentLocation, PortMapping};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
 
 /// Represents the environment variables provided by Arbitrium for deployments.
 #[derive(Debug, Clone, Resource)]
@@ -9,33 +13,87 @@ pub struct ArbitriumEnv {
     pub delete_url: String,
     /// Authorization token to call ARBITRIUM_DELETE_URL.
     pub delete_token: String,
-    /// JSON encoded string that contains data about the location of your deployment.
-    pub deployment_location: String,
+    /// Where your deployment is, from ARBITRIUM_DEPLOYMENT_LOCATION.
+    pub deployment_location: DeploymentLocation,
     /// URL to get the context of your deployment. Visit the API documentation for more details about this route.
     pub context_url: String,
     /// Authorization token to call ARBITRIUM_CONTEXT_URL.
     pub context_token: String,
     /// The public IP of your deployment.
     pub public_ip: String,
-    /// JSON string of the ports mapping of your deployment.
-    pub ports_mapping: String,
+    /// Your deployment's ports, by name, from ARBITRIUM_PORTS_MAPPING.
+    pub ports: HashMap<String, PortMapping>,
+}
+
+/// Why the Arbitrium env vars couldn't be read.
+#[derive(Debug)]
+pub enum ArbitriumEnvError {
+    /// The env var isn't set, or isn't unicode
+    Missing(&'static str, std::env::VarError),
+    /// The env var isn't the JSON Edgegap documents, or is missing fields
+    Invalid(&'static str, serde_json::Error),
+}
+
+impl fmt::Display for ArbitriumEnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArbitriumEnvError::Missing(var, e) => write!(f, "{var}: {e}"),
+            ArbitriumEnvError::Invalid(var, e) => write!(f, "{var} is invalid: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ArbitriumEnvError {}
+
+/// The shape of ARBITRIUM_PORTS_MAPPING
+#[derive(Deserialize)]
+struct PortsMapping {
+    ports: HashMap<String, PortMapping>,
+}
+
+/// Reads an env var: `std::env::var`, or a stand-in for tests.
+type Lookup<'a> = &'a dyn Fn(&'static str) -> Result<String, std::env::VarError>;
+
+fn var(lookup: Lookup, name: &'static str) -> Result<String, ArbitriumEnvError> {
+    lookup(name).map_err(|e| ArbitriumEnvError::Missing(name, e))
+}
+
+fn json_var<T: serde::de::DeserializeOwned>(
+    lookup: Lookup,
+    name: &'static str,
+) -> Result<T, ArbitriumEnvError> {
+    serde_json::from_str(&var(lookup, name)?).map_err(|e| ArbitriumEnvError::Invalid(name, e))
 }
 
 impl ArbitriumEnv {
     /// Creates a new instance of `ArbitriumEnv` from environment variables.
-    pub fn from_env() -> Result<Self, std::env::VarError> {
+    pub fn from_env() -> Result<Self, ArbitriumEnvError> {
+        Self::from_vars(&std::env::var)
+    }
+
+    fn from_vars(lookup: Lookup) -> Result<Self, ArbitriumEnvError> {
         Ok(Self {
-            request_id: std::env::var("ARBITRIUM_REQUEST_ID")?,
-            delete_url: std::env::var("ARBITRIUM_DELETE_URL")?,
-            delete_token: std::env::var("ARBITRIUM_DELETE_TOKEN")?,
-            deployment_location: std::env::var("ARBITRIUM_DEPLOYMENT_LOCATION")?,
-            context_url: std::env::var("ARBITRIUM_CONTEXT_URL")?,
-            context_token: std::env::var("ARBITRIUM_CONTEXT_TOKEN")?,
-            public_ip: std::env::var("ARBITRIUM_PUBLIC_IP")?,
-            ports_mapping: std::env::var("ARBITRIUM_PORTS_MAPPING")?,
+            request_id: var(lookup, "ARBITRIUM_REQUEST_ID")?,
+            delete_url: var(lookup, "ARBITRIUM_DELETE_URL")?,
+            delete_token: var(lookup, "ARBITRIUM_DELETE_TOKEN")?,
+            deployment_location: json_var(lookup, "ARBITRIUM_DEPLOYMENT_LOCATION")?,
+            context_url: var(lookup, "ARBITRIUM_CONTEXT_URL")?,
+            context_token: var(lookup, "ARBITRIUM_CONTEXT_TOKEN")?,
+            public_ip: var(lookup, "ARBITRIUM_PUBLIC_IP")?,
+            ports: json_var::<PortsMapping>(lookup, "ARBITRIUM_PORTS_MAPPING")?.ports,
         })
     }
 
+    /// The port mapping with this name, eg "gameport".
+    pub fn port(&self, name: &str) -> Option<&PortMapping> {
+        self.ports.get(name)
+    }
+
+    /// The port players connect to for the mapping with this name, eg "gameport".
+    pub fn external_port(&self, name: &str) -> Option<u16> {
+        self.port(name)?.external?.try_into().ok()
+    }
+
     /// Returns a tuple containing the request_id and security_number extracted from the context_url.
     /// The security_number is parsed as an i32.
     pub fn context_parts(&self) -> Option<(String, i32)> {
@@ -49,3 +107,106 @@ impl ArbitriumEnv {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::VarError;
+
+    const PORTS_MAPPING: &str = r#"{"ports": {
+        "gameport": {"name": "gameport", "internal": 6420, "external": 31504, "protocol": "UDP", "tls_upgrade": false},
+        "cert": {"name": "cert", "internal": 6421, "protocol": "HTTP"}
+    }}"#;
+
+    const DEPLOYMENT_LOCATION: &str = r#"{"city": "Montreal", "country": "Canada",
+        "continent": "North America", "administrative_division": "Quebec",
+        "timezone": "America/Toronto", "latitude": 45.5019, "longitude": -73.5674}"#;
+
+    fn vars(overrides: &[(&'static str, &str)]) -> HashMap<&'static str, String> {
+        let mut vars: HashMap<&'static str, String> = [
+            ("ARBITRIUM_REQUEST_ID", "93924761ccde"),
+            (
+                "ARBITRIUM_DELETE_URL",
+                "https://api.edgegap.com/v1/self/stop/93924761ccde/4567",
+            ),
+            ("ARBITRIUM_DELETE_TOKEN", "delete-token"),
+            ("ARBITRIUM_DEPLOYMENT_LOCATION", DEPLOYMENT_LOCATION),
+            (
+                "ARBITRIUM_CONTEXT_URL",
+                "https://api.edgegap.com/v1/context/93924761ccde/1234",
+            ),
+            ("ARBITRIUM_CONTEXT_TOKEN", "context-token"),
+            ("ARBITRIUM_PUBLIC_IP", "162.254.141.66"),
+            ("ARBITRIUM_PORTS_MAPPING", PORTS_MAPPING),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k, v.to_string()))
+        .collect();
+        for (name, value) in overrides {
+            vars.insert(name, value.to_string());
+        }
+        vars
+    }
+
+    fn parse(vars: &HashMap<&'static str, String>) -> Result<ArbitriumEnv, ArbitriumEnvError> {
+        ArbitriumEnv::from_vars(&|name| vars.get(name).cloned().ok_or(VarError::NotPresent))
+    }
+
+    #[test]
+    fn test_parses_ports_and_location() {
+        let env = parse(&vars(&[])).unwrap();
+        assert_eq!(env.request_id, "93924761ccde");
+        assert_eq!(env.deployment_location.city, "Montreal");
+        assert_eq!(env.deployment_location.latitude, 45.5019);
+        assert_eq!(env.port("gameport").unwrap().internal, Some(6420));
+        assert_eq!(env.external_port("gameport"), Some(31504));
+        // mapped, but without an external port
+        assert_eq!(env.external_port("cert"), None);
+        assert_eq!(env.external_port("nope"), None);
+    }
+
+    #[test]
+    fn test_context_parts() {
+        let env = parse(&vars(&[])).unwrap();
+        assert_eq!(
+            env.context_parts(),
+            Some(("93924761ccde".to_string(), 1234))
+        );
+        let env = parse(&vars(&[(
+            "ARBITRIUM_CONTEXT_URL",
+            "https://api.edgegap.com/v1/context/x",
+        )]))
+        .unwrap();
+        assert_eq!(env.context_parts(), None);
+    }
+
+    #[test]
+    fn test_malformed_and_missing_vars() {
+        let err = parse(&vars(&[(
+            "ARBITRIUM_PORTS_MAPPING",
+            r#"{"gameport": 31504}"#,
+        )]))
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ArbitriumEnvError::Invalid("ARBITRIUM_PORTS_MAPPING", _)
+        ));
+        let err = parse(&vars(&[(
+            "ARBITRIUM_DEPLOYMENT_LOCATION",
+            r#"{"city": "Montreal"}"#,
+        )]))
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ArbitriumEnvError::Invalid("ARBITRIUM_DEPLOYMENT_LOCATION", _)
+        ));
+
+        let mut missing = vars(&[]);
+        missing.remove("ARBITRIUM_PUBLIC_IP");
+        let err = parse(&missing).unwrap_err();
+        assert!(matches!(
+            err,
+            ArbitriumEnvError::Missing("ARBITRIUM_PUBLIC_IP", _)
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+//! Decides which lightyear clients may connect.
+//!
+//! The matchmaker writes every client id it issues a connect token for into `sessions_ly2eg`,
+//! keyed by the deployment's request id, along with the session it joined. We watch our own keys, and keep the client ids in memory,
+//! so lightyear can ask whether to accept a client without waiting on NATS.
+//!
+//! A client that disconnects keeps its place for `reconnect_grace`. If the matchmaker issues its
+//! client id a new token in that time, it can connect again as the same player.
+//!
+//! If the watch has to be restarted, it resumes after the last revision we saw, so tokens that
+//! were already used aren't replayed from the bucket's history and accepted again.
+use async_nats::jetstream::kv::{Operation, Store};
+use bevy::prelude::*;
+use bevygap_shared::nats::{client_session_key, SESSION_MAPPING_MAX_AGE};
+use bevygap_shared::protocol::SessionPlayer;
+use futures::StreamExt;
+use lightyear::connection::shared::{ConnectionRequestHandler, DeniedReason};
+use lightyear::prelude::PeerId;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Holds the [`BevygapConnectionRequestHandler`], which is created when the plugin is built.
+/// Hand [`CRH::handler`] to lightyear's netcode server config, so it's asked about each client.
+#[derive(Resource)]
+pub struct CRH(pub(crate) Arc<BevygapConnectionRequestHandler>);
+
+impl CRH {
+    pub fn handler(&self) -> Arc<BevygapConnectionRequestHandler> {
+        self.0.clone()
+    }
+}
+
+/// Only accepts connections from client ids the matchmaker issued a connect token for on this
+/// deployment, and only once each.
+#[derive(Clone, Debug, Default)]
+pub struct BevygapConnectionRequestHandler {
+    clients: Arc<Mutex<Clients>>,
+    /// Accept any client with a valid connect token, whether or not the matchmaker issued it.
+    /// For local development, where tokens are signed with a local key.
+    accept_any: bool,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    /// Client ids issued a token for this deployment, that haven't connected yet
+    issued: HashMap<u64, IssuedClient>,
+    /// Connected client ids, and their sessions if the matchmaker issued them a token. Several
+    /// clients can share a session.
+    connected: HashMap<u64, Option<SessionPlayer>>,
+    /// Clients that disconnected within `reconnect_grace`, whose places we're keeping in case the
+    /// matchmaker issues them a new token
+    dropped: HashMap<u64, DroppedClient>,
+    /// How long to keep a disconnected client's place for
+    reconnect_grace: Duration,
+    /// Deny new clients once this many are connected
+    max_clients: Option<usize>,
+    /// Deny all new clients, because we're shutting down
+    draining: bool,
+}
+
+#[derive(Debug)]
+struct IssuedClient {
+    player: SessionPlayer,
+    /// When the bucket entry expires
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct DroppedClient {
+    player: SessionPlayer,
+    dropped_at: Instant,
+}
+
+/// Whether a client that was let in has been here before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientConnection {
+    New,
+    /// Connected again within `reconnect_grace` of dropping, with a new token for the same
+    /// client id
+    Reconnected,
+}
+
+impl Clients {
+    /// The bucket drops expired entries without telling watchers, so we do the same.
+    fn expire_issued(&mut self) {
+        let now = Instant::now();
+        self.issued.retain(|_, issued| issued.expires_at > now);
+    }
+
+    fn expire_dropped(&mut self) {
+        let grace = self.reconnect_grace;
+        self.dropped
+            .retain(|_, dropped| dropped.dropped_at.elapsed() < grace);
+    }
+
+    /// Places taken by connected clients, and dropped ones other than this client.
+    fn places_taken(&self, client_id: u64) -> usize {
+        let dropped = self.dropped.keys().filter(|id| **id != client_id).count();
+        self.connected.len() + dropped
+    }
+}
+
+impl BevygapConnectionRequestHandler {
+    /// A handler for local development, which accepts any client.
+    pub(crate) fn accepting_any() -> Self {
+        Self {
+            accept_any: true,
+            ..default()
+        }
+    }
+
+    fn clients(&self) -> MutexGuard<'_, Clients> {
+        // the lock is never held across anything that can panic, so its data is fine
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Denies new clients with `ServerFull` once this many are connected.
+    pub fn set_max_clients(&self, max_clients: Option<usize>) {
+        self.clients().max_clients = max_clients;
+    }
+
+    pub(crate) fn set_draining(&self, draining: bool) {
+        self.clients().draining = draining;
+    }
+
+    /// Keeps the places of disconnected clients for this long, so they can reconnect.
+    pub(crate) fn set_reconnect_grace(&self, reconnect_grace: Duration) {
+        self.clients().reconnect_grace = reconnect_grace;
+    }
+
+    /// Number of currently connected clients.
+    pub fn num_connected(&self) -> usize {
+        self.clients().connected.len()
+    }
+
+    /// Records a client id the matchmaker issued a token for, `age` ago. It can't be used once
+    /// the bucket entry would have expired.
+    fn client_issued(&self, client_id: u64, player: SessionPlayer, age: Duration) {
+        let Some(lifetime) = SESSION_MAPPING_MAX_AGE.checked_sub(age) else {
+            return;
+        };
+        let expires_at = Instant::now() + lifetime;
+        let mut clients = self.clients();
+        clients.expire_issued();
+        clients
+            .issued
+            .insert(client_id, IssuedClient { player, expires_at });
+    }
+
+    fn client_revoked(&self, client_id: u64) {
+        self.clients().issued.remove(&client_id);
+    }
+
+    /// Marks a client as connected, returning `None` if it wasn't issued a token.
+    pub(crate) fn client_connected(&self, client_id: u64) -> Option<ClientConnection> {
+        let mut clients = self.clients();
+        let player = match clients.issued.remove(&client_id) {
+            Some(issued) => Some(issued.player),
+            None if self.accept_any => None,
+            None => return None,
+        };
+        clients.expire_dropped();
+        let connection = match clients.dropped.remove(&client_id) {
+            Some(_) => ClientConnection::Reconnected,
+            None => ClientConnection::New,
+        };
+        clients.connected.insert(client_id, player);
+        Some(connection)
+    }
+
+    /// Forgets a connected client, returning false if it wasn't connected. Its token can't be
+    /// used again, but its place is kept for `reconnect_grace`, in case the matchmaker issues it
+    /// a new one.
+    pub(crate) fn client_disconnected(&self, client_id: u64) -> bool {
+        let mut clients = self.clients();
+        clients.expire_dropped();
+        let Some(player) = clients.connected.remove(&client_id) else {
+            return false;
+        };
+        // clients the matchmaker didn't issue a token to can't ask it for another
+        if let Some(player) = player {
+            if !clients.reconnect_grace.is_zero() {
+                let dropped_at = Instant::now();
+                clients
+                    .dropped
+                    .insert(client_id, DroppedClient { player, dropped_at });
+            }
+        }
+        true
+    }
+
+    /// The session a connected client's token was issued for.
+    pub(crate) fn session_player(&self, client_id: u64) -> Option<SessionPlayer> {
+        self.clients().connected.get(&client_id)?.clone()
+    }
+}
+
+impl ConnectionRequestHandler for BevygapConnectionRequestHandler {
+    fn handle_request(&self, client_id: PeerId) -> Option<DeniedReason> {
+        // only netcode clients have a connect token from the matchmaker
+        let PeerId::Netcode(id) = client_id else {
+            warn!("Denying {client_id:?}, not a netcode client");
+            return Some(DeniedReason::InvalidToken);
+        };
+        let mut clients = self.clients();
+        clients.expire_issued();
+        clients.expire_dropped();
+        let denied = if clients.draining {
+            Some(DeniedReason::Custom("Server is shutting down".to_string()))
+        } else if clients.connected.contains_key(&id) {
+            Some(DeniedReason::AlreadyConnected)
+        } else if !self.accept_any && !clients.issued.contains_key(&id) {
+            Some(DeniedReason::InvalidToken)
+        } else if clients
+            .max_clients
+            .is_some_and(|max| clients.places_taken(id) >= max)
+        {
+            Some(DeniedReason::ServerFull)
+        } else {
+            None
+        };
+        match &denied {
+            Some(reason) => info!("Denying client {id}: {reason:?}"),
+            None => info!("Accepting client {id}"),
+        }
+        denied
+    }
+}
+
+/// Keeps the handler's issued client ids up to date from `sessions_ly2eg`, re-watching if the
+/// watch fails.
+pub(crate) async fn watch_issued_client_ids(
+    handler: Arc<BevygapConnectionRequestHandler>,
+    kv_c2s: Store,
+    request_id: String,
+) {
+    let prefix = client_session_key(&request_id, "");
+    let key = format!("{prefix}*");
+    // the last revision we've handled, so a restarted watch doesn't replay used tokens
+    let mut last_revision = None;
+    loop {
+        let watch = match last_revision {
+            None => kv_c2s.watch_with_history(&key).await,
+            Some(revision) => kv_c2s.watch_from_revision(&key, revision + 1).await,
+        };
+        let mut watcher = match watch {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("Failed to watch for issued client ids: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        info!("Watching for client ids issued for {request_id}");
+        while let Some(entry) = watcher.next().await {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("KV event error watching for issued client ids: {e:?}");
+                    continue;
+                }
+            };
+            last_revision = Some(entry.revision);
+            let Some(client_id) = entry
+                .key
+                .strip_prefix(&prefix)
+                .and_then(|id| id.parse::<u64>().ok())
+            else {
+                warn!("Ignoring unexpected client id key {}", entry.key);
+                continue;
+            };
+            if entry.operation != Operation::Put {
+                handler.client_revoked(client_id);
+                continue;
+            }
+            match serde_json::from_slice::<SessionPlayer>(&entry.value) {
+                Ok(player) => {
+                    info!(
+                        "Client id {client_id} issued for session {}",
+                        player.session_id
+                    );
+                    let age = SystemTime::now()
+                        .duration_since(SystemTime::from(entry.created))
+                        .unwrap_or_default();
+                    handler.client_issued(client_id, player, age);
+                }
+                Err(e) => warn!("Invalid session for client id {client_id}: {e}"),
+            }
+        }
+        warn!("Issued client ids watch ended, restarting");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(session_id: &str) -> SessionPlayer {
+        SessionPlayer {
+            session_id: session_id.to_string(),
+            client_ip: "1.2.3.4".to_string(),
+        }
+    }
+
+    fn handler() -> BevygapConnectionRequestHandler {
+        let handler = BevygapConnectionRequestHandler::default();
+        handler.set_reconnect_grace(Duration::from_secs(30));
+        handler
+    }
+
+    fn request(handler: &BevygapConnectionRequestHandler, id: u64) -> Option<DeniedReason> {
+        handler.handle_request(PeerId::Netcode(id))
+    }
+
+    #[test]
+    fn test_denies_ids_not_issued() {
+        let handler = handler();
+        assert!(matches!(
+            request(&handler, 1),
+            Some(DeniedReason::InvalidToken)
+        ));
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        assert!(request(&handler, 1).is_none());
+        assert_eq!(handler.client_connected(2), None);
+
+        // entries older than the bucket's max age have expired
+        handler.client_issued(2, player("s1"), SESSION_MAPPING_MAX_AGE);
+        assert!(matches!(
+            request(&handler, 2),
+            Some(DeniedReason::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_denies_already_connected() {
+        let handler = handler();
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        assert_eq!(handler.client_connected(1), Some(ClientConnection::New));
+        assert!(matches!(
+            request(&handler, 1),
+            Some(DeniedReason::AlreadyConnected)
+        ));
+        assert_eq!(handler.session_player(1), Some(player("s1")));
+
+        // its token was used up, so it can't come back without a new one
+        assert!(handler.client_disconnected(1));
+        assert!(matches!(
+            request(&handler, 1),
+            Some(DeniedReason::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_denial_order() {
+        let handler = handler();
+        handler.set_max_clients(Some(1));
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        handler.client_issued(2, player("s1"), Duration::ZERO);
+        handler.client_connected(1);
+
+        assert!(matches!(
+            request(&handler, 1),
+            Some(DeniedReason::AlreadyConnected)
+        ));
+        assert!(matches!(
+            request(&handler, 3),
+            Some(DeniedReason::InvalidToken)
+        ));
+        assert!(matches!(
+            request(&handler, 2),
+            Some(DeniedReason::ServerFull)
+        ));
+
+        handler.set_draining(true);
+        for id in 1..=3 {
+            assert!(matches!(
+                request(&handler, id),
+                Some(DeniedReason::Custom(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_server_full_counts_dropped_places_except_own() {
+        let handler = handler();
+        handler.set_max_clients(Some(2));
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        handler.client_issued(2, player("s1"), Duration::ZERO);
+        handler.client_connected(1);
+        handler.client_connected(2);
+        handler.client_disconnected(2);
+        assert_eq!(handler.num_connected(), 1);
+
+        // 2's place is kept for it, so 3 can't take it
+        handler.client_issued(3, player("s2"), Duration::ZERO);
+        assert!(matches!(
+            request(&handler, 3),
+            Some(DeniedReason::ServerFull)
+        ));
+        handler.client_issued(2, player("s1"), Duration::ZERO);
+        assert!(request(&handler, 2).is_none());
+    }
+
+    #[test]
+    fn test_reconnected_within_grace() {
+        let handler = handler();
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        assert_eq!(handler.client_connected(1), Some(ClientConnection::New));
+        assert!(handler.client_disconnected(1));
+        assert!(!handler.client_disconnected(1));
+
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        assert_eq!(
+            handler.client_connected(1),
+            Some(ClientConnection::Reconnected)
+        );
+
+        // without a grace period, dropped clients' places aren't kept
+        let handler = BevygapConnectionRequestHandler::default();
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        handler.client_connected(1);
+        handler.client_disconnected(1);
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        assert_eq!(handler.client_connected(1), Some(ClientConnection::New));
+    }
+
+    #[test]
+    fn test_revoked_on_delete() {
+        let handler = handler();
+        handler.client_issued(1, player("s1"), Duration::ZERO);
+        handler.client_revoked(1);
+        assert!(matches!(
+            request(&handler, 1),
+            Some(DeniedReason::InvalidToken)
+        ));
+        assert_eq!(handler.client_connected(1), None);
+    }
+
+    #[test]
+    fn test_accepting_any() {
+        let handler = BevygapConnectionRequestHandler::accepting_any();
+        assert!(request(&handler, 7).is_none());
+        assert_eq!(handler.client_connected(7), Some(ClientConnection::New));
+        assert_eq!(handler.session_player(7), None);
+    }
+}
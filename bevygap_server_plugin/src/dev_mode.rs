@@ -0,0 +1,97 @@
+//! Running the gameserver locally, without Edgegap, and optionally without NATS.
+//!
+//! Instead of reading the `ARBITRIUM_*` env vars and fetching the deployment context, we make
+//! them up from [`BevygapDevConfig`]. Any client with a valid connect token is accepted, so
+//! tokens signed with the game's local private key work without a matchmaker.
+use crate::arbitrium_env::ArbitriumEnv;
+use crate::edgegap_context::{ArbitriumContext, ContextLoaded};
+use crate::plugin::BevygapServerConfig;
+use bevy::prelude::*;
+use edgegap_async::models;
+use log::info;
+use std::collections::HashMap;
+
+/// Settings for local development, see [`BevygapServerConfig::dev`].
+#[derive(Debug, Clone)]
+pub struct BevygapDevConfig {
+    /// Stands in for the deployment's request id
+    pub request_id: String,
+    /// Stands in for the deployment's public IP
+    pub public_ip: String,
+    /// The port the gameserver listens on, reported as the deployment's only port
+    pub port: u16,
+    /// Connect to NATS as usual, using the `NATS_*` env vars. Otherwise run without NATS.
+    pub nats: bool,
+}
+
+impl Default for BevygapDevConfig {
+    fn default() -> Self {
+        Self {
+            request_id: "local".to_string(),
+            public_ip: "127.0.0.1".to_string(),
+            port: 6420,
+            nats: false,
+        }
+    }
+}
+
+impl BevygapDevConfig {
+    /// Env for a deployment that can't be deleted, and has no context url.
+    pub(crate) fn arbitrium_env(&self) -> ArbitriumEnv {
+        ArbitriumEnv {
+            request_id: self.request_id.clone(),
+            delete_url: String::new(),
+            delete_token: String::new(),
+            deployment_location: self.location(),
+            context_url: String::new(),
+            context_token: String::new(),
+            public_ip: self.public_ip.clone(),
+            ports: self.ports(),
+        }
+    }
+
+    fn location(&self) -> models::DeploymentLocation {
+        models::DeploymentLocation {
+            city: "Localhost".to_string(),
+            country: "Local".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Our only port, named "gameport"
+    fn ports(&self) -> HashMap<String, models::PortMapping> {
+        let port = models::PortMapping {
+            external: Some(self.port.into()),
+            internal: Some(self.port.into()),
+            protocol: Some("UDP".to_string()),
+            name: Some("gameport".to_string()),
+            ..Default::default()
+        };
+        HashMap::from([("gameport".to_string(), port)])
+    }
+
+    fn arbitrium_context(&self, sockets: Option<u32>) -> ArbitriumContext {
+        let mut deployment = models::Deployment::new(
+            self.request_id.clone(),
+            self.public_ip.clone(),
+            "Status.READY".to_string(),
+            true,
+            false,
+            self.public_ip.clone(),
+        );
+        deployment.ports = Some(self.ports());
+        deployment.location = Some(Box::new(self.location()));
+        deployment.sockets = sockets.map(|sockets| sockets as i32);
+        ArbitriumContext::try_from(deployment).expect("dev context has a location and ports")
+    }
+}
+
+/// Stands in for fetching the context from the Edgegap API.
+pub(crate) fn load_dev_context(config: Res<BevygapServerConfig>, mut commands: Commands) {
+    let Some(dev) = &config.dev else {
+        return;
+    };
+    info!("Using dev context for {}", dev.request_id);
+    commands.insert_resource(dev.arbitrium_context(config.max_clients));
+    commands.trigger(ContextLoaded);
+}
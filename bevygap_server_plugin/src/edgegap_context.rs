@@ -3,65 +3,130 @@
 /// location, public IP, and other metadata.
 use crate::arbitrium_env::ArbitriumEnv;
 use crate::bevy_tokio_tasks::TokioTasksRuntime;
+use crate::plugin::BevygapServerConfig;
+use crate::state::{self, BevygapServerError};
 use bevy::prelude::*;
-use log::{info, error};
+use edgegap_async::{models, EdgegapClient};
+use log::info;
+use std::collections::HashMap;
+use std::fmt;
 
 
 #[derive(Event)]
 pub(crate) struct ContextLoaded;
 
+/// The deployment's context, as fetched from ARBITRIUM_CONTEXT_URL.
 #[derive(Resource, Debug, Clone)]
 pub struct ArbitriumContext {
-    context: serde_json::Map<String, serde_json::Value>,
+    deployment: models::Deployment,
+    location: models::DeploymentLocation,
+    ports: HashMap<String, models::PortMapping>,
+    sockets: Option<u32>,
+}
+
+/// Why a deployment's context can't be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArbitriumContextError {
+    /// A field every deployment has isn't in the context
+    Missing(&'static str),
+    /// The field is set, but to something we can't use
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ArbitriumContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArbitriumContextError::Missing(field) => write!(f, "context has no {field}"),
+            ArbitriumContextError::Invalid(field) => write!(f, "context's {field} is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for ArbitriumContextError {}
+
+impl TryFrom<models::Deployment> for ArbitriumContext {
+    type Error = ArbitriumContextError;
+
+    /// Needs the location and ports. Sockets are only set if the app version asked for them.
+    fn try_from(deployment: models::Deployment) -> Result<Self, Self::Error> {
+        let location = deployment
+            .location
+            .as_deref()
+            .cloned()
+            .ok_or(ArbitriumContextError::Missing("location"))?;
+        let ports = deployment
+            .ports
+            .clone()
+            .ok_or(ArbitriumContextError::Missing("ports"))?;
+        let sockets = deployment
+            .sockets
+            .map(|sockets| {
+                u32::try_from(sockets).map_err(|_| ArbitriumContextError::Invalid("sockets"))
+            })
+            .transpose()?;
+        Ok(Self {
+            deployment,
+            location,
+            ports,
+            sockets,
+        })
+    }
 }
 
 impl ArbitriumContext {
-    pub fn location(&self) -> String {
-        let location = self
-            .context
-            .get("location")
-            .expect("Missing location key in context");
-        let city = location.get("city").expect("Missing city key in context");
-        let country = location
-            .get("country")
-            .expect("Missing country key in context");
-        format!("{}, {}", city, country)
+    /// Everything Edgegap told us about the deployment.
+    pub fn deployment(&self) -> &models::Deployment {
+        &self.deployment
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        serde_json::to_vec(&self.context).expect("Failed to serialize context to JSON")
+        serde_json::to_vec(&self.deployment).expect("Failed to serialize context to JSON")
     }
 
-    pub fn sockets(&self) -> u32 {
-        let serde_json::Value::Number(sockets) = self
-            .context
-            .get("sockets")
-            .expect("Missing sockets key in context")
-        else {
-            panic!("Sockets is not a number!");
-        };
-        sockets.as_u64().expect("Sockets is not a number") as u32
+    pub fn request_id(&self) -> &str {
+        &self.deployment.request_id
+    }
+
+    pub fn public_ip(&self) -> &str {
+        &self.deployment.public_ip
+    }
+
+    pub fn fqdn(&self) -> &str {
+        &self.deployment.fqdn
+    }
+
+    /// Where the deployment is, with its city, country, latitude and longitude.
+    pub fn location(&self) -> &models::DeploymentLocation {
+        &self.location
     }
 
-    pub fn top_level_string(&self, key: &str) -> String {
-        self.context
-            .get(key)
-            .expect("Missing {str} key in context")
-            .as_str()
-            .expect("{str} is not a string")
-            .to_string()
+    /// "City, Country"
+    pub fn location_name(&self) -> String {
+        format!("{}, {}", self.location.city, self.location.country)
     }
 
-    pub fn request_id(&self) -> String {
-        self.top_level_string("request_id")
+    /// The deployment's port mappings, and their names.
+    pub fn ports(&self) -> impl Iterator<Item = (&str, &models::PortMapping)> {
+        self.ports.iter().map(|(name, port)| (name.as_str(), port))
     }
 
-    pub fn public_ip(&self) -> String {
-        self.top_level_string("public_ip")
+    /// The port mapping with this name, eg "gameport".
+    pub fn port(&self, name: &str) -> Option<&models::PortMapping> {
+        self.ports.get(name)
     }
 
-    pub fn fqdn(&self) -> String {
-        self.top_level_string("fqdn")
+    /// The port players connect to for the mapping with this name, eg "gameport".
+    pub fn external_port(&self, name: &str) -> Option<u16> {
+        self.port(name)?.external?.try_into().ok()
+    }
+
+    pub fn tags(&self) -> &[String] {
+        self.deployment.tags.as_deref().unwrap_or_default()
+    }
+
+    /// How many players the deployment was created for, if Edgegap set it.
+    pub fn sockets(&self) -> Option<u32> {
+        self.sockets
     }
 }
 
@@ -70,41 +135,42 @@ async fn fetch_context_from_api(
     context_url: &str,
     context_token: &str,
 ) -> Result<ArbitriumContext, async_nats::Error> {
-    let context_response = crate::http_client::get_context(context_url, context_token).await?;
+    let deployment = EdgegapClient::unauthenticated()
+        .with_user_agent("bevy_edgegap_gameserver")
+        .context_for_self(context_url, context_token)
+        .await?;
 
-    let serde_json::Value::Object(context_map) = context_response else {
-        panic!("Context is not an object");
-    };
-    info!("Context fetched: {:?}", context_map);
+    let context = ArbitriumContext::try_from(deployment)?;
+    info!("Context fetched: {:?}", context.deployment);
 
-    Ok(ArbitriumContext {
-        context: context_map,
-    })
+    Ok(context)
 }
 
 pub fn fetch_context_on_nats_connected(
     _trigger: Trigger<crate::plugin::NatsConnected>,
     runtime: ResMut<TokioTasksRuntime>,
     arb_env: Res<ArbitriumEnv>,
+    config: Res<BevygapServerConfig>,
 ) {
     let context_url = arb_env.context_url.clone();
     let context_token = arb_env.context_token.clone();
+    let retry = config.retry.clone();
     info!("Fetching context: {context_url} ::::  {context_token}");
 
     runtime.spawn_background_task(|mut ctx| async move {
-        let arb_context = fetch_context_from_api(&context_url, &context_token)
-            .await
-            // .expect("Failed to fetch context from Edgegap API");
-            .unwrap_or_else(|_err| {
-                error!("Failed to fetch context from Edgegap API: {_err}");
-                panic!("Failed to fetch context");
-                // // panic, or use a fake value:
-                // warn!("Using fake context!");
-                // let mut context = serde_json::Map::new();
-                // context.insert("fake_data".into(), "lol".into());
-                // context.insert("fqdn".into(), "rj.example.com".into());
-                // ArbitriumContext { context }
-            });
+        let fetched = retry
+            .retry("Fetching context", || {
+                fetch_context_from_api(&context_url, &context_token)
+            })
+            .await;
+        let arb_context = match fetched {
+            Ok(arb_context) => arb_context,
+            Err(e) => {
+                let error = BevygapServerError::Context(e.to_string());
+                state::report_task_failure(&mut ctx, error).await;
+                return;
+            }
+        };
         info!("Got Context: {arb_context:?}");
         ctx.run_on_main_thread(move |ctx| {
             ctx.world.insert_resource(arb_context);
@@ -113,3 +179,62 @@ pub fn fetch_context_on_nats_connected(
         .await;
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> models::Deployment {
+        let mut deployment = models::Deployment::new(
+            "93924761ccde".to_string(),
+            "162.254.141.66".to_string(),
+            "Status.READY".to_string(),
+            true,
+            false,
+            "93924761ccde.pr.edgegap.net".to_string(),
+        );
+        let port = models::PortMapping {
+            external: Some(31504),
+            internal: Some(6420),
+            ..Default::default()
+        };
+        deployment.ports = Some(HashMap::from([("gameport".to_string(), port)]));
+        deployment.location = Some(Box::new(models::DeploymentLocation {
+            city: "Montreal".to_string(),
+            country: "Canada".to_string(),
+            ..Default::default()
+        }));
+        deployment
+    }
+
+    #[test]
+    fn test_context_needs_location_and_ports() {
+        let context = ArbitriumContext::try_from(deployment()).unwrap();
+        assert_eq!(context.location_name(), "Montreal, Canada");
+        assert_eq!(context.external_port("gameport"), Some(31504));
+        assert_eq!(context.sockets(), None);
+
+        let mut no_location = deployment();
+        no_location.location = None;
+        let err = ArbitriumContext::try_from(no_location).unwrap_err();
+        assert_eq!(err, ArbitriumContextError::Missing("location"));
+
+        let mut no_ports = deployment();
+        no_ports.ports = None;
+        let err = ArbitriumContext::try_from(no_ports).unwrap_err();
+        assert_eq!(err, ArbitriumContextError::Missing("ports"));
+    }
+
+    #[test]
+    fn test_context_sockets() {
+        let mut with_sockets = deployment();
+        with_sockets.sockets = Some(8);
+        let context = ArbitriumContext::try_from(with_sockets).unwrap();
+        assert_eq!(context.sockets(), Some(8));
+
+        let mut bad_sockets = deployment();
+        bad_sockets.sockets = Some(-1);
+        let err = ArbitriumContext::try_from(bad_sockets).unwrap_err();
+        assert_eq!(err, ArbitriumContextError::Invalid("sockets"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Keeps our entry in the `gameservers` NATS KV bucket fresh, so the matchmaker knows we're
+//! alive, where we are, and whether we have room for more players.
+use crate::arbitrium_env::ArbitriumEnv;
+use crate::edgegap_context::ArbitriumContext;
+use crate::plugin::{BevygapReady, BevygapServerConfig, NatsSender};
+use crate::state::BevygapServerState;
+use bevy::prelude::*;
+use bevy_state::prelude::State;
+use bevygap_shared::protocol::{
+    GameserverHeartbeat, GameserverOccupancy, GameserverPort, GameserverStatus,
+};
+use bevygap_shared::util::unix_now;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+impl From<BevygapServerState> for GameserverStatus {
+    fn from(state: BevygapServerState) -> Self {
+        match state {
+            BevygapServerState::Connecting | BevygapServerState::FetchingContext => {
+                GameserverStatus::Starting
+            }
+            BevygapServerState::Ready => GameserverStatus::Ready,
+            BevygapServerState::Degraded => GameserverStatus::Degraded,
+            BevygapServerState::Draining => GameserverStatus::Draining,
+        }
+    }
+}
+
+/// Only exists while we're sending heartbeats, from `BevygapReady` until the deployment stops.
+#[derive(Resource)]
+pub(crate) struct Heartbeat {
+    interval: Duration,
+    app_version: Option<String>,
+    /// When the last heartbeat was sent, in real time since startup
+    last_sent: Option<Duration>,
+    /// Frames run since the last heartbeat
+    frames: u32,
+}
+
+pub(crate) fn start_heartbeat_on_ready(
+    _trigger: Trigger<BevygapReady>,
+    config: Res<BevygapServerConfig>,
+    mut commands: Commands,
+) {
+    commands.insert_resource(Heartbeat {
+        interval: config.heartbeat_interval,
+        app_version: config.app_version.clone(),
+        last_sent: None,
+        frames: 0,
+    });
+}
+
+pub(crate) fn send_heartbeat(
+    time: Res<Time<Real>>,
+    state: Res<State<BevygapServerState>>,
+    arb_env: Res<ArbitriumEnv>,
+    context: Res<ArbitriumContext>,
+    occupancy: Res<GameserverOccupancy>,
+    nats_sender: Res<NatsSender>,
+    mut heartbeat: ResMut<Heartbeat>,
+) {
+    heartbeat.frames += 1;
+    let now = time.elapsed();
+    let ticks_per_second = match heartbeat.last_sent {
+        Some(last) if now - last < heartbeat.interval => return,
+        Some(last) => Some(heartbeat.frames as f32 / (now - last).as_secs_f32()),
+        None => None,
+    };
+    heartbeat.last_sent = Some(now);
+    heartbeat.frames = 0;
+    let sent_at = unix_now();
+    nats_sender.heartbeat(GameserverHeartbeat {
+        request_id: arb_env.request_id.clone(),
+        public_ip: arb_env.public_ip.clone(),
+        fqdn: Some(context.fqdn().to_string()),
+        location: Some(context.location_name()),
+        ports: gameserver_ports(&context),
+        players: occupancy.players,
+        max_players: occupancy.max_players,
+        status: (*state.get()).into(),
+        app_version: heartbeat.app_version.clone(),
+        ticks_per_second,
+        sent_at,
+    });
+}
+
+/// The context's port mappings, skipping any without both port numbers.
+fn gameserver_ports(context: &ArbitriumContext) -> BTreeMap<String, GameserverPort> {
+    context
+        .ports()
+        .filter_map(|(name, port)| {
+            let port = GameserverPort {
+                internal: port.internal?.try_into().ok()?,
+                external: port.external?.try_into().ok()?,
+                protocol: port.protocol.clone().unwrap_or_default(),
+            };
+            Some((name.to_string(), port))
+        })
+        .collect()
+}
+
+/// Stops sending heartbeats, and removes our entry, so the matchmaker forgets us straight away
+/// rather than waiting for it to expire.
+pub(crate) fn stop_heartbeat(
+    arb_env: &ArbitriumEnv,
+    nats_sender: &NatsSender,
+    commands: &mut Commands,
+) {
+    commands.remove_resource::<Heartbeat>();
+    nats_sender.gameserver_removed(arb_env.request_id.clone());
+}
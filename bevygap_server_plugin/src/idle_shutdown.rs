@@ -0,0 +1,85 @@
+//! Stops the deployment once nobody has been connected for
+//! `BevygapServerConfig::idle_timeout`, so idle gameservers don't keep running.
+use crate::arbitrium_env::ArbitriumEnv;
+use crate::bevy_tokio_tasks::TokioTasksRuntime;
+use crate::heartbeat;
+use crate::plugin::{BevygapReady, BevygapServerConfig, NatsSender};
+use crate::state::{self, BevygapServerError, BevygapServerState};
+use bevy::prelude::*;
+use bevy_state::prelude::NextState;
+use bevygap_shared::protocol::GameserverOccupancy;
+use edgegap_async::EdgegapClient;
+use log::info;
+
+/// Triggered when the idle timeout fires, just before the deployment is stopped.
+/// Observers run first, so the game can save any state. We're `Draining` from then on.
+#[derive(Event)]
+pub struct BevygapIdleShutdown;
+
+/// Counts down while nobody is connected. Only exists once we're ready for players.
+#[derive(Resource, Default)]
+pub(crate) struct IdleTimer(Option<Timer>);
+
+pub(crate) fn start_idle_timer_on_ready(_trigger: Trigger<BevygapReady>, mut commands: Commands) {
+    commands.init_resource::<IdleTimer>();
+}
+
+pub(crate) fn tick_idle_timer(
+    time: Res<Time>,
+    config: Res<BevygapServerConfig>,
+    occupancy: Res<GameserverOccupancy>,
+    mut idle: ResMut<IdleTimer>,
+    mut next_state: ResMut<NextState<BevygapServerState>>,
+    mut commands: Commands,
+) {
+    let Some(idle_timeout) = config.idle_timeout else {
+        return;
+    };
+    if occupancy.players > 0 {
+        if idle.0.take().is_some() {
+            info!("Player connected, cancelling idle shutdown");
+        }
+        return;
+    }
+    let timer = idle.0.get_or_insert_with(|| {
+        info!("No players connected, stopping the deployment in {idle_timeout:?} if none connect");
+        Timer::new(idle_timeout, TimerMode::Once)
+    });
+    if timer.tick(time.delta()).just_finished() {
+        info!("Idle for {idle_timeout:?}, stopping the deployment");
+        next_state.set(BevygapServerState::Draining);
+        commands.trigger(BevygapIdleShutdown);
+        // queued after the trigger, so game observers have run by the time we stop
+        commands.run_system_cached(stop_deployment);
+    }
+}
+
+/// Forgets our cert digest and registry entry, then asks Edgegap to stop this deployment.
+fn stop_deployment(
+    arb_env: Res<ArbitriumEnv>,
+    nats_sender: Res<NatsSender>,
+    runtime: Res<TokioTasksRuntime>,
+    mut commands: Commands,
+) {
+    nats_sender.cert_digest_removed(arb_env.public_ip.clone());
+    heartbeat::stop_heartbeat(&arb_env, &nats_sender, &mut commands);
+    if arb_env.delete_url.is_empty() {
+        info!("No ARBITRIUM_DELETE_URL in dev mode, leaving the gameserver running");
+        return;
+    }
+    let delete_url = arb_env.delete_url.clone();
+    let delete_token = arb_env.delete_token.clone();
+    runtime.spawn_background_task(|mut ctx| async move {
+        let deleted = EdgegapClient::unauthenticated()
+            .with_user_agent("bevy_edgegap_gameserver")
+            .delete_self(&delete_url, &delete_token)
+            .await;
+        match deleted {
+            Ok(deleted) => info!("Deployment stopping: {}", deleted.message),
+            Err(e) => {
+                let error = BevygapServerError::SelfDelete(e.to_string());
+                state::report_task_failure(&mut ctx, error).await;
+            }
+        }
+    });
+}
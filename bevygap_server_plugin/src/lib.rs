@@ -1,12 +1,23 @@
 mod arbitrium_env;
 mod bevy_tokio_tasks;
+mod connection_handler;
+mod dev_mode;
 mod edgegap_context;
-mod http_client;
+mod heartbeat;
+mod idle_shutdown;
 mod plugin;
+mod state;
 
 pub mod prelude {
-    pub use crate::arbitrium_env::ArbitriumEnv;
-    pub use crate::edgegap_context::ArbitriumContext;
+    pub use crate::arbitrium_env::{ArbitriumEnv, ArbitriumEnvError};
+    pub use crate::connection_handler::{BevygapConnectionRequestHandler, CRH};
+    pub use crate::dev_mode::BevygapDevConfig;
+    pub use crate::edgegap_context::{ArbitriumContext, ArbitriumContextError};
+    pub use crate::idle_shutdown::BevygapIdleShutdown;
     pub use crate::plugin::BevygapReady;
+    pub use crate::plugin::BevygapServerConfig;
     pub use crate::plugin::BevygapServerPlugin;
+    pub use crate::plugin::PlayerReconnected;
+    pub use crate::state::{BevygapServerError, BevygapServerState, RetrySettings};
+    pub use bevygap_shared::protocol::GameserverOccupancy;
 }
@@ -1,13 +1,22 @@
 use bevy::prelude::*;
-use log::{info, warn, error};
+use log::{debug, info, warn, error};
 use crate::bevy_tokio_tasks::{TokioTasksPlugin, TokioTasksRuntime};
 use bevygap_shared::nats::*;
+use bevygap_shared::protocol::{GameserverHeartbeat, GameserverOccupancy, SessionPlayer};
 use crate::arbitrium_env::ArbitriumEnv;
+use crate::connection_handler::{self, BevygapConnectionRequestHandler, ClientConnection, CRH};
+use crate::dev_mode::{self, BevygapDevConfig};
 use crate::edgegap_context::{self, ArbitriumContext};
-use lightyear::connection::shared::{ConnectionRequestHandler, DeniedReason};
-use lightyear::prelude::PeerId;
-use std::collections::HashMap;
+use crate::heartbeat;
+use crate::idle_shutdown;
+use crate::state::{self, BevygapServerError, BevygapServerState, RetrySettings};
+use async_nats::jetstream::kv::Store;
+use bevy_state::app::{AppExtStates, StatesPlugin};
+use bevy_state::prelude::{NextState, OnEnter, OnExit};
+use lightyear::prelude::server::ClientOf;
+use lightyear::prelude::{Connected, PeerId, RemoteId};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Resource)]
 struct CertDigest(String);
@@ -15,6 +24,46 @@ struct CertDigest(String);
 #[derive(Event)]
 pub struct NatsConnected;
 
+/// Game-specific configuration. To use dev mode, insert this before adding the plugin.
+#[derive(Resource, Debug, Clone)]
+pub struct BevygapServerConfig {
+    /// Deny clients with `ServerFull` once this many are connected. If unset, the deployment's
+    /// `sockets` from the Arbitrium context is used, and if that's unset too, there's no limit.
+    pub max_clients: Option<u32>,
+    /// Stop the deployment once nobody has been connected for this long, counting from
+    /// `BevygapReady` or the last player disconnecting. `None`, the default, keeps it running.
+    pub idle_timeout: Option<Duration>,
+    /// Run locally, without Edgegap: the `ARBITRIUM_*` env vars aren't needed, the deployment
+    /// context is made up from these settings, and any client with a valid token is accepted.
+    pub dev: Option<BevygapDevConfig>,
+    /// How often to refresh our entry in the `gameservers` KV bucket. Keep this well under
+    /// `GAMESERVER_HEARTBEAT_MAX_AGE`, or the matchmaker will think we've died.
+    pub heartbeat_interval: Duration,
+    /// Reported in our heartbeats, eg the Edgegap app version we were deployed as.
+    pub app_version: Option<String>,
+    /// Keep a disconnected player's place for this long, so they can get a new token for their
+    /// session from the matchmaker and reconnect as the same client id. The matchmaker waits its
+    /// own `reconnect_grace_secs` before removing them from the session, so keep the two in step.
+    pub reconnect_grace: Duration,
+    /// How hard to try talking to NATS and the Edgegap API before reporting a
+    /// `BevygapServerError`.
+    pub retry: RetrySettings,
+}
+
+impl Default for BevygapServerConfig {
+    fn default() -> Self {
+        Self {
+            max_clients: None,
+            idle_timeout: None,
+            dev: None,
+            heartbeat_interval: Duration::from_secs(10),
+            app_version: None,
+            reconnect_grace: Duration::from_secs(30),
+            retry: RetrySettings::default(),
+        }
+    }
+}
+
 pub struct BevygapServerPlugin;
 
 impl Plugin for BevygapServerPlugin {
@@ -22,10 +71,50 @@ impl Plugin for BevygapServerPlugin {
         if !app.is_plugin_added::<TokioTasksPlugin>() {
             app.add_plugins(TokioTasksPlugin::default());
         }
+        if !app.is_plugin_added::<StatesPlugin>() {
+            app.add_plugins(StatesPlugin);
+        }
+        app.init_state::<BevygapServerState>();
+        app.add_event::<BevygapServerError>();
+        app.init_resource::<BevygapServerConfig>();
+        let config = app.world().resource::<BevygapServerConfig>();
+        let dev = config.dev.clone();
+        let reconnect_grace = config.reconnect_grace;
+
         // Load the Edgegap ENVs
-        info!("Reading Arbitrium ENVs");
-        let arb_env = ArbitriumEnv::from_env().expect("Failed to read Arbitrium ENVs");
-        app.insert_resource(arb_env);
+        let arb_env = match &dev {
+            Some(dev) => {
+                info!("Dev mode, using {dev:?} instead of Arbitrium ENVs");
+                Ok(dev.arbitrium_env())
+            }
+            None => {
+                info!("Reading Arbitrium ENVs");
+                ArbitriumEnv::from_env()
+            }
+        };
+        // without them we can't tell anyone who we are, so don't connect to NATS.
+        let have_env = arb_env.is_ok();
+        match arb_env {
+            Ok(arb_env) => {
+                app.insert_resource(arb_env);
+            }
+            Err(e) => {
+                let error = BevygapServerError::Env(e.to_string());
+                app.add_systems(Startup, move |world: &mut World| {
+                    state::report_failure(world, error.clone());
+                });
+            }
+        }
+
+        // created up front, so the game can hand it to lightyear before NATS is connected.
+        // until then it denies every client.
+        let handler = if dev.is_some() {
+            BevygapConnectionRequestHandler::accepting_any()
+        } else {
+            BevygapConnectionRequestHandler::default()
+        };
+        handler.set_reconnect_grace(reconnect_grace);
+        app.insert_resource(CRH(Arc::new(handler)));
 
         // Legacy CA certificate injection from command line (deprecated)
         // Note: With LetsEncrypt certificates, this is no longer needed
@@ -33,13 +122,46 @@ impl Plugin for BevygapServerPlugin {
         inject_ca_root_env_var_from_cmdline_arg();
 
         app.add_systems(Startup, extract_cert_digest);
-
-        app.add_systems(Startup, extract_cert_digest);
-        app.add_systems(Startup, setup_nats);
-
-        app.add_observer(edgegap_context::fetch_context_on_nats_connected);
+        app.add_systems(Startup, setup_occupancy);
+        match &dev {
+            Some(dev) if !dev.nats => {
+                app.add_systems(Startup, setup_without_nats);
+            }
+            _ if have_env => {
+                app.add_systems(Startup, setup_nats);
+            }
+            _ => {}
+        }
+        if dev.is_some() {
+            // after NatsSender is inserted, and we've left Connecting
+            app.add_systems(
+                Startup,
+                dev_mode::load_dev_context
+                    .after(setup_occupancy)
+                    .after(extract_cert_digest)
+                    .after(setup_nats)
+                    .after(setup_without_nats),
+            );
+        } else {
+            app.add_observer(edgegap_context::fetch_context_on_nats_connected);
+        }
         app.add_observer(send_context_to_nats);
-        app.add_observer(setup_connection_request_handler);
+        app.add_observer(apply_context_capacity);
+        app.add_observer(idle_shutdown::start_idle_timer_on_ready);
+        app.add_observer(heartbeat::start_heartbeat_on_ready);
+        app.add_systems(OnEnter(BevygapServerState::Draining), start_draining);
+        app.add_systems(OnExit(BevygapServerState::Draining), stop_draining);
+        app.add_systems(
+            Update,
+            idle_shutdown::tick_idle_timer.run_if(resource_exists::<idle_shutdown::IdleTimer>),
+        );
+        app.add_systems(
+            Update,
+            heartbeat::send_heartbeat.run_if(resource_exists::<heartbeat::Heartbeat>),
+        );
+        app.add_observer(watch_issued_client_ids);
+        app.add_observer(report_client_connected);
+        app.add_observer(report_client_disconnected);
     }
 }
 
@@ -92,27 +214,153 @@ fn extract_cert_digest(
 #[derive(Event)]
 pub struct BevygapReady;
 
-/// We create a BevygapConnectionRequestHandler and store it in a resource.
-/// This is handed to lightyear, and used to accept or deny incoming client connections.
-fn setup_connection_request_handler(
+/// Triggered when a player whose connection dropped connects again, within
+/// `BevygapServerConfig::reconnect_grace`. They have a new client entity, but the same client id
+/// and session, so the game can hand them back whatever they had.
+#[derive(Event, Debug, Clone)]
+pub struct PlayerReconnected {
+    /// The new server-side client entity
+    pub entity: Entity,
+    pub client_id: u64,
+    pub session_id: String,
+}
+
+/// Once NATS is connected, feed the client ids issued for this deployment into the
+/// BevygapConnectionRequestHandler, which lightyear uses to accept or deny incoming clients.
+fn watch_issued_client_ids(
     _trigger: Trigger<NatsConnected>,
+    crh: Res<CRH>,
     bgnats: Res<BevygapNats>,
+    arb_env: Res<ArbitriumEnv>,
+    runtime: ResMut<TokioTasksRuntime>,
+) {
+    let handler = crh.handler();
+    let kv_c2s = bgnats.kv_c2s().clone();
+    let request_id = arb_env.request_id.clone();
+    runtime.spawn_background_task(|_ctx| {
+        connection_handler::watch_issued_client_ids(handler, kv_c2s, request_id)
+    });
+}
+
+/// While draining, deny any new clients.
+fn start_draining(crh: Res<CRH>) {
+    info!("Draining, denying new clients");
+    crh.0.set_draining(true);
+}
+
+fn stop_draining(crh: Res<CRH>) {
+    info!("No longer draining, accepting clients");
+    crh.0.set_draining(false);
+}
+
+/// The netcode client id of a server-side client entity, if it has one.
+fn netcode_client_id(q: &Query<&RemoteId, With<ClientOf>>, entity: Entity) -> Option<u64> {
+    match q.get(entity).ok()?.0 {
+        PeerId::Netcode(client_id) => Some(client_id),
+        _ => None,
+    }
+}
+
+/// When a client connects, record it in NATS against its session, so the matchmaker knows the
+/// connect token was used. Each player in a session gets their own entry.
+fn report_client_connected(
+    trigger: Trigger<OnAdd, Connected>,
+    q: Query<&RemoteId, With<ClientOf>>,
+    crh: Res<CRH>,
+    mut occupancy: ResMut<GameserverOccupancy>,
+    nats_sender: Option<Res<NatsSender>>,
     mut commands: Commands,
 ) {
-    // we store this in a resource, because we'll need to push new data into it
-    let crh = BevygapConnectionRequestHandler::new(bgnats.clone());
-    let arc_crh = Arc::new(crh);
-    commands.insert_resource(CRH(arc_crh.clone()));
-    // TODO: also register on_connect/on_disconnect callbacks to forward events to NATS
-    // Example:
-    // let tx = nats_sender.0.clone();
-    // netcode_cfg.on_connect(move |peer_id, _entity, _ctx| {
-    //     if let PeerId::Netcode(id) = peer_id { let _ = tx.send(NatsEvent::ClientConnected(id)); }
-    // });
-    // let tx2 = nats_sender.0.clone();
-    // netcode_cfg.on_disconnect(move |peer_id, _entity, _ctx| {
-    //     if let PeerId::Netcode(id) = peer_id { let _ = tx2.send(NatsEvent::ClientDisconnected(id)); }
-    // });
+    let entity = trigger.target();
+    let Some(client_id) = netcode_client_id(&q, entity) else {
+        return;
+    };
+    let Some(connection) = crh.0.client_connected(client_id) else {
+        error!("Client ID {client_id} is not mapped to a session id, not tracking it");
+        return;
+    };
+    occupancy.players = crh.0.num_connected() as u32;
+    if let (ClientConnection::Reconnected, Some(player)) =
+        (connection, crh.0.session_player(client_id))
+    {
+        info!("Client {client_id} reconnected to session {}", player.session_id);
+        commands.trigger(PlayerReconnected {
+            entity,
+            client_id,
+            session_id: player.session_id,
+        });
+    }
+    let Some(nats_sender) = nats_sender else {
+        warn!("Client {client_id} connected before NATS was set up, not reporting it");
+        return;
+    };
+    if let Some(player) = crh.0.session_player(client_id) {
+        nats_sender.client_connected(client_id, player);
+    }
+    nats_sender.occupancy(occupancy.clone());
+}
+
+/// When a client disconnects, remove it from NATS, so the matchmaker can clean up its session
+/// once the last player has left, and not reconnected within the grace period.
+fn report_client_disconnected(
+    trigger: Trigger<OnRemove, Connected>,
+    q: Query<&RemoteId, With<ClientOf>>,
+    crh: Res<CRH>,
+    mut occupancy: ResMut<GameserverOccupancy>,
+    nats_sender: Option<Res<NatsSender>>,
+) {
+    let Some(client_id) = netcode_client_id(&q, trigger.target()) else {
+        return;
+    };
+    let player = crh.0.session_player(client_id);
+    if !crh.0.client_disconnected(client_id) {
+        error!("Client {client_id} disconnected but wasn't mapped to a session id");
+        return;
+    }
+    occupancy.players = crh.0.num_connected() as u32;
+    let Some(nats_sender) = nats_sender else {
+        warn!("Client {client_id} disconnected before NATS was set up, not reporting it");
+        return;
+    };
+    if let Some(player) = player {
+        nats_sender.client_disconnected(client_id, player);
+    }
+    nats_sender.occupancy(occupancy.clone());
+}
+
+/// Starts tracking how many players are connected, limited by `BevygapServerConfig::max_clients`
+/// if it's set.
+fn setup_occupancy(
+    config: Res<BevygapServerConfig>,
+    arb_env: Option<Res<ArbitriumEnv>>,
+    crh: Res<CRH>,
+    mut commands: Commands,
+) {
+    crh.0.set_max_clients(config.max_clients.map(|max| max as usize));
+    commands.insert_resource(GameserverOccupancy {
+        request_id: arb_env.map(|env| env.request_id.clone()).unwrap_or_default(),
+        players: crh.0.num_connected() as u32,
+        max_players: config.max_clients,
+    });
+}
+
+/// Once we know how many sockets the deployment was created for, use that as the player limit,
+/// unless the game configured one. Then publish our occupancy for the first time.
+fn apply_context_capacity(
+    _trigger: Trigger<edgegap_context::ContextLoaded>,
+    config: Res<BevygapServerConfig>,
+    context: Res<ArbitriumContext>,
+    crh: Res<CRH>,
+    mut occupancy: ResMut<GameserverOccupancy>,
+    nats_sender: Res<NatsSender>,
+) {
+    if config.max_clients.is_none() {
+        let max_players = context.sockets();
+        info!("Limiting players to the deployment's sockets: {max_players:?}");
+        crh.0.set_max_clients(max_players.map(|max| max as usize));
+        occupancy.max_players = max_players;
+    }
+    nats_sender.occupancy(occupancy.clone());
 }
 
 /// Context loaded, nats connected: time to send our metadata to NATS,
@@ -120,53 +368,177 @@ fn setup_connection_request_handler(
 fn send_context_to_nats(
     _trigger: Trigger<edgegap_context::ContextLoaded>,
     context: Res<ArbitriumContext>,
+    arb_env: Res<ArbitriumEnv>,
     nats_sender: ResMut<NatsSender>,
+    mut next_state: ResMut<NextState<BevygapServerState>>,
     mut commands: Commands,
-    digest: Res<CertDigest>,
+    digest: Option<Res<CertDigest>>,
 ) {
     info!("CONTEXT added: {context:?}");
-    info!("CONTEXT fqdn: {}", context.fqdn());
-    nats_sender.cert_digest(context.public_ip(), digest.0.clone());
+    info!("CONTEXT fqdn: {:?}", context.fqdn());
+    match digest {
+        Some(digest) => nats_sender.cert_digest(arb_env.public_ip.clone(), digest.0.clone()),
+        None => warn!("No cert digest to send to NATS"),
+    }
     nats_sender.arbitrium_context(context.clone());
+    next_state.set(BevygapServerState::Ready);
     commands.trigger(BevygapReady);
 }
 
 #[derive(Debug, Event)]
 enum NatsEvent {
-    ClientConnected(u64),
-    ClientDisconnected(u64),
+    ClientConnected(u64, SessionPlayer),
+    ClientDisconnected(u64, SessionPlayer),
     ArbitriumContext(ArbitriumContext),
     CertDigest(String, String),
+    CertDigestRemoved(String),
+    Occupancy(GameserverOccupancy),
+    Heartbeat(GameserverHeartbeat),
+    GameserverRemoved(String),
 }
 
 #[derive(Resource)]
-struct NatsSender(tokio::sync::mpsc::UnboundedSender<NatsEvent>);
+pub(crate) struct NatsSender(tokio::sync::mpsc::UnboundedSender<NatsEvent>);
 
-// these sends should never fail, it's an unbounded channel and if the
-// receiving task can't recv, it panics anyway.
+// sends only fail if the NATS event loop has stopped, because NATS setup failed,
+// which has already been reported.
 impl NatsSender {
-    fn client_connected(&self, client_id: u64) {
-        self.0
-            .send(NatsEvent::ClientConnected(client_id))
-            .expect("Unable to send NatsEvent for client_connected")
+    fn send(&self, ev: NatsEvent) {
+        if let Err(e) = self.0.send(ev) {
+            warn!("NATS isn't connected, dropping {:?}", e.0);
+        }
     }
 
-    fn client_disconnected(&self, client_id: u64) {
-        self.0
-            .send(NatsEvent::ClientDisconnected(client_id))
-            .expect("Unable to send NatsEvent for client_disconnected")
+    fn client_connected(&self, client_id: u64, player: SessionPlayer) {
+        self.send(NatsEvent::ClientConnected(client_id, player))
+    }
+
+    fn client_disconnected(&self, client_id: u64, player: SessionPlayer) {
+        self.send(NatsEvent::ClientDisconnected(client_id, player))
     }
 
     fn arbitrium_context(&self, context: ArbitriumContext) {
-        self.0
-            .send(NatsEvent::ArbitriumContext(context))
-            .expect("Unable to send NatsEvent for arbitrium_context")
+        self.send(NatsEvent::ArbitriumContext(context))
     }
 
     fn cert_digest(&self, ip: String, digest: String) {
-        self.0
-            .send(NatsEvent::CertDigest(ip, digest))
-            .expect("Unable to send NatsEvent for cert_digest")
+        self.send(NatsEvent::CertDigest(ip, digest))
+    }
+
+    pub(crate) fn cert_digest_removed(&self, ip: String) {
+        self.send(NatsEvent::CertDigestRemoved(ip))
+    }
+
+    fn occupancy(&self, occupancy: GameserverOccupancy) {
+        self.send(NatsEvent::Occupancy(occupancy))
+    }
+
+    pub(crate) fn heartbeat(&self, heartbeat: GameserverHeartbeat) {
+        self.send(NatsEvent::Heartbeat(heartbeat))
+    }
+
+    pub(crate) fn gameserver_removed(&self, request_id: String) {
+        self.send(NatsEvent::GameserverRemoved(request_id))
+    }
+}
+
+/// Writes NatsEvents to NATS, retrying each write.
+struct NatsWriter {
+    retry: RetrySettings,
+    client: async_nats::Client,
+    kv_sessions: Store,
+    kv_cert_digests: Store,
+    kv_gameservers: Store,
+}
+
+impl NatsWriter {
+    async fn write(&self, ev: NatsEvent) -> Result<(), String> {
+        let retry = &self.retry;
+        match ev {
+            NatsEvent::ClientConnected(client_id, player) => {
+                info!("Client ID {client_id} associated with session id: {}, writing to nats kv", player.session_id);
+                let key = session_player_key(&player.session_id, &client_id.to_string());
+                let value = serde_json::to_vec(&player).map_err(|e| e.to_string())?;
+                retry
+                    .retry("Recording client", || {
+                        self.kv_sessions.put(&key, value.clone().into())
+                    })
+                    .await
+                    .map_err(|e| format!("Failed to put client_id in KV: {e}"))?;
+            }
+            NatsEvent::ClientDisconnected(client_id, player) => {
+                info!("Client disconnected: {}, writing to nats kv", client_id);
+                let key = session_player_key(&player.session_id, &client_id.to_string());
+                retry
+                    .retry("Removing client", || self.kv_sessions.delete(&key))
+                    .await
+                    .map_err(|e| format!("Failed to del client_id in KV: {e}"))?;
+            }
+            NatsEvent::ArbitriumContext(context) => {
+                info!("ArbitriumContext added: {context:?}");
+                let arb_context_bytes = context.to_bytes();
+                // TODO nats key should be on the subject?
+                retry
+                    .retry("Publishing context", || {
+                        self.client
+                            .publish("gameserver.contexts", arb_context_bytes.clone().into())
+                    })
+                    .await
+                    .map_err(|e| format!("Failed to write context to NATS: {e}"))?;
+            }
+            NatsEvent::CertDigest(ip, digest) => {
+                // TODO need cleanup when a deployment terminates, remove keys
+                info!("CertDigest added: {ip} -> {digest}");
+                retry
+                    .retry("Recording cert digest", || {
+                        self.kv_cert_digests.put(&ip, digest.clone().into())
+                    })
+                    .await
+                    .map_err(|e| format!("Failed to put digest in KV: {e}"))?;
+            }
+            NatsEvent::CertDigestRemoved(ip) => {
+                info!("CertDigest removed: {ip}");
+                retry
+                    .retry("Removing cert digest", || self.kv_cert_digests.delete(&ip))
+                    .await
+                    .map_err(|e| format!("Failed to delete digest from KV: {e}"))?;
+            }
+            NatsEvent::Occupancy(occupancy) => {
+                info!(
+                    "Occupancy: {} / {:?} players",
+                    occupancy.players, occupancy.max_players
+                );
+                let subject = occupancy.subject();
+                let bytes = serde_json::to_vec(&occupancy).map_err(|e| e.to_string())?;
+                retry
+                    .retry("Publishing occupancy", || {
+                        self.client.publish(subject.clone(), bytes.clone().into())
+                    })
+                    .await
+                    .map_err(|e| format!("Failed to write occupancy to NATS: {e}"))?;
+            }
+            NatsEvent::Heartbeat(heartbeat) => {
+                debug!("Heartbeat: {heartbeat:?}");
+                let bytes = serde_json::to_vec(&heartbeat).map_err(|e| e.to_string())?;
+                retry
+                    .retry("Sending heartbeat", || {
+                        self.kv_gameservers.put(&heartbeat.request_id, bytes.clone().into())
+                    })
+                    .await
+                    .map_err(|e| format!("Failed to put heartbeat in KV: {e}"))?;
+            }
+            NatsEvent::GameserverRemoved(request_id) => {
+                info!("Removing gameserver {request_id} from the registry");
+                retry
+                    .retry("Removing gameserver", || self.kv_gameservers.delete(&request_id))
+                    .await
+                    .map_err(|e| format!("Failed to delete gameserver from KV: {e}"))?;
+            }
+        }
+        retry
+            .retry("Flushing NATS", || self.client.flush())
+            .await
+            .map_err(|e| format!("Failed to flush NATS: {e}"))
     }
 }
 
@@ -180,38 +552,63 @@ impl<T: Event> bevy::prelude::Command for DeferredTriggerCommand<T> {
     }
 }
 
-fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
+/// Dev mode without NATS: there's nobody to tell about our clients, so just log the events.
+fn setup_without_nats(
+    runtime: ResMut<TokioTasksRuntime>,
+    mut next_state: ResMut<NextState<BevygapServerState>>,
+    mut commands: Commands,
+) {
+    info!("Running without NATS");
+    next_state.set(BevygapServerState::FetchingContext);
+    let (nats_event_sender, mut nats_event_receiver) =
+        tokio::sync::mpsc::unbounded_channel::<NatsEvent>();
+    commands.insert_resource(NatsSender(nats_event_sender));
+    runtime.spawn_background_task(|_ctx| async move {
+        while let Some(ev) = nats_event_receiver.recv().await {
+            info!("Not sending to NATS: {ev:?}");
+        }
+    });
+}
+
+fn setup_nats(
+    runtime: ResMut<TokioTasksRuntime>,
+    config: Res<BevygapServerConfig>,
+    mut commands: Commands,
+) {
     info!("Setting up NATS");
 
     let (nats_event_sender, mut nats_event_receiver) =
         tokio::sync::mpsc::unbounded_channel::<NatsEvent>();
     commands.insert_resource(NatsSender(nats_event_sender));
-
-    // this probably isn't ideal, but i need panics from tokio tasks to
-    // kill the process.
-    let default_panic = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_panic(info);
-        std::process::exit(1);
-    }));
+    let retry = config.retry.clone();
 
     runtime.spawn_background_task(|mut ctx| async move {
-        let bgnats = match BevygapNats::new_and_connect("bevygap_server_plugin").await {
+        let connected = retry
+            .retry("Connecting to NATS", || {
+                BevygapNats::new_and_connect("bevygap_server_plugin")
+            })
+            .await;
+        let bgnats = match connected {
             Ok(nats) => nats,
             Err(e) => {
-                error!("Failed to setup NATS: {}", e);
-                panic!("Failed to setup NATS");
+                let error = BevygapServerError::NatsConnect(e.to_string());
+                state::report_task_failure(&mut ctx, error).await;
+                return;
             }
         };
         info!("NATS connected");
 
-        let kv_c2s = bgnats.kv_c2s().clone();
-        let kv_sessions = bgnats.kv_active_connections().clone();
-        let kv_cert_digests = bgnats.kv_cert_digests().clone();
-        let client = bgnats.client().clone();
+        let writer = NatsWriter {
+            retry,
+            client: bgnats.client(),
+            kv_sessions: bgnats.kv_active_connections().clone(),
+            kv_cert_digests: bgnats.kv_cert_digests().clone(),
+            kv_gameservers: bgnats.kv_gameservers().clone(),
+        };
 
         ctx.run_on_main_thread(move |ctx| {
             ctx.world.insert_resource(bgnats);
+            state::nats_connected(ctx.world);
             // main thread work is executed by TokioTasks plugin by removing the TokioTasksRuntime resource,
             // doing the work, then reinserting the resource.
             // if we use a trigger here, the observer will fire instantly, and run while the TokioTasksRuntime is not present in the world.
@@ -226,136 +623,51 @@ fn setup_nats(runtime: ResMut<TokioTasksRuntime>, mut commands: Commands) {
         })
         .await;
 
-        let mut client_id_to_session_id = HashMap::new();
-
-        // Loop over nats_event_receiver and log received NatsEvents
+        // Loop over nats_event_receiver and write received NatsEvents
         info!("Starting NatsEvent loop");
-        loop {
-            let Some(ev) = nats_event_receiver.recv().await else {
-                // If we can't manage session stuff via nats, kill the server.
-                panic!("NatsEvent channel closed, aborting.");
-            };
-            match ev {
-                NatsEvent::ClientConnected(client_id) => {
-                    info!("Client connected: {}, writing to nats kv", client_id);
-                    // TODO lookup the session id here?
-                    let session_id = kv_c2s
-                        .get(client_id.to_string())
-                        .await
-                        .expect("Failed to get session_id from KV");
-                    match session_id {
-                        None => {
-                            panic!("Client ID is not mapped to a session id! wtf.");
-                        }
-                        Some(session_id) => {
-                            let session_id_key = String::from_utf8(session_id.into())
-                                .expect("Failed to convert session_id to string");
-                            info!("Client ID {client_id} associated with session id: {session_id_key}",);
-                            client_id_to_session_id.insert(client_id, session_id_key.clone());
-                            kv_sessions
-                                .put(session_id_key, client_id.to_string().into())
-                                .await
-                                .expect("Failed to put client_id in KV");
-                            // delete the mappings.
-                            // this signifies the session
-                            // let _ = kv_c2s.delete(client_id.to_string()).await;
-                        }
-                    }
-                }
-                NatsEvent::ClientDisconnected(client_id) => {
-                    info!("Client disconnected: {}, writing to nats kv", client_id);
-                    if let Some(session_id) = client_id_to_session_id.get(&client_id) {
-                        kv_sessions
-                            .delete(session_id)
-                            .await
-                            .expect("Failed to del client_id in KV");
-                    } else {
-                        error!("Client disconnected but not found in client_id_to_session_id");
-                    }
-                }
-                NatsEvent::ArbitriumContext(context) => {
-                    info!("ArbitriumContext added: {context:?}");
-                    let arb_context_bytes = context.to_bytes();
-                    // TODO nats key should be on the subject?
-                    client
-                        .publish("gameserver.contexts", arb_context_bytes.into())
-                        .await
-                        .expect("Failed to write context to NATS");
-                }
-                NatsEvent::CertDigest(ip, digest) => {
-                    // TODO need cleanup when a deployment terminates, remove keys
-                    info!("CertDigest added: {ip} -> {digest}");
-                    let key = ip;
-                    kv_cert_digests
-                        .put(key, digest.into())
-                        .await
-                        .expect("Failed to put digest in KV");
-                }
+        while let Some(ev) = nats_event_receiver.recv().await {
+            if let Err(e) = writer.write(ev).await {
+                state::report_task_failure(&mut ctx, BevygapServerError::NatsWrite(e)).await;
             }
-            client.flush().await.expect("Failed to flush NATS");
         }
+        info!("NatsEvent channel closed, stopping");
     });
 }
 
-// /// Reasons for denying a connection request
-// #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-// pub enum DeniedReason {
-//     ServerFull,
-//     Banned,
-//     InternalError,
-//     AlreadyConnected,
-//     TokenAlreadyUsed,
-//     InvalidToken,
-//     Custom(String),
-// }
-
-// / Trait for handling connection requests from clients.
-// pub trait ConnectionRequestHandler: Debug + Send + Sync {
-//     /// Handle a connection request from a client.
-//     /// Returns None if the connection is accepted,
-//     /// Returns Some(reason) if the connection is denied.
-//     fn handle_request(&self, client_id: ClientId) -> Option<DeniedReason>;
-// }
-
-#[derive(Resource, Default)]
-struct ConnectedClients(u8);
-
-#[derive(Resource)]
-pub struct CRH(Arc<BevygapConnectionRequestHandler>);
-
-/// Only accept connections where the ClientId is in NATS associated with a session.
-#[derive(Clone, Debug)]
-pub struct BevygapConnectionRequestHandler {
-    bgnats: BevygapNats,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_state::prelude::State;
 
-// this is set as a arc dyn trait object.
-// perhaps we should push valid ClientIDs into this struct as they arrive in nats?
-// or lookup as needed? we can keep an arc ref to this in the server plugin, and hand a clone
-// of it for registering with lightyear.
-//
-// Reasons we might actually want to reply with:
-// Full  - no, mm should prevent this? maybe we do want this, in case of lobbies and races when users pick a server.
-// banned - no, mm prevents
-// internal error - maybe
-// already connected - if client id is already connected?
-// token already used - same as already used client id?
-// invalidtoken - yep, if not registered in nats
-// custom(string) - hmm.
-//
-// we don't want this to block, so i think we need to push data into it so it's always ready.
-impl BevygapConnectionRequestHandler {
-    pub fn new(bgnats: BevygapNats) -> Self {
-        Self { bgnats }
+    fn server_state(app: &App) -> BevygapServerState {
+        *app.world().resource::<State<BevygapServerState>>().get()
     }
-}
 
-impl ConnectionRequestHandler for BevygapConnectionRequestHandler {
-    fn handle_request(
-        &self,
-        client_id: PeerId,
-    ) -> Option<DeniedReason> {
-        info!("BevygapConnectionRequestHandler({client_id:?})");
-        None
+    #[test]
+    fn test_dev_mode_with_nats_ends_ready() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.insert_resource(BevygapServerConfig {
+            dev: Some(BevygapDevConfig {
+                nats: true,
+                ..default()
+            }),
+            // there's no NATS server, so keep trying for the length of the test
+            retry: RetrySettings {
+                max_attempts: u32::MAX,
+                initial_backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(60),
+            },
+            ..default()
+        });
+        app.add_plugins(BevygapServerPlugin);
+        app.update();
+        app.update();
+        assert_eq!(server_state(&app), BevygapServerState::Ready);
+
+        // as when NATS connects, after the dev context was loaded
+        state::nats_connected(app.world_mut());
+        app.update();
+        assert_eq!(server_state(&app), BevygapServerState::Ready);
     }
 }
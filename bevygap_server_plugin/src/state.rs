@@ -0,0 +1,140 @@
+//! What the plugin is up to, and what went wrong, for game code to observe.
+//!
+//! Nothing in the plugin exits the process. Failures are retried a bounded number of times, then
+//! reported as a [`BevygapServerError`] event and the state moves to
+//! [`BevygapServerState::Degraded`]. Whether to keep running or exit is up to the game.
+use crate::bevy_tokio_tasks::TaskContext;
+use bevy::prelude::*;
+use bevy_state::prelude::{NextState, State, States};
+use log::{error, warn};
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(States, Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum BevygapServerState {
+    /// Connecting to NATS
+    #[default]
+    Connecting,
+    /// Fetching the deployment context from Edgegap
+    FetchingContext,
+    /// Accepting players, since `BevygapReady` was triggered
+    Ready,
+    /// Something failed, even after retrying. See the [`BevygapServerError`] events.
+    Degraded,
+    /// Shutting down, so new clients are denied. Entered before an idle shutdown, and game code
+    /// can enter it too, eg. before a planned restart.
+    Draining,
+}
+
+/// Sent when part of the plugin has failed, after any retries.
+#[derive(Event, Debug, Clone)]
+pub enum BevygapServerError {
+    /// The `ARBITRIUM_*` env vars couldn't be read
+    Env(String),
+    /// Couldn't connect to NATS
+    NatsConnect(String),
+    /// Couldn't fetch the deployment context from Edgegap
+    Context(String),
+    /// Couldn't write to NATS, so the matchmaker won't know about a change
+    NatsWrite(String),
+    /// Couldn't ask Edgegap to stop the deployment
+    SelfDelete(String),
+}
+
+impl fmt::Display for BevygapServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BevygapServerError::Env(msg) => write!(f, "Failed to read Arbitrium ENVs: {msg}"),
+            BevygapServerError::NatsConnect(msg) => write!(f, "Failed to connect to NATS: {msg}"),
+            BevygapServerError::Context(msg) => write!(f, "Failed to fetch context: {msg}"),
+            BevygapServerError::NatsWrite(msg) => write!(f, "Failed to write to NATS: {msg}"),
+            BevygapServerError::SelfDelete(msg) => {
+                write!(f, "Failed to stop the deployment: {msg}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BevygapServerError {}
+
+/// How hard to try talking to NATS and the Edgegap API before giving up.
+#[derive(Debug, Clone)]
+pub struct RetrySettings {
+    /// Attempts in total, including the first
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubling for each one after
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetrySettings {
+    /// Runs `f` until it succeeds, or fails `max_attempts` times.
+    pub(crate) async fn retry<T, E, F, Fut>(&self, what: &str, mut f: F) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt >= self.max_attempts => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "{what} failed (attempt {attempt}/{}): {e}, retrying in {backoff:?}",
+                        self.max_attempts
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the error event, and moves to `Degraded` unless we're already draining.
+pub(crate) fn report_failure(world: &mut World, error: BevygapServerError) {
+    error!("{error}");
+    world.send_event(error);
+    let draining = world
+        .get_resource::<State<BevygapServerState>>()
+        .is_some_and(|state| *state.get() == BevygapServerState::Draining);
+    if !draining {
+        world
+            .resource_mut::<NextState<BevygapServerState>>()
+            .set(BevygapServerState::Degraded);
+    }
+}
+
+/// NATS is connected, so on to fetching the context, unless we've already moved on, eg. dev mode
+/// made up the context before NATS connected.
+pub(crate) fn nats_connected(world: &mut World) {
+    let connecting = world
+        .get_resource::<State<BevygapServerState>>()
+        .is_some_and(|state| *state.get() == BevygapServerState::Connecting);
+    let mut next_state = world.resource_mut::<NextState<BevygapServerState>>();
+    if connecting && matches!(*next_state, NextState::Unchanged) {
+        next_state.set(BevygapServerState::FetchingContext);
+    }
+}
+
+/// [`report_failure`], from a background task.
+pub(crate) async fn report_task_failure(ctx: &mut TaskContext, error: BevygapServerError) {
+    ctx.run_on_main_thread(move |ctx| report_failure(ctx.world, error))
+        .await;
+}
+
@@ -0,0 +1,541 @@
+//! Layered configuration loading for the bevygap binaries.
+//!
+//! Each binary describes its settings as a serde struct with sensible defaults, and this
+//! module builds the effective config by stacking the following layers, later ones winning:
+//!
+//! 1. `Default::default()` of the settings struct
+//! 2. a TOML config file (`--config path.toml`, or the `<PREFIX>_CONFIG` env var)
+//! 3. legacy env vars registered with [`ConfigLoader::env_alias`], eg `EDGEGAP_API_KEY`
+//! 4. prefixed env vars, eg `BEVYGAP_MATCHMAKER_APP_NAME`, with `__` separating nested tables
+//!    so `BEVYGAP_MATCHMAKER_NATS__HOST` sets `nats.host`
+//! 5. CLI overrides – any serializable struct, where `None` fields are skipped
+//!
+//! Binaries flatten [`ConfigArgs`] into their clap `Cli` struct and call [`load_or_exit`].
+use log::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Printed in place of secret values by [`to_redacted_toml`].
+pub const REDACTED: &str = "<redacted>";
+
+/// Any key ending with one of these is considered a secret. Keys that only name where a secret
+/// is kept, like `edgegap_api_key_file`, are printed as they are.
+const SECRET_KEY_SUFFIXES: &[&str] = &["password", "private_key", "api_key", "token", "secret"];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Serialize(toml::ser::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "can't read config file {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => {
+                write!(f, "can't parse config file {}: {e}", path.display())
+            }
+            ConfigError::Serialize(e) => write!(f, "can't serialize config: {e}"),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The `--config` and `--print-config` args every binary takes. Flatten into the binary's
+/// clap struct with `#[command(flatten)]` and `#[serde(skip)]`.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConfigArgs {
+    /// Path to a TOML config file (or set <PREFIX>_CONFIG, eg BEVYGAP_MATCHMAKER_CONFIG)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Print the effective config, with secrets redacted, then exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+/// Loads the effective config using the file from `args`, printing any error and exiting.
+/// Handles `--print-config` by printing the redacted config and exiting, and otherwise exits
+/// if `validate` rejects the config, so a binary doesn't start only to fail later.
+///
+/// Set up logging before calling this, so the loader's messages aren't lost.
+pub fn load_or_exit<T, C>(
+    loader: ConfigLoader,
+    args: &ConfigArgs,
+    cli_overrides: &C,
+    validate: impl FnOnce(&T) -> Result<(), String>,
+) -> T
+where
+    T: Default + Serialize + DeserializeOwned,
+    C: Serialize,
+{
+    let settings: T = loader
+        .file(args.config.as_ref())
+        .load(cli_overrides)
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        });
+    if args.print_config {
+        match to_redacted_toml(&settings) {
+            Ok(toml) => println!("{toml}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if let Err(msg) = validate(&settings) {
+        eprintln!("{}", ConfigError::Invalid(msg));
+        std::process::exit(1);
+    }
+    settings
+}
+
+/// How a legacy env var maps onto a config key.
+#[derive(Debug, Clone)]
+enum EnvAlias {
+    /// The env var value is used as the value for the key.
+    Value { var: String, key: String },
+    /// The key is set to `true` if the env var is present at all (eg `NATS_INSECURE=set`).
+    Flag { var: String, key: String },
+}
+
+/// Builds a settings struct from defaults, a TOML file, env vars and CLI overrides.
+#[derive(Debug, Clone)]
+pub struct ConfigLoader {
+    env_prefix: String,
+    file: Option<PathBuf>,
+    aliases: Vec<EnvAlias>,
+}
+
+impl ConfigLoader {
+    /// `env_prefix` is used for overrides (eg `BEVYGAP_MATCHMAKER` → `BEVYGAP_MATCHMAKER_APP_NAME`)
+    /// and for the fallback config file env var (`BEVYGAP_MATCHMAKER_CONFIG`).
+    pub fn new(env_prefix: &str) -> Self {
+        Self {
+            env_prefix: env_prefix.to_uppercase(),
+            file: None,
+            aliases: Vec::new(),
+        }
+    }
+
+    /// Sets the TOML config file to read. If `None`, `<PREFIX>_CONFIG` is consulted instead.
+    pub fn file(mut self, path: Option<impl AsRef<Path>>) -> Self {
+        self.file = path.map(|p| p.as_ref().to_path_buf());
+        self
+    }
+
+    /// Maps a pre-existing env var onto a (dotted) config key, eg `EDGEGAP_API_KEY` → `edgegap.api_key`.
+    pub fn env_alias(mut self, var: &str, key: &str) -> Self {
+        self.aliases.push(EnvAlias::Value {
+            var: var.to_string(),
+            key: key.to_string(),
+        });
+        self
+    }
+
+    /// Like [`ConfigLoader::env_alias`], but sets the key to `true` when the env var is present.
+    pub fn env_flag(mut self, var: &str, key: &str) -> Self {
+        self.aliases.push(EnvAlias::Flag {
+            var: var.to_string(),
+            key: key.to_string(),
+        });
+        self
+    }
+
+    /// Registers the `NATS_*` env vars read by [`crate::nats::NatsSettings::from_env`] as
+    /// aliases for the `nats` table.
+    pub fn with_nats_env_aliases(self) -> Self {
+        self.env_alias("NATS_HOST", "nats.host")
+            .env_alias("NATS_USER", "nats.user")
+            .env_alias("NATS_PASSWORD", "nats.password")
+            .env_alias("NATS_CA", "nats.ca")
+            .env_flag("NATS_INSECURE", "nats.insecure")
+    }
+
+    fn config_file(&self) -> Option<PathBuf> {
+        self.file.clone().or_else(|| {
+            std::env::var(format!("{}_CONFIG", self.env_prefix))
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from)
+        })
+    }
+
+    /// Builds the effective config from the process environment.
+    pub fn load<T, C>(&self, cli_overrides: &C) -> Result<T, ConfigError>
+    where
+        T: Default + Serialize + DeserializeOwned,
+        C: Serialize,
+    {
+        self.load_from(std::env::vars(), cli_overrides)
+    }
+
+    /// Builds the effective config using the supplied env vars rather than the process env.
+    pub fn load_from<T, C>(
+        &self,
+        env: impl IntoIterator<Item = (String, String)>,
+        cli_overrides: &C,
+    ) -> Result<T, ConfigError>
+    where
+        T: Default + Serialize + DeserializeOwned,
+        C: Serialize,
+    {
+        let mut merged = to_table(&T::default())?;
+
+        if let Some(path) = self.config_file() {
+            info!("Loading config file {}", path.display());
+            let contents =
+                std::fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+            let file_table: Table =
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+            merge_tables(&mut merged, file_table);
+        }
+
+        let env: Vec<(String, String)> = env.into_iter().collect();
+
+        for alias in self.aliases.iter() {
+            match alias {
+                EnvAlias::Value { var, key } => {
+                    if let Some((_, val)) = env.iter().find(|(k, _)| k == var) {
+                        set_from_env(&mut merged, key, val);
+                    }
+                }
+                EnvAlias::Flag { var, key } => {
+                    if env.iter().any(|(k, _)| k == var) {
+                        set_path(&mut merged, key, Value::Boolean(true));
+                    }
+                }
+            }
+        }
+
+        let prefix = format!("{}_", self.env_prefix);
+        let config_var = format!("{}_CONFIG", self.env_prefix);
+        for (var, val) in env.iter() {
+            if *var == config_var {
+                continue;
+            }
+            let Some(rest) = var.strip_prefix(prefix.as_str()) else {
+                continue;
+            };
+            let key = rest.to_lowercase().replace("__", ".");
+            set_from_env(&mut merged, &key, val);
+        }
+
+        merge_tables(&mut merged, to_table(cli_overrides)?);
+
+        Value::Table(merged)
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Invalid(e.message().to_string()))
+    }
+}
+
+/// Serializes the config to TOML, replacing any secret values with [`REDACTED`].
+/// Used for `--print-config`.
+pub fn to_redacted_toml<T: Serialize>(config: &T) -> Result<String, ConfigError> {
+    let mut table = to_table(config)?;
+    redact_table(&mut table);
+    toml::to_string_pretty(&table).map_err(ConfigError::Serialize)
+}
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_KEY_SUFFIXES
+        .iter()
+        .any(|suffix| key.ends_with(suffix))
+}
+
+fn redact_table(table: &mut Table) {
+    for (key, val) in table.iter_mut() {
+        match val {
+            Value::Table(t) => redact_table(t),
+            Value::String(s) if s.is_empty() => {}
+            _ if is_secret_key(key) => *val = Value::String(REDACTED.to_string()),
+            _ => {}
+        }
+    }
+}
+
+fn to_table<T: Serialize>(val: &T) -> Result<Table, ConfigError> {
+    match Value::try_from(val).map_err(ConfigError::Serialize)? {
+        Value::Table(t) => Ok(t),
+        other => Err(ConfigError::Invalid(format!(
+            "expected a table, got {}",
+            other.type_str()
+        ))),
+    }
+}
+
+/// Recursively merges `overlay` on top of `base`.
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, val) in overlay {
+        match (base.get_mut(&key), val) {
+            (Some(Value::Table(base_t)), Value::Table(overlay_t)) => {
+                merge_tables(base_t, overlay_t)
+            }
+            (_, val) => {
+                base.insert(key, val);
+            }
+        }
+    }
+}
+
+/// Sets a string from the environment at the dotted `key`, coercing it to the type
+/// of any value already there. If there's no existing value, it's parsed as a TOML
+/// scalar where possible (`true`, `42`), and otherwise kept as a string.
+fn set_from_env(table: &mut Table, key: &str, raw: &str) {
+    let existing = get_path(table, key);
+    let val = match existing {
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        Some(Value::Array(_)) => parse_toml_scalar(&format!("[{raw}]")).unwrap_or_else(|| {
+            Value::Array(
+                raw.split(',')
+                    .map(|s| Value::String(s.trim().to_string()))
+                    .collect(),
+            )
+        }),
+        _ => parse_toml_scalar(raw).unwrap_or_else(|| Value::String(raw.to_string())),
+    };
+    set_path(table, key, val);
+}
+
+fn parse_toml_scalar(raw: &str) -> Option<Value> {
+    let doc: Table = toml::from_str(&format!("v = {raw}")).ok()?;
+    doc.get("v").cloned()
+}
+
+fn get_path<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+    let mut parts = key.split('.').peekable();
+    let mut current = table;
+    while let Some(part) = parts.next() {
+        let val = current.get(part)?;
+        if parts.peek().is_none() {
+            return Some(val);
+        }
+        current = val.as_table()?;
+    }
+    None
+}
+
+fn set_path(table: &mut Table, key: &str, val: Value) {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().expect("empty config key");
+    let mut current = table;
+    for part in parts {
+        let entry = current
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+        current = entry.as_table_mut().unwrap();
+    }
+    current.insert(last.to_string(), val);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    #[serde(default)]
+    struct Inner {
+        host: String,
+        password: String,
+        insecure: bool,
+    }
+
+    impl Default for Inner {
+        fn default() -> Self {
+            Self {
+                host: "localhost:4222".to_string(),
+                password: String::new(),
+                insecure: false,
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    #[serde(default)]
+    struct TestSettings {
+        app_version: String,
+        max_rooms: usize,
+        player_limit: Option<u8>,
+        api_key: String,
+        api_key_file: String,
+        nats: Inner,
+    }
+
+    impl Default for TestSettings {
+        fn default() -> Self {
+            Self {
+                app_version: "v0.0.1".to_string(),
+                max_rooms: 10,
+                player_limit: None,
+                api_key: String::new(),
+                api_key_file: String::new(),
+                nats: Inner::default(),
+            }
+        }
+    }
+
+    #[derive(Serialize, Default)]
+    struct TestCli {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_rooms: Option<usize>,
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults_when_nothing_set() {
+        let settings: TestSettings = ConfigLoader::new("TEST")
+            .load_from(env(&[]), &TestCli::default())
+            .unwrap();
+        assert_eq!(settings, TestSettings::default());
+    }
+
+    #[test]
+    fn test_layer_precedence() {
+        let path = std::env::temp_dir().join("bevygap_config_test_precedence.toml");
+        std::fs::write(
+            &path,
+            "max_rooms = 20\napp_version = \"2\"\n[nats]\nhost = \"file:4222\"\n",
+        )
+        .unwrap();
+        let loader = ConfigLoader::new("TEST")
+            .file(Some(&path))
+            .env_alias("LEGACY_MAX_ROOMS", "max_rooms")
+            .env_alias("NATS_HOST", "nats.host");
+
+        // file beats defaults
+        let settings: TestSettings = loader.load_from(env(&[]), &TestCli::default()).unwrap();
+        assert_eq!(settings.max_rooms, 20);
+        assert_eq!(settings.app_version, "2");
+        assert_eq!(settings.nats.host, "file:4222");
+
+        // legacy env beats file, prefixed env beats legacy env
+        let settings: TestSettings = loader
+            .load_from(
+                env(&[("LEGACY_MAX_ROOMS", "30"), ("NATS_HOST", "legacy:4222")]),
+                &TestCli::default(),
+            )
+            .unwrap();
+        assert_eq!(settings.max_rooms, 30);
+        assert_eq!(settings.nats.host, "legacy:4222");
+        let settings: TestSettings = loader
+            .load_from(
+                env(&[
+                    ("LEGACY_MAX_ROOMS", "30"),
+                    ("TEST_MAX_ROOMS", "40"),
+                    ("TEST_NATS__HOST", "prefixed:4222"),
+                ]),
+                &TestCli::default(),
+            )
+            .unwrap();
+        assert_eq!(settings.max_rooms, 40);
+        assert_eq!(settings.nats.host, "prefixed:4222");
+
+        // cli beats everything
+        let settings: TestSettings = loader
+            .load_from(
+                env(&[("TEST_MAX_ROOMS", "40")]),
+                &TestCli {
+                    max_rooms: Some(50),
+                },
+            )
+            .unwrap();
+        assert_eq!(settings.max_rooms, 50);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_env_values_coerced() {
+        let settings: TestSettings = ConfigLoader::new("TEST")
+            .env_flag("NATS_INSECURE", "nats.insecure")
+            .load_from(
+                env(&[
+                    // a numeric-looking string stays a string
+                    ("TEST_APP_VERSION", "1"),
+                    ("TEST_PLAYER_LIMIT", "4"),
+                    ("NATS_INSECURE", "set"),
+                ]),
+                &TestCli::default(),
+            )
+            .unwrap();
+        assert_eq!(settings.app_version, "1");
+        assert_eq!(settings.player_limit, Some(4));
+        assert!(settings.nats.insecure);
+    }
+
+    #[test]
+    fn test_invalid_value_is_an_error() {
+        let res: Result<TestSettings, _> = ConfigLoader::new("TEST")
+            .load_from(env(&[("TEST_MAX_ROOMS", "lots")]), &TestCli::default());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_config_args_flatten_into_cli() {
+        use clap::Parser;
+
+        #[derive(Parser, Serialize)]
+        struct Cli {
+            #[command(flatten)]
+            #[serde(skip)]
+            config: ConfigArgs,
+            #[arg(long)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            max_rooms: Option<usize>,
+        }
+
+        let cli = Cli::try_parse_from([
+            "test",
+            "--config",
+            "/etc/bevygap.toml",
+            "--print-config",
+            "--max-rooms",
+            "5",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.config.config.as_deref(),
+            Some(std::path::Path::new("/etc/bevygap.toml"))
+        );
+        assert!(cli.config.print_config);
+        let settings: TestSettings = ConfigLoader::new("TEST").load_from(env(&[]), &cli).unwrap();
+        assert_eq!(settings.max_rooms, 5);
+    }
+
+    #[test]
+    fn test_redacted_output() {
+        let settings = TestSettings {
+            api_key: "token abc".to_string(),
+            api_key_file: "/run/secrets/api_key".to_string(),
+            nats: Inner {
+                password: "hunter2".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let out = to_redacted_toml(&settings).unwrap();
+        assert!(!out.contains("abc"));
+        assert!(!out.contains("hunter2"));
+        assert!(out.contains(REDACTED));
+        assert!(out.contains("localhost:4222"));
+        assert!(out.contains("/run/secrets/api_key"));
+    }
+}
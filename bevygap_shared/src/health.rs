@@ -0,0 +1,212 @@
+//! Health and readiness reporting for the bevygap services.
+//!
+//! Services keep a [`Health`] handle, and update named checks as things happen
+//! (a worker starts or dies, an Edgegap API call succeeds, etc). The `/healthz` and
+//! `/readyz` endpoints then combine these with live NATS checks into a [`HealthReport`].
+//!
+//! * Liveness (`/healthz`) only considers checks that mean the process should be restarted
+//!   if they fail: the NATS connection and background workers.
+//! * Readiness (`/readyz`) considers everything, including JetStream buckets and the Edgegap API.
+use crate::util::unix_now;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Ok,
+    /// Working, but something is wrong that might need attention.
+    Degraded,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthCheck {
+    pub name: String,
+    pub state: HealthState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Unix timestamp of when the state last changed
+    pub since: u64,
+    /// Unix timestamp of the last time this check was reported Ok
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_ok: Option<u64>,
+    /// If true, a failure of this check fails liveness, not just readiness.
+    #[serde(skip)]
+    pub liveness: bool,
+}
+
+impl HealthCheck {
+    pub fn new(name: impl Into<String>, state: HealthState, detail: Option<String>) -> Self {
+        let now = unix_now();
+        Self {
+            name: name.into(),
+            state,
+            detail,
+            since: now,
+            last_ok: (state == HealthState::Ok).then_some(now),
+            liveness: false,
+        }
+    }
+
+    /// Marks this check as one that also counts towards liveness.
+    pub fn for_liveness(mut self) -> Self {
+        self.liveness = true;
+        self
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthReport {
+    /// The worst state of all the checks
+    pub state: HealthState,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn from_checks(checks: Vec<HealthCheck>) -> Self {
+        let state = checks
+            .iter()
+            .map(|c| c.state)
+            .max()
+            .unwrap_or(HealthState::Ok);
+        Self { state, checks }
+    }
+
+    /// 503 if anything failed, otherwise 200. Degraded is still considered up.
+    pub fn http_status(&self) -> u16 {
+        if self.state == HealthState::Failed {
+            503
+        } else {
+            200
+        }
+    }
+}
+
+/// Shared, cloneable registry of health checks for a service.
+#[derive(Clone, Default, Debug)]
+pub struct Health {
+    checks: Arc<RwLock<BTreeMap<String, HealthCheck>>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates (or creates) a readiness check.
+    pub fn set(&self, name: &str, state: HealthState, detail: Option<String>) {
+        self.update(name, state, detail, false);
+    }
+
+    /// Updates (or creates) a check that also counts towards liveness, eg a background worker.
+    pub fn set_liveness(&self, name: &str, state: HealthState, detail: Option<String>) {
+        self.update(name, state, detail, true);
+    }
+
+    fn update(&self, name: &str, state: HealthState, detail: Option<String>, liveness: bool) {
+        let now = unix_now();
+        let mut checks = self.checks.write().unwrap();
+        let check = checks
+            .entry(name.to_string())
+            .or_insert_with(|| HealthCheck::new(name, state, None));
+        if check.state != state {
+            check.since = now;
+        }
+        if state == HealthState::Ok {
+            check.last_ok = Some(now);
+        }
+        check.state = state;
+        check.detail = detail;
+        check.liveness = liveness;
+    }
+
+    /// Shorthand for a successful call to some external dependency, eg the Edgegap API.
+    pub fn record_success(&self, name: &str) {
+        self.set(name, HealthState::Ok, None);
+    }
+
+    /// Shorthand for a failed call to some external dependency, eg the Edgegap API.
+    /// This degrades rather than fails readiness, since one bad call doesn't mean the service is down.
+    pub fn record_failure(&self, name: &str, err: impl std::fmt::Display) {
+        self.set(name, HealthState::Degraded, Some(err.to_string()));
+    }
+
+    pub fn worker_running(&self, name: &str) {
+        self.set_liveness(name, HealthState::Ok, None);
+    }
+
+    pub fn worker_stopped(&self, name: &str, reason: impl std::fmt::Display) {
+        self.set_liveness(name, HealthState::Failed, Some(reason.to_string()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<HealthCheck> {
+        self.checks.read().unwrap().get(name).cloned()
+    }
+
+    pub fn checks(&self) -> Vec<HealthCheck> {
+        self.checks.read().unwrap().values().cloned().collect()
+    }
+
+    /// Report of registered liveness checks, plus any extra (eg live NATS) checks supplied.
+    pub fn liveness(&self, extra: Vec<HealthCheck>) -> HealthReport {
+        let checks = extra
+            .into_iter()
+            .chain(self.checks())
+            .filter(|c| c.liveness)
+            .collect();
+        HealthReport::from_checks(checks)
+    }
+
+    /// Report of all registered checks, plus any extra (eg live NATS) checks supplied.
+    pub fn readiness(&self, extra: Vec<HealthCheck>) -> HealthReport {
+        HealthReport::from_checks(extra.into_iter().chain(self.checks()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liveness_ignores_readiness_checks() {
+        let health = Health::new();
+        health.worker_running("reaper");
+        health.record_failure("edgegap", "timeout");
+        health.set("kv.active_connections", HealthState::Failed, None);
+
+        let live = health.liveness(vec![]);
+        assert_eq!(live.state, HealthState::Ok);
+        assert_eq!(live.http_status(), 200);
+        assert_eq!(live.checks.len(), 1);
+
+        let ready = health.readiness(vec![]);
+        assert_eq!(ready.state, HealthState::Failed);
+        assert_eq!(ready.http_status(), 503);
+        assert_eq!(ready.checks.len(), 3);
+    }
+
+    #[test]
+    fn test_dead_worker_fails_liveness() {
+        let health = Health::new();
+        health.worker_running("reaper");
+        health.worker_stopped("reaper", "boom");
+        let nats = HealthCheck::new("nats", HealthState::Ok, None).for_liveness();
+        let live = health.liveness(vec![nats]);
+        assert_eq!(live.state, HealthState::Failed);
+        let reaper = health.get("reaper").unwrap();
+        assert_eq!(reaper.detail.as_deref(), Some("boom"));
+        assert!(reaper.last_ok.is_some());
+    }
+
+    #[test]
+    fn test_degraded_is_still_up() {
+        let health = Health::new();
+        health.record_success("edgegap");
+        health.record_failure("edgegap", "503 from api");
+        let ready = health.readiness(vec![]);
+        assert_eq!(ready.state, HealthState::Degraded);
+        assert_eq!(ready.http_status(), 200);
+    }
+}
@@ -0,0 +1,781 @@
+//! Lobby rooms, stored in a NATS KV bucket so every httpd instance sees the same rooms.
+//!
+//! Changes to a room are compare-and-swap writes against the revision that was read, retried
+//! when another instance got there first. The bucket's `max_age` is the room TTL: a room that
+//! isn't written to for that long (ie. nobody joins, leaves or starts it) is dropped.
+//!
+//! Rooms that haven't started also have a key in the `lobby_waiting_rooms` bucket, with the same
+//! TTL, so `max_rooms` can be enforced by counting keys rather than reading every room.
+use crate::protocol::LobbyDeployment;
+use async_nats::jetstream::{self, kv};
+use async_nats::Client;
+use futures_util::{Stream, StreamExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+const LOBBY_ROOMS_BUCKET: &str = "lobby_rooms";
+const LOBBY_WAITING_BUCKET: &str = "lobby_waiting_rooms";
+/// How many times a change is retried after losing a race with another writer
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+/// Room ids are `ROOM` plus this many characters, skipping easily confused ones like 0/O
+const ROOM_ID_LEN: usize = 5;
+const ROOM_ID_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const INVITE_CODE_LEN: usize = 6;
+const SESSION_TOKEN_LEN: usize = 32;
+const SESSION_TOKEN_CHARS: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// A lobby room, as shown to players.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LobbyRoom {
+    pub id: String,
+    pub host_name: String,
+    pub game_mode: String,
+    pub created_at: u64,
+    pub started: bool,
+    pub status: RoomStatus,
+    pub current_players: u32,
+    pub max_players: u32,
+    /// Joining needs the password, or the invite code
+    #[serde(default)]
+    pub password_protected: bool,
+    /// Left out of room lists, joining needs the invite code
+    #[serde(default)]
+    pub invite_only: bool,
+    /// In the order they joined. The first is the host.
+    #[serde(default)]
+    pub members: Vec<LobbyMember>,
+    /// Session information when game server is deployed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_info: Option<SessionInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyMember {
+    pub player_name: String,
+    /// Everyone but the host must be ready before the room can start
+    pub ready: bool,
+    pub joined_at: u64,
+}
+
+/// Rooms move from Waiting to Deploying when started, then to Ready once their gameserver is
+/// running, and InGame once players start fetching connect tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomStatus {
+    #[default]
+    Waiting,
+    Deploying,
+    Ready,
+    InGame,
+    /// The deployment failed, the room can be started again
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Edgegap's request id for the room's deployment
+    pub request_id: Option<String>,
+    pub game_server_ip: Option<String>,
+    pub game_server_port: Option<u16>,
+    pub deployment_status: String,
+}
+
+impl SessionInfo {
+    pub fn status(deployment_status: impl Into<String>) -> Self {
+        Self {
+            request_id: None,
+            game_server_ip: None,
+            game_server_port: None,
+            deployment_status: deployment_status.into(),
+        }
+    }
+}
+
+/// A member's private details.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberSession {
+    pub player_name: String,
+    /// Given to the player when they create or join the room, to prove who they are later
+    pub session_token: String,
+    /// So Edgegap can deploy somewhere that suits every member
+    pub ip: String,
+}
+
+/// What's stored in the KV for each room: the public room, plus details players don't see.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LobbyRecord {
+    #[serde(flatten)]
+    pub room: LobbyRoom,
+    #[serde(default)]
+    pub sessions: Vec<MemberSession>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Lets players join without the password, or join invite-only rooms
+    #[serde(default)]
+    pub invite_code: String,
+    /// The room's gameserver, once it's running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<LobbyDeployment>,
+    /// Edgegap sessions handed out for the deployment, until Edgegap deletes them
+    #[serde(default)]
+    pub game_session_ids: Vec<String>,
+    /// The room's id in an external lobby service that mirrors it, eg Edgegap's Lobbies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    /// When the room was last written, see [`LobbyStore::update`]
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+impl LobbyRecord {
+    /// A waiting room with the host as its only member.
+    pub fn new(
+        id: String,
+        host_name: &str,
+        game_mode: &str,
+        max_players: u32,
+        host_ip: &str,
+    ) -> Self {
+        let mut record = Self {
+            room: LobbyRoom {
+                id,
+                host_name: host_name.to_string(),
+                game_mode: game_mode.to_string(),
+                created_at: now_secs(),
+                started: false,
+                status: RoomStatus::Waiting,
+                current_players: 0,
+                max_players,
+                password_protected: false,
+                invite_only: false,
+                members: Vec::new(),
+                session_info: None,
+            },
+            sessions: Vec::new(),
+            password: None,
+            invite_code: random_string(INVITE_CODE_LEN, ROOM_ID_CHARS),
+            deployment: None,
+            game_session_ids: Vec::new(),
+            external_id: None,
+            updated_at: now_secs(),
+        };
+        record.push_member(host_name, host_ip);
+        record
+    }
+
+    /// Makes joining need this password, or the invite code.
+    pub fn with_password(mut self, password: Option<String>) -> Self {
+        self.password = password.filter(|p| !p.is_empty());
+        self.room.password_protected = self.password.is_some();
+        self
+    }
+
+    /// Hides the room from room lists, so joining needs the invite code.
+    pub fn with_invite_only(mut self, invite_only: bool) -> Self {
+        self.room.invite_only = invite_only;
+        self
+    }
+
+    /// Adds a player, checking the password or invite code. Read their token with
+    /// [`Self::session_token`] afterwards.
+    pub fn add_member(
+        &mut self,
+        player_name: &str,
+        ip: &str,
+        password: Option<&str>,
+        invite_code: Option<&str>,
+    ) -> Result<(), LobbyError> {
+        if self.room.started {
+            return Err(LobbyError::Conflict("room already started".to_string()));
+        }
+        let invited = invite_code.is_some_and(|code| code.eq_ignore_ascii_case(&self.invite_code));
+        if !invited {
+            if self.room.invite_only {
+                return Err(LobbyError::Forbidden("invite code required".to_string()));
+            }
+            if self.password.is_some() && self.password.as_deref() != password {
+                return Err(LobbyError::Forbidden("wrong password".to_string()));
+            }
+        }
+        if player_name.trim().is_empty() {
+            return Err(LobbyError::Conflict("player name required".to_string()));
+        }
+        if self.member(player_name).is_some() {
+            return Err(LobbyError::Conflict("player name taken".to_string()));
+        }
+        if self.room.members.len() >= self.room.max_players as usize {
+            return Err(LobbyError::Conflict("room full".to_string()));
+        }
+        self.push_member(player_name, ip);
+        Ok(())
+    }
+
+    fn push_member(&mut self, player_name: &str, ip: &str) {
+        self.room.members.push(LobbyMember {
+            player_name: player_name.to_string(),
+            ready: false,
+            joined_at: now_secs(),
+        });
+        self.sessions.push(MemberSession {
+            player_name: player_name.to_string(),
+            session_token: random_string(SESSION_TOKEN_LEN, SESSION_TOKEN_CHARS),
+            ip: ip.to_string(),
+        });
+        self.room.current_players = self.room.members.len() as u32;
+    }
+
+    /// Removes a player. If they were the host, the longest-standing member takes over.
+    pub fn remove_member(&mut self, player_name: &str) -> Result<(), LobbyError> {
+        let Some(i) = self
+            .room
+            .members
+            .iter()
+            .position(|m| m.player_name == player_name)
+        else {
+            return Err(LobbyError::Conflict(format!(
+                "{player_name} isn't in this room"
+            )));
+        };
+        self.room.members.remove(i);
+        self.sessions.retain(|s| s.player_name != player_name);
+        self.room.current_players = self.room.members.len() as u32;
+        if self.room.host_name == player_name {
+            if let Some(next) = self.room.members.first() {
+                self.room.host_name = next.player_name.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `player_name`, if `by` is the host.
+    pub fn kick(&mut self, by: &str, player_name: &str) -> Result<(), LobbyError> {
+        self.require_host(by, "kick players")?;
+        if by == player_name {
+            return Err(LobbyError::Conflict(
+                "the host can't kick themselves".to_string(),
+            ));
+        }
+        self.remove_member(player_name)
+    }
+
+    pub fn set_ready(&mut self, player_name: &str, ready: bool) -> Result<(), LobbyError> {
+        if self.room.started {
+            return Err(LobbyError::Conflict("room already started".to_string()));
+        }
+        let member = self
+            .room
+            .members
+            .iter_mut()
+            .find(|m| m.player_name == player_name)
+            .ok_or_else(|| LobbyError::Conflict(format!("{player_name} isn't in this room")))?;
+        member.ready = ready;
+        Ok(())
+    }
+
+    /// Checks that `by` is the host, and everyone else is ready.
+    pub fn check_start(&self, by: &str) -> Result<(), LobbyError> {
+        self.require_host(by, "start the room")?;
+        let not_ready: Vec<&str> = self
+            .room
+            .members
+            .iter()
+            .filter(|m| !m.ready && m.player_name != by)
+            .map(|m| m.player_name.as_str())
+            .collect();
+        if !not_ready.is_empty() {
+            return Err(LobbyError::Conflict(format!(
+                "waiting for {} to be ready",
+                not_ready.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    fn require_host(&self, player_name: &str, action: &str) -> Result<(), LobbyError> {
+        if self.room.host_name != player_name {
+            return Err(LobbyError::Forbidden(format!("only the host can {action}")));
+        }
+        Ok(())
+    }
+
+    /// The member a session token belongs to.
+    pub fn authorize(&self, session_token: &str) -> Result<&str, LobbyError> {
+        self.sessions
+            .iter()
+            .find(|s| s.session_token == session_token)
+            .map(|s| s.player_name.as_str())
+            .ok_or(LobbyError::Unauthorized)
+    }
+
+    pub fn member(&self, player_name: &str) -> Option<&LobbyMember> {
+        self.room
+            .members
+            .iter()
+            .find(|m| m.player_name == player_name)
+    }
+
+    pub fn session_token(&self, player_name: &str) -> Option<&str> {
+        self.sessions
+            .iter()
+            .find(|s| s.player_name == player_name)
+            .map(|s| s.session_token.as_str())
+    }
+
+    /// Whether the room is still waiting for players, and hasn't changed for `idle_ttl`.
+    pub fn is_idle(&self, now: u64, idle_ttl: Duration) -> bool {
+        !self.room.started && now.saturating_sub(self.updated_at) >= idle_ttl.as_secs()
+    }
+
+    /// Forgets a deleted Edgegap session. Returns whether it was one of this room's.
+    pub fn end_game_session(&mut self, session_id: &str) -> bool {
+        let before = self.game_session_ids.len();
+        self.game_session_ids.retain(|id| id != session_id);
+        self.game_session_ids.len() != before
+    }
+
+    /// Whether the room's game has been played, and every player's session has been deleted.
+    pub fn is_finished(&self) -> bool {
+        self.room.status == RoomStatus::InGame && self.game_session_ids.is_empty()
+    }
+
+    /// The members' IPs, for placing the room's deployment.
+    pub fn player_ips(&self) -> Vec<String> {
+        self.sessions.iter().map(|s| s.ip.clone()).collect()
+    }
+}
+
+/// A change to a room, see [`LobbyStore::watch`].
+#[derive(Clone, Debug)]
+pub enum RoomChange {
+    Updated(Box<LobbyRecord>),
+    Removed(String),
+}
+
+#[derive(Debug)]
+pub enum LobbyError {
+    NotFound,
+    /// The change isn't allowed in the room's current state, eg. it's full or already started
+    Conflict(String),
+    /// There are already this many rooms waiting for players
+    TooManyRooms(usize),
+    /// No session token, or it isn't for a member of the room
+    Unauthorized,
+    /// The player may not do this, eg. they aren't the host or gave the wrong password
+    Forbidden(String),
+    /// Talking to the KV failed, or kept losing races with other writers
+    Store(String),
+    /// The lobby backend, eg Edgegap's Lobbies service, failed or refused the change
+    Backend(String),
+}
+
+impl LobbyError {
+    fn store(e: impl fmt::Display) -> Self {
+        Self::Store(e.to_string())
+    }
+
+    /// The HTTP status code to report this error with.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::NotFound => 404,
+            Self::Conflict(_) => 409,
+            Self::Unauthorized => 401,
+            Self::Forbidden(_) => 403,
+            Self::TooManyRooms(_) => 429,
+            Self::Store(_) => 500,
+            Self::Backend(_) => 502,
+        }
+    }
+}
+
+impl fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "room not found"),
+            Self::Conflict(msg) => write!(f, "{msg}"),
+            Self::TooManyRooms(max) => write!(f, "maximum active rooms reached ({max})"),
+            Self::Unauthorized => write!(f, "missing or unknown session token"),
+            Self::Forbidden(msg) => write!(f, "{msg}"),
+            Self::Store(msg) => write!(f, "lobby store error: {msg}"),
+            Self::Backend(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for LobbyError {}
+
+/// Lobby rooms in the `lobby_rooms` KV bucket.
+#[derive(Clone)]
+pub struct LobbyStore {
+    kv: kv::Store,
+    /// Ids of rooms that haven't started, see the module docs
+    waiting: kv::Store,
+    max_rooms: usize,
+}
+
+impl LobbyStore {
+    /// Opens the rooms bucket, creating it if needed. Rooms not written to for `room_ttl`
+    /// expire. The TTL is fixed when the bucket is created, so changing it later means
+    /// deleting the bucket.
+    pub async fn open(
+        client: Client,
+        max_rooms: usize,
+        room_ttl: Duration,
+    ) -> Result<Self, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(kv::Config {
+                bucket: LOBBY_ROOMS_BUCKET.to_string(),
+                description: "Lobby rooms, keyed by room id".to_string(),
+                max_age: room_ttl,
+                max_value_size: 16 * 1024,
+                ..Default::default()
+            })
+            .await?;
+        let waiting = jetstream
+            .create_key_value(kv::Config {
+                bucket: LOBBY_WAITING_BUCKET.to_string(),
+                description: "Ids of lobby rooms that haven't started".to_string(),
+                max_age: room_ttl,
+                max_value_size: 64,
+                ..Default::default()
+            })
+            .await?;
+        Ok(Self {
+            kv,
+            waiting,
+            max_rooms,
+        })
+    }
+
+    pub fn max_rooms(&self) -> usize {
+        self.max_rooms
+    }
+
+    pub async fn get(&self, id: &str) -> Result<LobbyRecord, LobbyError> {
+        self.entry(id).await.map(|(record, _)| record)
+    }
+
+    /// Every room in the store, oldest first.
+    pub async fn rooms(&self) -> Result<Vec<LobbyRecord>, LobbyError> {
+        let mut keys = self.kv.keys().await.map_err(LobbyError::store)?;
+        let mut records = Vec::new();
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(LobbyError::store)?;
+            match self.get(&key).await {
+                Ok(record) => records.push(record),
+                // deleted or expired since we listed the keys
+                Err(LobbyError::NotFound) => {}
+                Err(LobbyError::Store(e)) => warn!("Skipping lobby room {key}: {e}"),
+                Err(e) => return Err(e),
+            }
+        }
+        records.sort_by_key(|r| r.room.created_at);
+        Ok(records)
+    }
+
+    /// Streams changes to every room, made by any instance, from now on.
+    /// Rooms that expire through the TTL are dropped silently, so don't show up here.
+    pub async fn watch(
+        &self,
+    ) -> Result<impl Stream<Item = Result<RoomChange, LobbyError>>, LobbyError> {
+        let watch = self.kv.watch_all().await.map_err(LobbyError::store)?;
+        Ok(watch.map(|entry| {
+            let entry = entry.map_err(LobbyError::store)?;
+            match entry.operation {
+                kv::Operation::Put => serde_json::from_slice(&entry.value)
+                    .map(|record| RoomChange::Updated(Box::new(record)))
+                    .map_err(LobbyError::store),
+                kv::Operation::Delete | kv::Operation::Purge => Ok(RoomChange::Removed(entry.key)),
+            }
+        }))
+    }
+
+    /// Adds a room under a new unique id. `make_room` is given the id, and may be called more
+    /// than once if the id is taken. Fails if `max_rooms` rooms are already waiting. The new
+    /// room is counted before it's kept, so rooms created at the same moment by several
+    /// instances can't go over the limit.
+    pub async fn create(
+        &self,
+        mut make_room: impl FnMut(String) -> LobbyRecord,
+    ) -> Result<LobbyRecord, LobbyError> {
+        if self.waiting_rooms().await? >= self.max_rooms {
+            return Err(LobbyError::TooManyRooms(self.max_rooms));
+        }
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let record = make_room(new_room_id());
+            match self
+                .kv
+                .create(&record.room.id, encode(&record)?.into())
+                .await
+            {
+                Ok(_) => {
+                    let id = &record.room.id;
+                    self.set_waiting(id, true).await?;
+                    if self.waiting_rooms().await? > self.max_rooms {
+                        debug!("Lost the race for the last lobby room, removing {id}");
+                        self.kv.delete(id).await.map_err(LobbyError::store)?;
+                        self.set_waiting(id, false).await?;
+                        return Err(LobbyError::TooManyRooms(self.max_rooms));
+                    }
+                    return Ok(record);
+                }
+                Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => {
+                    debug!("Room id {} is taken, trying another", record.room.id);
+                }
+                Err(e) => return Err(LobbyError::store(e)),
+            }
+        }
+        Err(LobbyError::Store("no unused room id found".to_string()))
+    }
+
+    /// Applies `change` to the room and writes it back, as long as nobody else changed the
+    /// room in the meantime. If they did, `change` runs again on their version.
+    /// Errors returned by `change` abort the update. Bumps `updated_at`.
+    pub async fn update(
+        &self,
+        id: &str,
+        mut change: impl FnMut(&mut LobbyRecord) -> Result<(), LobbyError>,
+    ) -> Result<LobbyRecord, LobbyError> {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (mut record, revision) = self.entry(id).await?;
+            let was_waiting = !record.room.started;
+            change(&mut record)?;
+            record.updated_at = now_secs();
+            match self.kv.update(id, encode(&record)?.into(), revision).await {
+                Ok(_) => {
+                    // refreshes the waiting key's TTL along with the room's
+                    if !record.room.started || was_waiting {
+                        self.set_waiting(id, !record.room.started).await?;
+                    }
+                    return Ok(record);
+                }
+                Err(e) if e.kind() == kv::UpdateErrorKind::WrongLastRevision => {
+                    debug!("Lobby room {id} changed under us, retrying");
+                }
+                Err(e) => return Err(LobbyError::store(e)),
+            }
+        }
+        Err(LobbyError::Store(format!(
+            "too many concurrent changes to room {id}"
+        )))
+    }
+
+    /// Deletes the room if `should_remove` says so, unless it changed while deciding.
+    /// Returns whether the room was deleted.
+    pub async fn remove_if(
+        &self,
+        id: &str,
+        mut should_remove: impl FnMut(&LobbyRecord) -> bool,
+    ) -> Result<bool, LobbyError> {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (record, revision) = match self.entry(id).await {
+                Ok(entry) => entry,
+                Err(LobbyError::NotFound) => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            if !should_remove(&record) {
+                return Ok(false);
+            }
+            match self.kv.delete_expect_revision(id, Some(revision)).await {
+                Ok(()) => {
+                    if !record.room.started {
+                        self.set_waiting(id, false).await?;
+                    }
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == kv::DeleteErrorKind::WrongLastRevision => {
+                    debug!("Lobby room {id} changed under us, retrying");
+                }
+                Err(e) => return Err(LobbyError::store(e)),
+            }
+        }
+        Err(LobbyError::Store(format!(
+            "too many concurrent changes to room {id}"
+        )))
+    }
+
+    /// How many rooms haven't started, counted from their keys.
+    async fn waiting_rooms(&self) -> Result<usize, LobbyError> {
+        let mut keys = self.waiting.keys().await.map_err(LobbyError::store)?;
+        let mut count = 0;
+        while let Some(key) = keys.next().await {
+            key.map_err(LobbyError::store)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    async fn set_waiting(&self, id: &str, waiting: bool) -> Result<(), LobbyError> {
+        if waiting {
+            self.waiting
+                .put(id, Default::default())
+                .await
+                .map(|_| ())
+                .map_err(LobbyError::store)
+        } else {
+            self.waiting.delete(id).await.map_err(LobbyError::store)
+        }
+    }
+
+    async fn entry(&self, id: &str) -> Result<(LobbyRecord, u64), LobbyError> {
+        let entry = self
+            .kv
+            .entry(id)
+            .await
+            .map_err(LobbyError::store)?
+            .filter(|e| e.operation == kv::Operation::Put)
+            .ok_or(LobbyError::NotFound)?;
+        let record = serde_json::from_slice(&entry.value).map_err(LobbyError::store)?;
+        Ok((record, entry.revision))
+    }
+}
+
+fn encode(record: &LobbyRecord) -> Result<Vec<u8>, LobbyError> {
+    serde_json::to_vec(record).map_err(LobbyError::store)
+}
+
+/// A random room id, like `ROOMK7Q2F`.
+pub fn new_room_id() -> String {
+    format!("ROOM{}", random_string(ROOM_ID_LEN, ROOM_ID_CHARS))
+}
+
+fn random_string(len: usize, chars: &[u8]) -> String {
+    (0..len)
+        .map(|_| chars[rand::random::<u32>() as usize % chars.len()] as char)
+        .collect()
+}
+
+/// Seconds since the unix epoch, for `LobbyRoom::created_at`.
+pub fn now_secs() -> u64 {
+    crate::util::unix_now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::LobbyDeployment;
+
+    #[test]
+    fn test_record_keeps_private_fields_out_of_room() {
+        let mut record = LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4")
+            .with_password(Some("hunter2".to_string()));
+        record
+            .add_member("bob", "5.6.7.8", Some("hunter2"), None)
+            .unwrap();
+        record.deployment = Some(LobbyDeployment {
+            request_id: "93924761ccde".to_string(),
+            app_name: "mygame".to_string(),
+            app_version: "1".to_string(),
+            ip: "9.9.9.9".to_string(),
+            port: 31722,
+        });
+        let stored = serde_json::to_string(&record).unwrap();
+        let decoded: LobbyRecord = serde_json::from_str(&stored).unwrap();
+        assert_eq!(decoded.room.id, record.room.id);
+        assert_eq!(decoded.room.members, record.room.members);
+        assert_eq!(decoded.player_ips(), ["1.2.3.4", "5.6.7.8"]);
+        assert_eq!(decoded.session_token("bob"), record.session_token("bob"));
+        assert_eq!(decoded.deployment.unwrap().port, 31722);
+
+        let public = serde_json::to_value(&record.room).unwrap();
+        assert_eq!(public["host_name"], "alice");
+        assert_eq!(public["current_players"], 2);
+        assert_eq!(public["password_protected"], true);
+        assert!(!public.to_string().contains("hunter2"));
+        for private in ["sessions", "password", "invite_code", "deployment"] {
+            assert!(public.get(private).is_none(), "{private} is public");
+        }
+    }
+
+    #[test]
+    fn test_room_access() {
+        let mut record = LobbyRecord::new(new_room_id(), "alice", "ffa", 3, "1.2.3.4")
+            .with_password(Some("hunter2".to_string()));
+        let invite_code = record.invite_code.clone();
+        let err = record
+            .add_member("bob", "5.6.7.8", Some("wrong"), None)
+            .unwrap_err();
+        assert_eq!(err.status_code(), 403);
+        record
+            .add_member("bob", "5.6.7.8", None, Some(&invite_code.to_lowercase()))
+            .unwrap();
+        let err = record
+            .add_member("bob", "5.6.7.8", Some("hunter2"), None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "player name taken");
+        record
+            .add_member("carol", "5.6.7.9", Some("hunter2"), None)
+            .unwrap();
+        let err = record
+            .add_member("dave", "5.6.7.9", Some("hunter2"), None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "room full");
+
+        let mut hidden =
+            LobbyRecord::new(new_room_id(), "alice", "ffa", 3, "1.2.3.4").with_invite_only(true);
+        assert!(hidden.add_member("bob", "5.6.7.8", None, None).is_err());
+        let code = hidden.invite_code.clone();
+        hidden
+            .add_member("bob", "5.6.7.8", None, Some(&code))
+            .unwrap();
+
+        let bob_token = record.session_token("bob").unwrap().to_string();
+        assert_eq!(record.authorize(&bob_token).unwrap(), "bob");
+        assert_eq!(record.authorize("nope").unwrap_err().status_code(), 401);
+    }
+
+    #[test]
+    fn test_host_authority_and_ready_check() {
+        let mut record = LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4");
+        record.add_member("bob", "5.6.7.8", None, None).unwrap();
+        record.add_member("carol", "5.6.7.9", None, None).unwrap();
+
+        assert_eq!(record.check_start("bob").unwrap_err().status_code(), 403);
+        assert_eq!(record.kick("bob", "carol").unwrap_err().status_code(), 403);
+        let err = record.check_start("alice").unwrap_err();
+        assert_eq!(err.to_string(), "waiting for bob, carol to be ready");
+        record.set_ready("bob", true).unwrap();
+        record.kick("alice", "carol").unwrap();
+        assert!(record.session_token("carol").is_none());
+        record.check_start("alice").unwrap();
+
+        // the host leaving hands over to the longest-standing member
+        record.remove_member("alice").unwrap();
+        assert_eq!(record.room.host_name, "bob");
+        assert_eq!(record.room.current_players, 1);
+        assert_eq!(record.player_ips(), ["5.6.7.8"]);
+        record.check_start("bob").unwrap();
+    }
+
+    #[test]
+    fn test_idle_and_finished_rooms() {
+        let ttl = std::time::Duration::from_secs(600);
+        let mut record = LobbyRecord::new(new_room_id(), "alice", "ffa", 4, "1.2.3.4");
+        let now = record.updated_at;
+        assert!(!record.is_idle(now + 599, ttl));
+        assert!(record.is_idle(now + 600, ttl));
+
+        record.room.started = true;
+        record.room.status = RoomStatus::InGame;
+        assert!(!record.is_idle(now + 600, ttl));
+        record.game_session_ids = vec!["s1".to_string(), "s2".to_string()];
+        assert!(!record.end_game_session("other"));
+        assert!(record.end_game_session("s1"));
+        assert!(!record.is_finished());
+        assert!(record.end_game_session("s2"));
+        assert!(record.is_finished());
+    }
+
+    #[test]
+    fn test_new_room_ids() {
+        let id = new_room_id();
+        assert_eq!(id.len(), 9);
+        assert!(id.starts_with("ROOM"));
+        assert!(id
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()));
+        assert_ne!(new_room_id(), new_room_id());
+    }
+}
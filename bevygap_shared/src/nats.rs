@@ -1,11 +1,53 @@
 use async_nats::jetstream::stream::Stream;
 use async_nats::jetstream::{self, stream};
+use async_nats::connection::State as ConnectionState;
 use async_nats::Client;
+use crate::health::{HealthCheck, HealthState};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use std::net::{SocketAddr, ToSocketAddrs};
 
 use log::*;
 
+/// Connection settings for NATS.
+///
+/// Traditionally read from `NATS_*` env vars with [`NatsSettings::from_env`], but the binaries
+/// also accept these in the `[nats]` table of their config file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NatsSettings {
+    /// Server address, eg `nats.example.com:4222`
+    pub host: String,
+    pub user: String,
+    pub password: String,
+    /// Disable TLS verification entirely (development only)
+    pub insecure: bool,
+    /// Path to a custom CA certificate (deprecated, LetsEncrypt certs are preferred)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca: Option<String>,
+}
+
+impl NatsSettings {
+    /// Reads `NATS_HOST`, `NATS_USER`, `NATS_PASSWORD`, `NATS_INSECURE` and `NATS_CA`.
+    pub fn from_env() -> Self {
+        Self {
+            host: std::env::var("NATS_HOST").expect("Missing NATS_HOST env"),
+            user: std::env::var("NATS_USER").expect("Missing NATS_USER env"),
+            password: std::env::var("NATS_PASSWORD").expect("Missing NATS_PASSWORD env"),
+            insecure: std::env::var("NATS_INSECURE").is_ok(),
+            ca: std::env::var("NATS_CA").ok(),
+        }
+    }
+
+    /// Checks the settings are enough to try connecting with.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.host.trim().is_empty() {
+            return Err("missing NATS host, set nats.host or NATS_HOST".to_string());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "bevy", derive(bevy::prelude::Resource))]
 pub struct BevygapNats {
@@ -15,18 +57,59 @@ pub struct BevygapNats {
     kv_cert_digests: jetstream::kv::Store,
     kv_active_connections: jetstream::kv::Store,
     kv_unclaimed_sessions: jetstream::kv::Store,
+    kv_gameservers: jetstream::kv::Store,
+    kv_dropped_players: jetstream::kv::Store,
     delete_session_stream: Stream,
 }
 
 const DELETE_SESSION_STREAM: &str = "edgegap_delete_session_q";
 
+/// How long a client id <-> session id mapping lives in `sessions_ly2eg` and `sessions_eg2ly`.
+/// Entries expire without a watch event, so anything caching them must expire them itself.
+pub const SESSION_MAPPING_MAX_AGE: Duration = Duration::from_millis(30000);
+
+/// How long a gameserver's entry in `gameservers` lives without being refreshed by a heartbeat.
+/// Entries expire without a watch event, so the matchmaker also forgets servers it hasn't heard
+/// from for this long.
+pub const GAMESERVER_HEARTBEAT_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// How long a player's entry in `dropped_players` can outlive their time to reconnect. The
+/// matchmaker deletes entries as they expire, this only stops a stopped matchmaker leaking them.
+pub const DROPPED_PLAYER_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Key for a client id in `sessions_ly2eg`. The deployment's request id comes first, so each
+/// gameserver can watch just the client ids issued tokens for it, with `{request_id}.*`.
+pub fn client_session_key(request_id: &str, client_id: &str) -> String {
+    format!("{request_id}.{client_id}")
+}
+
+/// Key for one player of a session in `sessions_eg2ly` and `active_connections`. The session id
+/// comes first, so every player in a session can be found with `{session_id}.*`.
+pub fn session_player_key(session_id: &str, client_id: &str) -> String {
+    format!("{session_id}.{client_id}")
+}
+
+/// Splits a [`session_player_key`] into its session id and client id.
+pub fn parse_session_player_key(key: &str) -> Option<(&str, &str)> {
+    key.rsplit_once('.')
+        .filter(|(session_id, client_id)| !session_id.is_empty() && !client_id.is_empty())
+}
+
 impl BevygapNats {
     /// Connects to NATS based on environment variables.
     /// 
     /// This method performs a complete setup including Jetstream key-value stores.
     /// If you only need to test basic NATS connectivity, use `connect_to_nats()` directly.
     pub async fn new_and_connect(nats_client_name: &str) -> Result<Self, async_nats::Error> {
-        let client = Self::connect_to_nats(nats_client_name).await?;
+        Self::new_and_connect_with_settings(nats_client_name, &NatsSettings::from_env()).await
+    }
+
+    /// Like [`BevygapNats::new_and_connect`], but using explicit settings rather than env vars.
+    pub async fn new_and_connect_with_settings(
+        nats_client_name: &str,
+        settings: &NatsSettings,
+    ) -> Result<Self, async_nats::Error> {
+        let client = Self::connect_to_nats(nats_client_name, settings).await?;
         
         // Test Jetstream availability before proceeding
         info!("NATS: Testing Jetstream availability...");
@@ -82,6 +165,18 @@ impl BevygapNats {
                 e
             })?;
             
+        let kv_gameservers = Self::create_kv_gameservers(client.clone()).await
+            .map_err(|e| {
+                error!("NATS: Failed to create gameservers KV store: {}", e);
+                e
+            })?;
+            
+        let kv_dropped_players = Self::create_kv_dropped_players(client.clone()).await
+            .map_err(|e| {
+                error!("NATS: Failed to create dropped players KV store: {}", e);
+                e
+            })?;
+            
         let delete_session_stream = Self::create_session_delete_queue(&client).await
             .map_err(|e| {
                 error!("NATS: Failed to create delete session stream: {}", e);
@@ -97,6 +192,8 @@ impl BevygapNats {
             kv_cert_digests,
             kv_active_connections,
             kv_unclaimed_sessions,
+            kv_gameservers,
+            kv_dropped_players,
             delete_session_stream,
         })
     }
@@ -104,7 +201,7 @@ impl BevygapNats {
     /// Test only the basic NATS connection without Jetstream functionality.
     /// This is useful for diagnostic purposes and environments where Jetstream is not available.
     pub async fn test_basic_connection(nats_client_name: &str) -> Result<Client, async_nats::Error> {
-        Self::connect_to_nats(nats_client_name).await
+        Self::connect_to_nats(nats_client_name, &NatsSettings::from_env()).await
     }
 
     pub fn client(&self) -> Client {
@@ -113,6 +210,7 @@ impl BevygapNats {
     pub fn kv_s2c(&self) -> &jetstream::kv::Store {
         &self.kv_s2c
     }
+    /// Client ids to session ids, keyed by [`client_session_key`]
     pub fn kv_c2s(&self) -> &jetstream::kv::Store {
         &self.kv_c2s
     }
@@ -125,20 +223,79 @@ impl BevygapNats {
     pub fn kv_cert_digests(&self) -> &jetstream::kv::Store {
         &self.kv_cert_digests
     }
+    /// Gameserver heartbeats, keyed by deployment request id
+    pub fn kv_gameservers(&self) -> &jetstream::kv::Store {
+        &self.kv_gameservers
+    }
+    /// Players waiting to reconnect after their connection dropped, keyed by
+    /// [`session_player_key`]
+    pub fn kv_dropped_players(&self) -> &jetstream::kv::Store {
+        &self.kv_dropped_players
+    }
     pub fn delete_session_stream(&self) -> &Stream {
         &self.delete_session_stream
     }
 
+    /// Live checks of the NATS connection (counts towards liveness) and each of the
+    /// JetStream buckets and streams we rely on (readiness only).
+    pub async fn health_checks(&self) -> Vec<HealthCheck> {
+        let conn_state = self.client.connection_state();
+        let conn = match conn_state {
+            ConnectionState::Connected => HealthCheck::new("nats", HealthState::Ok, None),
+            ConnectionState::Pending => HealthCheck::new(
+                "nats",
+                HealthState::Degraded,
+                Some("reconnecting".to_string()),
+            ),
+            ConnectionState::Disconnected => HealthCheck::new(
+                "nats",
+                HealthState::Failed,
+                Some("disconnected".to_string()),
+            ),
+        };
+        let mut checks = vec![conn.for_liveness()];
+
+        for kv in [
+            &self.kv_s2c,
+            &self.kv_c2s,
+            &self.kv_cert_digests,
+            &self.kv_active_connections,
+            &self.kv_unclaimed_sessions,
+            &self.kv_gameservers,
+            &self.kv_dropped_players,
+        ] {
+            let name = format!("kv.{}", kv.name);
+            checks.push(match kv.status().await {
+                Ok(_) => HealthCheck::new(name, HealthState::Ok, None),
+                Err(e) => HealthCheck::new(name, HealthState::Failed, Some(e.to_string())),
+            });
+        }
+
+        let mut stream = self.delete_session_stream.clone();
+        checks.push(match stream.info().await {
+            Ok(_) => HealthCheck::new("stream.delete_session", HealthState::Ok, None),
+            Err(e) => HealthCheck::new(
+                "stream.delete_session",
+                HealthState::Failed,
+                Some(e.to_string()),
+            ),
+        });
+        checks
+    }
+
+    /// The subject session delete jobs are published on. Subscribe to `delete_session_subject("*")`
+    /// to hear about every session being deleted.
+    pub fn delete_session_subject(session_id: &str) -> String {
+        format!("{DELETE_SESSION_STREAM}.{session_id}")
+    }
+
     /// Enqueues a job to delete a session id via the edgegap API
     pub async fn enqueue_session_delete(
         &self,
         session_id: String,
     ) -> Result<(), async_nats::Error> {
         let js = jetstream::new(self.client.clone());
-        js.publish(
-            format!("{DELETE_SESSION_STREAM}.{session_id}"),
-            session_id.into(),
-        )
+        js.publish(Self::delete_session_subject(&session_id), session_id.into())
         .await?
         .await?;
         Ok(())
@@ -149,6 +306,8 @@ impl BevygapNats {
     /// This method connects to NATS servers with trusted certificates (e.g., LetsEncrypt).
     /// TLS verification uses the system's trusted CA store.
     /// 
+    /// Settings usually come from [`NatsSettings::from_env`], or a binary's config file.
+    ///
     /// ## Required Environment Variables
     /// - `NATS_HOST`: Server address (e.g., `nats.example.com:4222`)
     /// - `NATS_USER`: Username for authentication
@@ -166,14 +325,17 @@ impl BevygapNats {
     /// ## Retry Behavior
     /// Connection retries are handled automatically by async_nats when `retry_on_initial_connect()` is enabled.
     /// The function will try multiple host variants (original, IPv6, IPv4) with async_nats handling retries for each.
-    async fn connect_to_nats(nats_client_name: &str) -> Result<Client, async_nats::Error> {
+    async fn connect_to_nats(
+        nats_client_name: &str,
+        settings: &NatsSettings,
+    ) -> Result<Client, async_nats::Error> {
         info!("NATS: setting up, client name: {nats_client_name}");
 
-        let nats_insecure = std::env::var("NATS_INSECURE").is_ok();
+        let nats_insecure = settings.insecure;
         
         // Legacy support for custom CA certificates (deprecated)
         // With LetsEncrypt certificates, the system trust store is sufficient
-        let nats_self_signed_ca: Option<String> = std::env::var("NATS_CA").ok().or_else(|| {
+        let nats_self_signed_ca: Option<String> = settings.ca.clone().or_else(|| {
             if let Ok(ca_contents) = std::env::var("NATS_CA_CONTENTS") {
                 warn!("NATS: NATS_CA_CONTENTS is deprecated. LetsEncrypt certificates should be used instead.");
                 // Still support for legacy deployments
@@ -203,9 +365,9 @@ impl BevygapNats {
             warn!("NATS: NATS_CA is deprecated. LetsEncrypt certificates should be used instead.");
         }
 
-        let nats_host = std::env::var("NATS_HOST").expect("Missing NATS_HOST env");
-        let nats_user = std::env::var("NATS_USER").expect("Missing NATS_USER env");
-        let nats_pass = std::env::var("NATS_PASSWORD").expect("Missing NATS_PASSWORD env");
+        let nats_host = settings.host.clone();
+        let nats_user = settings.user.clone();
+        let nats_pass = settings.password.clone();
 
         if nats_insecure {
             warn!("😬 NATS: insecure mode - TLS verification is disabled. Not recommended for production!");
@@ -362,6 +524,38 @@ impl BevygapNats {
         Ok(kv)
     }
 
+    pub async fn create_kv_gameservers(
+        client: Client,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: "gameservers".to_string(),
+                description: "Heartbeats from running gameservers, keyed by deployment request id".to_string(),
+                max_age: GAMESERVER_HEARTBEAT_MAX_AGE,
+                max_value_size: 4096,
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
+
+    pub async fn create_kv_dropped_players(
+        client: Client,
+    ) -> Result<jetstream::kv::Store, async_nats::Error> {
+        let jetstream = jetstream::new(client);
+        let kv = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: "dropped_players".to_string(),
+                description: "Players whose gameserver connection dropped, until their time to reconnect is up".to_string(),
+                max_age: DROPPED_PLAYER_MAX_AGE,
+                max_value_size: 1024,
+                ..Default::default()
+            })
+            .await?;
+        Ok(kv)
+    }
+
     pub async fn create_session_delete_queue(client: &Client) -> Result<Stream, async_nats::Error> {
         let js = jetstream::new(client.clone());
         let stream = js
@@ -401,10 +595,10 @@ impl BevygapNats {
         let kv_s2c = jetstream
             .create_key_value(async_nats::jetstream::kv::Config {
                 bucket: "sessions_eg2ly".to_string(),
-                description: "Maps Edgegap Session IDs to Lightyear Client IDs".to_string(),
+                description: "Lightyear Client IDs issued for each Edgegap Session ID".to_string(),
                 max_value_size: 1024,
                 // shouldn't need long for the client to receive token, and make connection to gameserver.
-                max_age: Duration::from_millis(30000),
+                max_age: SESSION_MAPPING_MAX_AGE,
                 // storage: StorageType::File,
                 ..Default::default()
             })
@@ -416,7 +610,7 @@ impl BevygapNats {
                 description: "Maps Lightyear Client IDs to Edgegap Session IDs".to_string(),
                 max_value_size: 1024,
                 // shouldn't need long for the client to receive token, and make connection to gameserver.
-                max_age: Duration::from_millis(30000),
+                max_age: SESSION_MAPPING_MAX_AGE,
                 // storage: StorageType::File,
                 ..Default::default()
             })
@@ -425,3 +619,56 @@ impl BevygapNats {
         Ok((kv_s2c, kv_c2s))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_connection_hosts_with_ip() {
+        let hosts = BevygapNats::generate_connection_hosts("192.168.1.1:4222");
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0], ("original".to_string(), "192.168.1.1:4222".to_string()));
+    }
+
+    #[test]
+    fn test_generate_connection_hosts_with_hostname() {
+        let hosts = BevygapNats::generate_connection_hosts("localhost:4222");
+        // Should have at least the original
+        assert!(!hosts.is_empty());
+        assert_eq!(hosts[0], ("original".to_string(), "localhost:4222".to_string()));
+        
+        // Should have IPv6 and IPv4 variants (if localhost resolves to both)
+        // The exact number depends on the system, but we expect at least 2 (original + at least one resolved)
+        assert!(hosts.len() >= 1);
+    }
+
+    #[test]
+    fn test_generate_connection_hosts_without_port() {
+        let hosts = BevygapNats::generate_connection_hosts("example.com");
+        assert!(!hosts.is_empty());
+        assert_eq!(hosts[0], ("original".to_string(), "example.com".to_string()));
+    }
+
+    #[test]
+    fn test_session_player_keys() {
+        use crate::nats::{parse_session_player_key, session_player_key};
+        let key = session_player_key("950dd2eaff09-S", "1234");
+        assert_eq!(key, "950dd2eaff09-S.1234");
+        assert_eq!(parse_session_player_key(&key), Some(("950dd2eaff09-S", "1234")));
+        assert_eq!(parse_session_player_key("950dd2eaff09-S"), None);
+        assert_eq!(parse_session_player_key(".1234"), None);
+    }
+
+    #[test]
+    fn test_nats_settings_need_a_host() {
+        use crate::nats::NatsSettings;
+        let settings = NatsSettings::default();
+        assert!(settings.validate().unwrap_err().contains("missing NATS host"));
+        let settings = NatsSettings {
+            host: "nats.example.com:4222".to_string(),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_ok());
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum LobbyAction {
     Create,
@@ -22,6 +23,14 @@ pub enum SessionRequestFeedback {
         ip: String,
         port: u16,
         cert_digest: String,
+        /// The client id in the token. Send it back as `reconnect_client_id` to rejoin the
+        /// session after being disconnected.
+        #[serde(default)]
+        client_id: Option<u64>,
+        /// Lets other players join this session. Share it along with the session id, and they
+        /// send both in their `RequestSession`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        join_token: Option<String>,
     },
     /// There was an error.
     Error(u16, String),
@@ -40,12 +49,136 @@ impl fmt::Display for SessionRequestFeedback {
                 ip,
                 port,
                 cert_digest: _,
+                client_id: _,
+                join_token: _,
             } => write!(f, "Session Ready! {ip}:{port}"),
             SessionRequestFeedback::Error(code, msg) => write!(f, "Error {code}: {msg}"),
         }
     }
 }
 
+/// Sent by the httpd to the matchmaker's `lobby.deploy` service to start a lobby room's
+/// gameserver. The reply is a [`LobbyDeployment`], once the deployment is running.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LobbyDeployRequest {
+    pub room_id: String,
+    /// The players' IPs, so Edgegap can pick a location that suits all of them
+    pub ip_list: Vec<String>,
+}
+
+/// A running deployment for a lobby room. Players get their own connect token for it by
+/// sending a session request with its `request_id` as `deployment_request_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LobbyDeployment {
+    /// Edgegap's deployment request id
+    pub request_id: String,
+    /// The app and version deployed, which session requests must be sent for
+    pub app_name: String,
+    pub app_version: String,
+    pub ip: String,
+    pub port: u16,
+}
+
+/// Published by gameservers on [`GameserverOccupancy::subject`] when they start, and whenever a
+/// player connects or disconnects, so the matchmaker can tell which servers are full.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "bevy", derive(bevy::prelude::Resource))]
+pub struct GameserverOccupancy {
+    /// Edgegap's deployment request id
+    pub request_id: String,
+    /// Currently connected players
+    pub players: u32,
+    /// How many players may connect at once, if limited
+    pub max_players: Option<u32>,
+}
+
+impl GameserverOccupancy {
+    /// NATS subject for this gameserver's occupancy updates
+    pub fn subject(&self) -> String {
+        format!("gameserver.occupancy.{}", self.request_id)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.max_players.is_some_and(|max| self.players >= max)
+    }
+}
+
+/// Written by gameservers to the `gameservers` NATS KV bucket, keyed by request id, and refreshed
+/// every few seconds. The bucket expires entries that stop being refreshed, so the matchmaker
+/// can tell which gameservers are alive, and route players to ones with room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GameserverHeartbeat {
+    /// Edgegap's deployment request id
+    pub request_id: String,
+    pub public_ip: String,
+    pub fqdn: Option<String>,
+    /// eg "Montreal, Canada"
+    pub location: Option<String>,
+    /// The deployment's ports, by name, eg "gameport"
+    #[serde(default)]
+    pub ports: BTreeMap<String, GameserverPort>,
+    /// Currently connected players
+    pub players: u32,
+    /// How many players may connect at once, if limited
+    pub max_players: Option<u32>,
+    pub status: GameserverStatus,
+    /// The app version the gameserver reports, if configured
+    pub app_version: Option<String>,
+    /// Frames run per second since the last heartbeat. A low rate means the server is struggling.
+    /// `None` in the first heartbeat, before there's anything to measure.
+    pub ticks_per_second: Option<f32>,
+    /// Unix timestamp of this heartbeat, in seconds
+    pub sent_at: u64,
+}
+
+/// One of a deployment's port mappings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GameserverPort {
+    pub internal: u16,
+    pub external: u16,
+    /// eg "UDP"
+    pub protocol: String,
+}
+
+/// What a gameserver is up to, as reported in its [`GameserverHeartbeat`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameserverStatus {
+    /// Still connecting to NATS or fetching its context
+    Starting,
+    /// Accepting players
+    Ready,
+    /// Something failed, it may not be able to accept players
+    Degraded,
+    /// Shutting down, new players are denied
+    Draining,
+}
+
+impl GameserverHeartbeat {
+    pub fn is_full(&self) -> bool {
+        self.max_players.is_some_and(|max| self.players >= max)
+    }
+
+    /// Whether a new player can be sent here.
+    pub fn accepting_players(&self) -> bool {
+        self.status == GameserverStatus::Ready && !self.is_full()
+    }
+
+    /// The external port with this name, eg "gameport".
+    pub fn external_port(&self, name: &str) -> Option<u16> {
+        self.ports.get(name).map(|port| port.external)
+    }
+}
+
+/// A player's place in a session. The matchmaker writes this to `sessions_ly2eg` for each connect
+/// token it issues, and the gameserver copies it to `active_connections` once the player connects.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionPlayer {
+    /// The Edgegap session the player was added to
+    pub session_id: String,
+    /// The player's IP, as given to Edgegap, so they can be removed from the session again
+    pub client_ip: String,
+}
+
 /// Send up the websocket to the matchmaker when a client wants to play.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RequestSession {
@@ -58,6 +191,56 @@ pub struct RequestSession {
     /// optional player limit (1-4)
     #[serde(default)]
     pub player_limit: Option<u8>,
+    /// optional placement filters, checked against the matchmaker's allow-list
+    #[serde(default, skip_serializing_if = "SessionPlacement::is_empty")]
+    pub placement: SessionPlacement,
+    /// join another player's existing session, rather than starting a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// the join token for `session_id`, from a player already in it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_token: Option<String>,
+    /// rejoin `session_id` as this client id, after being disconnected from its gameserver
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_client_id: Option<u64>,
+}
+
+/// Where a session's gameserver may be placed. Each field is passed to Edgegap as a filter,
+/// so eg. setting `region` locks the session to that region.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SessionPlacement {
+    /// Edgegap region, eg "Europe"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// eg "North America"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    /// Deployment tags the gameserver must have, eg "tournament"
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub selectors: Vec<String>,
+}
+
+impl SessionPlacement {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Fills in any fields not set here from `defaults`.
+    pub fn or(mut self, defaults: &SessionPlacement) -> Self {
+        self.region = self.region.or_else(|| defaults.region.clone());
+        self.continent = self.continent.or_else(|| defaults.continent.clone());
+        self.country = self.country.or_else(|| defaults.country.clone());
+        self.city = self.city.or_else(|| defaults.city.clone());
+        if self.selectors.is_empty() {
+            self.selectors.clone_from(&defaults.selectors);
+        }
+        self
+    }
 }
 
 impl RequestSession {
@@ -0,0 +1,549 @@
+//! A small supervisor for long-running background tasks, in the spirit of an OTP supervision tree.
+//!
+//! Each child is a named task built by a factory closure, so it can be started again after it
+//! exits. Children are supervised independently (one-for-one): when one exits, only that child is
+//! restarted, after an exponential backoff with jitter. If a child restarts more than
+//! `max_restarts` times within `restart_window_secs`, the supervisor gives up on it and reports it
+//! as failed.
+//!
+//! Children receive a [`Shutdown`] signal, which they can await to exit cleanly. When
+//! [`Supervisor::shutdown`] is called, children get `shutdown_grace_secs` to finish before they
+//! are aborted.
+//!
+//! ```no_run
+//! # use bevygap_shared::supervisor::*;
+//! # async fn example() {
+//! let supervisor = Supervisor::new("matchmaker", SupervisorSettings::default());
+//! supervisor.spawn(ChildSpec::new("reaper"), |mut shutdown| async move {
+//!     shutdown.cancelled().await;
+//!     Ok::<(), String>(())
+//! });
+//! supervisor.wait().await;
+//! # }
+//! ```
+use crate::health::{Health, HealthState};
+use crate::util::unix_now;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Restart tuning, shared by all children of a supervisor unless overridden in the [`ChildSpec`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SupervisorSettings {
+    /// Delay before the first restart of a child
+    pub backoff_initial_ms: u64,
+    /// Upper limit for the restart delay
+    pub backoff_max_ms: u64,
+    /// Each consecutive restart multiplies the delay by this
+    pub backoff_multiplier: f64,
+    /// Randomise each delay by up to this fraction, eg 0.2 = ±20%
+    pub backoff_jitter: f64,
+    /// Give up on a child that restarts more than this many times within the window
+    pub max_restarts: u32,
+    pub restart_window_secs: u64,
+    /// How long children get to exit once shutdown is requested, before being aborted
+    pub shutdown_grace_secs: u64,
+}
+
+impl Default for SupervisorSettings {
+    fn default() -> Self {
+        Self {
+            backoff_initial_ms: 500,
+            backoff_max_ms: 30_000,
+            backoff_multiplier: 2.0,
+            backoff_jitter: 0.2,
+            max_restarts: 10,
+            restart_window_secs: 60,
+            shutdown_grace_secs: 5,
+        }
+    }
+}
+
+impl SupervisorSettings {
+    /// The delay before restart number `attempt` (starting at 0), without jitter.
+    pub fn backoff_delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .backoff_multiplier
+            .max(1.0)
+            .powi(attempt.min(64) as i32);
+        let ms = (self.backoff_initial_ms as f64 * factor).min(self.backoff_max_ms as f64);
+        Duration::from_millis(ms as u64)
+    }
+
+    /// [`SupervisorSettings::backoff_delay`] with random jitter applied.
+    pub fn jittered_backoff_delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff_delay(attempt);
+        let jitter = self.backoff_jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        // uniform in -1.0..=1.0
+        let r = rand::random::<f64>() * 2.0 - 1.0;
+        delay.mul_f64(1.0 + r * jitter)
+    }
+
+    fn restart_window(&self) -> Duration {
+        Duration::from_secs(self.restart_window_secs)
+    }
+
+    fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+/// When a child should be restarted after it exits.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// Always restart, this task is never supposed to finish.
+    Permanent,
+    /// Restart only if it returned an error or panicked.
+    Transient,
+    /// Never restart.
+    Temporary,
+}
+
+/// Describes a child task. Anything not set uses the supervisor's settings.
+#[derive(Debug, Clone)]
+pub struct ChildSpec {
+    name: String,
+    restart: RestartPolicy,
+    settings: Option<SupervisorSettings>,
+}
+
+impl ChildSpec {
+    /// A [`RestartPolicy::Permanent`] child.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            restart: RestartPolicy::Permanent,
+            settings: None,
+        }
+    }
+
+    pub fn restart(mut self, restart: RestartPolicy) -> Self {
+        self.restart = restart;
+        self
+    }
+
+    /// Overrides the supervisor's backoff and restart limits for this child.
+    pub fn settings(mut self, settings: SupervisorSettings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Exited, waiting for the backoff delay before starting again.
+    Restarting,
+    /// Finished and not due a restart.
+    Completed,
+    /// Restarted too often, the supervisor has given up on it.
+    Failed,
+    /// Stopped because of a shutdown request.
+    Stopped,
+}
+
+/// Status of one supervised task, for health checks and debugging.
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    /// Total number of restarts since the supervisor started
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Unix timestamp of when the task was last (re)started
+    pub started_at: u64,
+}
+
+/// Passed to each child, resolves once shutdown has been requested.
+#[derive(Clone, Debug)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until shutdown is requested.
+    pub async fn cancelled(&mut self) {
+        // if the sender is dropped the supervisor is gone, so treat that as shutdown too.
+        let _ = self.0.wait_for(|stop| *stop).await;
+    }
+}
+
+struct Inner {
+    name: String,
+    settings: SupervisorSettings,
+    health: Option<Health>,
+    statuses: RwLock<BTreeMap<String, TaskStatus>>,
+    monitors: Mutex<Vec<JoinHandle<()>>>,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+/// Supervises a set of named child tasks. Cheap to clone.
+#[derive(Clone)]
+pub struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+impl Supervisor {
+    pub fn new(name: impl Into<String>, settings: SupervisorSettings) -> Self {
+        let (shutdown_tx, _) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                name: name.into(),
+                settings,
+                health: None,
+                statuses: RwLock::new(BTreeMap::new()),
+                monitors: Mutex::new(Vec::new()),
+                shutdown_tx,
+            }),
+        }
+    }
+
+    /// Reports each child as a liveness check named after it.
+    /// Must be called before any children are spawned.
+    pub fn with_health(mut self, health: Health) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("with_health must be called before the supervisor is cloned")
+            .health = Some(health);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// A shutdown signal, for things that aren't children but should stop with them.
+    pub fn shutdown_signal(&self) -> Shutdown {
+        Shutdown(self.inner.shutdown_tx.subscribe())
+    }
+
+    /// Starts a child task. `factory` is called again to build a fresh future on each restart.
+    pub fn spawn<F, Fut, E>(&self, spec: ChildSpec, factory: F)
+    where
+        F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let monitor = tokio::spawn(async move { supervisor.run_child(spec, factory).await });
+        self.inner.monitors.lock().unwrap().push(monitor);
+    }
+
+    /// Statuses of all children, sorted by name.
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        self.inner
+            .statuses
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn status(&self, name: &str) -> Option<TaskStatus> {
+        self.inner.statuses.read().unwrap().get(name).cloned()
+    }
+
+    /// Waits until every child has exited for good (completed, failed or stopped).
+    pub async fn wait(&self) {
+        loop {
+            let monitors = std::mem::take(&mut *self.inner.monitors.lock().unwrap());
+            if monitors.is_empty() {
+                return;
+            }
+            for monitor in monitors {
+                let _ = monitor.await;
+            }
+        }
+    }
+
+    /// Asks all children to stop, and waits for them. Children that don't stop within
+    /// the grace period are aborted.
+    pub async fn shutdown(&self) {
+        info!("Supervisor {} shutting down", self.inner.name);
+        self.inner.shutdown_tx.send_replace(true);
+        self.wait().await;
+    }
+
+    async fn run_child<F, Fut, E>(&self, spec: ChildSpec, factory: F)
+    where
+        F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display + Send + 'static,
+    {
+        let settings = spec
+            .settings
+            .clone()
+            .unwrap_or_else(|| self.inner.settings.clone());
+        let name = spec.name.as_str();
+        let mut shutdown = self.shutdown_signal();
+        let mut recent_restarts: VecDeque<Instant> = VecDeque::new();
+        let mut attempt = 0;
+        let mut restarts = 0;
+
+        loop {
+            if shutdown.is_shutdown() {
+                self.set_status(name, TaskState::Stopped, restarts, None);
+                return;
+            }
+            info!("[{}] starting {name}", self.inner.name);
+            self.set_status(name, TaskState::Running, restarts, None);
+            let started = Instant::now();
+            let mut handle = tokio::spawn(factory(self.shutdown_signal()));
+
+            let outcome = tokio::select! {
+                res = &mut handle => res,
+                _ = shutdown.cancelled() => {
+                    match tokio::time::timeout(settings.shutdown_grace(), &mut handle).await {
+                        Ok(_) => info!("[{}] {name} stopped", self.inner.name),
+                        Err(_) => {
+                            warn!("[{}] {name} didn't stop in time, aborting", self.inner.name);
+                            handle.abort();
+                        }
+                    }
+                    self.set_status(name, TaskState::Stopped, restarts, None);
+                    return;
+                }
+            };
+
+            let error = match outcome {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(e) if e.is_panic() => Some("panicked".to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            let should_restart = match spec.restart {
+                RestartPolicy::Permanent => true,
+                RestartPolicy::Transient => error.is_some(),
+                RestartPolicy::Temporary => false,
+            };
+            match &error {
+                Some(e) => error!("[{}] {name} exited with error: {e}", self.inner.name),
+                None => info!("[{}] {name} exited", self.inner.name),
+            }
+            if !should_restart {
+                let state = if error.is_some() {
+                    TaskState::Failed
+                } else {
+                    TaskState::Completed
+                };
+                self.set_status(name, state, restarts, error);
+                return;
+            }
+
+            // a child that ran for longer than the restart window was healthy,
+            // so the backoff starts over.
+            if started.elapsed() > settings.restart_window() {
+                attempt = 0;
+            }
+            let now = Instant::now();
+            recent_restarts.retain(|t| now.duration_since(*t) < settings.restart_window());
+            if recent_restarts.len() >= settings.max_restarts as usize {
+                error!(
+                    "[{}] {name} restarted {} times in {:?}, giving up",
+                    self.inner.name,
+                    recent_restarts.len(),
+                    settings.restart_window()
+                );
+                let error = error.unwrap_or_else(|| "exited".to_string());
+                self.set_status(
+                    name,
+                    TaskState::Failed,
+                    restarts,
+                    Some(format!("gave up after too many restarts: {error}")),
+                );
+                return;
+            }
+            recent_restarts.push_back(now);
+
+            let delay = settings.jittered_backoff_delay(attempt);
+            attempt += 1;
+            restarts += 1;
+            warn!("[{}] restarting {name} in {delay:?}", self.inner.name);
+            self.set_status(name, TaskState::Restarting, restarts, error);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+    }
+
+    fn set_status(&self, name: &str, state: TaskState, restarts: u32, error: Option<String>) {
+        let mut statuses = self.inner.statuses.write().unwrap();
+        let status = statuses
+            .entry(name.to_string())
+            .or_insert_with(|| TaskStatus {
+                name: name.to_string(),
+                state,
+                restarts,
+                last_error: None,
+                started_at: unix_now(),
+            });
+        if state == TaskState::Running {
+            status.started_at = unix_now();
+        }
+        status.state = state;
+        status.restarts = restarts;
+        if error.is_some() {
+            status.last_error = error.clone();
+        }
+        drop(statuses);
+
+        let Some(health) = &self.inner.health else {
+            return;
+        };
+        match state {
+            TaskState::Running | TaskState::Completed | TaskState::Stopped => {
+                health.set_liveness(name, HealthState::Ok, None)
+            }
+            TaskState::Restarting => health.set_liveness(
+                name,
+                HealthState::Degraded,
+                Some(format!(
+                    "restarting ({restarts} restarts): {}",
+                    error.unwrap_or_else(|| "exited".to_string())
+                )),
+            ),
+            TaskState::Failed => health.set_liveness(name, HealthState::Failed, error),
+        }
+    }
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix (eg `docker stop`).
+pub async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Can't listen for SIGTERM: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    info!("Termination signal received");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::{Health, HealthState};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn fast_settings() -> SupervisorSettings {
+        SupervisorSettings {
+            backoff_initial_ms: 1,
+            backoff_max_ms: 5,
+            backoff_jitter: 0.0,
+            max_restarts: 3,
+            restart_window_secs: 60,
+            shutdown_grace_secs: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let settings = SupervisorSettings {
+            backoff_initial_ms: 100,
+            backoff_max_ms: 1000,
+            backoff_multiplier: 2.0,
+            backoff_jitter: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(settings.backoff_delay(0), Duration::from_millis(100));
+        assert_eq!(settings.backoff_delay(2), Duration::from_millis(400));
+        assert_eq!(settings.backoff_delay(10), Duration::from_millis(1000));
+        for _ in 0..20 {
+            let d = settings.jittered_backoff_delay(1);
+            assert!(d >= Duration::from_millis(100) && d <= Duration::from_millis(300));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_restarts() {
+        let health = Health::new();
+        let supervisor = Supervisor::new("test", fast_settings()).with_health(health.clone());
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        supervisor.spawn(ChildSpec::new("flaky"), move |_| {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>("boom")
+            }
+        });
+        supervisor.wait().await;
+
+        // first run, plus max_restarts restarts
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        let status = supervisor.status("flaky").unwrap();
+        assert_eq!(status.state, TaskState::Failed);
+        assert_eq!(status.restarts, 3);
+        assert!(status.last_error.unwrap().contains("boom"));
+        assert_eq!(health.get("flaky").unwrap().state, HealthState::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_transient_child_not_restarted_on_success() {
+        let supervisor = Supervisor::new("test", fast_settings());
+        supervisor.spawn(
+            ChildSpec::new("once").restart(RestartPolicy::Transient),
+            |_| async { Ok::<(), String>(()) },
+        );
+        supervisor.wait().await;
+        let status = supervisor.status("once").unwrap();
+        assert_eq!(status.state, TaskState::Completed);
+        assert_eq!(status.restarts, 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_children() {
+        let supervisor = Supervisor::new("test", fast_settings());
+        supervisor.spawn(ChildSpec::new("polite"), |mut shutdown| async move {
+            shutdown.cancelled().await;
+            Ok::<(), String>(())
+        });
+        supervisor.spawn(ChildSpec::new("stubborn"), |_| async {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok::<(), String>(())
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            supervisor.status("polite").unwrap().state,
+            TaskState::Running
+        );
+        tokio::time::timeout(Duration::from_secs(5), supervisor.shutdown())
+            .await
+            .expect("shutdown should abort the stubborn child after the grace period");
+        for status in supervisor.statuses() {
+            assert_eq!(status.state, TaskState::Stopped, "{}", status.name);
+        }
+    }
+}
@@ -5,35 +5,91 @@ use axum::{
     response::{IntoResponse, Response},
     routing::get,
     routing::post,
-    Router,
+    Json, Router,
 };
+use bevygap_shared::config::*;
+use bevygap_shared::health::{Health, HealthReport};
 use bevygap_shared::nats::*;
+use clap::Parser;
 use log::*;
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tracing_subscriber::{layer::*, util::*};
 
+/// Prefix for env var overrides, eg `BEVYGAP_WEBHOOK_SINK_BIND=0.0.0.0:8080`.
+const ENV_PREFIX: &str = "BEVYGAP_WEBHOOK_SINK";
+
+/// Command line args. Anything given here overrides the config file and env vars.
+#[derive(Parser, Serialize, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    #[serde(skip)]
+    config: ConfigArgs,
+    /// The ip:port to bind the http listener to
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+struct Settings {
+    /// The ip:port to bind the http listener to
+    bind: String,
+    nats: NatsSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:3001".to_string(),
+            nats: NatsSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    fn load() -> Self {
+        let cli = Cli::parse();
+        let loader = ConfigLoader::new(ENV_PREFIX).with_nats_env_aliases();
+        load_or_exit(loader, &cli.config, &cli, |settings: &Self| {
+            settings.nats.validate()
+        })
+    }
+}
+
 struct AppState {
     bgnats: BevygapNats,
+    health: Health,
 }
 
 #[tokio::main]
 async fn main() {
     setup_logging();
+    let settings = Settings::load();
 
-    let bgnats = BevygapNats::new_and_connect("bevygap_webhook_sink")
+    let bgnats = BevygapNats::new_and_connect_with_settings("bevygap_webhook_sink", &settings.nats)
         .await
         .unwrap();
-    let app_state = Arc::new(AppState { bgnats });
+    let app_state = Arc::new(AppState {
+        bgnats,
+        health: Health::new(),
+    });
 
     // build our application with a route
     let app = Router::new()
         .route("/", get(index_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
         .route("/hook/:hookname", post(hook_handler))
         .with_state(app_state);
 
     // run it
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(settings.bind.as_str())
+        .await
+        .unwrap();
     info!(
         "bevygap_webhook_sink listening on {}",
         listener.local_addr().unwrap()
@@ -51,6 +107,20 @@ async fn index_handler() -> Html<&'static str> {
     Html("<h1>Webhook catcher</h1><p>Nothing to see here, move along.</p>")
 }
 
+async fn healthz_handler(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.liveness(state.bgnats.health_checks().await);
+    (health_status_code(&report), Json(report))
+}
+
+async fn readyz_handler(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.readiness(state.bgnats.health_checks().await);
+    (health_status_code(&report), Json(report))
+}
+
+fn health_status_code(report: &HealthReport) -> StatusCode {
+    StatusCode::from_u16(report.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 async fn hook_handler(
     Path(hook_name): Path<String>,
     State(state): State<Arc<AppState>>,
@@ -58,7 +128,11 @@ async fn hook_handler(
 ) -> Result<impl IntoResponse, AppError> {
     let subject = format!("webhook.{hook_name}");
     info!("NATS publish {subject} = {body}");
-    state.bgnats.client().publish(subject, body.into()).await?;
+    if let Err(e) = state.bgnats.client().publish(subject, body.into()).await {
+        state.health.record_failure("nats.publish", &e);
+        return Err(e.into());
+    }
+    state.health.record_success("nats.publish");
     Ok("OK")
 }
 
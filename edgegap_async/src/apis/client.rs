@@ -0,0 +1,220 @@
+//! The http client used by every generated `*_api` function, via `configuration.client`.
+//!
+//! It builds requests with reqwest as before, but sends them through
+//! [`ApiClient::execute`], which applies the optional [`RetryPolicy`] and circuit breaker.
+//! Without a policy (the default) each request is sent exactly once, as reqwest would.
+use super::retry::{CircuitBreaker, CircuitState, RetryPolicy};
+use reqwest::{Method, Request, Response};
+use std::fmt;
+use std::time::Duration;
+
+/// Why [`ApiClient::execute`] didn't return a response.
+#[derive(Debug)]
+pub enum ClientError {
+    Reqwest(reqwest::Error),
+    /// The circuit breaker is open because Edgegap keeps failing; no request was sent.
+    CircuitOpen {
+        retry_in: Duration,
+    },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Reqwest(e) => write!(f, "{e}"),
+            ClientError::CircuitOpen { retry_in } => write!(
+                f,
+                "circuit breaker open, Edgegap API unavailable (retry in {retry_in:?})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Reqwest(e)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiClient {
+    inner: reqwest::Client,
+    retry: Option<RetryPolicy>,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl ApiClient {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            inner: client,
+            retry: None,
+            breaker: None,
+        }
+    }
+
+    /// Enables retries, per-endpoint timeouts and (if configured) the circuit breaker.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.breaker = policy.circuit_breaker.clone().map(CircuitBreaker::new);
+        self.retry = Some(policy);
+        self
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    /// The underlying reqwest client.
+    pub fn reqwest_client(&self) -> &reqwest::Client {
+        &self.inner
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        self.inner.request(method, url)
+    }
+
+    pub async fn execute(&self, request: Request) -> Result<Response, ClientError> {
+        let Some(policy) = &self.retry else {
+            return Ok(self.inner.execute(request).await?);
+        };
+
+        let mut request = request;
+        if request.timeout().is_none() {
+            *request.timeout_mut() = policy.timeout_for(request.method(), request.url().path());
+        }
+        let idempotent = policy.is_idempotent(request.method(), request.url().path());
+        let mut attempt = 0;
+
+        // the breaker counts whole requests, so retries don't need to get past it again.
+        if let Some(breaker) = &self.breaker {
+            breaker
+                .try_acquire()
+                .map_err(|retry_in| ClientError::CircuitOpen { retry_in })?;
+        }
+
+        loop {
+            // requests with streaming bodies can't be cloned, so they only get one go.
+            let retry_request = if attempt < policy.max_retries {
+                request.try_clone()
+            } else {
+                None
+            };
+
+            let result = self.inner.execute(request).await;
+
+            let delay = match (&result, &retry_request) {
+                (_, None) => None,
+                (Ok(resp), Some(_)) if policy.should_retry_status(resp.status(), idempotent) => {
+                    policy.retry_delay(attempt, Some(resp.headers()))
+                }
+                (Err(e), Some(_)) if policy.should_retry_error(e, idempotent) => {
+                    policy.retry_delay(attempt, None)
+                }
+                _ => None,
+            };
+            // if another request opened the circuit meanwhile, there's no point waiting to retry.
+            let circuit_open = self
+                .breaker
+                .as_ref()
+                .is_some_and(|breaker| matches!(breaker.state(), CircuitState::Open { .. }));
+            let (Some(delay), Some(next), false) = (delay, retry_request, circuit_open) else {
+                if let Some(breaker) = &self.breaker {
+                    let server_failed = match &result {
+                        Ok(resp) => resp.status().is_server_error(),
+                        Err(e) => e.is_connect() || e.is_timeout(),
+                    };
+                    if server_failed {
+                        breaker.record_failure();
+                    } else {
+                        breaker.record_success();
+                    }
+                }
+                return Ok(result?);
+            };
+
+            match &result {
+                Ok(resp) => log::warn!(
+                    "{} {} returned {}, retrying in {delay:?}",
+                    next.method(),
+                    next.url().path(),
+                    resp.status()
+                ),
+                Err(e) => log::warn!(
+                    "{} {} failed: {e}, retrying in {delay:?}",
+                    next.method(),
+                    next.url().path()
+                ),
+            }
+            tokio::time::sleep(delay).await;
+            request = next;
+            attempt += 1;
+        }
+    }
+}
+
+impl From<reqwest::Client> for ApiClient {
+    fn from(client: reqwest::Client) -> Self {
+        Self::new(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apis::retry::{CircuitBreakerPolicy, CircuitState, RetryPolicy};
+    use crate::fixtures::*;
+    use reqwest::{Method, StatusCode};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_retried_request_counts_as_one_failure() {
+        let cassette = Cassette {
+            interactions: vec![Interaction {
+                request: RecordedRequest {
+                    method: "GET".to_string(),
+                    path: "/v1/status".to_string(),
+                    query: None,
+                    body: None,
+                },
+                response: RecordedResponse {
+                    status: 502,
+                    headers: Default::default(),
+                    body: serde_json::Value::Null,
+                    text: None,
+                },
+            }],
+        };
+        let server = FixtureServer::replay(cassette).await.unwrap();
+        let client = server
+            .configuration()
+            .client
+            .with_retry_policy(RetryPolicy {
+                max_retries: 3,
+                initial_backoff: Duration::from_millis(1),
+                jitter: 0.0,
+                circuit_breaker: Some(CircuitBreakerPolicy {
+                    failure_threshold: 2,
+                    open_duration: Duration::from_secs(30),
+                }),
+                ..Default::default()
+            });
+        let url = format!("{}/v1/status", server.base_path());
+        let get = || client.request(Method::GET, &url).build().unwrap();
+
+        let resp = client.execute(get()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(server.received().len(), 4);
+        let breaker = client.circuit_breaker().unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        client.execute(get()).await.unwrap();
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+        assert!(client.execute(get()).await.is_err());
+        assert_eq!(server.received().len(), 8);
+    }
+}
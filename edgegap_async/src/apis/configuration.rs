@@ -9,12 +9,14 @@
  */
 
 
+use super::client::ApiClient;
+use super::retry::RetryPolicy;
 
 #[derive(Debug, Clone)]
 pub struct Configuration {
     pub base_path: String,
     pub user_agent: Option<String>,
-    pub client: reqwest::Client,
+    pub client: ApiClient,
     pub basic_auth: Option<BasicAuth>,
     pub oauth_access_token: Option<String>,
     pub bearer_access_token: Option<String>,
@@ -34,6 +36,12 @@ impl Configuration {
     pub fn new() -> Configuration {
         Configuration::default()
     }
+
+    /// Opts in to retries, per-endpoint timeouts and the circuit breaker for every API call.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Configuration {
+        self.client = self.client.with_retry_policy(policy);
+        self
+    }
 }
 
 impl Default for Configuration {
@@ -41,7 +49,7 @@ impl Default for Configuration {
         Configuration {
             base_path: "http://localhost".to_owned(),
             user_agent: Some("OpenAPI-Generator/1.0.0/rust".to_owned()),
-            client: reqwest::Client::new(),
+            client: ApiClient::new(reqwest::Client::new()),
             basic_auth: None,
             oauth_access_token: None,
             bearer_access_token: None,
@@ -14,6 +14,8 @@ pub enum Error<T> {
     Serde(serde_json::Error),
     Io(std::io::Error),
     ResponseError(ResponseContent<T>),
+    /// Not sent, because the circuit breaker is open after repeated failures.
+    CircuitOpen(std::time::Duration),
 }
 
 impl <T> fmt::Display for Error<T> {
@@ -23,6 +25,7 @@ impl <T> fmt::Display for Error<T> {
             Error::Serde(e) => ("serde", e.to_string()),
             Error::Io(e) => ("IO", e.to_string()),
             Error::ResponseError(e) => ("response", format!("status code {}", e.status)),
+            Error::CircuitOpen(d) => ("circuit breaker", format!("open, retry in {:?}", d)),
         };
         write!(f, "error in {}: {}", module, e)
     }
@@ -35,6 +38,7 @@ impl <T: fmt::Debug> error::Error for Error<T> {
             Error::Serde(e) => e,
             Error::Io(e) => e,
             Error::ResponseError(_) => return None,
+            Error::CircuitOpen(_) => return None,
         })
     }
 }
@@ -45,6 +49,15 @@ impl <T> From<reqwest::Error> for Error<T> {
     }
 }
 
+impl <T> From<client::ClientError> for Error<T> {
+    fn from(e: client::ClientError) -> Self {
+        match e {
+            client::ClientError::Reqwest(e) => Error::Reqwest(e),
+            client::ClientError::CircuitOpen { retry_in } => Error::CircuitOpen(retry_in),
+        }
+    }
+}
+
 impl <T> From<serde_json::Error> for Error<T> {
     fn from(e: serde_json::Error) -> Self {
         Error::Serde(e)
@@ -107,4 +120,7 @@ pub mod relays_api;
 pub mod sessions_api;
 pub mod telemetry_api;
 
+pub mod client;
 pub mod configuration;
+pub mod pagination;
+pub mod retry;
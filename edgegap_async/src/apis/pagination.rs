@@ -0,0 +1,371 @@
+//! Lazy `Stream`s over every page of the Edgegap list endpoints.
+//!
+//! The generated list functions return a single page. These helpers request `page=1,2,..`
+//! with the given `limit` as the stream is polled, and yield the items one at a time:
+//!
+//! ```no_run
+//! # async fn example(configuration: &edgegap_async::apis::configuration::Configuration) {
+//! use futures::TryStreamExt;
+//! use edgegap_async::apis::pagination::list_sessions_stream;
+//!
+//! let sessions: Vec<_> = list_sessions_stream(configuration, 100)
+//!     .try_collect()
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+//!
+//! Paging stops when the response's `pagination.has_next` is false, or, for responses without
+//! pagination info, when a page comes back with fewer than `limit` items.
+//!
+//! `lobby_list` isn't covered: despite the name it returns a single lobby.
+use super::applications_api::{AppVersionsGetError, ApplicationsGetError};
+use super::deployments_api::DeploymentsGetError;
+use super::endpoint_storage_api::{
+    endpoints_list, pull_profile_list, EndpointsListError, PullProfileListError,
+};
+use super::fleets_api::{fleet_policies_list, fleets, FleetPoliciesListError, FleetsError};
+use super::sessions_api::ListSessionsError;
+use super::{configuration, Error, ResponseContent};
+use crate::models;
+use futures::{stream, Stream, TryStreamExt};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+
+/// One page of a list response.
+pub trait Page {
+    type Item;
+
+    fn into_items(self) -> Vec<Self::Item>;
+
+    /// The response's paging info, if the endpoint returns any.
+    fn pagination(&self) -> Option<&models::Pagination> {
+        None
+    }
+}
+
+/// Works out which page to request after `page`, or `None` if that was the last one.
+pub fn next_page(
+    page: i32,
+    limit: i32,
+    items: usize,
+    pagination: Option<&models::Pagination>,
+) -> Option<i32> {
+    if items == 0 {
+        return None;
+    }
+    match pagination.and_then(|p| p.has_next.map(|has_next| (has_next, p.next_page_number))) {
+        Some((false, _)) => None,
+        Some((true, next)) => Some(next.filter(|n| *n > page).unwrap_or(page + 1)),
+        // no paging info, so assume a short page is the last one
+        None if items < limit.max(1) as usize => None,
+        None => Some(page + 1),
+    }
+}
+
+/// Streams the items of every page, calling `fetch(page, limit)` for each page as it's needed.
+pub fn paginate<'a, P, E, F, Fut>(
+    limit: i32,
+    mut fetch: F,
+) -> impl Stream<Item = Result<P::Item, Error<E>>> + 'a
+where
+    P: Page + 'a,
+    P::Item: 'a,
+    E: 'a,
+    F: FnMut(i32, i32) -> Fut + 'a,
+    Fut: Future<Output = Result<P, Error<E>>> + 'a,
+{
+    let limit = limit.max(1);
+    stream::try_unfold(Some(1), move |page| {
+        let request = page.map(|page| (page, fetch(page, limit)));
+        async move {
+            let Some((page, request)) = request else {
+                return Ok::<_, Error<E>>(None);
+            };
+            let response = request.await?;
+            let pagination = response.pagination().cloned();
+            let items = response.into_items();
+            let next = next_page(page, limit, items.len(), pagination.as_ref());
+            Ok(Some((stream::iter(items.into_iter().map(Ok)), next)))
+        }
+    })
+    .try_flatten()
+}
+
+/// Every session.
+pub fn list_sessions_stream(
+    configuration: &configuration::Configuration,
+    limit: i32,
+) -> impl Stream<Item = Result<models::SessionContext, Error<ListSessionsError>>> + '_ {
+    paginate(limit, move |page, limit| {
+        get_page::<models::Sessions, _>(configuration, "/v1/sessions", page, limit, vec![])
+    })
+}
+
+/// Every deployment, optionally filtered by `query` as with `deployments_get`.
+pub fn deployments_stream<'a>(
+    configuration: &'a configuration::Configuration,
+    query: Option<&'a str>,
+    limit: i32,
+) -> impl Stream<Item = Result<models::DeploymentListData, Error<DeploymentsGetError>>> + 'a {
+    paginate(limit, move |page, limit| {
+        let extra = query
+            .map(|q| vec![("query", q.to_string())])
+            .unwrap_or_default();
+        get_page::<models::Deployments, _>(configuration, "/v1/deployments", page, limit, extra)
+    })
+}
+
+/// Every application.
+pub fn applications_stream(
+    configuration: &configuration::Configuration,
+    limit: i32,
+) -> impl Stream<Item = Result<models::Application, Error<ApplicationsGetError>>> + '_ {
+    paginate(limit, move |page, limit| {
+        get_page::<models::Applications, _>(configuration, "/v1/apps", page, limit, vec![])
+    })
+}
+
+/// Every version of an application.
+pub fn app_versions_stream<'a>(
+    configuration: &'a configuration::Configuration,
+    app_name: &'a str,
+    limit: i32,
+) -> impl Stream<Item = Result<models::AppVersionPayload, Error<AppVersionsGetError>>> + 'a {
+    let path = format!("/v1/app/{}/versions", super::urlencode(app_name));
+    paginate(limit, move |page, limit| {
+        let path = path.clone();
+        async move {
+            get_page::<models::AppVersionList, _>(configuration, &path, page, limit, vec![]).await
+        }
+    })
+}
+
+/// Every fleet.
+pub fn fleets_stream(
+    configuration: &configuration::Configuration,
+    limit: i32,
+) -> impl Stream<Item = Result<models::FleetGetResponse, Error<FleetsError>>> + '_ {
+    paginate(limit, move |page, limit| {
+        fleets(configuration, Some(page), Some(limit))
+    })
+}
+
+/// Every policy of a fleet.
+pub fn fleet_policies_stream<'a>(
+    configuration: &'a configuration::Configuration,
+    fleet_name: &'a str,
+    limit: i32,
+) -> impl Stream<Item = Result<models::FleetPoliciesGetResponse, Error<FleetPoliciesListError>>> + 'a
+{
+    paginate(limit, move |page, limit| {
+        fleet_policies_list(configuration, fleet_name, Some(page), Some(limit), None)
+    })
+}
+
+/// Every storage endpoint.
+pub fn endpoints_stream(
+    configuration: &configuration::Configuration,
+    limit: i32,
+) -> impl Stream<Item = Result<models::EndpointStorageGetResponse, Error<EndpointsListError>>> + '_
+{
+    paginate(limit, move |page, limit| {
+        endpoints_list(configuration, Some(page), Some(limit), None)
+    })
+}
+
+/// Every pull profile of a storage endpoint.
+pub fn pull_profiles_stream<'a>(
+    configuration: &'a configuration::Configuration,
+    endpoint_name: &'a str,
+    limit: i32,
+) -> impl Stream<Item = Result<models::PullProfileGetResponse, Error<PullProfileListError>>> + 'a {
+    paginate(limit, move |page, limit| {
+        pull_profile_list(configuration, endpoint_name, Some(page), Some(limit))
+    })
+}
+
+/// GETs one page of a list endpoint whose generated function has no `page`/`limit` params.
+/// Mirrors the generated functions, including the error mapping.
+async fn get_page<P: DeserializeOwned, E: DeserializeOwned>(
+    configuration: &configuration::Configuration,
+    path: &str,
+    page: i32,
+    limit: i32,
+    extra_query: Vec<(&str, String)>,
+) -> Result<P, Error<E>> {
+    let client = &configuration.client;
+    let uri_str = format!("{}{}", configuration.base_path, path);
+    let mut req_builder = client
+        .request(reqwest::Method::GET, uri_str.as_str())
+        .query(&[("page", page.to_string()), ("limit", limit.to_string())])
+        .query(&extra_query);
+
+    if let Some(ref user_agent) = configuration.user_agent {
+        req_builder = req_builder.header(reqwest::header::USER_AGENT, user_agent.clone());
+    }
+    if let Some(ref apikey) = configuration.api_key {
+        let value = match apikey.prefix {
+            Some(ref prefix) => format!("{} {}", prefix, apikey.key),
+            None => apikey.key.clone(),
+        };
+        req_builder = req_builder.header("authorization", value);
+    }
+
+    let req = req_builder.build()?;
+    let resp = client.execute(req).await?;
+    let status = resp.status();
+    let content = resp.text().await?;
+
+    if !status.is_client_error() && !status.is_server_error() {
+        serde_json::from_str(&content).map_err(Error::from)
+    } else {
+        let entity: Option<E> = serde_json::from_str(&content).ok();
+        Err(Error::ResponseError(ResponseContent {
+            status,
+            content,
+            entity,
+        }))
+    }
+}
+
+macro_rules! impl_page {
+    ($page:ty, $items:ident, $item:ty) => {
+        impl_page!($page, $items, $item, |_p: &$page| None);
+    };
+    ($page:ty, $items:ident, $item:ty, paginated) => {
+        impl_page!($page, $items, $item, |p: &$page| p.pagination.as_deref());
+    };
+    ($page:ty, $items:ident, $item:ty, $pagination:expr) => {
+        impl Page for $page {
+            type Item = $item;
+
+            fn into_items(self) -> Vec<$item> {
+                self.$items.unwrap_or_default()
+            }
+
+            fn pagination(&self) -> Option<&models::Pagination> {
+                let f: fn(&$page) -> Option<&models::Pagination> = $pagination;
+                f(self)
+            }
+        }
+    };
+}
+
+impl_page!(models::Sessions, data, models::SessionContext, paginated);
+impl_page!(
+    models::Deployments,
+    data,
+    models::DeploymentListData,
+    paginated
+);
+impl_page!(models::Applications, applications, models::Application);
+impl_page!(models::AppVersionList, versions, models::AppVersionPayload);
+impl_page!(
+    models::FleetList,
+    fleets,
+    models::FleetGetResponse,
+    paginated
+);
+impl_page!(
+    models::EndpointStorageListResponse,
+    endpoints,
+    models::EndpointStorageGetResponse,
+    paginated
+);
+impl_page!(
+    models::PullProfilesListResponse,
+    pull_profiles,
+    models::PullProfileGetResponse,
+    paginated
+);
+impl_page!(
+    models::HorizontalScalerConstraintList,
+    policies,
+    models::FleetPoliciesGetResponse,
+    paginated
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apis::Error;
+    use crate::models::{Pagination, SessionContext, Sessions};
+    use futures::executor::block_on;
+    use futures::{StreamExt, TryStreamExt};
+
+    fn sessions_page(ids: &[&str], has_next: Option<bool>) -> Sessions {
+        Sessions {
+            data: Some(
+                ids.iter()
+                    .map(|id| SessionContext {
+                        session_id: id.to_string(),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            total_count: None,
+            pagination: has_next.map(|has_next| {
+                Box::new(Pagination {
+                    has_next: Some(has_next),
+                    ..Default::default()
+                })
+            }),
+        }
+    }
+
+    #[test]
+    fn test_next_page() {
+        let more = Pagination {
+            has_next: Some(true),
+            next_page_number: Some(3),
+            ..Default::default()
+        };
+        let last = Pagination {
+            has_next: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(next_page(2, 10, 10, Some(&more)), Some(3));
+        assert_eq!(next_page(2, 10, 10, Some(&last)), None);
+        // without paging info, a short page is the last one
+        assert_eq!(next_page(1, 10, 10, None), Some(2));
+        assert_eq!(next_page(1, 10, 4, None), None);
+        assert_eq!(next_page(1, 10, 0, Some(&more)), None);
+    }
+
+    #[test]
+    fn test_paginate_walks_all_pages_lazily() {
+        let mut requested = Vec::new();
+        let ids: Vec<String> = block_on(
+            paginate(2, |page, limit| {
+                requested.push((page, limit));
+                let response = match page {
+                    1 => sessions_page(&["a", "b"], Some(true)),
+                    2 => sessions_page(&["c", "d"], Some(true)),
+                    _ => sessions_page(&["e"], Some(false)),
+                };
+                async move { Ok::<_, Error<()>>(response) }
+            })
+            .map_ok(|s| s.session_id)
+            .try_collect(),
+        )
+        .unwrap();
+        assert_eq!(ids, ["a", "b", "c", "d", "e"]);
+        assert_eq!(requested, [(1, 2), (2, 2), (3, 2)]);
+    }
+
+    #[test]
+    fn test_paginate_stops_at_error() {
+        let results: Vec<_> = block_on(
+            paginate(1, |page, _| async move {
+                match page {
+                    1 => Ok(sessions_page(&["a"], None)),
+                    _ => Err(Error::<()>::Io(std::io::ErrorKind::Other.into())),
+                }
+            })
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+    }
+}
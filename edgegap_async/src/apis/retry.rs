@@ -0,0 +1,381 @@
+//! Opt-in retry policy and circuit breaker, applied by [`super::client::ApiClient`] to every request.
+//!
+//! Which requests get retried:
+//! * connection errors, where the request never reached Edgegap – always
+//! * `429 Too Many Requests` – always, since the request wasn't processed
+//! * timeouts and `5xx` responses – only for idempotent requests, which are `GET`, `HEAD`, `PUT`,
+//!   `DELETE` and `OPTIONS`, plus any endpoints added with [`RetryPolicy::idempotent_endpoint`].
+//!   A `POST /v1/session` that timed out may have created a session, so it isn't retried.
+//!
+//! Delays back off exponentially with jitter, except that a `Retry-After` header is honoured.
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Method, StatusCode};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A timeout for requests matching a method (or any method) and path prefix.
+#[derive(Debug, Clone)]
+pub struct EndpointTimeout {
+    pub method: Option<Method>,
+    /// Matched against the start of the url path, eg `/v1/session`
+    pub path_prefix: String,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, so 3 means up to 4 requests in total
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub backoff_multiplier: f64,
+    /// Randomise each delay by up to this fraction, eg 0.2 = ±20%
+    pub jitter: f64,
+    /// If a `Retry-After` asks us to wait longer than this, give up instead.
+    pub max_retry_after: Duration,
+    /// Timeout for requests that don't match any of `endpoint_timeouts`
+    pub default_timeout: Option<Duration>,
+    pub endpoint_timeouts: Vec<EndpointTimeout>,
+    /// Non-idempotent requests that are nonetheless safe to retry, as (method, path prefix)
+    pub idempotent_endpoints: Vec<(Method, String)>,
+    /// Set to `None` to disable the circuit breaker.
+    pub circuit_breaker: Option<CircuitBreakerPolicy>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+            backoff_multiplier: 2.0,
+            jitter: 0.2,
+            max_retry_after: Duration::from_secs(30),
+            default_timeout: Some(Duration::from_secs(30)),
+            endpoint_timeouts: Vec::new(),
+            idempotent_endpoints: Vec::new(),
+            circuit_breaker: Some(CircuitBreakerPolicy::default()),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sets the timeout for requests to a path prefix, with any method if `method` is `None`.
+    pub fn endpoint_timeout(
+        mut self,
+        method: Option<Method>,
+        path_prefix: &str,
+        timeout: Duration,
+    ) -> Self {
+        self.endpoint_timeouts.push(EndpointTimeout {
+            method,
+            path_prefix: path_prefix.to_string(),
+            timeout,
+        });
+        self
+    }
+
+    /// Marks a non-idempotent endpoint as safe to retry after timeouts and 5xx responses.
+    pub fn idempotent_endpoint(mut self, method: Method, path_prefix: &str) -> Self {
+        self.idempotent_endpoints
+            .push((method, path_prefix.to_string()));
+        self
+    }
+
+    /// The timeout for a request, preferring the longest matching endpoint prefix.
+    pub fn timeout_for(&self, method: &Method, path: &str) -> Option<Duration> {
+        let path = normalize_path(path);
+        self.endpoint_timeouts
+            .iter()
+            .filter(|t| t.method.as_ref().is_none_or(|m| m == method))
+            .filter(|t| path.starts_with(normalize_path(&t.path_prefix)))
+            .max_by_key(|t| t.path_prefix.len())
+            .map(|t| t.timeout)
+            .or(self.default_timeout)
+    }
+
+    pub fn is_idempotent(&self, method: &Method, path: &str) -> bool {
+        if matches!(
+            *method,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        ) {
+            return true;
+        }
+        let path = normalize_path(path);
+        self.idempotent_endpoints
+            .iter()
+            .any(|(m, prefix)| m == method && path.starts_with(normalize_path(prefix)))
+    }
+
+    /// Whether a response with this status should be retried.
+    pub fn should_retry_status(&self, status: StatusCode, idempotent: bool) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || (idempotent && status.is_server_error())
+    }
+
+    /// Whether a failed request should be retried.
+    pub fn should_retry_error(&self, err: &reqwest::Error, idempotent: bool) -> bool {
+        // nothing was sent if we couldn't connect
+        err.is_connect() || (idempotent && (err.is_timeout() || err.is_request()))
+    }
+
+    /// The exponential backoff delay before retry number `attempt` (starting at 0), with jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .backoff_multiplier
+            .max(1.0)
+            .powi(attempt.min(64) as i32);
+        let delay = self.initial_backoff.mul_f64(factor).min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        // uniform in -1.0..=1.0
+        let r = rand::random::<f64>() * 2.0 - 1.0;
+        delay.mul_f64(1.0 + r * jitter)
+    }
+
+    /// The delay before the next retry, honouring `Retry-After` if the response had one.
+    /// Returns `None` if Retry-After asks for longer than `max_retry_after`.
+    pub fn retry_delay(&self, attempt: u32, headers: Option<&HeaderMap>) -> Option<Duration> {
+        match headers.and_then(parse_retry_after) {
+            Some(wait) if wait > self.max_retry_after => None,
+            Some(wait) => Some(wait),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+/// Parses a `Retry-After` header, in either delay-seconds or HTTP-date form.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(value).ok()?;
+    Some(
+        when.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// The base path usually ends in a `/`, so paths can start with `//v1/..`.
+fn normalize_path(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerPolicy {
+    /// Consecutive failed requests that open the circuit. A request only counts once, when its
+    /// retries run out.
+    pub failure_threshold: u32,
+    /// How long to fail fast for, before letting a trial request through
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests flow normally.
+    Closed,
+    /// Edgegap looks down, requests fail fast until the deadline.
+    Open { until: Instant },
+    /// The open period is over, one trial request is in flight.
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: CircuitState,
+    consecutive_failures: u32,
+}
+
+/// Shared between clones of the client, so every API call counts towards the same circuit.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    inner: Arc<Mutex<BreakerInner>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        Self {
+            policy,
+            inner: Arc::new(Mutex::new(BreakerInner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Checks whether a request may be sent. If not, returns how long until the circuit
+    /// allows a trial request.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open { until } => {
+                let now = Instant::now();
+                if now >= until {
+                    inner.state = CircuitState::HalfOpen { since: now };
+                    Ok(())
+                } else {
+                    Err(until - now)
+                }
+            }
+            // only the one trial request gets through, unless it never reported back
+            CircuitState::HalfOpen { since } => {
+                let now = Instant::now();
+                if now.duration_since(since) >= self.policy.open_duration {
+                    inner.state = CircuitState::HalfOpen { since: now };
+                    Ok(())
+                } else {
+                    Err(self.policy.open_duration - now.duration_since(since))
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.state = CircuitState::Closed;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        if matches!(inner.state, CircuitState::HalfOpen { .. })
+            || inner.consecutive_failures >= self.policy.failure_threshold
+        {
+            inner.state = CircuitState::Open {
+                until: Instant::now() + self.policy.open_duration,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use reqwest::{Method, StatusCode};
+    use std::time::Duration;
+
+    #[test]
+    fn test_only_idempotent_requests_retry_server_errors() {
+        let policy = RetryPolicy::default().idempotent_endpoint(Method::POST, "/v1/lobbies:start");
+        assert!(policy.is_idempotent(&Method::GET, "//v1/session/abc"));
+        assert!(!policy.is_idempotent(&Method::POST, "//v1/session"));
+        assert!(policy.is_idempotent(&Method::POST, "/v1/lobbies:start"));
+
+        assert!(policy.should_retry_status(StatusCode::TOO_MANY_REQUESTS, false));
+        assert!(policy.should_retry_status(StatusCode::BAD_GATEWAY, true));
+        assert!(!policy.should_retry_status(StatusCode::BAD_GATEWAY, false));
+        assert!(!policy.should_retry_status(StatusCode::NOT_FOUND, true));
+    }
+
+    #[test]
+    fn test_endpoint_timeouts_prefer_longest_prefix() {
+        let policy = RetryPolicy {
+            default_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        }
+        .endpoint_timeout(None, "/v1/session", Duration::from_secs(10))
+        .endpoint_timeout(Some(Method::DELETE), "/v1/session/", Duration::from_secs(5));
+
+        let timeout = |m: Method, p: &str| policy.timeout_for(&m, p);
+        assert_eq!(
+            timeout(Method::GET, "/v1/apps"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            timeout(Method::POST, "//v1/session"),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            timeout(Method::GET, "/v1/session/x"),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            timeout(Method::DELETE, "/v1/session/x"),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_retry_after_is_honoured() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            jitter: 0.0,
+            max_retry_after: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.retry_delay(2, None),
+            Some(Duration::from_millis(400))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert_eq!(
+            policy.retry_delay(0, Some(&headers)),
+            Some(Duration::from_secs(3))
+        );
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(policy.retry_delay(0, Some(&headers)), None);
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens_and_recovers() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(20),
+        });
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+        assert!(breaker.try_acquire().is_err());
+
+        std::thread::sleep(Duration::from_millis(25));
+        // one trial request is let through
+        assert!(breaker.try_acquire().is_ok());
+        assert!(breaker.try_acquire().is_err());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_failed_trial_reopens_circuit() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 1,
+            open_duration: Duration::from_millis(10),
+        });
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+    }
+}
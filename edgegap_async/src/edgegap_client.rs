@@ -0,0 +1,814 @@
+//! A hand-written client for the parts of the Edgegap API that bevygap uses.
+//!
+//! The generated `apis::*_api` functions each take a `&Configuration` and return their own
+//! error enum. [`EdgegapClient`] wraps them with typed methods that share one error type,
+//! [`EdgegapError`], and owns the configuration: base path, API key and retry policy.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), edgegap_async::EdgegapError> {
+//! use edgegap_async::{EdgegapClient, NewSession};
+//! use std::time::Duration;
+//!
+//! let client = EdgegapClient::from_env()?;
+//! let session = client
+//!     .create_session(NewSession::new("mygame").version("v1").client_ip("81.128.157.100"))
+//!     .await?;
+//! let ready = client
+//!     .wait_ready(&session.session_id, Duration::from_millis(500), Duration::from_secs(60))
+//!     .await?;
+//! println!("session ready on {:?}", ready.deployment.map(|d| d.fqdn));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Anything not covered here is still available by passing [`EdgegapClient::configuration`]
+//! to the generated functions.
+use crate::apis::configuration::{ApiKey, Configuration};
+use crate::apis::retry::RetryPolicy;
+use crate::apis::{
+    applications_api, context_api, deployments_api, lobbies_api, matchmaker_api, sessions_api,
+    Error as ApiError,
+};
+use crate::models;
+use futures::{pin_mut, stream, Stream, TryStreamExt};
+use reqwest::StatusCode;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+pub const DEFAULT_BASE_PATH: &str = "https://api.edgegap.com";
+
+/// Everything that can go wrong calling Edgegap through [`EdgegapClient`].
+#[derive(Debug)]
+pub enum EdgegapError {
+    /// Edgegap responded with an error status. `message` is from the response body if it had one.
+    Api {
+        status: StatusCode,
+        message: String,
+    },
+    /// The request couldn't be sent, or the response couldn't be read.
+    Http(reqwest::Error),
+    /// The response body wasn't what the API spec says it should be.
+    Decode(serde_json::Error),
+    Io(std::io::Error),
+    /// Not sent, because the circuit breaker is open after repeated failures.
+    CircuitOpen(Duration),
+    /// Neither EDGEGAP_API_KEY nor EDGEGAP_API_KEY_FILE was set, or the key file was empty.
+    MissingApiKey,
+    /// `ARBITRIUM_CONTEXT_URL` didn't look like `<base>/v1/context/<request_id>/<number>`.
+    InvalidContextUrl(String),
+    /// `ARBITRIUM_DELETE_URL` didn't look like `<base>/v1/self/stop/<request_id>/<number>`.
+    InvalidDeleteUrl(String),
+    /// Edgegap reported an error for the session while we were waiting for it.
+    SessionFailed {
+        session_id: String,
+        reason: String,
+    },
+    /// The session wasn't ready in time.
+    Timeout {
+        session_id: String,
+        waited: Duration,
+    },
+    /// Edgegap reported an error for the deployment while we were waiting for it.
+    DeploymentFailed {
+        request_id: String,
+        reason: String,
+    },
+    /// The deployment wasn't running in time.
+    DeploymentTimeout {
+        request_id: String,
+        waited: Duration,
+    },
+}
+
+impl EdgegapError {
+    /// The HTTP status, if Edgegap responded with an error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            EdgegapError::Api { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// True for the 404 and 410 responses you get when deleting something that's already gone.
+    pub fn is_gone(&self) -> bool {
+        matches!(
+            self.status(),
+            Some(StatusCode::NOT_FOUND) | Some(StatusCode::GONE)
+        )
+    }
+}
+
+impl fmt::Display for EdgegapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdgegapError::Api { status, message } => {
+                write!(f, "Edgegap API error {}: {message}", status.as_u16())
+            }
+            EdgegapError::Http(e) => write!(f, "Edgegap API request failed: {e}"),
+            EdgegapError::Decode(e) => write!(f, "unexpected Edgegap API response: {e}"),
+            EdgegapError::Io(e) => write!(f, "{e}"),
+            EdgegapError::CircuitOpen(retry_in) => write!(
+                f,
+                "Edgegap API unavailable, circuit breaker open (retry in {retry_in:?})"
+            ),
+            EdgegapError::MissingApiKey => write!(
+                f,
+                "Edgegap API key not set, use EDGEGAP_API_KEY or EDGEGAP_API_KEY_FILE"
+            ),
+            EdgegapError::InvalidContextUrl(url) => write!(f, "invalid context url: {url}"),
+            EdgegapError::InvalidDeleteUrl(url) => write!(f, "invalid delete url: {url}"),
+            EdgegapError::SessionFailed { session_id, reason } => {
+                write!(f, "session {session_id} failed: {reason}")
+            }
+            EdgegapError::Timeout { session_id, waited } => {
+                write!(f, "session {session_id} still not ready after {waited:?}")
+            }
+            EdgegapError::DeploymentFailed { request_id, reason } => {
+                write!(f, "deployment {request_id} failed: {reason}")
+            }
+            EdgegapError::DeploymentTimeout { request_id, waited } => {
+                write!(
+                    f,
+                    "deployment {request_id} still not running after {waited:?}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for EdgegapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EdgegapError::Http(e) => Some(e),
+            EdgegapError::Decode(e) => Some(e),
+            EdgegapError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<ApiError<T>> for EdgegapError {
+    fn from(e: ApiError<T>) -> Self {
+        match e {
+            ApiError::Reqwest(e) => EdgegapError::Http(e),
+            ApiError::Serde(e) => EdgegapError::Decode(e),
+            ApiError::Io(e) => EdgegapError::Io(e),
+            ApiError::CircuitOpen(retry_in) => EdgegapError::CircuitOpen(retry_in),
+            // the generated error enums are untagged, so their variant doesn't tell us anything
+            // the status doesn't. Just keep the message.
+            ApiError::ResponseError(resp) => EdgegapError::Api {
+                status: resp.status,
+                message: error_message(resp.status, &resp.content),
+            },
+        }
+    }
+}
+
+/// Edgegap errors usually look like `{"message": "..."}`, but not always.
+fn error_message(status: StatusCode, content: &str) -> String {
+    let from_json = serde_json::from_str::<serde_json::Value>(content)
+        .ok()
+        .and_then(|v| v.get("message")?.as_str().map(str::to_string));
+    match from_json {
+        Some(message) => message,
+        None if !content.trim().is_empty() => content.trim().to_string(),
+        None => status
+            .canonical_reason()
+            .unwrap_or("unknown error")
+            .to_string(),
+    }
+}
+
+/// Builds the request for [`EdgegapClient::create_session`].
+#[derive(Debug, Clone)]
+pub struct NewSession {
+    model: models::SessionModel,
+}
+
+impl NewSession {
+    pub fn new(app_name: impl Into<String>) -> Self {
+        Self {
+            model: models::SessionModel::new(app_name.into()),
+        }
+    }
+
+    /// The app version, otherwise Edgegap uses the app's default version.
+    pub fn version(mut self, version_name: impl Into<String>) -> Self {
+        self.model.version_name = Some(version_name.into());
+        self
+    }
+
+    /// A player's IP, used to pick a deployment near them. Can be called once per player.
+    pub fn client_ip(mut self, ip: impl Into<String>) -> Self {
+        self.model
+            .ip_list
+            .get_or_insert_with(Vec::new)
+            .push(ip.into());
+        self
+    }
+
+    /// Only place the session in this region.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.model.region = Some(region.into());
+        self
+    }
+
+    /// Only place the session on this continent.
+    pub fn continent(mut self, continent: impl Into<String>) -> Self {
+        self.model.continent = Some(continent.into());
+        self
+    }
+
+    /// Only place the session in this country.
+    pub fn country(mut self, country: impl Into<String>) -> Self {
+        self.model.country = Some(country.into());
+        self
+    }
+
+    /// Only place the session in this city.
+    pub fn city(mut self, city: impl Into<String>) -> Self {
+        self.model.city = Some(city.into());
+        self
+    }
+
+    /// Only link the session to deployments with this tag. Can be called more than once.
+    pub fn selector(mut self, tag: impl Into<String>) -> Self {
+        self.model
+            .selectors
+            .get_or_insert_with(Vec::new)
+            .push(models::SelectorModel::new(tag.into()));
+        self
+    }
+
+    /// Link the session to this deployment, instead of letting Edgegap pick one.
+    pub fn deployment(mut self, request_id: impl Into<String>) -> Self {
+        self.model.deployment_request_id = Some(request_id.into());
+        self
+    }
+
+    /// Where Edgegap should POST session status updates.
+    pub fn webhook_url(mut self, url: Option<String>) -> Self {
+        self.model.webhook_url = url;
+        self
+    }
+}
+
+impl From<NewSession> for models::SessionModel {
+    fn from(session: NewSession) -> Self {
+        session.model
+    }
+}
+
+/// See the module docs.
+#[derive(Debug, Clone)]
+pub struct EdgegapClient {
+    configuration: Configuration,
+}
+
+impl EdgegapClient {
+    /// A client using this API key, which should include the "token " prefix.
+    pub fn new(api_key: impl Into<String>) -> Self {
+        let mut client = Self::unauthenticated();
+        client.configuration.api_key = Some(ApiKey {
+            prefix: None,
+            key: api_key.into(),
+        });
+        client
+    }
+
+    /// A client without an API key, which can only use [`Self::context_for_self`].
+    pub fn unauthenticated() -> Self {
+        Self {
+            configuration: Configuration {
+                base_path: DEFAULT_BASE_PATH.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Reads the API key from `EDGEGAP_API_KEY`, or from the file named by
+    /// `EDGEGAP_API_KEY_FILE` (eg a docker secret). `EDGEGAP_BASE_PATH` overrides the base path.
+    pub fn from_env() -> Result<Self, EdgegapError> {
+        let client = match std::env::var("EDGEGAP_API_KEY") {
+            Ok(key) if !key.trim().is_empty() => Self::new(key.trim()),
+            _ => match std::env::var_os("EDGEGAP_API_KEY_FILE") {
+                Some(path) => Self::from_key_file(path)?,
+                None => return Err(EdgegapError::MissingApiKey),
+            },
+        };
+        Ok(match std::env::var("EDGEGAP_BASE_PATH") {
+            Ok(base_path) if !base_path.is_empty() => client.with_base_path(base_path),
+            _ => client,
+        })
+    }
+
+    /// Reads the API key from a file, ignoring surrounding whitespace.
+    pub fn from_key_file(path: impl AsRef<Path>) -> Result<Self, EdgegapError> {
+        let key = std::fs::read_to_string(path).map_err(EdgegapError::Io)?;
+        if key.trim().is_empty() {
+            return Err(EdgegapError::MissingApiKey);
+        }
+        Ok(Self::new(key.trim()))
+    }
+
+    pub fn with_base_path(mut self, base_path: impl Into<String>) -> Self {
+        self.configuration.base_path = base_path.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Enables retries, timeouts and the circuit breaker, see [`RetryPolicy`].
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.configuration = self.configuration.with_retry_policy(policy);
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.configuration.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Uses a configuration as-is, eg one pointed at a test server.
+    pub fn with_configuration(configuration: Configuration) -> Self {
+        Self { configuration }
+    }
+
+    /// For calling the generated `apis::*_api` functions directly.
+    pub fn configuration(&self) -> &Configuration {
+        &self.configuration
+    }
+
+    pub async fn application(&self, app_name: &str) -> Result<models::Application, EdgegapError> {
+        Ok(applications_api::application_get(&self.configuration, app_name).await?)
+    }
+
+    pub async fn app_version(
+        &self,
+        app_name: &str,
+        version_name: &str,
+    ) -> Result<models::AppVersionPayload, EdgegapError> {
+        Ok(applications_api::app_version_get(&self.configuration, app_name, version_name).await?)
+    }
+
+    /// Asks Edgegap for a session. It usually won't be ready yet, see [`Self::wait_ready`].
+    pub async fn create_session(
+        &self,
+        session: impl Into<models::SessionModel>,
+    ) -> Result<models::SessionRequest, EdgegapError> {
+        Ok(sessions_api::session_post(&self.configuration, session.into()).await?)
+    }
+
+    pub async fn get_session(&self, session_id: &str) -> Result<models::SessionGet, EdgegapError> {
+        Ok(sessions_api::get_session(&self.configuration, session_id).await?)
+    }
+
+    /// Gets the session now, then every `interval` until it's ready. The last item is the ready
+    /// session, or an error if Edgegap reports one for the session.
+    pub fn poll_session<'a>(
+        &'a self,
+        session_id: &'a str,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<models::SessionGet, EdgegapError>> + 'a {
+        stream::try_unfold(Some(true), move |first| async move {
+            let Some(first) = first else {
+                return Ok(None);
+            };
+            if !first {
+                tokio::time::sleep(interval).await;
+            }
+            let session = self.get_session(session_id).await?;
+            if let Some(reason) = session.error.clone().filter(|e| !e.is_empty()) {
+                return Err(EdgegapError::SessionFailed {
+                    session_id: session_id.to_string(),
+                    reason,
+                });
+            }
+            let next = (!session.ready).then_some(false);
+            Ok(Some((session, next)))
+        })
+    }
+
+    /// Polls the session every `interval` until it's ready, giving up after `timeout`.
+    pub async fn wait_ready(
+        &self,
+        session_id: &str,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<models::SessionGet, EdgegapError> {
+        let wait = async {
+            let polls = self.poll_session(session_id, interval);
+            pin_mut!(polls);
+            let mut last = None;
+            while let Some(session) = polls.try_next().await? {
+                last = Some(session);
+            }
+            Ok(last.expect("poll_session yields until the session is ready"))
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or_else(|_| {
+                Err(EdgegapError::Timeout {
+                    session_id: session_id.to_string(),
+                    waited: timeout,
+                })
+            })
+    }
+
+    /// Deletes a session. Use [`EdgegapError::is_gone`] to spot sessions that were already gone.
+    pub async fn delete_session(
+        &self,
+        session_id: &str,
+    ) -> Result<models::SessionDelete, EdgegapError> {
+        Ok(sessions_api::session_delete(&self.configuration, session_id).await?)
+    }
+
+    /// Adds players, by IP, to an existing session on its deployment.
+    pub async fn add_session_users(
+        &self,
+        session_id: &str,
+        ip_list: Vec<String>,
+    ) -> Result<models::SessionUserContext, EdgegapError> {
+        let payload = models::PatchSessionModel::new(ip_list);
+        Ok(sessions_api::put_users_session(&self.configuration, session_id, payload).await?)
+    }
+
+    /// Removes players, by IP, from a session. The session lives on until it's deleted.
+    pub async fn remove_session_users(
+        &self,
+        session_id: &str,
+        ip_list: Vec<String>,
+    ) -> Result<models::SessionUserContext, EdgegapError> {
+        let payload = models::PatchSessionModel::new(ip_list);
+        Ok(sessions_api::delete_users_session(&self.configuration, session_id, payload).await?)
+    }
+
+    /// Starts a dedicated deployment.
+    pub async fn deploy(
+        &self,
+        deployment: models::DeployModel,
+    ) -> Result<models::Request, EdgegapError> {
+        Ok(deployments_api::deploy(&self.configuration, deployment).await?)
+    }
+
+    pub async fn deployment_status(
+        &self,
+        request_id: &str,
+    ) -> Result<models::Status, EdgegapError> {
+        Ok(deployments_api::deployment_status_get(&self.configuration, request_id).await?)
+    }
+
+    /// Polls the deployment's status every `interval` until it's running, giving up after
+    /// `timeout`.
+    pub async fn wait_deployment_ready(
+        &self,
+        request_id: &str,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<models::Status, EdgegapError> {
+        let wait = async {
+            loop {
+                let status = self.deployment_status(request_id).await?;
+                if status.error {
+                    return Err(EdgegapError::DeploymentFailed {
+                        request_id: request_id.to_string(),
+                        reason: status
+                            .error_detail
+                            .filter(|e| !e.is_empty())
+                            .unwrap_or(status.current_status),
+                    });
+                }
+                if status.running {
+                    return Ok(status);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or_else(|_| {
+                Err(EdgegapError::DeploymentTimeout {
+                    request_id: request_id.to_string(),
+                    waited: timeout,
+                })
+            })
+    }
+
+    /// Stops a deployment by its request id.
+    pub async fn stop_deployment(&self, request_id: &str) -> Result<models::Delete, EdgegapError> {
+        Ok(deployments_api::deployment_delete(&self.configuration, request_id, None).await?)
+    }
+
+    /// Gets a hosted lobby service by name. Its `url` is empty until it has been deployed.
+    /// Use [`EdgegapError::is_gone`] to spot services that don't exist.
+    pub async fn lobby_service(
+        &self,
+        name: &str,
+    ) -> Result<models::LobbyReadResponse, EdgegapError> {
+        Ok(lobbies_api::lobby_get(&self.configuration, name).await?)
+    }
+
+    /// Creates a hosted lobby service, which then needs deploying.
+    pub async fn create_lobby_service(
+        &self,
+        name: &str,
+    ) -> Result<models::LobbyReadResponse, EdgegapError> {
+        let payload = models::LobbyCreatePayload::new(name.to_string());
+        Ok(lobbies_api::lobby_create(&self.configuration, payload).await?)
+    }
+
+    /// Deploys, or redeploys, a hosted lobby service.
+    pub async fn deploy_lobby_service(
+        &self,
+        name: &str,
+    ) -> Result<models::LobbyReadResponse, EdgegapError> {
+        let payload = models::LobbyDeployPayload::new(name.to_string());
+        Ok(lobbies_api::lobby_deploy(&self.configuration, payload).await?)
+    }
+
+    /// Stops a hosted lobby service, keeping it so it can be deployed again.
+    pub async fn terminate_lobby_service(
+        &self,
+        name: &str,
+    ) -> Result<models::LobbyReadResponse, EdgegapError> {
+        let payload = models::LobbyTerminatePayload::new(name.to_string());
+        Ok(lobbies_api::lobby_terminate(&self.configuration, payload).await?)
+    }
+
+    /// Gets a release of an Edgegap-managed matchmaker, including the app and version it deploys.
+    pub async fn matchmaker_release(
+        &self,
+        matchmaker_name: &str,
+        release_version: &str,
+    ) -> Result<models::MatchmakerManagedReleaseResponse, EdgegapError> {
+        Ok(matchmaker_api::get_matchmaker_managed_release(
+            &self.configuration,
+            matchmaker_name,
+            release_version,
+        )
+        .await?)
+    }
+
+    /// Fetches a gameserver's own deployment, using the `ARBITRIUM_CONTEXT_URL` and
+    /// `ARBITRIUM_CONTEXT_TOKEN` that Edgegap gives each deployment. Needs no API key.
+    pub async fn context_for_self(
+        &self,
+        context_url: &str,
+        context_token: &str,
+    ) -> Result<models::Deployment, EdgegapError> {
+        let invalid = || EdgegapError::InvalidContextUrl(context_url.to_string());
+        let (base_path, rest) = context_url.split_once("/v1/context/").ok_or_else(invalid)?;
+        let (request_id, security_number) = rest
+            .trim_end_matches('/')
+            .split_once('/')
+            .ok_or_else(invalid)?;
+        let security_number = security_number.parse().map_err(|_| invalid())?;
+        let configuration = Configuration {
+            base_path: base_path.to_string(),
+            ..self.configuration.clone()
+        };
+        Ok(
+            context_api::context_get(&configuration, request_id, security_number, context_token)
+                .await?,
+        )
+    }
+
+    /// Stops a gameserver's own deployment, using the `ARBITRIUM_DELETE_URL` and
+    /// `ARBITRIUM_DELETE_TOKEN` that Edgegap gives each deployment. Needs no API key.
+    pub async fn delete_self(
+        &self,
+        delete_url: &str,
+        delete_token: &str,
+    ) -> Result<models::Delete, EdgegapError> {
+        let invalid = || EdgegapError::InvalidDeleteUrl(delete_url.to_string());
+        let (base_path, rest) = delete_url
+            .split_once("/v1/self/stop/")
+            .ok_or_else(invalid)?;
+        let (request_id, access_point_id) = rest
+            .trim_end_matches('/')
+            .split_once('/')
+            .ok_or_else(invalid)?;
+        let access_point_id = access_point_id.parse().map_err(|_| invalid())?;
+        let configuration = Configuration {
+            base_path: base_path.to_string(),
+            ..self.configuration.clone()
+        };
+        Ok(deployments_api::self_deployment_delete(
+            &configuration,
+            request_id,
+            access_point_id,
+            delete_token,
+            None,
+        )
+        .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_create_and_wait_for_session() {
+        let server = FixtureServer::from_env("session_lifecycle").await.unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+
+        let new_session = NewSession::new("mygame")
+            .version("v1")
+            .client_ip("81.128.157.100")
+            .webhook_url(Some("https://example.com/edgegap/session".to_string()));
+        let session = client.create_session(new_session).await.unwrap();
+        let ready = client
+            .wait_ready(
+                &session.session_id,
+                Duration::from_millis(1),
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert!(ready.ready);
+        assert_eq!(ready.deployment.unwrap().public_ip, "172.104.244.82");
+
+        client.delete_session(&session.session_id).await.unwrap();
+        let gone = client.delete_session("gone-S").await.unwrap_err();
+        assert!(gone.is_gone());
+        assert!(server.misses().is_empty());
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_errors_keep_status_and_message() {
+        let server = FixtureServer::from_env("session_post_errors")
+            .await
+            .unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+
+        let mut errors = Vec::new();
+        for _ in 0..4 {
+            match client.create_session(NewSession::new("mygame")).await {
+                Err(EdgegapError::Api { status, message }) => errors.push((status, message)),
+                other => panic!("expected an api error, got {other:?}"),
+            }
+        }
+        assert_eq!(
+            errors,
+            [
+                (
+                    StatusCode::BAD_REQUEST,
+                    "No available location for this session".to_string()
+                ),
+                (StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
+                (
+                    StatusCode::CONFLICT,
+                    "App version is not active".to_string()
+                ),
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Too Many Requests".to_string()
+                ),
+            ]
+        );
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_context_for_self() {
+        let server = FixtureServer::from_env("context").await.unwrap();
+        let client = EdgegapClient::unauthenticated();
+
+        let url = format!("{}/v1/context/b1e6c8a2f4d9/7331", server.base_path());
+        let context = client.context_for_self(&url, "ctx-token").await.unwrap();
+        assert_eq!(context.fqdn, "b1e6c8a2f4d9.pr.edgegap.net");
+        assert_eq!(context.sockets, Some(10));
+
+        let url = format!("{}/v1/context/b1e6c8a2f4d9/1", server.base_path());
+        let err = client.context_for_self(&url, "wrong").await.unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::UNAUTHORIZED));
+
+        assert!(matches!(
+            client
+                .context_for_self("https://example.com/nope", "x")
+                .await,
+            Err(EdgegapError::InvalidContextUrl(_))
+        ));
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_self() {
+        let server = FixtureServer::from_env("self_delete").await.unwrap();
+        let client = EdgegapClient::unauthenticated();
+
+        let url = format!("{}/v1/self/stop/b1e6c8a2f4d9/7331", server.base_path());
+        let deleted = client.delete_self(&url, "delete-token").await.unwrap();
+        assert!(deleted.message.contains("b1e6c8a2f4d9"));
+
+        assert!(matches!(
+            client
+                .delete_self("https://example.com/v1/self/stop/x", "x")
+                .await,
+            Err(EdgegapError::InvalidDeleteUrl(_))
+        ));
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_session_users() {
+        let server = FixtureServer::from_env("session_users").await.unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+
+        let users = client
+            .add_session_users("950dd2eaff09-S", vec!["81.128.157.124".into()])
+            .await
+            .unwrap();
+        assert_eq!(users.session_users.len(), 2);
+
+        let users = client
+            .remove_session_users("950dd2eaff09-S", vec!["81.128.157.123".into()])
+            .await
+            .unwrap();
+        let ips: Vec<_> = users.session_users.iter().map(|u| u.ip.as_str()).collect();
+        assert_eq!(ips, ["81.128.157.124"]);
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deploy_and_wait_for_deployment() {
+        let server = FixtureServer::from_env("deployment_lifecycle")
+            .await
+            .unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+
+        let mut deploy = crate::models::DeployModel::new("mygame".to_string());
+        deploy.version_name = Some("v1".to_string());
+        deploy.ip_list = Some(vec!["81.128.157.100".into(), "81.128.157.123".into()]);
+        deploy.tags = Some(vec!["lobby-ROOM001".to_string()]);
+        let request = client.deploy(deploy).await.unwrap();
+
+        let status = client
+            .wait_deployment_ready(
+                &request.request_id,
+                Duration::from_millis(1),
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert!(status.running);
+        assert_eq!(status.ports.unwrap()["gameport"].external, Some(31722));
+
+        let failed = client
+            .wait_deployment_ready(
+                "0badc0ffee00",
+                Duration::from_millis(1),
+                Duration::from_secs(5),
+            )
+            .await;
+        assert!(matches!(
+            failed,
+            Err(EdgegapError::DeploymentFailed { reason, .. }) if reason == "Image pull failed"
+        ));
+
+        client.stop_deployment(&request.request_id).await.unwrap();
+        assert!(server.misses().is_empty());
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_deploy_and_terminate_lobby_service() {
+        let server = FixtureServer::from_env("lobby_service").await.unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+
+        let missing = client.lobby_service("mygame-lobby").await.unwrap_err();
+        assert!(missing.is_gone());
+        let created = client.create_lobby_service("mygame-lobby").await.unwrap();
+        assert!(created.url.is_empty());
+        let deployed = client.deploy_lobby_service("mygame-lobby").await.unwrap();
+        assert_eq!(deployed.url, "https://f2c8a1b9e3d7.edgegap.net");
+        client
+            .terminate_lobby_service("mygame-lobby")
+            .await
+            .unwrap();
+        assert!(server.misses().is_empty());
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_matchmaker_release() {
+        let server = FixtureServer::from_env("matchmaker_release").await.unwrap();
+        let client = EdgegapClient::with_configuration(server.configuration());
+
+        let release = client.matchmaker_release("mygame-mm", "r1").await.unwrap();
+        assert_eq!(
+            (release.app_name.as_str(), release.version_name.as_str()),
+            ("mygame", "v1")
+        );
+        let missing = client
+            .matchmaker_release("mygame-mm", "r2")
+            .await
+            .unwrap_err();
+        assert!(missing.is_gone());
+        server.finish().await.unwrap();
+    }
+}
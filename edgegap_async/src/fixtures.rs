@@ -0,0 +1,591 @@
+//! Record/replay HTTP fixtures, for testing code that talks to the Edgegap API without a network.
+//!
+//! A [`FixtureServer`] is a small local HTTP server. Point a [`Configuration`] at it with
+//! [`FixtureServer::configuration`], then make API calls as normal:
+//!
+//! * **replay** – answers each request from a [`Cassette`] of recorded interactions, matched by
+//!   method, path and (if recorded) query string. Requests it has no fixture for get a 404 and
+//!   show up in [`FixtureServer::misses`].
+//! * **record** – forwards each request to the real API and saves the responses to the cassette
+//!   file when [`FixtureServer::finish`] is called.
+//!
+//! [`FixtureServer::from_env`] records if `EDGEGAP_RECORD` is set, using `EDGEGAP_API_KEY` and
+//! optionally `EDGEGAP_BASE_PATH`, and replays otherwise. To re-record a cassette:
+//!
+//! ```text
+//! EDGEGAP_RECORD=1 EDGEGAP_API_KEY="token ..." cargo test -p edgegap_async
+//! ```
+//!
+//! Cassettes only contain responses and the request bodies we sent – never the api key.
+//! Enabled with the `fixtures` feature, for use from other crates' tests.
+use crate::apis::configuration::{ApiKey, Configuration};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Response headers worth keeping in a cassette. Everything else is dropped when recording.
+const RECORDED_HEADERS: &[&str] = &["content-type", "retry-after"];
+
+/// The path of a cassette in this crate's `fixtures` directory.
+pub fn fixture_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(format!("{name}.json"))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// The url path, relative to the base path, eg `/v1/session/abc`
+    pub path: String,
+    /// If set, only requests with exactly this query string match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// The JSON body. Non-JSON bodies are stored as a string in `text` instead.
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub body: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl RecordedResponse {
+    fn from_text(status: u16, headers: BTreeMap<String, String>, text: String) -> Self {
+        match serde_json::from_str(&text) {
+            Ok(body) => Self {
+                status,
+                headers,
+                body,
+                text: None,
+            },
+            Err(_) => Self {
+                status,
+                headers,
+                body: serde_json::Value::Null,
+                text: (!text.is_empty()).then_some(text),
+            },
+        }
+    }
+
+    fn body_text(&self) -> String {
+        match (&self.text, &self.body) {
+            (Some(text), _) => text.clone(),
+            (None, serde_json::Value::Null) => String::new(),
+            (None, body) => body.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+/// A list of interactions, stored as one JSON file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        serde_json::from_str(&json).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        std::fs::write(path, json + "\n")
+    }
+}
+
+enum Mode {
+    Replay,
+    Record {
+        upstream: Box<Configuration>,
+        http: reqwest::Client,
+        save_to: PathBuf,
+    },
+}
+
+#[derive(Default)]
+struct ServerState {
+    cassette: Cassette,
+    /// Whether each cassette interaction has been replayed yet
+    used: Vec<bool>,
+    received: Vec<RecordedRequest>,
+    misses: Vec<RecordedRequest>,
+}
+
+impl ServerState {
+    /// Replays the first unused matching interaction. Once they're all used up, the last match
+    /// keeps being replayed, so a recorded polling loop can run for longer than it did live.
+    fn replay(&mut self, request: &RecordedRequest) -> Option<RecordedResponse> {
+        let matching: Vec<usize> = self
+            .cassette
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| matches(&i.request, request))
+            .map(|(n, _)| n)
+            .collect();
+        let n = matching
+            .iter()
+            .copied()
+            .find(|n| !self.used[*n])
+            .or(matching.last().copied())?;
+        self.used[n] = true;
+        Some(self.cassette.interactions[n].response.clone())
+    }
+}
+
+fn matches(recorded: &RecordedRequest, request: &RecordedRequest) -> bool {
+    recorded.method.eq_ignore_ascii_case(&request.method)
+        && recorded.path == request.path
+        && recorded
+            .query
+            .as_ref()
+            .is_none_or(|q| Some(q) == request.query.as_ref())
+}
+
+/// A local HTTP server that replays or records Edgegap API responses. See the module docs.
+pub struct FixtureServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    mode: Arc<Mode>,
+    task: JoinHandle<()>,
+}
+
+impl FixtureServer {
+    /// Serves the interactions in `cassette`.
+    pub async fn replay(cassette: Cassette) -> io::Result<Self> {
+        let used = vec![false; cassette.interactions.len()];
+        let state = ServerState {
+            cassette,
+            used,
+            ..Default::default()
+        };
+        Self::start(state, Mode::Replay).await
+    }
+
+    /// Forwards requests to `upstream`, saving the interactions to `save_to` on [`Self::finish`].
+    pub async fn record(upstream: Configuration, save_to: impl Into<PathBuf>) -> io::Result<Self> {
+        let mode = Mode::Record {
+            upstream: Box::new(upstream),
+            http: reqwest::Client::new(),
+            save_to: save_to.into(),
+        };
+        Self::start(ServerState::default(), mode).await
+    }
+
+    /// Replays the named cassette from the `fixtures` directory, or records it if
+    /// `EDGEGAP_RECORD` is set.
+    pub async fn from_env(name: &str) -> io::Result<Self> {
+        let path = fixture_path(name);
+        if std::env::var_os("EDGEGAP_RECORD").is_none() {
+            return Self::replay(Cassette::load(&path)?).await;
+        }
+        let key = std::env::var("EDGEGAP_API_KEY").map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "EDGEGAP_RECORD needs EDGEGAP_API_KEY to be set",
+            )
+        })?;
+        let upstream = Configuration {
+            base_path: std::env::var("EDGEGAP_BASE_PATH")
+                .unwrap_or_else(|_| "https://api.edgegap.com".to_string()),
+            api_key: Some(ApiKey { prefix: None, key }),
+            ..Default::default()
+        };
+        Self::record(upstream, path).await
+    }
+
+    async fn start(state: ServerState, mode: Mode) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(state));
+        let mode = Arc::new(mode);
+        let task = tokio::spawn({
+            let state = state.clone();
+            let mode = mode.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+                    let mode = mode.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &state, &mode).await {
+                            log::warn!("fixture server connection failed: {e}");
+                        }
+                    });
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            state,
+            mode,
+            task,
+        })
+    }
+
+    pub fn base_path(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// A configuration pointed at this server, with a dummy api key.
+    pub fn configuration(&self) -> Configuration {
+        Configuration {
+            base_path: self.base_path(),
+            api_key: Some(ApiKey {
+                prefix: None,
+                key: "token fixture".to_string(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Every request received so far, in order.
+    pub fn received(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Requests that had no matching fixture, when replaying.
+    pub fn misses(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().misses.clone()
+    }
+
+    /// Stops the server. When recording, saves the cassette.
+    pub async fn finish(self) -> io::Result<()> {
+        self.task.abort();
+        if let Mode::Record { save_to, .. } = self.mode.as_ref() {
+            let cassette = self.state.lock().unwrap().cassette.clone();
+            cassette.save(save_to)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    state: &Mutex<ServerState>,
+    mode: &Mode,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(());
+    };
+    let method = method.to_string();
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    // the generated code joins "{base_path}/v1/..", so tolerate a doubled slash
+    let path = format!("/{}", path.trim_start_matches('/'));
+
+    let mut content_length = 0;
+    let mut content_type = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "content-type" => content_type = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    let request = RecordedRequest {
+        method,
+        path,
+        query,
+        body: serde_json::from_slice(&body).ok(),
+    };
+    state.lock().unwrap().received.push(request.clone());
+
+    let response = match mode {
+        Mode::Replay => {
+            let replayed = state.lock().unwrap().replay(&request);
+            replayed.unwrap_or_else(|| {
+                state.lock().unwrap().misses.push(request.clone());
+                RecordedResponse {
+                    status: 404,
+                    headers: BTreeMap::new(),
+                    body: serde_json::json!({
+                        "message": format!("no fixture for {} {}", request.method, request.path)
+                    }),
+                    text: None,
+                }
+            })
+        }
+        Mode::Record { upstream, http, .. } => {
+            let response = forward(upstream, http, &request, content_type, body).await;
+            let response = response.unwrap_or_else(|e| RecordedResponse {
+                status: 502,
+                headers: BTreeMap::new(),
+                body: serde_json::Value::Null,
+                text: Some(format!("fixture recorder couldn't reach upstream: {e}")),
+            });
+            let mut state = state.lock().unwrap();
+            state.cassette.interactions.push(Interaction {
+                request: request.clone(),
+                response: response.clone(),
+            });
+            state.used.push(true);
+            response
+        }
+    };
+
+    let mut stream = reader.into_inner();
+    stream.write_all(&encode_response(&response)).await?;
+    stream.shutdown().await
+}
+
+async fn forward(
+    upstream: &Configuration,
+    http: &reqwest::Client,
+    request: &RecordedRequest,
+    content_type: Option<String>,
+    body: Vec<u8>,
+) -> Result<RecordedResponse, reqwest::Error> {
+    let mut url = format!(
+        "{}{}",
+        upstream.base_path.trim_end_matches('/'),
+        request.path
+    );
+    if let Some(query) = &request.query {
+        url = format!("{url}?{query}");
+    }
+    let method =
+        reqwest::Method::from_bytes(request.method.as_bytes()).unwrap_or(reqwest::Method::GET);
+    let mut req = http.request(method, url).body(body);
+    if let Some(content_type) = content_type {
+        req = req.header(reqwest::header::CONTENT_TYPE, content_type);
+    }
+    if let Some(apikey) = &upstream.api_key {
+        let value = match &apikey.prefix {
+            Some(prefix) => format!("{} {}", prefix, apikey.key),
+            None => apikey.key.clone(),
+        };
+        req = req.header("authorization", value);
+    }
+    let resp = req.send().await?;
+    let status = resp.status().as_u16();
+    let headers = resp
+        .headers()
+        .iter()
+        .filter(|(name, _)| RECORDED_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let text = resp.text().await?;
+    Ok(RecordedResponse::from_text(status, headers, text))
+}
+
+fn encode_response(response: &RecordedResponse) -> Vec<u8> {
+    let body = response.body_text();
+    let reason = reqwest::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("");
+    let mut head = format!("HTTP/1.1 {} {reason}\r\n", response.status);
+    if !response.headers.contains_key("content-type") {
+        head.push_str("content-type: application/json\r\n");
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!(
+        "content-length: {}\r\nconnection: close\r\n\r\n",
+        body.len()
+    ));
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(body.as_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apis::applications_api::*;
+    use crate::apis::pagination::list_sessions_stream;
+    use crate::apis::sessions_api::*;
+    use crate::apis::Error;
+    use crate::models::SessionModel;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn test_all_cassettes_load() {
+        let dir = fixture_path("x").parent().unwrap().to_path_buf();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let cassette = Cassette::load(entry.unwrap().path()).unwrap();
+            assert!(!cassette.interactions.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() {
+        let server = FixtureServer::from_env("session_lifecycle").await.unwrap();
+        let config = server.configuration();
+
+        let session_model = SessionModel {
+            app_name: "mygame".to_string(),
+            version_name: Some("v1".to_string()),
+            ip_list: Some(vec!["81.128.157.100".to_string()]),
+            webhook_url: Some("https://example.com/edgegap/session".to_string()),
+            ..Default::default()
+        };
+        let session = session_post(&config, session_model).await.unwrap();
+        assert_eq!(session.session_id, "4bd5b3f3c6a7-S");
+        // the request body we sent matches the one recorded
+        let cassette = Cassette::load(fixture_path("session_lifecycle")).unwrap();
+        assert_eq!(server.received()[0], cassette.interactions[0].request);
+
+        let seeking = get_session(&config, &session.session_id).await.unwrap();
+        assert!(!seeking.ready);
+        assert!(seeking.deployment.is_none());
+
+        let ready = get_session(&config, &session.session_id).await.unwrap();
+        assert!(ready.ready);
+        let deployment = ready.deployment.unwrap();
+        assert_eq!(deployment.fqdn, "b1e6c8a2f4d9.pr.edgegap.net");
+        let port = &deployment.ports.unwrap()["gameport"];
+        assert_eq!((port.external, port.internal), (Some(31504), Some(6420)));
+        assert_eq!(deployment.location.unwrap().city, "London");
+
+        // polling past the end of the recording keeps returning the last response
+        assert!(
+            get_session(&config, &session.session_id)
+                .await
+                .unwrap()
+                .ready
+        );
+
+        let deleted = session_delete(&config, &session.session_id).await.unwrap();
+        assert_eq!(deleted.session_id, session.session_id);
+
+        match session_delete(&config, "gone-S").await {
+            Err(Error::ResponseError(e)) => {
+                assert_eq!(e.status.as_u16(), 410);
+                assert!(e.entity.is_some());
+            }
+            other => panic!("expected a 410, got {other:?}"),
+        }
+        assert!(server.misses().is_empty());
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_post_errors() {
+        let server = FixtureServer::from_env("session_post_errors")
+            .await
+            .unwrap();
+        let config = server.configuration();
+
+        for (status, message) in [
+            (400, "No available location for this session"),
+            (401, "Invalid token"),
+            (409, "App version is not active"),
+        ] {
+            match session_post(&config, SessionModel::default()).await {
+                Err(Error::ResponseError(e)) => {
+                    assert_eq!(e.status.as_u16(), status);
+                    // every variant wraps the same model, and the enum is untagged, so the
+                    // body always decodes as the first variant. Only the status is reliable.
+                    match e.entity {
+                        Some(SessionPostError::Status409(err)) => assert_eq!(err.message, message),
+                        other => panic!("unexpected entity {other:?}"),
+                    }
+                }
+                other => panic!("expected a {status}, got {other:?}"),
+            }
+        }
+
+        match session_post(&config, SessionModel::default()).await {
+            Err(Error::ResponseError(e)) => {
+                assert_eq!(e.status.as_u16(), 429);
+                assert_eq!(e.content, "Too Many Requests");
+                assert!(e.entity.is_none());
+            }
+            other => panic!("expected a 429, got {other:?}"),
+        }
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_stream_pages() {
+        let server = FixtureServer::from_env("list_sessions_paged")
+            .await
+            .unwrap();
+        let config = server.configuration();
+        let ids: Vec<String> = list_sessions_stream(&config, 2)
+            .map_ok(|s| s.session_id)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(ids, ["a1-S", "a2-S", "a3-S"]);
+        assert!(server.misses().is_empty());
+        server.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_application_and_version() {
+        let server = FixtureServer::from_env("application").await.unwrap();
+        let config = server.configuration();
+
+        assert!(application_get(&config, "mygame").await.unwrap().is_active);
+
+        let version = app_version_get(&config, "mygame", "v1").await.unwrap();
+        assert_eq!(version.is_active, Some(true));
+        assert_eq!(version.session_config.unwrap().sockets, 10);
+        assert_eq!(version.ports.unwrap()[0].port, 6420);
+
+        match app_version_get(&config, "mygame", "nope").await {
+            Err(Error::ResponseError(e)) => assert_eq!(e.status.as_u16(), 404),
+            other => panic!("expected a 404, got {other:?}"),
+        }
+        // anything not in the cassette is reported as a miss
+        assert!(application_get(&config, "other").await.is_err());
+        assert_eq!(server.misses()[0].path, "/v1/app/other");
+        server.finish().await.unwrap();
+    }
+}
@@ -8,4 +8,10 @@ extern crate url;
 extern crate reqwest;
 
 pub mod apis;
+pub mod edgegap_client;
 pub mod models;
+
+pub use edgegap_client::{EdgegapClient, EdgegapError, NewSession};
+
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;